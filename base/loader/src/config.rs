@@ -18,7 +18,7 @@
  */
 
 use alloc::string::{String, ToString};
-use crate::{firmware, fs, ldrprintln, uuid::GUID};
+use crate::{firmware, fs::{self, ChecksumPolicy, FsError, MountOptions}, ldrprintln, uuid::GUID};
 
 
 pub struct Config {
@@ -27,9 +27,10 @@ pub struct Config {
 }
 
 impl Default for Config {
+    /// When booted from a CD, the root is the disc itself unless the config says otherwise
     fn default() -> Self {
         Self {
-            rootfs:     GUID::new(0,0,0, [0; 8]),
+            rootfs:     firmware::misc::get_boot_disc().unwrap_or(GUID::new(0,0,0, [0; 8])),
             resolution: String::from("native"),
         }
     }
@@ -38,28 +39,64 @@ impl Default for Config {
 
 
 /// Reads and parses the cfg file from the ESP
-pub fn parse_cfg() -> Config {
-    let file = fs::File::open_by_guid(firmware::misc::get_esp_guid(), "/EFI/BOOT/ZOS/LOADER.CFG");
-    let s = file.read_to_string().unwrap();
+pub fn parse_cfg() -> Result<Config, FsError> {
+    let file = fs::File::open_by_guid(firmware::misc::get_esp_guid()?, "/EFI/BOOT/ZOS/LOADER.CFG");
+    let s = file.read_to_string()?;
 
     let mut config = Config::default();
+    let mut root = None;
     for line in s.lines() {
         let (key, value) = parse_key_value_pair(line);
 
         match key.as_str() {
+            // Looked up after every other option, since finding it mounts volumes and ext_checksums can come later in the file
             "root" => {
-                config.rootfs = GUID::new_from_string(&value);
+                root = Some(value);
             }
 
             "resolution" => {
                 config.resolution=value;
             }
 
+            // Set to "warn" to boot from an EXT volume whose metadata doesn't match its checksums
+            "ext_checksums" => {
+                match value.as_str() {
+                    "warn"  => fs::set_mount_options(MountOptions { ext_checksums: ChecksumPolicy::Warn }),
+                    "error" => fs::set_mount_options(MountOptions { ext_checksums: ChecksumPolicy::Error }),
+                    _       => { ldrprintln!("WARNING: Invalid ext_checksums value \"{}\", expected warn or error. Ignoring.", value); }
+                }
+            }
+
             _ => { ldrprintln!("WARNING: Unknown configuration option \"{}\". Ignoring.", key); }
         }
     }
 
-    config
+    // Leave the root unset if it can't be found, main() will ask the user for one
+    if let Some(root) = root {
+        match parse_root(&root) {
+            Ok(guid)    => config.rootfs = guid,
+            Err(err)    => { ldrprintln!("WARNING: Could not find root \"{}\": {}", root, err); }
+        }
+    }
+
+    Ok(config)
+}
+
+
+
+
+/// Parses the root slice, given either as a partition GUID or as "UUID=<filesystem uuid>" / "LABEL=<filesystem label>"
+fn parse_root(value: &str) -> Result<GUID, FsError> {
+    if let Some(uuid) = value.strip_prefix("UUID=") {
+        let uuid = fs::parse_fs_uuid(uuid).ok_or(FsError::VolumeNotFound)?;
+        fs::find_volume_by_uuid(uuid)
+    }
+    else if let Some(label) = value.strip_prefix("LABEL=") {
+        fs::find_volume_by_label(label)
+    }
+    else {
+        GUID::new_from_string(value).ok_or(FsError::VolumeNotFound)
+    }
 }
 
 
@@ -42,6 +42,12 @@ macro_rules! ldrprintln {
 }
 
 
+/// Waits for a key press and returns the character typed
+pub fn getc() -> char {
+    firmware::console::getc()
+}
+
+
 /// Clears the console
 pub fn clear() {
     let cursor = CURSOR.lock();
@@ -20,105 +20,318 @@
 #![allow(dead_code)]
 
 
-use alloc::{string::{String, ToString}, vec, vec::Vec};
-use crate::{extfs, fat};
+
+use alloc::{string::{String, ToString}, vec::Vec};
+use crate::firmware;
+use crate::firmware::disk::SliceDevice;
+use crate::libloader::mutex::Mutex;
 use crate::uuid::GUID;
 
+pub use zosfs::{FileType, FilesystemType, FsError, MountOptions};
+pub use zosfs::extfs::ChecksumPolicy;
+
+
+/// Identifiers of every slice with a known filesystem. Filled the first time a volume is looked up.
+static VOLUMES: Mutex<Vec<Volume>> = Mutex::new(Vec::new());
 
+/// Filesystems mounted so far. Mounting reads the superblock and whatever else the driver needs up front, like the replayed EXT
+/// journal, so it's done once per slice and the caches it fills last across operations.
+static MOUNTS: Mutex<Vec<MountedVolume>> = Mutex::new(Vec::new());
 
-#[derive(Clone, Copy, PartialEq, Eq)]
-enum FilesystemType {
-    FAT,
-    EXT,
-    UNKNOWN,
+/// Options slices are mounted with, set from the config file
+static MOUNT_OPTIONS: Mutex<MountOptions> = Mutex::new(MountOptions { ext_checksums: ChecksumPolicy::Error });
+
+type Mount = zosfs::Mount<SliceDevice>;
+
+struct MountedVolume {
+    slice:      GUID,
+    /// Path of the archive or image on the slice's filesystem this was opened from, None for that filesystem itself
+    archive:    Option<String>,
+    mount:      Mount,
 }
 
 
+
 /// Detects the filesystem of the slice
-fn detect_fs_type(guid: GUID) -> FilesystemType {
-    if extfs::detect(guid) {
-        return FilesystemType::EXT;
+pub fn detect_fs_type(guid: GUID) -> Result<FilesystemType, FsError> {
+    zosfs::mount::detect_fs_type(&SliceDevice::open(guid)?)
+}
+
+
+/// Runs *f* on the filesystem on a slice, or with *archive* set on the tar/cpio archive or SquashFS image stored in that file on
+/// it. Each is mounted the first time it's used and kept in MOUNTS afterwards.
+fn with_mount<T>(slice: GUID, archive: Option<&str>, f: impl FnOnce(&Mount) -> Result<T, FsError>) -> Result<T, FsError> {
+    let mut mounts = MOUNTS.lock();
+
+    let index = match mounts.iter().position(|mounted| mounted.slice == slice && mounted.archive.as_deref() == archive) {
+        Some(index) => index,
+        None        => {
+            // Opening an archive takes over the mount it's read through, so it gets one of its own
+            let options = *MOUNT_OPTIONS.lock();
+            let mount = zosfs::mount_with(SliceDevice::open(slice)?, options)?;
+            let mount = match archive {
+                Some(archive)   => mount.open_archive(archive)?,
+                None            => mount,
+            };
+
+            mounts.push(MountedVolume { slice, archive: archive.map(String::from), mount });
+            mounts.len() - 1
+        }
+    };
+
+    f(&mounts[index].mount)
+}
+
+
+/// Sets the options slices are mounted with. Anything mounted before is mounted again with them the next time it's used, and the
+/// volumes are probed again since slices that failed to mount under the old options may mount now.
+pub fn set_mount_options(options: MountOptions) {
+    *MOUNT_OPTIONS.lock() = options;
+    VOLUMES.lock().clear();
+    MOUNTS.lock().clear();
+}
+
+
+/// Runs *f* on whatever holds *path* on a slice, along with the path within it. That's the slice's own filesystem, except for
+/// "archive:<archive>!<path>" paths which go through the tar/cpio archive or SquashFS image stored in a file on it.
+fn with_path<T>(slice: GUID, path: &str, f: impl FnOnce(&Mount, &str) -> Result<T, FsError>) -> Result<T, FsError> {
+    match zosfs::split_archive_path(path) {
+        Some((archive, path))   => with_mount(slice, Some(archive), |mount| f(mount, path)),
+        None                    => with_mount(slice, None, |mount| f(mount, path)),
     }
-    else if fat::detect(guid) {
-        return FilesystemType::FAT;
+}
+
+
+
+/// Recognizes the compressed format the file at *path* is in from its magic number, or returns None if it isn't compressed
+fn compressed_format(mount: &Mount, path: &str) -> Result<Option<zosfs::compress::Format>, FsError> {
+    let mut magic = [0u8; 6];
+    let read = mount.read_at(path, 0, &mut magic)?;
+
+    Ok(zosfs::compress::detect(&magic[..read]))
+}
+
+
+/// Decompresses *contents* if they're gzip, zstd, lz4 or xz compressed, logging how fast it went so the formats can be compared.
+/// Anything else is returned as is.
+fn decompress(path: &str, contents: Vec<u8>) -> Result<Vec<u8>, FsError> {
+    let Some(format) = zosfs::compress::detect(&contents) else { return Ok(contents) };
+
+    let start = firmware::misc::get_timestamp_us();
+    let decompressed = zosfs::compress::decompress(format, &contents)?;
+    let elapsed = (firmware::misc::get_timestamp_us() - start).max(1);
+
+    ldrprintln!("Decompressed {} ({:?}): {} -> {} bytes in {} ms, {} KiB/s", path, format, contents.len(), decompressed.len(),
+                elapsed / 1000, decompressed.len() as u64 * 1_000_000 / 1024 / elapsed);
+
+    Ok(decompressed)
+}
+
+
+
+/// Identifiers a filesystem stores about itself, as opposed to the partition GUID which belongs to the partition table
+#[derive(Clone)]
+pub struct Volume {
+    pub slice:      GUID,
+    pub fs_type:    FilesystemType,
+    pub uuid:       u128,
+    pub label:      String,
+}
+
+
+/// Probes every slice for a filesystem and caches their UUIDs and labels. Slices that can't be read are skipped.
+pub fn probe_volumes() -> Vec<Volume> {
+    let mut volumes = VOLUMES.lock();
+
+    if volumes.is_empty() {
+        for slice in firmware::disk::list_slices() {
+            let probe = |slice| -> Result<Option<Volume>, FsError> {
+                let probed = with_mount(slice, None, |mount| {
+                    let (uuid, label) = mount.volume_id()?;
+
+                    Ok(Volume {
+                        slice,
+                        fs_type: mount.fs_type(),
+                        uuid,
+                        label,
+                    })
+                });
+
+                match probed {
+                    Ok(volume)                          => Ok(Some(volume)),
+                    Err(FsError::UnknownFilesystem)     => Ok(None),
+                    Err(err)                            => Err(err),
+                }
+            };
+
+            match probe(slice) {
+                Ok(Some(volume))    => {
+                    ldrprintln!("Found {:?} filesystem on slice with GUID '{}'", volume.fs_type, slice.as_string());
+                    volumes.push(volume);
+                }
+                Ok(None)            => {}
+                Err(err)            => { ldrprintln!("WARNING: Could not probe slice {}: {}", slice.as_string(), err); }
+            }
+        }
     }
-    else {
-        return FilesystemType::UNKNOWN;
+
+    volumes.clone()
+}
+
+
+/// Returns the one slice matching *matches*, or an error if there are none or several
+fn find_volume(matches: impl Fn(&Volume) -> bool) -> Result<GUID, FsError> {
+    let found: Vec<GUID> = probe_volumes().iter().filter(|volume| matches(volume)).map(|volume| volume.slice).collect();
+
+    match found.len() {
+        0 => Err(FsError::VolumeNotFound),
+        1 => Ok(found[0]),
+        _ => Err(FsError::AmbiguousVolume(found)),
     }
 }
 
 
+/// Finds the slice holding the filesystem with UUID *uuid*. FAT volume IDs are 32 bits and are compared as such.
+pub fn find_volume_by_uuid(uuid: u128) -> Result<GUID, FsError> {
+    find_volume(|volume| volume.uuid == uuid)
+}
+
+
+/// Finds the slice holding the filesystem labelled *label*
+pub fn find_volume_by_label(label: &str) -> Result<GUID, FsError> {
+    find_volume(|volume| !volume.label.is_empty() && volume.label == label)
+}
+
+
+/// Lists the names and types of the entries in the directory at *path*
+pub fn list_dir(slice: GUID, path: &str) -> Result<Vec<(String, FileType)>, FsError> {
+    with_path(slice, path, |mount, path| mount.list_dir(path))
+}
+
+
+/// Returns the value of the extended attribute *name*, like "user.comment", of the file at *path*, or None if it doesn't have it
+pub fn get_xattr(slice: GUID, path: &str, name: &str) -> Result<Option<Vec<u8>>, FsError> {
+    with_path(slice, path, |mount, path| mount.get_xattr(path, name))
+}
+
+
+/// Lists the names of the extended attributes of the file at *path*
+pub fn list_xattrs(slice: GUID, path: &str) -> Result<Vec<String>, FsError> {
+    with_path(slice, path, |mount, path| mount.list_xattrs(path))
+}
+
+
+/// Names the optional on-disk features the filesystem on a slice uses
+pub fn features(slice: GUID) -> Result<Vec<String>, FsError> {
+    with_mount(slice, None, |mount| Ok(mount.features()))
+}
+
+
+/// Parses a filesystem UUID as printed by blkid, e.g. "1b4e28ba-2fa1-11d2-883f-b9a761bde3fb" for EXT, "ABCD-1234" for FAT or
+/// "2024-05-17-12-34-56-00" for ISO9660
+pub fn parse_fs_uuid(uuid: &str) -> Option<u128> {
+    u128::from_str_radix(&uuid.replace('-', ""), 16).ok()
+}
+
+
 
 /// Start the filesystem driver
 pub fn start() {
-    // Do something
+    // Driver messages go to the console
+    zosfs::log::set_logger(crate::console::_ldrprint);
 }
 
 
-// Generic filetype, used inside all other file types
+// Generic filetype, used inside all other file types. The path can point inside an archive, e.g "archive:/boot/extensions.tar!/zxt/hello.zxt".
+// Files compressed with gzip, zstd, lz4 or xz are decompressed when read, so callers always see the plain contents.
 pub struct File {
-    slice:  GUID,
-    path:   String,
+    slice:          GUID,
+    path:           String,
+    /// Contents of a compressed file whose format doesn't record its decompressed size, kept from when read_raw was asked for
+    /// the size until it's asked for the contents
+    decompressed:   Mutex<Option<Vec<u8>>>,
 }
 
 impl File {
     pub fn open_by_guid(slice: GUID, path: &str) -> Self {
         Self {
-            slice:  slice,
-            path:   path.to_string(),
+            slice:          slice,
+            path:           path.to_string(),
+            decompressed:   Mutex::new(None),
         }
     }
 
-    pub fn open_by_uuid(_uuid: u128, _path: &str) -> Self {
-        todo!();
-        // let slice: GUID = unsafe { core::mem::zeroed() };
+    /// Opens a file on the slice whose filesystem has UUID *uuid*
+    pub fn open_by_uuid(uuid: u128, path: &str) -> Result<Self, FsError> {
+        Ok(Self::open_by_guid(find_volume_by_uuid(uuid)?, path))
+    }
 
-        // Self {
-        //     slice:  slice,
-        //     uuid:   Some(uuid),
-        //     path:   path.to_string(),
-        // }
+    /// Opens a file on the slice whose filesystem is labelled *label*
+    pub fn open_by_label(label: &str, path: &str) -> Result<Self, FsError> {
+        Ok(Self::open_by_guid(find_volume_by_label(label)?, path))
     }
 
     /// Reads a files entire contents into *buffer*
     ///
     /// If *buffer* is a null ptr, this fn returns the buffer size needed to contain the file. Otherwise, it returns None.
-    pub unsafe fn read_raw(&self, buffer: *mut u8) -> Option<u64> {
-        match detect_fs_type(self.slice) {
-            FilesystemType::FAT => {
-                return fat::read_bytes_raw(self.slice, &self.path, buffer);
-            }
+    pub unsafe fn read_raw(&self, buffer: *mut u8) -> Result<Option<u64>, FsError> {
+        if buffer.is_null() {
+            // Plain files have their size in their metadata, compressed ones may have it in their headers, which are read alone
+            let size = with_path(self.slice, &self.path, |mount, path| {
+                let len = mount.metadata(path)?.size;
+                let Some(format) = compressed_format(mount, path)? else { return Ok(Some(len)) };
 
-            FilesystemType::EXT => {
-                return extfs::read_bytes_raw(self.slice, &self.path, buffer);
-                // return extfs::read_bytes_raw(self.slice, &self.path, buffer).unwrap();
-            }
+                Ok(zosfs::compress::decompressed_size_at(format, len, |offset, buffer| {
+                    (mount.read_at(path, offset, buffer).ok()? == buffer.len()).then_some(())
+                }))
+            })?;
 
-            FilesystemType::UNKNOWN => {
-                // let path = &self.path;
-                // if self.uuid.is_some() {
-                //     panic!("Trying to open \"{path}\" on slice with UUID {} failed: Unknown filesystem \nHalting.", self.uuid.unwrap())
-                // }
-                // else {
-                //     panic!("Trying to open \"{path}\" on slice with GUID {} failed: Unknown filesystem \nHalting.", self.slice.as_string())
-                // }
-
-                let path = &self.path;
-                panic!("Trying to open \"{path}\" on slice with GUID {} failed: Unknown filesystem \nHalting.", self.slice.as_string())
+            if size.is_some() {
+                return Ok(size);
             }
+
+            // The format doesn't say, so the size is only known once it's been decompressed. Keep the result for the next call.
+            let contents = with_path(self.slice, &self.path, |mount, path| mount.read_file(path))?;
+            let contents = decompress(&self.path, contents)?;
+            let size = contents.len() as u64;
+            *self.decompressed.lock() = Some(contents);
+
+            return Ok(Some(size));
         }
-    }
 
-    /// Reads the entire contents of the file into a String
-    pub fn read_to_string(&self) -> Result<String, ()> {
-        let filesize = unsafe { self.read_raw(core::ptr::null_mut()).unwrap() };
-        let contents: Vec<u8> = { 
-            let mut buffer: Vec<u8> = vec![0; filesize.try_into().unwrap()];
-            unsafe { self.read_raw(buffer.as_mut_ptr()) };
+        let kept = self.decompressed.lock().take();
+        let contents = match kept {
+            Some(contents)  => contents,
+            None            => {
+                let contents = with_path(self.slice, &self.path, |mount, path| mount.read_file(path))?;
+                let recorded = zosfs::compress::detect(&contents).and_then(|format| zosfs::compress::decompressed_size(format, &contents));
+                let contents = decompress(&self.path, contents)?;
+
+                // The buffer may have been sized from the header, which a corrupt file can get wrong
+                if recorded.is_some_and(|size| size != contents.len() as u64) {
+                    return Err(FsError::Corrupt("decompressed size doesn't match the one recorded in the header"));
+                }
 
-            buffer
+                contents
+            }
         };
 
+        unsafe { core::ptr::copy(contents.as_ptr(), buffer, contents.len()) };
+
+        return Ok(None);
+    }
+
+    /// Reads the entire contents of the file into a Vec
+    pub fn read_to_vec(&self) -> Result<Vec<u8>, FsError> {
+        let contents = with_path(self.slice, &self.path, |mount, path| mount.read_file(path))?;
+
+        decompress(&self.path, contents)
+    }
+
+    /// Reads the entire contents of the file into a String
+    pub fn read_to_string(&self) -> Result<String, FsError> {
+        let contents = self.read_to_vec()?;
+
         let mut s = String::new();
 
         for b in &contents {
@@ -127,4 +340,14 @@ impl File {
 
         Ok(s)
     }
+
+    /// Replaces the file's contents with *contents*, creating it if it doesn't exist. The parent directory must already exist.
+    pub fn write(&self, contents: &[u8]) -> Result<(), FsError> {
+        with_path(self.slice, &self.path, |mount, path| mount.write_file(path, contents, crate::firmware::misc::get_time()))?;
+
+        // Archives and images opened from this slice were read before the write and may now be stale
+        MOUNTS.lock().retain(|mounted| mounted.slice != self.slice || mounted.archive.is_none());
+
+        Ok(())
+    }
 }
@@ -19,8 +19,6 @@
 
 #[macro_use]
 pub mod console;
-pub mod extfs;
-pub mod fat;
 pub mod fs;
 
 
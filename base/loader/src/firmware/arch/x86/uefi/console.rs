@@ -19,6 +19,7 @@
 
 #![allow(dead_code)]
 
+use super::libuefi::protocol::simple_text_input::SimpleTextInputProtocol;
 use super::libuefi::protocol::simple_text_output::SimpleTextOutputProtocol;
 
 
@@ -42,6 +43,19 @@ pub fn putc(c: char) {
 }
 
 
+/// Waits for a key press and returns the character typed
+pub fn getc() -> char {
+    loop {
+        if let Some(key) = SimpleTextInputProtocol::read_key_stroke() {
+            // Keys without a character (arrows, function keys, ...) have a unicode_char of 0
+            if key.unicode_char != 0 {
+                return char::from_u32(key.unicode_char as u32).unwrap_or(char::REPLACEMENT_CHARACTER);
+            }
+        }
+    }
+}
+
+
 pub fn clear() {
     SimpleTextOutputProtocol::reset();
 }
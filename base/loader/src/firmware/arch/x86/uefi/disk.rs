@@ -20,111 +20,191 @@
 #![allow(dead_code)]
 
 use core::ptr;
-use alloc::{format, vec};
-use alloc::{string::{String, ToString}, vec::Vec};
-use super::libuefi::{bootservices::BootServices, protocol::{block_io::BlockIOProtocol, device_path::{DevicePathProtocol, HardDriveDevicePath}}};
+use alloc::vec;
+use alloc::vec::Vec;
+use zosfs::BlockDevice;
+use super::libuefi::{bootservices::BootServices, protocol::{block_io::BlockIOProtocol, device_path::{CDROMDevicePath, DevicePathProtocol, HardDriveDevicePath}}};
+use crate::libloader::mutex::Mutex;
 use crate::uuid::GUID;
 
+pub use zosfs::DiskError;
 
-static mut DISK_SLICE_INFO: Vec<DiskSliceInfo> = Vec::new();
+
+static DISK_SLICE_INFO: Mutex<Vec<DiskSliceInfo>> = Mutex::new(Vec::new());
 
 #[derive(Clone, Copy)]
 struct DiskSliceInfo {
     guid:                   GUID,
     handle:                 *const usize,
+    /// The slice holding the whole disc, for El Torito boot images
+    parent:                 Option<GUID>,
 }
 
+/// A slice accessed through the firmware's BlockIO protocol, for use with the zosfs drivers
 #[derive(Clone, Copy)]
-pub struct Disk {
-    pub guid:           GUID,
-    pub removable:      bool,
-    pub block_size:     u8,
+pub struct SliceDevice {
+    slice:          GUID,
+    block_size:     usize,
 }
 
-impl DiskSliceInfo {
-    pub const fn new(guid: GUID, handle: *const usize) -> Self {
-        Self {
-            guid,
-            handle,
-        }
+impl SliceDevice {
+    pub fn open(slice: GUID) -> Result<Self, DiskError> {
+        Ok(Self {
+            slice,
+            block_size: get_phys_block_size(slice)? as usize,
+        })
     }
 }
 
-/// Returns a list of the detected storage devices
-pub fn list_disks() -> Vec<Disk> {
-    todo!();
-}
+impl BlockDevice for SliceDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
 
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), DiskError> {
+        unsafe { read_blocks(self.slice, lba, buffer.len() as u64, buffer.as_mut_ptr()) }
+    }
 
-pub fn get_disk_info(guid: GUID) -> Result<Disk, String> {
-    for disk in list_disks() {
-        if disk.guid == guid {
-            return Ok(disk);
-        }
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), DiskError> {
+        write_blocks(self.slice, lba, buffer)
     }
 
-    Err(format!("Could not find disk: {}", guid.as_string()).to_string())
+    fn flush(&self) -> Result<(), DiskError> {
+        let block_io_protocol = BootServices::handle_protocol::<BlockIOProtocol>(lookup_handle(self.slice)?);
+
+        match block_io_protocol.flush_blocks() {
+            0       => Ok(()),
+            status  => Err(DiskError::Io(status)),
+        }
+    }
 }
 
+impl DiskSliceInfo {
+    pub const fn new(guid: GUID, handle: *const usize, parent: Option<GUID>) -> Self {
+        Self {
+            guid,
+            handle,
+            parent,
+        }
+    }
+}
 
 /// Fills SLICE_ENTRIES with a list of slice GUIDs and their EFI Handle
 pub fn init() {
     // Get a list of handles that support the BlockIOProtocol. This list includes every storage media device + their partitions.
     let handles = BootServices::locate_handle_by_protocol::<BlockIOProtocol>();
-    
-    // Iterate through the handles, looking specifically for the Hard Drive Device Path node. This specific node indicates that the handle belongs to a slice
+
+    // Keep the handles that belong to a slice: partitions, El Torito boot images and whole CDs
     let mut partition_entries: Vec<DiskSliceInfo> = Vec::new();
 
     for i in 0..handles.len() {
-        let mut node = BootServices::handle_protocol::<DevicePathProtocol>(handles[i] as *const usize);
-
-        while (node._type, node.subtype) != (0x7F, 0xFF)  {
-            match (node._type, node.subtype, node.length[0] + node.length[1]) {
-                // Hard drive device path
-                (4, 1, 42) => {
-                    // Cast the current node as HardDriveDevicePath so we can read the GUID
-                    #[allow(invalid_reference_casting)]
-                    let hddp: &HardDriveDevicePath = unsafe { &*((node as *const DevicePathProtocol).cast()) };
-                    let guid = hddp.partition_sig;
-            
-                    partition_entries.push(DiskSliceInfo::new(guid, handles[i] as *const usize));
-                }
-
-                _ => {}
-            }
-
-            node = node.next();
+        if let Some((guid, parent)) = identify_slice(handles[i] as *const usize) {
+            partition_entries.push(DiskSliceInfo::new(guid, handles[i] as *const usize, parent));
         }
     }
 
 
-    assert!(partition_entries.is_empty() == false);
-    unsafe { DISK_SLICE_INFO = partition_entries; }
+    *DISK_SLICE_INFO.lock() = partition_entries;
 }
 
 
-pub fn read_bytes(slice: GUID, lba: u64, count: usize, buffer: &mut [u8]) -> Result<(), String> {
-    assert!(count <= buffer.len());
-    let result;
-    unsafe {
-        result = read_bytes_raw(slice, lba, count, buffer.as_mut_ptr());
+/// Works out the GUID of the slice behind a BlockIO handle from its device path, along with the GUID of the whole disc for El Torito
+/// boot images. Returns None for handles that aren't slices, like whole hard drives.
+///
+/// Partitions use their partition GUID. CDs have no GUIDs, so one is made up from a hash of the drive's device path, which stays the same
+/// across boots: the whole disc gets the hash followed by zeros, and its El Torito boot images the hash followed by 1 and the boot entry.
+pub fn identify_slice(handle: *const usize) -> Option<(GUID, Option<GUID>)> {
+    let start = BootServices::handle_protocol::<DevicePathProtocol>(handle);
+    let mut node = start;
+
+    while (node._type, node.subtype) != (0x7F, 0xFF)  {
+        match (node._type, node.subtype, u16::from_le_bytes(node.length)) {
+            // Hard drive device path
+            (4, 1, 42) => {
+                // Cast the current node as HardDriveDevicePath so we can read the GUID
+                #[allow(invalid_reference_casting)]
+                let hddp: &HardDriveDevicePath = unsafe { &*((node as *const DevicePathProtocol).cast()) };
+
+                return Some((hddp.partition_sig, None));
+            }
+
+            // CD-ROM device path, i.e an El Torito boot image
+            (4, 2, 24) => {
+                #[allow(invalid_reference_casting)]
+                let cddp: &CDROMDevicePath = unsafe { &*((node as *const DevicePathProtocol).cast()) };
+                let hash = hash_device_path(start, node);
+
+                return Some((cd_slice_guid(hash, Some(cddp.boot_entry)), Some(cd_slice_guid(hash, None))));
+            }
+
+            _ => {}
+        }
+
+        node = node.next();
     }
-    
-    if result.is_ok() {
-        Ok(result.ok().unwrap())
+
+    // No media node, so this is a whole device. CDs hold their filesystem directly instead of in partitions.
+    let media = unsafe { &*BootServices::handle_protocol::<BlockIOProtocol>(handle).media };
+
+    if media.media_present && !media.logical_partition && media.block_size == 2048 {
+        return Some((cd_slice_guid(hash_device_path(start, node), None), None));
     }
     else {
-        Err(result.err().unwrap())
+        return None;
+    }
+}
+
+
+/// Hashes the device path nodes from *start* up to, but not including, *end* with FNV-1a
+fn hash_device_path(start: &DevicePathProtocol, end: &DevicePathProtocol) -> u64 {
+    let len = end as *const DevicePathProtocol as usize - start as *const DevicePathProtocol as usize;
+    let bytes = unsafe { core::slice::from_raw_parts((start as *const DevicePathProtocol).cast::<u8>(), len) };
+
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+
+/// Makes up the GUID of a CD slice, see identify_slice()
+fn cd_slice_guid(hash: u64, boot_entry: Option<u32>) -> GUID {
+    let data4 = match boot_entry {
+        Some(entry) => {
+            let entry = entry.to_le_bytes();
+            [1, 0, 0, 0, entry[0], entry[1], entry[2], entry[3]]
+        }
+        None        => [0; 8],
+    };
+
+    GUID::new((hash >> 32) as u32, (hash >> 16) as u16, hash as u16, data4)
+}
+
+
+/// Returns the slice holding the whole disc an El Torito boot image is on, or None for other slices
+pub fn get_parent_slice(slice: GUID) -> Option<GUID> {
+    find_slice(slice).ok()?.parent
+}
+
+
+/// Returns the GUIDs of every slice found by init()
+pub fn list_slices() -> Vec<GUID> {
+    DISK_SLICE_INFO.lock().iter().map(|partition| partition.guid).collect()
+}
+
+
+pub fn read_bytes(slice: GUID, lba: u64, count: usize, buffer: &mut [u8]) -> Result<(), DiskError> {
+    if count > buffer.len() {
+        return Err(DiskError::BufferTooSmall);
     }
+
+    unsafe { read_bytes_raw(slice, lba, count, buffer.as_mut_ptr()) }
 }
 
-pub unsafe fn read_bytes_raw(slice: GUID, lba: u64, count: usize, buffer: *mut u8) -> Result<(), String> {
+pub unsafe fn read_bytes_raw(slice: GUID, lba: u64, count: usize, buffer: *mut u8) -> Result<(), DiskError> {
     //
     // TODO: determine if we should use u64 or usize
     //
     let count = count as u64;
     
-    let phys_block_size = get_phys_block_size(slice);
+    let phys_block_size = get_phys_block_size(slice)?;
 
     // if 'count' is equal to an even multiple of physical blocks we can simply read the blocks into buffer
     //
@@ -138,22 +218,20 @@ pub unsafe fn read_bytes_raw(slice: GUID, lba: u64, count: usize, buffer: *mut u
         let rem = count % phys_block_size;
 
         // Read the amount of blocks that fit into 'count' evenly into the buffer
-        read_blocks(slice, lba, full_count, buffer).unwrap();
+        if full_count > 0 {
+            read_blocks(slice, lba, full_count, buffer)?;
+        }
 
         // Create a temporary buffer = to size of one block
-        let buff_size: usize = phys_block_size.try_into().unwrap();
-        let mut tmp: Vec<u8> = vec![0; buff_size];
+        let mut tmp: Vec<u8> = vec![0; phys_block_size as usize];
 
 
-        // Read the remainder of bytes into the temporary buffer
-        read_blocks(slice, lba, phys_block_size, tmp.as_mut_ptr().cast()).unwrap();
+        // Read the block holding the remainder of bytes into the temporary buffer
+        read_blocks(slice, lba + (full_count / phys_block_size), phys_block_size, tmp.as_mut_ptr().cast())?;
 
         // Copy 'remainder' into 'buffer'
         unsafe {
-            let rem_ptr = {
-                let buff_ptr = buffer.as_mut().unwrap() as *mut u8;
-                buff_ptr.offset(full_count as isize)
-            };
+            let rem_ptr = buffer.offset(full_count as isize);
 
             ptr::copy(tmp.as_ptr(), rem_ptr, rem as usize);
         }
@@ -166,11 +244,14 @@ pub unsafe fn read_bytes_raw(slice: GUID, lba: u64, count: usize, buffer: *mut u
 
 
 
-pub unsafe fn read_blocks(guid: GUID, lba: u64, buffer_size: u64, buffer: *mut u8) -> Result<(), String> {
-    let block_io_protocol = BootServices::handle_protocol::<BlockIOProtocol>(lookup_handle(guid));
+pub unsafe fn read_blocks(guid: GUID, lba: u64, buffer_size: u64, buffer: *mut u8) -> Result<(), DiskError> {
+    let block_io_protocol = BootServices::handle_protocol::<BlockIOProtocol>(lookup_handle(guid)?);
     let block_size = (*block_io_protocol.media).block_size as usize;
 
-    assert_eq!(buffer_size % block_size as u64, 0, "'buffer_size' must be a multiple of the disk's physical block size. (e.g 512 bytes)");
+    // 'buffer_size' must be a multiple of the disk's physical block size. (e.g 512 bytes)
+    if buffer_size % block_size as u64 != 0 {
+        return Err(DiskError::Unaligned);
+    }
 
     let status = block_io_protocol.read_blocks(lba, buffer_size as usize, buffer);
     if status == 0 {
@@ -178,7 +259,7 @@ pub unsafe fn read_blocks(guid: GUID, lba: u64, buffer_size: u64, buffer: *mut u
     }
 
     else {
-        Err(alloc::format!("EFI ERROR: {}", status).to_string())
+        Err(DiskError::Io(status))
     }
     
 }
@@ -186,43 +267,54 @@ pub unsafe fn read_blocks(guid: GUID, lba: u64, buffer_size: u64, buffer: *mut u
 
 
 
-pub fn get_phys_block_size(slice: GUID) -> u64 {
-    let block_io_protocol = BootServices::handle_protocol::<BlockIOProtocol>(lookup_handle(slice));
+/// Writes *buffer*, which must be a multiple of the block size, starting at block *lba*
+pub fn write_blocks(guid: GUID, lba: u64, buffer: &[u8]) -> Result<(), DiskError> {
+    let block_io_protocol = BootServices::handle_protocol::<BlockIOProtocol>(lookup_handle(guid)?);
+    let media = unsafe { &*block_io_protocol.media };
+
+    if buffer.len() % media.block_size as usize != 0 {
+        return Err(DiskError::Unaligned);
+    }
+    if media.read_only {
+        return Err(DiskError::ReadOnly);
+    }
+
+    let status = block_io_protocol.write_blocks(lba, buffer.len(), buffer.as_ptr());
+    if status == 0 {
+        Ok(())
+    }
+
+    else {
+        Err(DiskError::Io(status))
+    }
+}
+
+
+
+
+pub fn get_phys_block_size(slice: GUID) -> Result<u64, DiskError> {
+    let block_io_protocol = BootServices::handle_protocol::<BlockIOProtocol>(lookup_handle(slice)?);
     
-    unsafe { (*block_io_protocol.media).block_size as u64 }
+    unsafe { Ok((*block_io_protocol.media).block_size as u64) }
 }
 
 
 
 
 /// Returns the EFI_HANDLE belonging to a given slice
-fn lookup_handle(guid: GUID) -> *const usize {
-    unsafe {
-        assert_eq!(DISK_SLICE_INFO.is_empty(), false);
-        
-        for partition in DISK_SLICE_INFO.iter() {
-            if partition.guid.as_string() == guid.as_string() {
-                return partition.handle
-            }
-        }
-    }
-
-    panic!("Partition not found: {}", guid.as_string());
+fn lookup_handle(guid: GUID) -> Result<*const usize, DiskError> {
+    Ok(find_slice(guid)?.handle)
 }
 
 
 
 /// Finds a SliceEntry by GUID
-fn find_slice(guid: GUID) -> DiskSliceInfo {
-    unsafe {
-        assert_eq!(DISK_SLICE_INFO.is_empty(), false);
-        
-        for partition in DISK_SLICE_INFO.iter() {
-            if partition.guid == guid {
-                return *partition
-            }
+fn find_slice(guid: GUID) -> Result<DiskSliceInfo, DiskError> {
+    for partition in DISK_SLICE_INFO.lock().iter() {
+        if partition.guid == guid {
+            return Ok(*partition)
         }
-
-        panic!("Could not find slice with GUID: {}.", guid.as_string());
     }
+
+    Err(DiskError::NotFound)
 }
@@ -60,7 +60,7 @@ pub struct BootServices {
     _unload_image:                                  *const c_void,
     _exit_boot_services:                            *const c_void,
     _get_next_monotonic_count:                      *const c_void,
-    _stall:                                         *const c_void,
+    _stall:                                         unsafe extern "efiapi" fn (usize) -> u32,
     _set_watchdog_timer:                            *const c_void,
     _connect_controller:                            *const c_void,
     _disconnect_controller:                         *const c_void,
@@ -182,4 +182,14 @@ impl BootServices {
     pub fn free_pool(buffer: *const usize) -> u32 {
         unsafe { (Self::get()._free_pool)(buffer as *const c_void) }
     }
-}
\ No newline at end of file
+}
+
+
+
+/* Miscellaneous Boot Services */
+impl BootServices {
+    /// Busy waits for *microseconds*
+    pub fn stall(microseconds: usize) {
+        unsafe { (Self::get()._stall)(microseconds) };
+    }
+}
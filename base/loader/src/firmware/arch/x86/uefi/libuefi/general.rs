@@ -22,6 +22,8 @@
 use core::{ffi::c_void, sync::atomic::{AtomicPtr, Ordering}};
 
 use super::bootservices::BootServices;
+use super::runtimeservices::RuntimeServices;
+use super::protocol::simple_text_input::SimpleTextInputProtocol;
 use super::protocol::simple_text_output::SimpleTextOutputProtocol;
 
 pub static SYSTEM_TABLE_PTR: AtomicPtr<SystemTable> = AtomicPtr::new(core::ptr::dangling_mut());
@@ -45,12 +47,12 @@ pub struct SystemTable {
     pub firmware_vendor:                            *const u16,
     pub firmware_revision:                          u32,
     pub console_in_handle:                          *const c_void,
-    pub simple_text_input_protocol:                 *const c_void,
+    pub simple_text_input_protocol:                 *const SimpleTextInputProtocol,
     pub console_out_handle:                         *const c_void,
     pub simple_text_output_protocol:                *const SimpleTextOutputProtocol,
     pub standard_error_handle:                      *const c_void,
     pub std_error:                                  *const c_void,
-    pub runtime_services:                           *const c_void,
+    pub runtime_services:                           *const RuntimeServices,
     pub boot_services:                              *const BootServices,
     pub number_of_table_entries:                    usize,
     pub configuration_table:                        *const c_void
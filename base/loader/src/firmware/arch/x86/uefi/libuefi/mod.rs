@@ -21,5 +21,6 @@
 pub mod bootservices;
 pub mod general;
 pub mod protocol;
+pub mod runtimeservices;
 
 pub use general::*;
\ No newline at end of file
@@ -29,8 +29,8 @@ pub struct BlockIOProtocol {
     pub media:          *const BlockIOMedia,
     _reset:             *const c_void,
     _read_blocks:       unsafe extern "efiapi" fn (*const Self, u32, u64, usize, *const c_void) -> u32,
-    _write_blocks:      *const c_void,
-    _flush_blocks:      *const c_void,
+    _write_blocks:      unsafe extern "efiapi" fn (*const Self, u32, u64, usize, *const c_void) -> u32,
+    _flush_blocks:      unsafe extern "efiapi" fn (*const Self) -> u32,
 }
 
 impl BlockIOProtocol {
@@ -39,6 +39,16 @@ impl BlockIOProtocol {
     pub fn read_blocks(&self, lba: u64, buffer_size: usize, buffer: *mut u8) -> u32 {
         unsafe { (self._read_blocks)(self, (*self.media).media_id, lba, buffer_size, buffer as *const c_void) }
     }
+
+    /// Writes to the disk. The data may sit in the device's cache until flush_blocks is called.
+    pub fn write_blocks(&self, lba: u64, buffer_size: usize, buffer: *const u8) -> u32 {
+        unsafe { (self._write_blocks)(self, (*self.media).media_id, lba, buffer_size, buffer as *const c_void) }
+    }
+
+    /// Writes any cached data to the disk
+    pub fn flush_blocks(&self) -> u32 {
+        unsafe { (self._flush_blocks)(self) }
+    }
 }
 
 impl EFIProtocol for BlockIOProtocol {
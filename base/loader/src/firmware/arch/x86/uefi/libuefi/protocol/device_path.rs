@@ -38,7 +38,7 @@ impl DevicePathProtocol {
     }
 
     pub fn next(&self) -> &Self {
-        let next_node = unsafe { &*(((self as *const Self as usize) + self.length[0] as usize + self.length[1] as usize) as *const Self) };
+        let next_node = unsafe { &*(((self as *const Self as usize) + u16::from_le_bytes(self.length) as usize) as *const Self) };
 
         // Check the node type is sane to ensure we don't return a bad reference
         match next_node._type {
@@ -85,3 +85,14 @@ pub struct HardDriveDevicePath {
     pub partition_format: u8,
     pub sig_type: u8,
 }
+
+/// The El Torito boot image a CD was booted from. The firmware exposes it as a partition of the CD.
+#[repr(C, packed)]
+pub struct CDROMDevicePath {
+    pub _type: u8,
+    pub subtype: u8,
+    pub length: [u8; 2],
+    pub boot_entry: u32,
+    pub partition_start: u64,
+    pub partition_size: u64,
+}
@@ -23,6 +23,7 @@ pub mod loaded_image;
 pub mod file;
 pub mod filesystem;
 pub mod graphics_output;
+pub mod simple_text_input;
 pub mod simple_text_output;
 
 pub trait EFIProtocol {
@@ -0,0 +1,64 @@
+/*  simple_text_input.rs - UEFI SimpleTextInputProtocol implementation
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+#![allow(dead_code)]
+
+use core::{ffi::c_void, sync::atomic::Ordering};
+use super::super::SYSTEM_TABLE_PTR;
+
+
+#[repr(C)]
+pub struct SimpleTextInputProtocol {
+    _reset:                     unsafe extern "efiapi" fn (*const Self, bool) -> u32,
+    _read_key_stroke:           unsafe extern "efiapi" fn (*const Self, *mut InputKey) -> u32,
+    _wait_for_key:              *const c_void,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct InputKey {
+    pub scan_code:              u16,
+    pub unicode_char:           u16,
+}
+
+
+impl SimpleTextInputProtocol {
+    /// Returns a reference to SimpleTextInputProtocol
+    fn get() -> &'static Self {
+        unsafe { &*(*(SYSTEM_TABLE_PTR.load(Ordering::SeqCst))).simple_text_input_protocol }
+    }
+
+    /// Resets the input device
+    pub fn reset() {
+        unsafe { (Self::get()._reset)(Self::get(), false) };
+    }
+
+    /// Reads the next keystroke. Returns None if no key is waiting.
+    pub fn read_key_stroke() -> Option<InputKey> {
+        let mut key = InputKey::default();
+        let status = unsafe { (Self::get()._read_key_stroke)(Self::get(), &mut key) };
+
+        if status == 0 {
+            Some(key)
+        }
+        else {
+            None
+        }
+    }
+}
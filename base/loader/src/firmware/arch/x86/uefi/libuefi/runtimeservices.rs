@@ -0,0 +1,88 @@
+/*  runtimeservices.rs - UEFI RuntimeServices implementation
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+#![allow(dead_code)]
+
+use core::{ffi::c_void, sync::atomic::Ordering};
+
+use super::{TableHeader, SYSTEM_TABLE_PTR};
+
+
+#[repr(C)]
+pub struct RuntimeServices {
+    pub header:                                     TableHeader,
+    _get_time:                                      unsafe extern "efiapi" fn (*mut Time, *mut c_void) -> u32,
+    _set_time:                                      *const c_void,
+    _get_wakeup_time:                               *const c_void,
+    _set_wakeup_time:                               *const c_void,
+    _set_virtual_address_map:                       *const c_void,
+    _convert_pointer:                               *const c_void,
+    _get_variable:                                  *const c_void,
+    _get_next_variable_name:                        *const c_void,
+    _set_variable:                                  *const c_void,
+    _get_next_high_monotonic_count:                 *const c_void,
+    _reset_system:                                  *const c_void,
+    _update_capsule:                                *const c_void,
+    _query_capsule_capabilities:                    *const c_void,
+    _query_variable_info:                           *const c_void
+}
+
+impl RuntimeServices {
+    /// Returns a reference to RuntimeServices
+    fn get() -> &'static Self {
+        unsafe { &*(*(SYSTEM_TABLE_PTR.load(Ordering::SeqCst))).runtime_services }
+    }
+}
+
+
+
+/* Time Services */
+
+/// The firmware's idea of the current time. Usually local time, with *time_zone* saying how far from UTC it is if the firmware knows.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Time {
+    pub year:           u16,
+    pub month:          u8,
+    pub day:            u8,
+    pub hour:           u8,
+    pub minute:         u8,
+    pub second:         u8,
+    _pad1:              u8,
+    pub nanosecond:     u32,
+    pub time_zone:      i16,
+    pub daylight:       u8,
+    _pad2:              u8,
+}
+
+impl RuntimeServices {
+
+    /// Reads the real time clock
+    pub fn get_time() -> Result<Time, u32> {
+        let mut time = Time::default();
+        let status = unsafe { (Self::get()._get_time)(&mut time, core::ptr::null_mut()) };
+
+        if status == 0 {
+            Ok(time)
+        }
+        else {
+            Err(status)
+        }
+    }
+}
@@ -17,32 +17,72 @@
  *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
  */
 
-use super::libuefi::{bootservices::BootServices, protocol::{device_path::{DevicePathProtocol, HardDriveDevicePath}, loaded_image::LoadedImageProtocol}};
+use super::disk::{self, DiskError};
+use super::libuefi::{bootservices::BootServices, runtimeservices::RuntimeServices, protocol::loaded_image::LoadedImageProtocol};
+use core::sync::atomic::{AtomicU64, Ordering};
 use crate::uuid::GUID;
+use zosfs::time::DateTime;
 
 
-/// Returns the GUID partition signature of the ESP
-pub fn get_esp_guid() -> GUID {
+/// Time stamp counter ticks per microsecond, measured the first time get_timestamp_us is called
+static TSC_PER_US: AtomicU64 = AtomicU64::new(0);
+
+
+/// Returns the GUID of the slice the loader was started from: the ESP, or the El Torito boot image when booting from a CD
+pub fn get_esp_guid() -> Result<GUID, DiskError> {
     let handle = BootServices::handle_protocol::<LoadedImageProtocol>(super::libuefi::IMAGE_HANDLE.load(core::sync::atomic::Ordering::SeqCst)).device_handle;
-    let mut node = BootServices::handle_protocol::<DevicePathProtocol>(handle as *const usize);
-
-    while (node._type, node.subtype) != (0x7F, 0xFF)  {
-                match (node._type, node.subtype, node.length[0] + node.length[1]) {
-                    // Hard drive device path
-                    (4, 1, 42) => {
-                        // Cast the current node as HardDriveDevicePath so we can read the GUID
-                        #[allow(invalid_reference_casting)]
-                        let hddp: &HardDriveDevicePath = unsafe { &*((node as *const DevicePathProtocol).cast()) };
-                        let guid = hddp.partition_sig;
-
-                        return guid;
-                    }
-    
-                    _ => {}
-                }
-    
-                node = node.next();
-            }
-
-    panic!("FS error: Could not find EFI System Partition. Halting.");
-}
\ No newline at end of file
+
+    match disk::identify_slice(handle as *const usize) {
+        Some((guid, _)) => Ok(guid),
+        None            => Err(DiskError::NotFound),
+    }
+}
+
+
+/// Returns the slice holding the whole CD when the loader was booted from an El Torito image, so the root filesystem can be found on it
+pub fn get_boot_disc() -> Option<GUID> {
+    disk::get_parent_slice(get_esp_guid().ok()?)
+}
+
+
+
+/// Returns the current time from the firmware's clock, in seconds since the UNIX epoch. The firmware usually keeps local time and no time
+/// zone is applied. Returns 0 if the clock can't be read.
+pub fn get_time() -> i64 {
+    match RuntimeServices::get_time() {
+        Ok(time) => {
+            DateTime {
+                year:   time.year as i64,
+                month:  time.month,
+                day:    time.day,
+                hour:   time.hour,
+                minute: time.minute,
+                second: time.second,
+            }.to_unix()
+        }
+
+        Err(_) => 0,
+    }
+}
+
+
+/// Returns a timestamp in microseconds for measuring how long something takes. It counts from an arbitrary point, unlike get_time, and
+/// comes from the CPU's time stamp counter as the firmware clock usually only has whole seconds.
+pub fn get_timestamp_us() -> u64 {
+    let mut tsc_per_us = TSC_PER_US.load(Ordering::Relaxed);
+
+    // Calibrated against a 10ms stall
+    if tsc_per_us == 0 {
+        let start = rdtsc();
+        BootServices::stall(10_000);
+        tsc_per_us = ((rdtsc() - start) / 10_000).max(1);
+        TSC_PER_US.store(tsc_per_us, Ordering::Relaxed);
+    }
+
+    rdtsc() / tsc_per_us
+}
+
+
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
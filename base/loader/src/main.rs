@@ -39,8 +39,9 @@ mod tests;
 mod uuid;
 
 use core::panic::PanicInfo;
-use config::parse_cfg;
+use config::{parse_cfg, Config};
 use drivers::*;
+use uuid::GUID;
 // use debugutils::hexdump_blocks;
 
 
@@ -57,8 +58,25 @@ fn main() {
     ldrprintln!("FB bpp: {} bytes", fb.size / fb.width as usize / fb.height as usize);
 
 
-    let cfg = parse_cfg();
+    let mut cfg = match parse_cfg() {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            ldrprintln!("WARNING: Could not read LOADER.CFG: {}. Using defaults.", err);
+            Config::default()
+        }
+    };
+
+    // Make sure the root slice can actually be read, otherwise let the user pick another one
+    while let Err(err) = fs::list_dir(cfg.rootfs, "/") {
+        cfg.rootfs = pick_root(cfg.rootfs, &err);
+    }
+
     ldrprintln!("root={}", cfg.rootfs.as_string());
+    if let Ok(features) = fs::features(cfg.rootfs) {
+        if !features.is_empty() {
+            ldrprintln!("root features: {}", features.join(" "));
+        }
+    }
     ldrprintln!("resolution={}", cfg.resolution);
 
     ldrprintln!("Done. Looping.");
@@ -66,6 +84,41 @@ fn main() {
 }
 
 
+/// Shows why *root* could not be used and asks the user to pick another slice
+fn pick_root(root: GUID, err: &fs::FsError) -> GUID {
+    console::clear();
+
+    ldrprintln!("ERROR: Could not use slice {} as the root filesystem: {}", root.as_string(), err);
+    ldrprintln!("");
+    ldrprintln!("Check the \"root\" option in /EFI/BOOT/ZOS/LOADER.CFG. Slices with a known filesystem:");
+
+    // Only single key presses are read, so only the first 9 slices can be picked
+    let volumes = fs::probe_volumes();
+    for (i, volume) in volumes.iter().take(9).enumerate() {
+        ldrprintln!("  [{}] {}  {:?}  UUID={:X}  LABEL=\"{}\"", i + 1, volume.slice.as_string(), volume.fs_type, volume.uuid, volume.label);
+    }
+
+    if volumes.is_empty() {
+        ldrprintln!("  None found. Halting.");
+        loop {}
+    }
+
+    loop {
+        ldrprint!("Select a root slice: ");
+        let c = console::getc();
+        ldrprintln!("{}", c);
+
+        match c.to_digit(10) {
+            Some(n) if n >= 1 && n as usize <= volumes.len().min(9) => {
+                return volumes[n as usize - 1].slice;
+            }
+
+            _ => { ldrprintln!("Invalid choice \"{}\"", c); }
+        }
+    }
+}
+
+
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
     ldrprintln!("{}", _info);
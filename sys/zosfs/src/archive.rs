@@ -0,0 +1,627 @@
+/*  archive.rs - tar and cpio archives as filesystems
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Read only filesystem over an archive held in memory, so files can be pulled out of a tarball or cpio image the same way as out of a
+//! disk. Supports ustar with the pax and GNU long name extensions, and the newc, crc and odc cpio formats.
+//!
+//! The whole archive is indexed into a directory tree when it's opened. Members can appear in any order, directories that only show up
+//! as part of a path are made up, and a later member replaces an earlier one with the same path like it would when extracting.
+
+use alloc::{collections::BTreeMap, format, string::{String, ToString}, vec::Vec, vec};
+use crate::block;
+use crate::error::FsError;
+use crate::fs::{CaseRule, DirEntry, FileType, Filesystem, Metadata};
+
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ArchiveFormat {
+    Tar,
+    /// cpio "new ASCII" format, with or without checksums
+    CpioNewc,
+    /// cpio "old portable ASCII" format
+    CpioOdc,
+}
+
+
+/// ustar header. Numbers are NUL or space terminated octal strings.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct TarHeader {
+    pub name:               [u8; 100],
+    pub mode:               [u8; 8],
+    pub uid:                [u8; 8],
+    pub gid:                [u8; 8],
+    pub size:               [u8; 12],
+    pub mtime:              [u8; 12],
+    pub checksum:           [u8; 8],
+    pub typeflag:           u8,
+    pub linkname:           [u8; 100],
+    pub magic:              [u8; 6],
+    pub version:            [u8; 2],
+    pub uname:              [u8; 32],
+    pub gname:              [u8; 32],
+    pub devmajor:           [u8; 8],
+    pub devminor:           [u8; 8],
+    pub prefix:             [u8; 155],
+    _pad:                   [u8; 12],
+}
+
+const TAR_BLOCK: usize = 512;
+
+const TAR_REGULAR: u8 = b'0';
+const TAR_REGULAR_OLD: u8 = 0;
+const TAR_HARDLINK: u8 = b'1';
+const TAR_SYMLINK: u8 = b'2';
+const TAR_DIRECTORY: u8 = b'5';
+const TAR_CONTIGUOUS: u8 = b'7';
+const TAR_PAX_LOCAL: u8 = b'x';
+const TAR_PAX_GLOBAL: u8 = b'g';
+const TAR_GNU_LONG_NAME: u8 = b'L';
+const TAR_GNU_LONG_LINK: u8 = b'K';
+
+const CPIO_NEWC_MAGIC: &[u8] = b"070701";
+const CPIO_CRC_MAGIC: &[u8] = b"070702";
+const CPIO_ODC_MAGIC: &[u8] = b"070707";
+const CPIO_NEWC_HEADER: usize = 110;
+const CPIO_ODC_HEADER: usize = 76;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+
+/// A member of the archive, as found in its header
+struct Member {
+    path:       String,
+    file_type:  FileType,
+    mode:       u16,
+    uid:        u32,
+    gid:        u32,
+    mtime:      i64,
+    /// Where the contents are in the archive
+    offset:     usize,
+    size:       usize,
+    /// Target of a symlink
+    link:       Option<String>,
+    /// Path of the member a hard link shares its contents with
+    hardlink:   Option<String>,
+}
+
+
+/// A node of the tree built from the archive
+struct ArchiveNode {
+    name:       String,
+    file_type:  FileType,
+    mode:       u16,
+    uid:        u32,
+    gid:        u32,
+    mtime:      i64,
+    offset:     usize,
+    size:       usize,
+    link:       Option<String>,
+    children:   Vec<usize>,
+}
+
+
+
+/// Works out the format of an archive from its first header
+pub fn detect(data: &[u8]) -> Option<ArchiveFormat> {
+    if data.starts_with(CPIO_NEWC_MAGIC) || data.starts_with(CPIO_CRC_MAGIC) {
+        return Some(ArchiveFormat::CpioNewc);
+    }
+    else if data.starts_with(CPIO_ODC_MAGIC) {
+        return Some(ArchiveFormat::CpioOdc);
+    }
+    // Pre-POSIX tar has no magic, but its header checksum is still there
+    else if data.len() >= TAR_BLOCK && (&data[257..262] == b"ustar" || tar_checksum_ok(&data[..TAR_BLOCK])) {
+        return Some(ArchiveFormat::Tar);
+    }
+    else {
+        return None;
+    }
+}
+
+
+
+/// An archive mounted as a filesystem. Nodes are indices into the tree, the root is 0.
+pub struct ArchiveFs {
+    data:       Vec<u8>,
+    format:     ArchiveFormat,
+    nodes:      Vec<ArchiveNode>,
+}
+
+impl ArchiveFs {
+    /// Indexes the archive in *data*
+    pub fn new(data: Vec<u8>) -> Result<Self, FsError> {
+        let format = detect(&data).ok_or(FsError::UnknownFilesystem)?;
+
+        let members = match format {
+            ArchiveFormat::Tar      => parse_tar(&data)?,
+            _                       => parse_cpio(&data, format)?,
+        };
+
+        let mut fs = Self {
+            data,
+            format,
+            nodes:  vec![ArchiveNode::directory(String::new())],
+        };
+
+        let mut hardlinks: Vec<(usize, String)> = Vec::new();
+        for member in members {
+            let Some(index) = fs.insert(&member) else { continue };
+
+            if let Some(target) = member.hardlink {
+                hardlinks.push((index, target));
+            }
+        }
+
+        // Hard links may come before the file they point to in cpio archives, so they're only resolved once every member is in
+        for (index, target) in hardlinks {
+            let Some(target) = fs.find(&target) else {
+                return Err(FsError::Corrupt("hard link to a missing archive member"));
+            };
+
+            let (offset, size, file_type) = (fs.nodes[target].offset, fs.nodes[target].size, fs.nodes[target].file_type);
+            let node = &mut fs.nodes[index];
+            node.offset = offset;
+            node.size = size;
+            node.file_type = file_type;
+        }
+
+        Ok(fs)
+    }
+
+
+    pub fn format(&self) -> ArchiveFormat {
+        self.format
+    }
+
+
+    /// Adds a member to the tree, making up any missing parent directories. Returns its node, or None if its path is empty.
+    fn insert(&mut self, member: &Member) -> Option<usize> {
+        let mut components: Vec<&str> = Vec::new();
+        for component in member.path.split('/') {
+            match component {
+                "" | "."    => {}
+                ".."        => { components.pop(); }
+                _           => components.push(component),
+            }
+        }
+
+        let (name, parents) = components.split_last()?;
+
+        let mut dir = 0;
+        for component in parents {
+            dir = match self.child(dir, component) {
+                Some(child) if self.nodes[child].file_type == FileType::Directory => child,
+                _ => self.add_child(dir, ArchiveNode::directory(component.to_string())),
+            };
+        }
+
+        let node = ArchiveNode {
+            name:       name.to_string(),
+            file_type:  member.file_type,
+            mode:       member.mode,
+            uid:        member.uid,
+            gid:        member.gid,
+            mtime:      member.mtime,
+            offset:     member.offset,
+            size:       member.size,
+            link:       member.link.clone(),
+            children:   Vec::new(),
+        };
+
+        // A directory listed again, e.g after its contents, keeps its children
+        match self.child(dir, name) {
+            Some(existing) => {
+                let children = if node.file_type == FileType::Directory { core::mem::take(&mut self.nodes[existing].children) } else { Vec::new() };
+                self.nodes[existing] = node;
+                self.nodes[existing].children = children;
+
+                Some(existing)
+            }
+
+            None => Some(self.add_child(dir, node)),
+        }
+    }
+
+
+    fn add_child(&mut self, dir: usize, node: ArchiveNode) -> usize {
+        self.nodes.push(node);
+        let index = self.nodes.len() - 1;
+        self.nodes[dir].children.push(index);
+
+        index
+    }
+
+
+    fn child(&self, dir: usize, name: &str) -> Option<usize> {
+        self.nodes[dir].children.iter().copied().find(|child| self.nodes[*child].name == name)
+    }
+
+
+    /// Finds a member by its path in the archive, without following symlinks
+    fn find(&self, path: &str) -> Option<usize> {
+        path.split('/').filter(|c| !c.is_empty() && *c != ".").try_fold(0, |dir, name| self.child(dir, name))
+    }
+
+
+    /// Lists the interesting properties of the archive as name/value pairs, for debugging tools
+    pub fn superblock_info(&self) -> Vec<(&'static str, String)> {
+        let format = match self.format {
+            ArchiveFormat::Tar          => "tar",
+            ArchiveFormat::CpioNewc     => "cpio (newc)",
+            ArchiveFormat::CpioOdc      => "cpio (odc)",
+        };
+
+        vec![
+            ("Archive format",      format.to_string()),
+            ("Archive size",        self.data.len().to_string()),
+            ("Entries",             (self.nodes.len() - 1).to_string()),
+        ]
+    }
+}
+
+impl Filesystem for ArchiveFs {
+    type Node = usize;
+
+    fn case_rule(&self) -> CaseRule {
+        CaseRule::Sensitive
+    }
+
+    fn root(&self) -> Result<DirEntry<usize>, FsError> {
+        Ok(DirEntry {
+            name:       "/".to_string(),
+            file_type:  FileType::Directory,
+            node:       0,
+        })
+    }
+
+    fn read_dir(&self, dir: &usize) -> Result<Vec<DirEntry<usize>>, FsError> {
+        Ok(self.nodes[*dir].children.iter().map(|child| DirEntry {
+            name:       self.nodes[*child].name.clone(),
+            file_type:  self.nodes[*child].file_type,
+            node:       *child,
+        }).collect())
+    }
+
+    fn lookup(&self, dir: &usize, name: &str) -> Result<Option<DirEntry<usize>>, FsError> {
+        Ok(self.child(*dir, name).map(|child| DirEntry {
+            name:       self.nodes[child].name.clone(),
+            file_type:  self.nodes[child].file_type,
+            node:       child,
+        }))
+    }
+
+    fn read_link(&self, node: &usize) -> Result<String, FsError> {
+        self.nodes[*node].link.clone().ok_or(FsError::Corrupt("symlink without a target"))
+    }
+
+    fn metadata(&self, node: &usize) -> Result<Metadata, FsError> {
+        let node_info = &self.nodes[*node];
+
+        Ok(Metadata {
+            file_type:  node_info.file_type,
+            size:       node_info.size as u64,
+            mode:       node_info.mode,
+            uid:        node_info.uid,
+            gid:        node_info.gid,
+            mtime:      node_info.mtime,
+            id:         *node as u64,
+        })
+    }
+
+    fn read(&self, node: &usize, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let node = &self.nodes[*node];
+        if node.file_type != FileType::Regular || offset >= node.size as u64 {
+            return Ok(0);
+        }
+
+        let start = node.offset + offset as usize;
+        let len = buffer.len().min(node.size - offset as usize);
+        buffer[..len].copy_from_slice(&self.data[start..start + len]);
+
+        Ok(len)
+    }
+}
+
+
+impl ArchiveNode {
+    /// A directory that only appears as part of other members' paths
+    fn directory(name: String) -> Self {
+        Self {
+            name,
+            file_type:  FileType::Directory,
+            mode:       0o755,
+            uid:        0,
+            gid:        0,
+            mtime:      0,
+            offset:     0,
+            size:       0,
+            link:       None,
+            children:   Vec::new(),
+        }
+    }
+}
+
+
+
+/* tar */
+
+/// Lists the members of a tar archive, applying pax and GNU extension headers to the member that follows them
+fn parse_tar(data: &[u8]) -> Result<Vec<Member>, FsError> {
+    let mut members = Vec::new();
+    let mut global: BTreeMap<String, String> = BTreeMap::new();
+    let mut local: BTreeMap<String, String> = BTreeMap::new();
+    let mut pos = 0;
+
+    while pos + TAR_BLOCK <= data.len() {
+        let raw = &data[pos..pos + TAR_BLOCK];
+
+        // The archive ends with two zero blocks, but one is enough to know we're done
+        if raw.iter().all(|b| *b == 0) {
+            break;
+        }
+        if !tar_checksum_ok(raw) {
+            return Err(FsError::Corrupt("tar header checksum mismatch"));
+        }
+
+        let header: TarHeader = block::struct_from_bytes(raw);
+        let mut size = parse_number(&header.size, 8).ok_or(FsError::Corrupt("invalid tar member size"))? as usize;
+
+        // A pax size overrides the header's, which can't hold more than 8GiB
+        if let Some(pax_size) = local.get("size").or(global.get("size")) {
+            size = pax_size.parse().map_err(|_| FsError::Corrupt("invalid pax size"))?;
+        }
+
+        // Sizes come from the archive, so even the end of the member can overflow
+        let offset = pos + TAR_BLOCK;
+        let end = offset.checked_add(size).filter(|end| *end <= data.len())
+            .ok_or(FsError::Corrupt("tar member runs past the end of the archive"))?;
+        let contents = &data[offset..end];
+        pos = offset + size.div_ceil(TAR_BLOCK) * TAR_BLOCK;
+
+        match header.typeflag {
+            TAR_PAX_LOCAL       => { local.extend(parse_pax(contents)?); continue; }
+            TAR_PAX_GLOBAL      => { global.extend(parse_pax(contents)?); continue; }
+            TAR_GNU_LONG_NAME   => { local.insert("path".to_string(), c_string(contents)); continue; }
+            TAR_GNU_LONG_LINK   => { local.insert("linkpath".to_string(), c_string(contents)); continue; }
+            _ => {}
+        }
+
+        let attribute = |key: &str| local.get(key).or(global.get(key)).cloned();
+
+        let path = match attribute("path") {
+            Some(path) => path,
+            None       => {
+                let name = c_string(&header.name);
+                let prefix = c_string(&header.prefix);
+
+                // GNU tar uses the prefix field for other things, only ustar archives have a prefix
+                if &header.magic == b"ustar\0" && !prefix.is_empty() { format!("{}/{}", prefix, name) } else { name }
+            }
+        };
+        let linkname = attribute("linkpath").unwrap_or_else(|| c_string(&header.linkname));
+
+        let file_type = match header.typeflag {
+            TAR_REGULAR | TAR_REGULAR_OLD | TAR_CONTIGUOUS | TAR_HARDLINK  => FileType::Regular,
+            TAR_SYMLINK                                                     => FileType::Symlink,
+            TAR_DIRECTORY                                                   => FileType::Directory,
+            _                                                               => FileType::Other,
+        };
+
+        // Old archives mark directories with a trailing slash instead of a type
+        let file_type = if header.typeflag == TAR_REGULAR_OLD && path.ends_with('/') { FileType::Directory } else { file_type };
+
+        let mtime = match attribute("mtime") {
+            // pax times can have a fractional part
+            Some(mtime) => mtime.split('.').next().unwrap().parse().map_err(|_| FsError::Corrupt("invalid pax mtime"))?,
+            None        => parse_number(&header.mtime, 12).unwrap_or(0) as i64,
+        };
+
+        members.push(Member {
+            path,
+            file_type,
+            mode:       (parse_number(&header.mode, 8).unwrap_or(0) & 0o7777) as u16,
+            uid:        attribute("uid").and_then(|uid| uid.parse().ok()).unwrap_or(parse_number(&header.uid, 8).unwrap_or(0) as u32),
+            gid:        attribute("gid").and_then(|gid| gid.parse().ok()).unwrap_or(parse_number(&header.gid, 8).unwrap_or(0) as u32),
+            mtime,
+            offset,
+            size:       if file_type == FileType::Regular { size } else { 0 },
+            link:       if file_type == FileType::Symlink { Some(linkname.clone()) } else { None },
+            hardlink:   if header.typeflag == TAR_HARDLINK { Some(linkname) } else { None },
+        });
+
+        local.clear();
+    }
+
+    Ok(members)
+}
+
+
+/// Checks a tar header's checksum, the sum of its bytes with the checksum field counted as spaces
+fn tar_checksum_ok(raw: &[u8]) -> bool {
+    let Some(expected) = parse_number(&raw[148..156], 8) else { return false };
+
+    let sum: u64 = raw.iter().enumerate().map(|(i, b)| if (148..156).contains(&i) { b' ' as u64 } else { *b as u64 }).sum();
+
+    sum == expected
+}
+
+
+/// Parses a tar number. They're octal strings, except GNU tar stores big ones in base 256 with the top bit of the first byte set.
+fn parse_number(raw: &[u8], radix: u32) -> Option<u64> {
+    if raw.first().is_some_and(|b| b & 0x80 != 0) {
+        return Some(raw[1..].iter().fold((raw[0] & 0x7F) as u64, |n, b| (n << 8) | *b as u64));
+    }
+
+    let digits = core::str::from_utf8(raw).ok()?.trim_matches(|c: char| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        return Some(0);
+    }
+
+    u64::from_str_radix(digits, if radix == 8 || radix == 12 { 8 } else { radix }).ok()
+}
+
+
+/// Parses the "<length> <key>=<value>\n" records of a pax extended header
+fn parse_pax(raw: &[u8]) -> Result<BTreeMap<String, String>, FsError> {
+    let mut attributes = BTreeMap::new();
+    let mut pos = 0;
+
+    while pos < raw.len() {
+        let space = raw[pos..].iter().position(|b| *b == b' ').ok_or(FsError::Corrupt("invalid pax record"))?;
+        let len: usize = core::str::from_utf8(&raw[pos..pos + space]).ok().and_then(|len| len.parse().ok())
+            .ok_or(FsError::Corrupt("invalid pax record length"))?;
+
+        if len <= space + 1 || pos + len > raw.len() {
+            return Err(FsError::Corrupt("invalid pax record length"));
+        }
+
+        // The record includes its length and the trailing newline
+        let record = String::from_utf8_lossy(&raw[pos + space + 1..pos + len - 1]);
+        if let Some((key, value)) = record.split_once('=') {
+            attributes.insert(key.to_string(), value.to_string());
+        }
+
+        pos += len;
+    }
+
+    Ok(attributes)
+}
+
+
+/// Reads a NUL terminated string out of a fixed size field
+fn c_string(raw: &[u8]) -> String {
+    let end = raw.iter().position(|b| *b == 0).unwrap_or(raw.len());
+
+    String::from_utf8_lossy(&raw[..end]).to_string()
+}
+
+
+
+/* cpio */
+
+/// Lists the members of a cpio archive
+fn parse_cpio(data: &[u8], format: ArchiveFormat) -> Result<Vec<Member>, FsError> {
+    let mut members: Vec<Member> = Vec::new();
+    // Hard linked files share one inode. Only one of their members holds the contents, the rest have a size of 0.
+    let mut inodes: BTreeMap<(u64, u64), String> = BTreeMap::new();
+    let mut links: Vec<(usize, (u64, u64))> = Vec::new();
+    let mut pos = 0;
+
+    loop {
+        let newc = format == ArchiveFormat::CpioNewc;
+        let header_size = if newc { CPIO_NEWC_HEADER } else { CPIO_ODC_HEADER };
+
+        if pos + header_size > data.len() {
+            return Err(FsError::Corrupt("cpio archive has no trailer"));
+        }
+        let header = &data[pos..pos + header_size];
+
+        // newc fields are 8 hex digits, odc fields 6 octal digits except for the 11 digit mtime and file size
+        let field = |index: usize| -> Result<u64, FsError> {
+            let (start, len, radix) = if newc {
+                (6 + index * 8, 8, 16)
+            } else {
+                const ODC_FIELDS: [(usize, usize); 10] = [(6, 6), (12, 6), (18, 6), (24, 6), (30, 6), (36, 6), (42, 6), (48, 11), (59, 6), (65, 11)];
+                (ODC_FIELDS[index].0, ODC_FIELDS[index].1, 8)
+            };
+
+            core::str::from_utf8(&header[start..start + len]).ok().and_then(|digits| u64::from_str_radix(digits, radix).ok())
+                .ok_or(FsError::Corrupt("invalid cpio header"))
+        };
+
+        if !(header.starts_with(CPIO_NEWC_MAGIC) || header.starts_with(CPIO_CRC_MAGIC) || header.starts_with(CPIO_ODC_MAGIC)) {
+            return Err(FsError::Corrupt("invalid cpio magic"));
+        }
+
+        // Field numbers in each format: ino, mode, uid, gid, nlink, mtime, filesize, namesize, and the device for hard links
+        let (ino, mode, uid, gid, nlink, mtime, size, name_size, dev) = if newc {
+            (field(0)?, field(1)?, field(2)?, field(3)?, field(4)?, field(5)?, field(6)?, field(11)?, (field(7)? << 32) | field(8)?)
+        } else {
+            (field(1)?, field(2)?, field(3)?, field(4)?, field(5)?, field(7)?, field(9)?, field(8)?, field(0)?)
+        };
+        let (size, name_size) = (size as usize, name_size as usize);
+
+        // newc pads the header and name, then the contents, to 4 bytes
+        let name_start = pos + header_size;
+        let offset = if newc { (name_start + name_size).next_multiple_of(4) } else { name_start + name_size };
+        let end = offset + size;
+
+        if end > data.len() || name_size == 0 {
+            return Err(FsError::Corrupt("cpio member runs past the end of the archive"));
+        }
+
+        let path = c_string(&data[name_start..name_start + name_size]);
+        let contents = &data[offset..end];
+        pos = if newc { end.next_multiple_of(4) } else { end };
+
+        if path == CPIO_TRAILER {
+            break;
+        }
+
+        if header.starts_with(CPIO_CRC_MAGIC) {
+            let sum = contents.iter().fold(0u32, |sum, b| sum.wrapping_add(*b as u32));
+            if sum != field(12)? as u32 {
+                return Err(FsError::Corrupt("cpio member checksum mismatch"));
+            }
+        }
+
+        let file_type = match mode as u32 & S_IFMT {
+            S_IFREG => FileType::Regular,
+            S_IFDIR => FileType::Directory,
+            S_IFLNK => FileType::Symlink,
+            _       => FileType::Other,
+        };
+
+        if file_type == FileType::Regular && nlink > 1 {
+            if size > 0 {
+                inodes.insert((dev, ino), path.clone());
+            }
+            else {
+                links.push((members.len(), (dev, ino)));
+            }
+        }
+
+        members.push(Member {
+            path,
+            file_type,
+            mode:       (mode & 0o7777) as u16,
+            uid:        uid as u32,
+            gid:        gid as u32,
+            mtime:      mtime as i64,
+            offset,
+            size:       if file_type == FileType::Regular { size } else { 0 },
+            link:       if file_type == FileType::Symlink { Some(String::from_utf8_lossy(contents).to_string()) } else { None },
+            hardlink:   None,
+        });
+    }
+
+    // Links without contents of their own point at the member that has them. If none has, they really are empty.
+    for (index, inode) in links {
+        if let Some(target) = inodes.get(&inode) {
+            members[index].hardlink = Some(target.clone());
+        }
+    }
+
+    Ok(members)
+}
@@ -0,0 +1,603 @@
+/*  mkfs-zxfs.rs - Creates ZXFS filesystems
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Usage: mkfs-zxfs [-b block-size] [-L label] [-U uuid] [-d root-dir] [-e max-extent] <image> [size]
+//!
+//! Formats *image* as ZXFS, optionally filled with a copy of *root-dir*. The image is created or resized to *size* bytes (which may end in
+//! K, M or G); without it an existing image keeps its size.
+
+#![allow(clippy::needless_return)]
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zosfs::block::{struct_from_bytes, struct_to_bytes};
+use zosfs::zxfs::*;
+
+
+const USAGE: &str = "Usage: mkfs-zxfs [-b block-size] [-L label] [-U uuid] [-d root-dir] [-e max-extent] <image> [size]
+
+Options:
+    -b block-size       Block size in bytes, a power of two from 1024 to 65536 (default 4096)
+    -L label            Volume label, up to 64 bytes
+    -U uuid             Volume UUID, e.g. 1b4e28ba-2fa1-11d2-883f-b9a761bde3fb (default random)
+    -d root-dir         Copy the contents of root-dir into the filesystem
+    -e max-extent       Longest extent in blocks, to test fragmented files
+
+size is in bytes and may end in K, M or G. Without it the image must exist and keeps its size.";
+
+
+struct Options {
+    block_size: u32,
+    label:      String,
+    uuid:       Option<u128>,
+    root_dir:   Option<String>,
+    max_extent: u32,
+    image:      String,
+    size:       Option<u64>,
+}
+
+
+fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1).collect()) {
+        Some(options)   => options,
+        None            => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match mkfs(&options) {
+        Ok(())      => ExitCode::SUCCESS,
+        Err(err)    => {
+            eprintln!("mkfs-zxfs: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+
+fn parse_args(args: Vec<String>) -> Option<Options> {
+    let mut options = Options {
+        block_size: 4096,
+        label:      String::new(),
+        uuid:       None,
+        root_dir:   None,
+        max_extent: u32::MAX,
+        image:      String::new(),
+        size:       None,
+    };
+    let mut positional = Vec::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-b" => options.block_size = args.next()?.parse().ok()?,
+            "-L" => options.label = args.next()?,
+            "-U" => options.uuid = Some(u128::from_str_radix(&args.next()?.replace('-', ""), 16).ok()?),
+            "-d" => options.root_dir = Some(args.next()?),
+            "-e" => options.max_extent = args.next()?.parse().ok().filter(|max| *max > 0)?,
+            _ if arg.starts_with('-') => return None,
+            _ => positional.push(arg),
+        }
+    }
+
+    match positional.as_slice() {
+        [image]         => options.image = image.clone(),
+        [image, size]   => {
+            options.image = image.clone();
+            options.size = Some(parse_size(size)?);
+        }
+        _ => return None,
+    }
+
+    if !options.block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&options.block_size) || options.label.len() > 64 {
+        return None;
+    }
+
+    Some(options)
+}
+
+
+/// Parses a size in bytes with an optional K, M or G suffix
+fn parse_size(size: &str) -> Option<u64> {
+    let (number, multiplier) = match size.as_bytes().last()? {
+        b'K' | b'k' => (&size[..size.len() - 1], 1 << 10),
+        b'M' | b'm' => (&size[..size.len() - 1], 1 << 20),
+        b'G' | b'g' => (&size[..size.len() - 1], 1 << 30),
+        _           => (size, 1),
+    };
+
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+
+/// Makes up a version 4 UUID
+fn random_uuid() -> u128 {
+    if let Some(uuid) = std::fs::read_to_string("/proc/sys/kernel/random/uuid").ok().and_then(|uuid| u128::from_str_radix(&uuid.trim().replace('-', ""), 16).ok()) {
+        return uuid;
+    }
+    else {
+        // Not random, but different every run, which is what matters for telling volumes apart
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_nanos()).unwrap_or(0);
+        let seed = nanos ^ ((std::process::id() as u128) << 64);
+        let mixed = seed.wrapping_mul(0x2545_f491_4f6c_dd1d_9e37_79b9_7f4a_7c15);
+
+        return (mixed & !(0xf000 << 64) & !(0xc << 60)) | (0x4000 << 64) | (0x8 << 60);
+    }
+}
+
+
+fn mkfs(options: &Options) -> Result<(), String> {
+    let image_error = |err: std::io::Error| format!("{}: {}", options.image, err);
+
+    let file = OpenOptions::new().read(true).write(true).create(options.size.is_some()).truncate(false).open(&options.image).map_err(image_error)?;
+    let size = match options.size {
+        Some(size)  => {
+            file.set_len(size).map_err(image_error)?;
+            size
+        }
+        None        => file.metadata().map_err(image_error)?.len(),
+    };
+
+    let entries = match &options.root_dir {
+        Some(root_dir)  => count_entries(Path::new(root_dir))?,
+        None            => 0,
+    };
+
+    let mut builder = Builder::new(file, options, size, entries)?;
+
+    match &options.root_dir {
+        Some(root_dir)  => {
+            let metadata = std::fs::symlink_metadata(root_dir).map_err(|err| format!("{}: {}", root_dir, err))?;
+            if !metadata.is_dir() {
+                return Err(format!("{}: not a directory", root_dir));
+            }
+            builder.add_dir(Path::new(root_dir), INODE_ROOT)?;
+        }
+        None            => {
+            builder.init_inode(INODE_ROOT, S_IFDIR | 0o755, 0, 0, builder.time);
+            builder.inodes[INODE_ROOT as usize].links = 2;
+            builder.write_dir(INODE_ROOT, &[])?;
+        }
+    }
+
+    builder.finish(options)
+}
+
+
+/// Counts the files and directories under *dir*, to know how big the inode table must be
+fn count_entries(dir: &Path) -> Result<u64, String> {
+    let mut count = 0;
+
+    for entry in std::fs::read_dir(dir).map_err(|err| format!("{}: {}", dir.display(), err))? {
+        let entry = entry.map_err(|err| format!("{}: {}", dir.display(), err))?;
+        count += 1;
+
+        if entry.file_type().map_err(|err| format!("{}: {}", entry.path().display(), err))?.is_dir() {
+            count += count_entries(&entry.path())?;
+        }
+    }
+
+    Ok(count)
+}
+
+
+
+/// Lays the filesystem out on the image. Blocks are handed out in order: the superblock, the free space bitmap, the inode table, then
+/// file contents and extent blocks as files are added.
+struct Builder {
+    image:          File,
+    block_size:     u64,
+    block_count:    u64,
+    next_block:     u64,
+    max_extent:     u32,
+    bitmap_blocks:  u64,
+    table_blocks:   u64,
+    /// Indexed by inode number
+    inodes:         Vec<Inode>,
+    next_inode:     u64,
+    /// Inodes of the files seen so far by device and inode number on the host, so hard links stay links
+    hard_links:     HashMap<(u64, u64), u64>,
+    time:           i64,
+}
+
+impl Builder {
+    fn new(image: File, options: &Options, size: u64, entries: u64) -> Result<Self, String> {
+        let block_size = options.block_size as u64;
+        let block_count = size / block_size;
+
+        let bitmap_blocks = block_count.div_ceil(8).div_ceil(block_size);
+        let inode_count = FIRST_INODE + entries;
+        let table_blocks = (inode_count * INODE_SIZE as u64).div_ceil(block_size);
+
+        if 1 + bitmap_blocks + table_blocks + 1 > block_count {
+            return Err(format!("{}: too small for a filesystem", options.image));
+        }
+
+        let mut builder = Self {
+            image,
+            block_size,
+            block_count,
+            next_block:     1 + bitmap_blocks + table_blocks,
+            max_extent:     options.max_extent,
+            bitmap_blocks,
+            table_blocks,
+            inodes:         vec![struct_from_bytes(&[0u8; INODE_SIZE]); inode_count as usize],
+            next_inode:     FIRST_INODE,
+            hard_links:     HashMap::new(),
+            time:           SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs() as i64).unwrap_or(0),
+        };
+
+        let time = builder.time;
+        builder.init_inode(INODE_TABLE, S_IFREG | 0o600, 0, 0, time);
+        builder.init_inode(INODE_BITMAP, S_IFREG | 0o600, 0, 0, time);
+
+        Ok(builder)
+    }
+
+
+    fn write_block(&mut self, block: u64, data: &[u8]) -> Result<(), String> {
+        self.image.seek(SeekFrom::Start(block * self.block_size)).map_err(|err| err.to_string())?;
+        self.image.write_all(data).map_err(|err| err.to_string())
+    }
+
+
+    /// Takes *count* blocks from the free space
+    fn allocate(&mut self, count: u64) -> Result<u64, String> {
+        if self.next_block + count > self.block_count {
+            return Err("image is too small for the files".to_string());
+        }
+
+        let block = self.next_block;
+        self.next_block += count;
+
+        Ok(block)
+    }
+
+
+    fn init_inode(&mut self, number: u64, mode: u16, uid: u32, gid: u32, mtime: i64) {
+        let inode = &mut self.inodes[number as usize];
+
+        inode.mode = mode;
+        inode.links = 1;
+        inode.uid = uid;
+        inode.gid = gid;
+        inode.atime = mtime;
+        inode.mtime = mtime;
+        inode.ctime = mtime;
+        inode.crtime = self.time;
+        inode.number = number;
+    }
+
+
+    /// Gives a host file's ownership, permissions and timestamps to an inode
+    fn copy_metadata(&mut self, number: u64, metadata: &std::fs::Metadata) {
+        self.init_inode(number, metadata.mode() as u16, metadata.uid(), metadata.gid(), metadata.mtime());
+
+        let inode = &mut self.inodes[number as usize];
+        inode.atime = metadata.atime();
+        inode.ctime = metadata.ctime();
+    }
+
+
+    /// Points an inode's extent tree root at *extents*, given as (first logical block, entry)
+    fn set_root(&mut self, number: u64, depth: u16, entries: &[(u64, Vec<u8>)]) {
+        let header = ExtentHeader {
+            magic:  EXTENT_MAGIC,
+            depth,
+            count:  entries.len() as u16,
+            max:    ROOT_EXTENTS,
+        };
+
+        let mut root = struct_to_bytes(&header);
+        for (_, entry) in entries {
+            root.extend_from_slice(entry);
+        }
+        root.resize(INLINE_SIZE, 0);
+
+        self.inodes[number as usize].data.copy_from_slice(&root);
+    }
+
+
+    /// Stores *contents* as the contents of inode *number*. Blocks of zeros are left as holes, and with *inline* contents that fit are
+    /// kept in the inode.
+    fn write_contents(&mut self, number: u64, contents: &[u8], inline: bool) -> Result<(), String> {
+        self.inodes[number as usize].size = contents.len() as u64;
+
+        if inline && contents.len() <= INLINE_SIZE {
+            let inode = &mut self.inodes[number as usize];
+            inode.flags |= INODE_INLINE_DATA;
+            inode.data[..contents.len()].copy_from_slice(contents);
+
+            return Ok(());
+        }
+
+        let block_size = self.block_size as usize;
+        let mut extents: Vec<ExtentEntry> = Vec::new();
+
+        for (index, chunk) in contents.chunks(block_size).enumerate() {
+            if chunk.iter().all(|byte| *byte == 0) {
+                continue;
+            }
+
+            let physical = self.allocate(1)?;
+            let mut block = chunk.to_vec();
+            block.resize(block_size, 0);
+            self.write_block(physical, &block)?;
+
+            let logical = index as u64;
+            match extents.last_mut() {
+                Some(last) if last.logical + last.length as u64 == logical && last.physical + last.length as u64 == physical
+                              && last.length < self.max_extent => last.length += 1,
+                _ => extents.push(ExtentEntry { logical, physical, length: 1, flags: 0 }),
+            }
+        }
+
+        let mut blocks: u64 = extents.iter().map(|extent| extent.length as u64).sum();
+        let mut entries: Vec<(u64, Vec<u8>)> = extents.iter().map(|extent| (extent.logical, struct_to_bytes(extent))).collect();
+
+        // Extents that don't fit in the inode go to extent blocks, adding levels of index blocks until the top level fits
+        let per_block = (block_size - size_of::<ExtentBlockHeader>()) / size_of::<ExtentEntry>();
+        let mut depth = 0;
+
+        while entries.len() > ROOT_EXTENTS as usize {
+            let mut parents = Vec::new();
+
+            for chunk in entries.chunks(per_block) {
+                let header = ExtentBlockHeader {
+                    header:     ExtentHeader {
+                        magic:  EXTENT_MAGIC,
+                        depth,
+                        count:  chunk.len() as u16,
+                        max:    per_block as u16,
+                    },
+                    owner:      number,
+                    checksum:   0,
+                    _reserved:  0,
+                };
+
+                let mut block = struct_to_bytes(&header);
+                for (_, entry) in chunk {
+                    block.extend_from_slice(entry);
+                }
+                block.resize(block_size, 0);
+                let sum = checksum(&block, EXTENT_BLOCK_CHECKSUM);
+                block[EXTENT_BLOCK_CHECKSUM..EXTENT_BLOCK_CHECKSUM + 4].copy_from_slice(&sum.to_le_bytes());
+
+                let physical = self.allocate(1)?;
+                self.write_block(physical, &block)?;
+                blocks += 1;
+
+                let index = IndexEntry {
+                    logical:    chunk[0].0,
+                    block:      physical,
+                    _reserved:  0,
+                };
+                parents.push((chunk[0].0, struct_to_bytes(&index)));
+            }
+
+            entries = parents;
+            depth += 1;
+        }
+
+        self.set_root(number, depth, &entries);
+        self.inodes[number as usize].blocks = blocks;
+
+        Ok(())
+    }
+
+
+    /// Writes the directory listing of inode *number*. *entries* are (name, inode, type).
+    fn write_dir(&mut self, number: u64, entries: &[(Vec<u8>, u64, u8)]) -> Result<(), String> {
+        let block_size = self.block_size as usize;
+        let usable = block_size - DIR_TAIL_SIZE;
+        let mut contents = Vec::new();
+        let mut block = Vec::new();
+        // Start of the last entry in the block, which is stretched to fill it
+        let mut last = 0;
+
+        let finish_block = |contents: &mut Vec<u8>, block: &mut Vec<u8>, last: usize| {
+            if block.is_empty() {
+                let empty = DirEntryHeader { inode: 0, entry_len: usable as u16, name_len: 0, file_type: 0, _reserved: 0 };
+                block.extend_from_slice(&struct_to_bytes(&empty));
+            }
+            else {
+                let entry_len = (usable - last) as u16;
+                block[last + 8..last + 10].copy_from_slice(&entry_len.to_le_bytes());
+            }
+            block.resize(usable, 0);
+
+            let tail = DirTail { magic: DIR_TAIL_MAGIC, checksum: 0 };
+            block.extend_from_slice(&struct_to_bytes(&tail));
+            let sum = checksum(block, block_size - 4);
+            block[block_size - 4..].copy_from_slice(&sum.to_le_bytes());
+
+            contents.append(block);
+        };
+
+        for (name, inode, file_type) in entries {
+            let entry_len = (size_of::<DirEntryHeader>() + name.len()).next_multiple_of(8);
+            if block.len() + entry_len > usable {
+                finish_block(&mut contents, &mut block, last);
+            }
+
+            let header = DirEntryHeader {
+                inode:      *inode,
+                entry_len:  entry_len as u16,
+                name_len:   name.len() as u8,
+                file_type:  *file_type,
+                _reserved:  0,
+            };
+
+            last = block.len();
+            block.extend_from_slice(&struct_to_bytes(&header));
+            block.extend_from_slice(name);
+            block.resize(last + entry_len, 0);
+        }
+        finish_block(&mut contents, &mut block, last);
+
+        self.write_contents(number, &contents, false)
+    }
+
+
+    /// Copies the directory *path* and everything under it into inode *number*
+    fn add_dir(&mut self, path: &Path, number: u64) -> Result<(), String> {
+        let path_error = |err: std::io::Error| format!("{}: {}", path.display(), err);
+
+        let metadata = std::fs::symlink_metadata(path).map_err(path_error)?;
+        self.copy_metadata(number, &metadata);
+        self.inodes[number as usize].links = 2;
+
+        let mut children: Vec<_> = std::fs::read_dir(path).map_err(path_error)?.collect::<Result<_, _>>().map_err(path_error)?;
+        children.sort_by_key(|entry| entry.file_name());
+
+        let mut entries = Vec::new();
+        for child in children {
+            let name = child.file_name().as_bytes().to_vec();
+            if name.len() > MAX_NAME_LEN {
+                return Err(format!("{}: name too long", child.path().display()));
+            }
+
+            let (child_number, file_type) = self.add(&child.path())?;
+            if file_type == TYPE_DIRECTORY {
+                self.inodes[number as usize].links += 1;
+            }
+
+            entries.push((name, child_number, file_type));
+        }
+
+        self.write_dir(number, &entries)
+    }
+
+
+    /// Copies the file, symlink or directory at *path*, returning its inode and directory entry type
+    fn add(&mut self, path: &Path) -> Result<(u64, u8), String> {
+        let path_error = |err: std::io::Error| format!("{}: {}", path.display(), err);
+        let metadata = std::fs::symlink_metadata(path).map_err(path_error)?;
+        let file_type = metadata.file_type();
+
+        let entry_type = match metadata.mode() as u16 & S_IFMT {
+            S_IFREG     => TYPE_REGULAR,
+            S_IFDIR     => TYPE_DIRECTORY,
+            S_IFLNK     => TYPE_SYMLINK,
+            0o020000    => TYPE_CHARDEV,
+            0o060000    => TYPE_BLOCKDEV,
+            0o010000    => TYPE_FIFO,
+            _           => TYPE_SOCKET,
+        };
+
+        if !file_type.is_dir() {
+            if let Some(number) = self.hard_links.get(&(metadata.dev(), metadata.ino())) {
+                self.inodes[*number as usize].links += 1;
+                return Ok((*number, entry_type));
+            }
+        }
+
+        let number = self.next_inode;
+        self.next_inode += 1;
+
+        if file_type.is_dir() {
+            self.add_dir(path, number)?;
+        }
+        else {
+            self.copy_metadata(number, &metadata);
+            self.hard_links.insert((metadata.dev(), metadata.ino()), number);
+
+            if file_type.is_file() {
+                let contents = std::fs::read(path).map_err(path_error)?;
+                self.write_contents(number, &contents, true)?;
+            }
+            else if file_type.is_symlink() {
+                let target = std::fs::read_link(path).map_err(path_error)?;
+                self.write_contents(number, target.as_os_str().as_bytes(), true)?;
+            }
+        }
+
+        Ok((number, entry_type))
+    }
+
+
+    /// Writes the free space bitmap, the inode table and the superblock
+    fn finish(mut self, options: &Options) -> Result<(), String> {
+        let bitmap_start = 1;
+        let table_start = bitmap_start + self.bitmap_blocks;
+
+        let mut bitmap = vec![0u8; (self.bitmap_blocks * self.block_size) as usize];
+        for block in 0..self.next_block {
+            bitmap[(block / 8) as usize] |= 1 << (block % 8);
+        }
+        self.write_block(bitmap_start, &bitmap)?;
+
+        for (number, start, blocks, size) in [
+            (INODE_BITMAP, bitmap_start, self.bitmap_blocks, self.block_count.div_ceil(8)),
+            (INODE_TABLE, table_start, self.table_blocks, self.inodes.len() as u64 * INODE_SIZE as u64),
+        ] {
+            let extent = ExtentEntry { logical: 0, physical: start, length: blocks as u32, flags: 0 };
+            self.set_root(number, 0, &[(0, struct_to_bytes(&extent))]);
+
+            let inode = &mut self.inodes[number as usize];
+            inode.size = size;
+            inode.blocks = blocks;
+        }
+
+        let mut table = Vec::new();
+        for inode in self.inodes.iter_mut() {
+            if inode.number != 0 {
+                inode.checksum = 0;
+                inode.checksum = checksum(&struct_to_bytes(inode), INODE_CHECKSUM);
+            }
+            table.extend_from_slice(&struct_to_bytes(inode));
+        }
+        table.resize((self.table_blocks * self.block_size) as usize, 0);
+        self.write_block(table_start, &table)?;
+
+        let mut label = [0u8; 64];
+        label[..options.label.len()].copy_from_slice(options.label.as_bytes());
+
+        let mut sb: SuperBlock = struct_from_bytes(&[0u8; SUPERBLOCK_SIZE]);
+        sb.magic = MAGIC;
+        sb.version = VERSION;
+        sb.block_size = self.block_size as u32;
+        sb.block_count = self.block_count;
+        sb.free_blocks = self.block_count - self.next_block;
+        sb.inode_count = self.inodes.iter().filter(|inode| inode.number != 0).count() as u64;
+        sb.root_inode = INODE_ROOT;
+        sb.uuid = options.uuid.unwrap_or_else(random_uuid).to_be_bytes();
+        sb.label = label;
+        sb.created = self.time;
+        sb.modified = self.time;
+        sb.inode_table = self.inodes[INODE_TABLE as usize];
+
+        let mut raw = struct_to_bytes(&sb);
+        let sum = checksum(&raw, SUPERBLOCK_CHECKSUM);
+        raw[SUPERBLOCK_CHECKSUM..].copy_from_slice(&sum.to_le_bytes());
+
+        // The rest of block 0 is cleared so nothing left over from a previous filesystem is detected
+        raw.resize(self.block_size as usize, 0);
+        self.write_block(0, &raw)?;
+
+        self.image.flush().map_err(|err| err.to_string())
+    }
+}
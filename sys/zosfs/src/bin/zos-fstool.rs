@@ -0,0 +1,207 @@
+/*  zos-fstool.rs - Inspects disk images using the same filesystem drivers as the loader
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Usage: zos-fstool <image> <command> [args]
+//!
+//! Lets the drivers be checked against debugfs/mtools without booting anything. Partitions are given by their number in the GPT (starting at
+//! 1, like Linux), their unique GUID, or 0 for an image that holds a filesystem without a partition table.
+
+#![allow(clippy::needless_return)]
+
+use std::process::ExitCode;
+use zosfs::gpt::{self, GptEntry};
+use zosfs::image::ImageDevice;
+use zosfs::uuid::GUID;
+use zosfs::{mount, FileType, FsError, Mount, Partition};
+
+
+const USAGE: &str = "Usage: zos-fstool <image> <command> [args]
+
+Commands:
+    parts                       List the GPT partitions and their filesystems
+    ls <part> [path]            List a directory
+    cat <part> <path>           Write a file to stdout
+    stat <part> <path>          Show a file's metadata
+    superblock <part>           Show the filesystem's superblock
+    extents <part> <path>       Show the blocks holding a file
+
+<part> is a GPT partition number starting at 1, a partition GUID, or 0 for the whole image.";
+
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+
+    let result = match args.as_slice() {
+        [image, "parts"]                    => parts(image),
+        [image, "ls", part]                 => ls(image, part, "/"),
+        [image, "ls", part, path]           => ls(image, part, path),
+        [image, "cat", part, path]          => cat(image, part, path),
+        [image, "stat", part, path]         => stat(image, part, path),
+        [image, "superblock", part]         => superblock(image, part),
+        [image, "extents", part, path]      => extents(image, part, path),
+
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match result {
+        Ok(())      => ExitCode::SUCCESS,
+        Err(err)    => {
+            eprintln!("zos-fstool: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+
+/// Opens *image* read only
+fn open_image(image: &str) -> Result<ImageDevice, String> {
+    ImageDevice::open(image).map_err(|err| format!("{}: {}", image, err))
+}
+
+
+/// Finds the partition *part* refers to and mounts its filesystem
+fn open_part(image: &str, part: &str) -> Result<Mount<Partition<ImageDevice>>, String> {
+    let dev = open_image(image)?;
+
+    let partition = if part == "0" {
+        let blocks = dev.blocks().map_err(|err| format!("{}: {}", image, err))?;
+        Partition::new(dev, 0, blocks)
+    }
+    else {
+        let partitions = gpt::read_partitions(&dev).map_err(|err| err.to_string())?;
+        let entry = find_part(&partitions, part).ok_or(format!("no partition {}", part))?;
+
+        entry.open(dev)
+    };
+
+    mount(partition).map_err(|err| format!("partition {}: {}", part, err))
+}
+
+
+/// Looks a partition up by number or GUID
+fn find_part<'a>(partitions: &'a [GptEntry], part: &str) -> Option<&'a GptEntry> {
+    if let Ok(number) = part.parse::<usize>() {
+        return partitions.iter().find(|entry| entry.index + 1 == number);
+    }
+    else {
+        let guid = GUID::new_from_string(part)?;
+        return partitions.iter().find(|entry| entry.guid == guid);
+    }
+}
+
+
+fn parts(image: &str) -> Result<(), String> {
+    let dev = open_image(image)?;
+    let partitions = gpt::read_partitions(&dev).map_err(|err| err.to_string())?;
+
+    println!("{:<4} {:>12} {:>12}  {:<36}  {:<36}  {:<7} NAME", "#", "START", "END", "GUID", "TYPE", "FS");
+    for entry in &partitions {
+        let fs_type = match zosfs::mount::detect_fs_type(&entry.open(&dev)) {
+            Ok(fs_type) => format!("{:?}", fs_type),
+            Err(err)    => format!("<{}>", err),
+        };
+
+        println!("{:<4} {:>12} {:>12}  {}  {}  {:<7} {}",
+                 entry.index + 1, entry.first_lba, entry.last_lba, entry.guid.as_string(), entry.type_guid.as_string(), fs_type, entry.name);
+    }
+
+    Ok(())
+}
+
+
+fn ls(image: &str, part: &str, path: &str) -> Result<(), String> {
+    let mount = open_part(image, part)?;
+    let mut entries = mount.list_dir(path).map_err(|err| path_error(path, err))?;
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (name, file_type) in entries {
+        let suffix = match file_type {
+            FileType::Directory => "/",
+            FileType::Symlink   => "@",
+            _                   => "",
+        };
+
+        println!("{}{}", name, suffix);
+    }
+
+    Ok(())
+}
+
+
+fn cat(image: &str, part: &str, path: &str) -> Result<(), String> {
+    use std::io::Write;
+
+    let mount = open_part(image, part)?;
+    let contents = mount.read_file(path).map_err(|err| path_error(path, err))?;
+
+    std::io::stdout().write_all(&contents).map_err(|err| err.to_string())
+}
+
+
+fn stat(image: &str, part: &str, path: &str) -> Result<(), String> {
+    let mount = open_part(image, part)?;
+    let metadata = mount.metadata(path).map_err(|err| path_error(path, err))?;
+
+    println!("  File: {}", path);
+    println!("  Type: {:?}", metadata.file_type);
+    println!("  Size: {}", metadata.size);
+    println!(" Inode: {}", metadata.id);
+    println!("  Mode: {:04o}", metadata.mode);
+    println!("   Uid: {}", metadata.uid);
+    println!("   Gid: {}", metadata.gid);
+    println!(" Mtime: {}", metadata.mtime);
+
+    Ok(())
+}
+
+
+fn superblock(image: &str, part: &str) -> Result<(), String> {
+    let mount = open_part(image, part)?;
+    let fields = mount.superblock_info().map_err(|err| err.to_string())?;
+
+    println!("{:<26}{:?}", "Filesystem type:", mount.fs_type());
+    for (name, value) in fields {
+        println!("{:<26}{}", format!("{}:", name), value);
+    }
+
+    Ok(())
+}
+
+
+fn extents(image: &str, part: &str, path: &str) -> Result<(), String> {
+    let mount = open_part(image, part)?;
+    let extents = mount.extents(path).map_err(|err| path_error(path, err))?;
+
+    println!("{:>12} {:>12} {:>8}", "LOGICAL", "PHYSICAL", "LENGTH");
+    for extent in extents {
+        println!("{:>12} {:>12} {:>8}", extent.logical, extent.physical, extent.length);
+    }
+
+    Ok(())
+}
+
+
+/// Prefixes an error with the path it happened on
+fn path_error(path: &str, err: FsError) -> String {
+    format!("{}: {}", path, err)
+}
@@ -0,0 +1,209 @@
+/*  block.rs - Block device interface
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use core::{fmt, mem::size_of, ptr};
+use alloc::{vec, vec::Vec};
+
+
+/// Errors returned by block devices
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DiskError {
+    /// The device could not be found
+    NotFound,
+    /// The read or write is not a multiple of the block size
+    Unaligned,
+    /// The buffer can't hold the number of bytes requested
+    BufferTooSmall,
+    /// The read or write goes past the end of the device
+    OutOfRange,
+    /// The device can't be written to
+    ReadOnly,
+    /// The device returned an error status
+    Io(u32),
+}
+
+impl fmt::Display for DiskError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiskError::NotFound         => write!(f, "device not found"),
+            DiskError::Unaligned        => write!(f, "access is not a multiple of the block size"),
+            DiskError::BufferTooSmall   => write!(f, "buffer is too small"),
+            DiskError::OutOfRange       => write!(f, "access past the end of the device"),
+            DiskError::ReadOnly         => write!(f, "device is read only"),
+            DiskError::Io(status)       => write!(f, "I/O error {}", status),
+        }
+    }
+}
+
+
+/// Something the drivers can read blocks from, e.g a slice through UEFI BlockIO or a disk image on the host
+pub trait BlockDevice {
+    /// Size of one block in bytes
+    fn block_size(&self) -> usize;
+
+    /// Reads whole blocks starting at *lba*. *buffer* must be a multiple of the block size.
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), DiskError>;
+
+    /// Writes whole blocks starting at *lba*. *buffer* must be a multiple of the block size.
+    fn write_blocks(&self, _lba: u64, _buffer: &[u8]) -> Result<(), DiskError> {
+        Err(DiskError::ReadOnly)
+    }
+
+    /// Reads *buffer.len()* bytes starting at byte *offset*, which doesn't need to be block aligned
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> Result<(), DiskError> {
+        let block_size = self.block_size() as u64;
+        let first_block = offset / block_size;
+        let skip = (offset % block_size) as usize;
+
+        // Aligned reads can go straight into the caller's buffer
+        if skip == 0 && (buffer.len() as u64).is_multiple_of(block_size) {
+            return self.read_blocks(first_block, buffer);
+        }
+
+        let count = (skip + buffer.len()).div_ceil(block_size as usize);
+        let mut tmp: Vec<u8> = vec![0; count * block_size as usize];
+        self.read_blocks(first_block, &mut tmp)?;
+
+        let len = buffer.len();
+        buffer.copy_from_slice(&tmp[skip..skip + len]);
+        Ok(())
+    }
+
+    /// Writes *buffer* starting at byte *offset*. Blocks that are only partly covered are read first so the rest of them is kept.
+    fn write_bytes(&self, offset: u64, buffer: &[u8]) -> Result<(), DiskError> {
+        let block_size = self.block_size() as u64;
+        let first_block = offset / block_size;
+        let skip = (offset % block_size) as usize;
+
+        if skip == 0 && (buffer.len() as u64).is_multiple_of(block_size) {
+            return self.write_blocks(first_block, buffer);
+        }
+
+        let count = (skip + buffer.len()).div_ceil(block_size as usize);
+        let mut tmp: Vec<u8> = vec![0; count * block_size as usize];
+
+        // Only the first and last blocks can be partly covered
+        self.read_blocks(first_block, &mut tmp[..block_size as usize])?;
+        if count > 1 {
+            let last = (count - 1) * block_size as usize;
+            self.read_blocks(first_block + count as u64 - 1, &mut tmp[last..])?;
+        }
+
+        tmp[skip..skip + buffer.len()].copy_from_slice(buffer);
+        self.write_blocks(first_block, &tmp)
+    }
+
+    /// Makes sure everything written so far has reached the storage
+    fn flush(&self) -> Result<(), DiskError> {
+        Ok(())
+    }
+}
+
+
+impl<T: BlockDevice + ?Sized> BlockDevice for &T {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), DiskError> {
+        (**self).read_blocks(lba, buffer)
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), DiskError> {
+        (**self).write_blocks(lba, buffer)
+    }
+
+    fn flush(&self) -> Result<(), DiskError> {
+        (**self).flush()
+    }
+}
+
+
+
+/// A range of blocks on another device, e.g a GPT partition within a disk image
+pub struct Partition<D: BlockDevice> {
+    dev:    D,
+    start:  u64,
+    blocks: u64,
+}
+
+impl<D: BlockDevice> Partition<D> {
+    /// Exposes *blocks* blocks of *dev* starting at *start* as a device of their own
+    pub fn new(dev: D, start: u64, blocks: u64) -> Self {
+        Self {
+            dev,
+            start,
+            blocks,
+        }
+    }
+
+    /// Makes sure an access of *len* bytes at *lba* stays within the partition
+    fn check_range(&self, lba: u64, len: usize) -> Result<(), DiskError> {
+        let count = (len / self.dev.block_size()) as u64;
+
+        if lba.checked_add(count).is_none_or(|end| end > self.blocks) {
+            return Err(DiskError::OutOfRange);
+        }
+
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for Partition<D> {
+    fn block_size(&self) -> usize {
+        self.dev.block_size()
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), DiskError> {
+        self.check_range(lba, buffer.len())?;
+        self.dev.read_blocks(self.start + lba, buffer)
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), DiskError> {
+        self.check_range(lba, buffer.len())?;
+        self.dev.write_blocks(self.start + lba, buffer)
+    }
+
+    fn flush(&self) -> Result<(), DiskError> {
+        self.dev.flush()
+    }
+}
+
+
+/// Reads a plain on-disk structure starting at byte *offset*
+pub fn read_struct<T: Copy, D: BlockDevice + ?Sized>(dev: &D, offset: u64) -> Result<T, DiskError> {
+    let mut buffer: Vec<u8> = vec![0; size_of::<T>()];
+    dev.read_bytes(offset, &mut buffer)?;
+
+    Ok(struct_from_bytes(&buffer))
+}
+
+
+/// Returns the bytes of a plain on-disk structure, for writing it out
+pub fn struct_to_bytes<T: Copy>(value: &T) -> Vec<u8> {
+    unsafe { core::slice::from_raw_parts((value as *const T).cast::<u8>(), size_of::<T>()) }.to_vec()
+}
+
+
+/// Picks a plain on-disk structure out of the start of *bytes*
+pub fn struct_from_bytes<T: Copy>(bytes: &[u8]) -> T {
+    assert!(bytes.len() >= size_of::<T>());
+
+    unsafe { ptr::read_unaligned(bytes.as_ptr().cast()) }
+}
@@ -0,0 +1,87 @@
+/*  checksum.rs - CRC-32 and CRC-32C
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! The two CRC-32 variants used on disk: plain CRC-32 as in gzip and xz, and CRC-32C (Castagnoli) as in ext4 and ZXFS metadata, plus
+//! the CRC-64 xz checks files with by default. All are the reflected forms, computed a byte at a time from a table built at compile time.
+
+/// Builds the lookup table of a reflected CRC-32 with polynomial *poly*
+const fn table(poly: u32) -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ poly } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const CRC32_TABLE: [u32; 256] = table(0xEDB88320);
+const CRC32C_TABLE: [u32; 256] = table(0x82F63B78);
+
+
+fn update(table: &[u32; 256], crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, byte| table[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+
+/// CRC-32 of *data*, as stored by gzip and xz
+pub fn crc32(data: &[u8]) -> u32 {
+    !update(&CRC32_TABLE, !0, data)
+}
+
+
+/// CRC-32C of *data*
+pub fn crc32c(data: &[u8]) -> u32 {
+    !crc32c_update(!0, data)
+}
+
+
+/// Continues a CRC-32C from *crc* without the initial and final inversions, for formats that chain checksums over several buffers
+pub fn crc32c_update(crc: u32, data: &[u8]) -> u32 {
+    update(&CRC32C_TABLE, crc, data)
+}
+
+
+const CRC64_TABLE: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xC96C5795D7870F42 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+
+/// CRC-64 (ECMA-182) of *data*, as stored by xz
+pub fn crc64(data: &[u8]) -> u64 {
+    !data.iter().fold(!0u64, |crc, byte| CRC64_TABLE[((crc ^ *byte as u64) & 0xFF) as usize] ^ (crc >> 8))
+}
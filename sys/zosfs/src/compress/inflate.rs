@@ -0,0 +1,408 @@
+/*  inflate.rs - DEFLATE decompression
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Decoder for DEFLATE streams (RFC 1951) and the zlib (RFC 1950) and gzip (RFC 1952) wrappers around them.
+//!
+//! Prefix codes are decoded with a single table indexed by the next *max length* bits of input, which is plenty fast for the few MiB the
+//! loader ever inflates and much simpler than the usual two level tables.
+
+use alloc::{vec, vec::Vec};
+use crate::checksum::crc32;
+use crate::error::FsError;
+
+
+/// Longest prefix code DEFLATE allows
+const MAX_BITS: usize = 15;
+
+/// Base lengths and extra bits of length codes 257..=285
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+
+/// Base distances and extra bits of distance codes 0..=29
+const DIST_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097,
+                              6145, 8193, 12289, 16385, 24577];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+/// Order the code length code lengths are stored in
+const CLEN_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+const GZIP_FHCRC: u8 = 0x02;
+const GZIP_FEXTRA: u8 = 0x04;
+const GZIP_FNAME: u8 = 0x08;
+const GZIP_FCOMMENT: u8 = 0x10;
+
+
+
+/// Reads bits least significant first, as DEFLATE packs them
+struct BitReader<'a> {
+    data:   &'a [u8],
+    pos:    usize,
+    bits:   u64,
+    count:  usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0, bits: 0, count: 0 }
+    }
+
+    /// Makes sure at least *n* bits are buffered. Past the end of input, zeros are shifted in so a code can be peeked at; overrun()
+    /// tells whether any were actually used.
+    fn fill(&mut self, n: usize) {
+        while self.count < n {
+            let byte = self.data.get(self.pos).copied().unwrap_or(0);
+            self.bits |= (byte as u64) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+    }
+
+    fn peek(&mut self, n: usize) -> u32 {
+        self.fill(n);
+        (self.bits & ((1u64 << n) - 1)) as u32
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.bits >>= n;
+        self.count -= n;
+    }
+
+    fn read(&mut self, n: usize) -> u32 {
+        let value = self.peek(n);
+        self.consume(n);
+        value
+    }
+
+    /// Drops the bits left in the current byte
+    fn align(&mut self) {
+        self.consume(self.count % 8);
+    }
+
+    /// Whether more bits were used than the input holds
+    fn overrun(&self) -> bool {
+        self.pos > self.data.len() + self.count / 8
+    }
+
+    /// Number of whole bytes of input used so far
+    fn bytes_used(&self) -> usize {
+        self.pos - self.count / 8
+    }
+}
+
+
+
+/// Decoding table for one prefix code. Entries are indexed by the next *bits* bits of input and hold the symbol and its length, a length
+/// of 0 marking bit patterns no code starts with.
+struct Huffman {
+    table:  Vec<(u16, u8)>,
+    bits:   usize,
+}
+
+impl Huffman {
+    /// Builds the table for the canonical code with code lengths *lengths*
+    fn new(lengths: &[u8]) -> Result<Self, FsError> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for len in lengths {
+            counts[*len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // More codes of some length than there is room for
+        let mut left: i32 = 1;
+        for count in &counts[1..] {
+            left = left * 2 - *count as i32;
+            if left < 0 {
+                return Err(FsError::Corrupt("oversubscribed deflate code"));
+            }
+        }
+
+        let bits = (1..=MAX_BITS).rev().find(|len| counts[*len] != 0).unwrap_or(1);
+        let mut next_code = [0u32; MAX_BITS + 2];
+        for len in 1..=MAX_BITS {
+            next_code[len + 1] = (next_code[len] + counts[len] as u32) << 1;
+        }
+
+        let mut table = vec![(0u16, 0u8); 1 << bits];
+        for (symbol, len) in lengths.iter().enumerate().filter(|(_, len)| **len != 0) {
+            let len = *len as usize;
+            let code = next_code[len];
+            next_code[len] += 1;
+
+            // Codes are stored most significant bit first, but input is read the other way around
+            let reversed = code.reverse_bits() >> (32 - len);
+            for fill in 0..1 << (bits - len) {
+                table[(reversed | (fill << len)) as usize] = (symbol as u16, len as u8);
+            }
+        }
+
+        Ok(Self { table, bits })
+    }
+
+    fn decode(&self, input: &mut BitReader) -> Result<u16, FsError> {
+        let (symbol, len) = self.table[input.peek(self.bits) as usize];
+        if len == 0 {
+            return Err(FsError::Corrupt("invalid deflate code"));
+        }
+
+        input.consume(len as usize);
+        Ok(symbol)
+    }
+}
+
+
+
+/// Inflates the raw DEFLATE stream at the start of *input*, appending to *output*. Returns the number of bytes of input used.
+pub fn inflate(input: &[u8], output: &mut Vec<u8>) -> Result<usize, FsError> {
+    let mut bits = BitReader::new(input);
+
+    loop {
+        let last = bits.read(1) == 1;
+
+        match bits.read(2) {
+            0 => stored_block(&mut bits, output)?,
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+
+                compressed_block(&mut bits, output, &Huffman::new(&lengths)?, &Huffman::new(&[5; 30])?)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut bits)?;
+                compressed_block(&mut bits, output, &literals, &distances)?;
+            }
+            _ => return Err(FsError::Corrupt("invalid deflate block type")),
+        }
+
+        if bits.overrun() {
+            return Err(FsError::Corrupt("truncated deflate stream"));
+        }
+        if last {
+            return Ok(bits.bytes_used());
+        }
+    }
+}
+
+
+fn stored_block(bits: &mut BitReader, output: &mut Vec<u8>) -> Result<(), FsError> {
+    bits.align();
+    let len = bits.read(16) as usize;
+    let nlen = bits.read(16) as usize;
+    if len != !nlen & 0xFFFF {
+        return Err(FsError::Corrupt("invalid deflate stored block length"));
+    }
+
+    // Whole bytes are buffered after aligning, so the block starts right where the buffered ones end
+    let start = bits.bytes_used();
+    let data = bits.data.get(start..start + len).ok_or(FsError::Corrupt("truncated deflate stream"))?;
+    output.extend_from_slice(data);
+
+    *bits = BitReader { data: bits.data, pos: start + len, bits: 0, count: 0 };
+    Ok(())
+}
+
+
+/// Reads the code lengths of a dynamic block and builds its literal/length and distance codes
+fn dynamic_codes(bits: &mut BitReader) -> Result<(Huffman, Huffman), FsError> {
+    let nlit = bits.read(5) as usize + 257;
+    let ndist = bits.read(5) as usize + 1;
+    let nclen = bits.read(4) as usize + 4;
+
+    let mut clens = [0u8; 19];
+    for i in 0..nclen {
+        clens[CLEN_ORDER[i]] = bits.read(3) as u8;
+    }
+    let clen_code = Huffman::new(&clens)?;
+
+    let mut lengths = vec![0u8; nlit + ndist];
+    let mut i = 0;
+    while i < nlit + ndist {
+        let symbol = clen_code.decode(bits)?;
+
+        let (value, repeat) = match symbol {
+            0..=15  => (symbol as u8, 1),
+            16      => {
+                if i == 0 {
+                    return Err(FsError::Corrupt("deflate length repeat with no previous length"));
+                }
+                (lengths[i - 1], 3 + bits.read(2) as usize)
+            }
+            17      => (0, 3 + bits.read(3) as usize),
+            _       => (0, 11 + bits.read(7) as usize),
+        };
+
+        if i + repeat > nlit + ndist {
+            return Err(FsError::Corrupt("too many deflate code lengths"));
+        }
+        lengths[i..i + repeat].fill(value);
+        i += repeat;
+    }
+
+    if lengths[256] == 0 {
+        return Err(FsError::Corrupt("deflate block has no end code"));
+    }
+
+    Ok((Huffman::new(&lengths[..nlit])?, Huffman::new(&lengths[nlit..])?))
+}
+
+
+fn compressed_block(bits: &mut BitReader, output: &mut Vec<u8>, literals: &Huffman, distances: &Huffman) -> Result<(), FsError> {
+    loop {
+        let symbol = literals.decode(bits)? as usize;
+
+        if symbol < 256 {
+            output.push(symbol as u8);
+        }
+        else if symbol == 256 {
+            return Ok(());
+        }
+        else {
+            let code = symbol - 257;
+            if code >= LENGTH_BASE.len() {
+                return Err(FsError::Corrupt("invalid deflate length code"));
+            }
+            let len = LENGTH_BASE[code] as usize + bits.read(LENGTH_EXTRA[code] as usize) as usize;
+
+            let code = distances.decode(bits)? as usize;
+            if code >= DIST_BASE.len() {
+                return Err(FsError::Corrupt("invalid deflate distance code"));
+            }
+            let dist = DIST_BASE[code] as usize + bits.read(DIST_EXTRA[code] as usize) as usize;
+
+            if dist > output.len() {
+                return Err(FsError::Corrupt("deflate distance before the start of the output"));
+            }
+            copy_match(output, dist, len);
+        }
+
+        if bits.overrun() {
+            return Err(FsError::Corrupt("truncated deflate stream"));
+        }
+    }
+}
+
+
+/// Appends *len* bytes copied from *dist* bytes back in *output*. The source can overlap what's being appended.
+pub(crate) fn copy_match(output: &mut Vec<u8>, dist: usize, len: usize) {
+    let start = output.len() - dist;
+
+    if dist >= len {
+        output.extend_from_within(start..start + len);
+    }
+    else {
+        output.reserve(len);
+        for i in 0..len {
+            output.push(output[start + i]);
+        }
+    }
+}
+
+
+
+/// Decompresses a zlib stream and checks its Adler-32
+pub fn zlib_decompress(input: &[u8]) -> Result<Vec<u8>, FsError> {
+    if input.len() < 6 || input[0] & 0x0F != 8 || !((input[0] as u16) << 8 | input[1] as u16).is_multiple_of(31) {
+        return Err(FsError::Corrupt("invalid zlib header"));
+    }
+    if input[1] & 0x20 != 0 {
+        return Err(FsError::Unsupported("zlib preset dictionaries"));
+    }
+
+    let mut output = Vec::new();
+    let used = 2 + inflate(&input[2..], &mut output)?;
+
+    let expected = input.get(used..used + 4).ok_or(FsError::Corrupt("truncated zlib stream"))?;
+    if adler32(&output) != u32::from_be_bytes(expected.try_into().unwrap()) {
+        return Err(FsError::Corrupt("zlib checksum mismatch"));
+    }
+
+    Ok(output)
+}
+
+
+/// Decompresses a gzip file, which can be several gzip members one after the other, and checks their CRC-32s
+pub fn gzip_decompress(input: &[u8]) -> Result<Vec<u8>, FsError> {
+    let mut output = Vec::new();
+    let mut pos = 0;
+
+    while pos < input.len() {
+        let member = &input[pos..];
+        if member.len() < 18 || member[0] != 0x1F || member[1] != 0x8B || member[2] != 8 {
+            // Padding after the last member, which tar and dd like to leave behind
+            if pos != 0 && member.iter().all(|b| *b == 0) {
+                break;
+            }
+            return Err(FsError::Corrupt("invalid gzip header"));
+        }
+
+        let flags = member[3];
+        let mut header = 10;
+
+        if flags & GZIP_FEXTRA != 0 {
+            header += 2 + u16::from_le_bytes([member[10], member[11]]) as usize;
+        }
+        for flag in [GZIP_FNAME, GZIP_FCOMMENT] {
+            if flags & flag != 0 {
+                let end = member.get(header..).and_then(|rest| rest.iter().position(|b| *b == 0)).ok_or(FsError::Corrupt("invalid gzip header"))?;
+                header += end + 1;
+            }
+        }
+        if flags & GZIP_FHCRC != 0 {
+            header += 2;
+        }
+        if header > member.len() {
+            return Err(FsError::Corrupt("invalid gzip header"));
+        }
+
+        let start = output.len();
+        let used = header + inflate(&member[header..], &mut output)?;
+
+        let trailer = member.get(used..used + 8).ok_or(FsError::Corrupt("truncated gzip stream"))?;
+        let crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+        let size = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+
+        if crc32(&output[start..]) != crc || (output.len() - start) as u32 != size {
+            return Err(FsError::Corrupt("gzip checksum mismatch"));
+        }
+
+        pos += used + 8;
+    }
+
+    Ok(output)
+}
+
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+
+    // 5552 bytes is the most that can be summed before b could overflow
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+
+    (b << 16) | a
+}
+
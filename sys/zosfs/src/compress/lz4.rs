@@ -0,0 +1,213 @@
+/*  lz4.rs - LZ4 decompression
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Decoder for LZ4 blocks, and the frame format lz4(1) writes around them. The legacy frame format is also understood as that's what
+//! Linux kernels compressed with LZ4 use.
+
+use alloc::vec::Vec;
+use crate::error::FsError;
+use super::inflate::copy_match;
+
+
+const FRAME_MAGIC: u32 = 0x184D2204;
+const LEGACY_MAGIC: u32 = 0x184C2102;
+
+const FLG_BLOCK_CHECKSUM: u8 = 0x10;
+const FLG_CONTENT_SIZE: u8 = 0x08;
+const FLG_CONTENT_CHECKSUM: u8 = 0x04;
+const FLG_DICT_ID: u8 = 0x01;
+
+/// Set in a block size when the block is stored uncompressed
+const BLOCK_UNCOMPRESSED: u32 = 0x80000000;
+
+
+
+/// Decompresses one LZ4 block, appending to *output*. Earlier contents of *output* can be referenced by matches, as blocks of a frame
+/// may depend on each other.
+pub fn decompress_block(input: &[u8], output: &mut Vec<u8>) -> Result<(), FsError> {
+    let mut pos = 0;
+
+    // Lengths of 15 continue in the following bytes, each adding up to 255
+    let read_length = |pos: &mut usize, mut len: usize| -> Result<usize, FsError> {
+        if len == 15 {
+            loop {
+                let byte = *input.get(*pos).ok_or(FsError::Corrupt("truncated lz4 block"))?;
+                *pos += 1;
+                len += byte as usize;
+                if byte != 255 {
+                    break;
+                }
+            }
+        }
+        Ok(len)
+    };
+
+    loop {
+        let token = *input.get(pos).ok_or(FsError::Corrupt("truncated lz4 block"))?;
+        pos += 1;
+
+        let literals = read_length(&mut pos, (token >> 4) as usize)?;
+        let data = input.get(pos..pos + literals).ok_or(FsError::Corrupt("truncated lz4 block"))?;
+        output.extend_from_slice(data);
+        pos += literals;
+
+        // The last sequence only has literals
+        if pos == input.len() {
+            return Ok(());
+        }
+
+        let offset = input.get(pos..pos + 2).ok_or(FsError::Corrupt("truncated lz4 block"))?;
+        let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+        pos += 2;
+
+        let len = read_length(&mut pos, (token & 0x0F) as usize)? + 4;
+        if offset == 0 || offset > output.len() {
+            return Err(FsError::Corrupt("lz4 match before the start of the output"));
+        }
+        copy_match(output, offset, len);
+    }
+}
+
+
+/// Decompresses an LZ4 file in the frame or legacy format. Several frames can follow each other.
+pub fn decompress(input: &[u8]) -> Result<Vec<u8>, FsError> {
+    let mut output = Vec::new();
+    let mut pos = 0;
+
+    let read_u32 = |pos: usize| -> Result<u32, FsError> {
+        let bytes = input.get(pos..pos + 4).ok_or(FsError::Corrupt("truncated lz4 frame"))?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+
+    while pos < input.len() {
+        let magic = read_u32(pos)?;
+        pos += 4;
+
+        if magic == LEGACY_MAGIC {
+            // Independent blocks of up to 8MiB, until the end of input or another magic number
+            while pos < input.len() {
+                let size = read_u32(pos)? as usize;
+                if size == LEGACY_MAGIC as usize || size == FRAME_MAGIC as usize {
+                    break;
+                }
+
+                let block = input.get(pos + 4..pos + 4 + size).ok_or(FsError::Corrupt("truncated lz4 frame"))?;
+                decompress_block(block, &mut output)?;
+                pos += 4 + size;
+            }
+        }
+        else if magic == FRAME_MAGIC {
+            let flags = *input.get(pos).ok_or(FsError::Corrupt("truncated lz4 frame"))?;
+            if flags >> 6 != 1 {
+                return Err(FsError::Unsupported("lz4 frame version"));
+            }
+            if flags & FLG_DICT_ID != 0 {
+                return Err(FsError::Unsupported("lz4 dictionaries"));
+            }
+
+            // Flags, block descriptor, then the optional content size and the header checksum
+            pos += 2 + if flags & FLG_CONTENT_SIZE != 0 { 8 } else { 0 } + 1;
+
+            // Blocks may reference the ones before them, which are still in the output
+            loop {
+                let size = read_u32(pos)?;
+                pos += 4;
+                if size == 0 {
+                    break;
+                }
+
+                let len = (size & !BLOCK_UNCOMPRESSED) as usize;
+                let block = input.get(pos..pos + len).ok_or(FsError::Corrupt("truncated lz4 frame"))?;
+                if size & BLOCK_UNCOMPRESSED != 0 {
+                    output.extend_from_slice(block);
+                }
+                else {
+                    decompress_block(block, &mut output)?;
+                }
+
+                pos += len + if flags & FLG_BLOCK_CHECKSUM != 0 { 4 } else { 0 };
+            }
+
+            if flags & FLG_CONTENT_CHECKSUM != 0 {
+                pos += 4;
+            }
+        }
+        // Skippable frames carry metadata lz4 doesn't know about
+        else if magic & 0xFFFFFFF0 == 0x184D2A50 {
+            pos += 4 + read_u32(pos)? as usize;
+        }
+        else {
+            return Err(FsError::Corrupt("invalid lz4 magic"));
+        }
+    }
+
+    Ok(output)
+}
+
+
+/// Adds up the content sizes frame headers record in a *len* byte file, reading only the frame and block headers through *read*.
+/// Returns None if a frame leaves it out, which lz4 does unless asked for it, and for the legacy format which has no such field.
+pub fn content_size(len: u64, mut read: impl FnMut(u64, &mut [u8]) -> Option<()>) -> Option<u64> {
+    let mut size = 0u64;
+    let mut pos = 0u64;
+
+    let mut read_u32 = |pos: u64| -> Option<u32> {
+        let mut bytes = [0u8; 4];
+        read(pos, &mut bytes)?;
+        Some(u32::from_le_bytes(bytes))
+    };
+
+    while pos < len {
+        let magic = read_u32(pos)?;
+        pos += 4;
+
+        if magic == FRAME_MAGIC {
+            // The flags and block descriptor, then the content size when the flags say it's there
+            let flags = read_u32(pos)? as u8;
+            if flags & FLG_CONTENT_SIZE == 0 {
+                return None;
+            }
+
+            size += read_u32(pos + 2)? as u64 | (read_u32(pos + 6)? as u64) << 32;
+            pos += 2 + 8 + if flags & FLG_DICT_ID != 0 { 4 } else { 0 } + 1;
+
+            loop {
+                let block = read_u32(pos)?;
+                pos += 4;
+                if block == 0 {
+                    break;
+                }
+
+                pos += (block & !BLOCK_UNCOMPRESSED) as u64 + if flags & FLG_BLOCK_CHECKSUM != 0 { 4 } else { 0 };
+            }
+
+            if flags & FLG_CONTENT_CHECKSUM != 0 {
+                pos += 4;
+            }
+        }
+        else if magic & 0xFFFFFFF0 == 0x184D2A50 {
+            pos += 4 + read_u32(pos)? as u64;
+        }
+        else {
+            return None;
+        }
+    }
+
+    Some(size)
+}
@@ -0,0 +1,94 @@
+/*  mod.rs - Decompressors
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Decompressors for the formats filesystems and boot files are compressed with. They work on whole buffers, which is all the loader
+//! needs, and return FsError::Corrupt for malformed input like the filesystem drivers do.
+
+use alloc::vec::Vec;
+use crate::error::FsError;
+
+pub mod inflate;
+pub mod lz4;
+pub mod xz;
+pub mod zstd;
+
+
+/// Compressed file formats that can be recognized by their magic number
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Gzip,
+    Zstd,
+    Lz4,
+    Xz,
+}
+
+
+/// Recognizes a compressed file from its first bytes, or returns None if it isn't in a known format
+pub fn detect(data: &[u8]) -> Option<Format> {
+    if data.starts_with(&[0x1F, 0x8B, 0x08]) {
+        return Some(Format::Gzip);
+    }
+    else if data.starts_with(&[0x28, 0xB5, 0x2F, 0xFD]) {
+        return Some(Format::Zstd);
+    }
+    // Frame format, or the legacy format Linux kernels use
+    else if data.starts_with(&[0x04, 0x22, 0x4D, 0x18]) || data.starts_with(&[0x02, 0x21, 0x4C, 0x18]) {
+        return Some(Format::Lz4);
+    }
+    else if data.starts_with(&[0xFD, b'7', b'z', b'X', b'Z', 0x00]) {
+        return Some(Format::Xz);
+    }
+    else {
+        return None;
+    }
+}
+
+
+/// Decompresses a whole file in *format*
+pub fn decompress(format: Format, data: &[u8]) -> Result<Vec<u8>, FsError> {
+    match format {
+        Format::Gzip    => inflate::gzip_decompress(data),
+        Format::Zstd    => zstd::decompress(data),
+        Format::Lz4     => lz4::decompress(data),
+        Format::Xz      => xz::decompress(data),
+    }
+}
+
+
+/// Returns how big a whole file in *format* will be once decompressed, if the format records it, without decompressing it.
+/// gzip only records the size of its last member modulo 4GiB, which isn't enough for files with several members, so gzip files
+/// need decompressing to learn their size.
+pub fn decompressed_size(format: Format, data: &[u8]) -> Option<u64> {
+    decompressed_size_at(format, data.len() as u64, |offset, buffer| {
+        buffer.copy_from_slice(data.get(usize::try_from(offset).ok()?..)?.get(..buffer.len())?);
+        Some(())
+    })
+}
+
+
+/// Like decompressed_size, for a *len* byte file that isn't in memory. Only the headers recording sizes are read, through *read*
+/// which fills its buffer from the given offset of the file, or returns None if it can't.
+pub fn decompressed_size_at(format: Format, len: u64, read: impl FnMut(u64, &mut [u8]) -> Option<()>) -> Option<u64> {
+    match format {
+        Format::Gzip    => None,
+        Format::Zstd    => zstd::content_size(len, read),
+        Format::Lz4     => lz4::content_size(len, read),
+        Format::Xz      => xz::content_size(len, read),
+    }
+}
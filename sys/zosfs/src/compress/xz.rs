@@ -0,0 +1,669 @@
+/*  xz.rs - xz decompression
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Decoder for the .xz container and the LZMA2 compression inside it. Only LZMA2 on its own is supported, which is what xz(1) writes
+//! unless told to add a BCJ or delta filter. CRC-32 and CRC-64 checks are verified, SHA-256 ones are skipped.
+
+use alloc::{vec, vec::Vec};
+use crate::checksum::{crc32, crc64};
+use crate::error::FsError;
+use super::inflate::copy_match;
+
+
+const HEADER_MAGIC: [u8; 6] = [0xFD, b'7', b'z', b'X', b'Z', 0];
+const FOOTER_MAGIC: [u8; 2] = [b'Y', b'Z'];
+
+const CHECK_CRC32: u8 = 0x01;
+const CHECK_CRC64: u8 = 0x04;
+
+const BLOCK_COMPRESSED_SIZE: u8 = 0x40;
+const BLOCK_UNCOMPRESSED_SIZE: u8 = 0x80;
+
+const FILTER_LZMA2: u64 = 0x21;
+
+/// LZMA states, from the kinds of the last few symbols decoded
+const STATES: usize = 12;
+/// States after which a literal is decoded using the byte at the last distance
+const LIT_STATES: usize = 7;
+
+const POS_STATES_MAX: usize = 16;
+const DIST_STATES: usize = 4;
+const DIST_SLOTS: usize = 64;
+const END_POS_MODEL_INDEX: u32 = 14;
+const FULL_DISTANCES: usize = 128;
+const ALIGN_BITS: u32 = 4;
+const MATCH_LEN_MIN: usize = 2;
+
+/// Probabilities are 11 bit fixed point numbers, starting at one half
+const PROB_BITS: u32 = 11;
+const PROB_INIT: u16 = 1 << (PROB_BITS - 1);
+const MOVE_BITS: u32 = 5;
+
+
+
+/// Size of the integrity check of each type. Checks are grouped in threes of the same size.
+fn check_size(check: u8) -> usize {
+    match check {
+        0       => 0,
+        1..=3   => 4,
+        4..=6   => 8,
+        7..=9   => 16,
+        10..=12 => 32,
+        _       => 64,
+    }
+}
+
+
+/// Reads a variable length integer, 7 bits per byte with the high bit set on all but the last
+fn read_varint(input: &[u8], pos: &mut usize) -> Result<u64, FsError> {
+    let mut value = 0u64;
+
+    for i in 0..9 {
+        let byte = *input.get(*pos).ok_or(FsError::Corrupt("truncated xz stream"))?;
+        *pos += 1;
+        value |= ((byte & 0x7F) as u64) << (i * 7);
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(FsError::Corrupt("invalid xz integer"))
+}
+
+
+fn read_u32(input: &[u8], pos: usize) -> Result<u32, FsError> {
+    let bytes = input.get(pos..pos + 4).ok_or(FsError::Corrupt("truncated xz stream"))?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+
+
+/// Decompresses an xz file, which can hold several streams separated by padding
+pub fn decompress(input: &[u8]) -> Result<Vec<u8>, FsError> {
+    let mut output = Vec::new();
+    let mut pos = 0;
+
+    while pos < input.len() {
+        // Stream padding comes in multiples of 4 zero bytes
+        if input[pos..].starts_with(&[0; 4]) && pos != 0 {
+            pos += 4;
+            continue;
+        }
+
+        pos += decompress_stream(&input[pos..], &mut output)?;
+    }
+
+    Ok(output)
+}
+
+
+/// Adds up the uncompressed sizes in the index at the end of every stream of a *len* byte file, reading only the footers, indexes and
+/// stream headers through *read*. Streams are found from the end of the file backwards, through the index size their footers record.
+/// Returns None if the file doesn't look like xz.
+pub fn content_size(len: u64, mut read: impl FnMut(u64, &mut [u8]) -> Option<()>) -> Option<u64> {
+    let mut size = 0u64;
+    let mut end = len;
+
+    while end > 0 {
+        // Stream padding comes in multiples of 4 zero bytes
+        let mut padding = [0xFFu8; 4];
+        if end >= 4 {
+            read(end - 4, &mut padding)?;
+        }
+        if padding == [0; 4] {
+            end -= 4;
+            continue;
+        }
+
+        let mut footer = [0u8; 12];
+        read(end.checked_sub(12)?, &mut footer)?;
+        if footer[10..] != FOOTER_MAGIC || crc32(&footer[4..10]) != read_u32(&footer, 0).ok()? {
+            return None;
+        }
+
+        let index_size = (read_u32(&footer, 4).ok()? as usize + 1) * 4;
+        let index_start = (end - 12).checked_sub(index_size as u64)?;
+        let mut index = vec![0u8; index_size];
+        read(index_start, &mut index)?;
+        if index[0] != 0 || crc32(&index[..index_size - 4]) != read_u32(&index, index_size - 4).ok()? {
+            return None;
+        }
+
+        // Records hold the size of each block without its padding, then its uncompressed size
+        let mut pos = 1;
+        let mut blocks_size = 0u64;
+        for _ in 0..read_varint(&index, &mut pos).ok()? {
+            blocks_size += read_varint(&index, &mut pos).ok()?.next_multiple_of(4);
+            size += read_varint(&index, &mut pos).ok()?;
+        }
+
+        end = index_start.checked_sub(blocks_size)?.checked_sub(12)?;
+
+        let mut header = [0u8; 6];
+        read(end, &mut header)?;
+        if header != HEADER_MAGIC {
+            return None;
+        }
+    }
+
+    Some(size)
+}
+
+
+/// Decompresses one stream, appending to *output*. Returns the size of the stream.
+fn decompress_stream(input: &[u8], output: &mut Vec<u8>) -> Result<usize, FsError> {
+    let header = input.get(..12).ok_or(FsError::Corrupt("truncated xz stream"))?;
+    if header[..6] != HEADER_MAGIC {
+        return Err(FsError::Corrupt("invalid xz magic"));
+    }
+    if crc32(&header[6..8]) != read_u32(header, 8)? {
+        return Err(FsError::Corrupt("xz stream header checksum mismatch"));
+    }
+
+    let flags = [header[6], header[7]];
+    let check = flags[1];
+    if flags[0] != 0 || check > 0x0F {
+        return Err(FsError::Unsupported("xz stream flags"));
+    }
+
+    let mut pos = 12;
+    let mut blocks = 0u64;
+
+    // Blocks follow until the index, which starts with a zero byte where a block header size would be
+    loop {
+        let header_size = *input.get(pos).ok_or(FsError::Corrupt("truncated xz stream"))? as usize;
+        if header_size == 0 {
+            break;
+        }
+
+        let start = output.len();
+        pos += decompress_block(&input[pos..], output)?;
+
+        let size = check_size(check);
+        let expected = input.get(pos..pos + size).ok_or(FsError::Corrupt("truncated xz stream"))?;
+        let matches = match check {
+            CHECK_CRC32 => crc32(&output[start..]).to_le_bytes() == expected,
+            CHECK_CRC64 => crc64(&output[start..]).to_le_bytes() == expected,
+            _           => true,
+        };
+        if !matches {
+            return Err(FsError::Corrupt("xz checksum mismatch"));
+        }
+
+        pos += size;
+        blocks += 1;
+    }
+
+    // The index repeats the size of every block, which is only needed for seeking. Its CRC-32 is checked as it covers the record count.
+    let index_start = pos;
+    pos += 1;
+    if read_varint(input, &mut pos)? != blocks {
+        return Err(FsError::Corrupt("xz index doesn't match the blocks"));
+    }
+    for _ in 0..blocks * 2 {
+        read_varint(input, &mut pos)?;
+    }
+    pos = pos.next_multiple_of(4);
+    if crc32(input.get(index_start..pos).ok_or(FsError::Corrupt("truncated xz stream"))?) != read_u32(input, pos)? {
+        return Err(FsError::Corrupt("xz index checksum mismatch"));
+    }
+    pos += 4;
+
+    let footer = input.get(pos..pos + 12).ok_or(FsError::Corrupt("truncated xz stream"))?;
+    if footer[10..] != FOOTER_MAGIC || footer[8..10] != flags || crc32(&footer[4..10]) != read_u32(footer, 0)? {
+        return Err(FsError::Corrupt("invalid xz stream footer"));
+    }
+
+    Ok(pos + 12)
+}
+
+
+/// Decompresses a block, appending to *output*. Returns the size of the block up to its check.
+fn decompress_block(input: &[u8], output: &mut Vec<u8>) -> Result<usize, FsError> {
+    let header_size = (input[0] as usize + 1) * 4;
+    let header = input.get(..header_size).ok_or(FsError::Corrupt("truncated xz block"))?;
+    if crc32(&header[..header_size - 4]) != read_u32(header, header_size - 4)? {
+        return Err(FsError::Corrupt("xz block header checksum mismatch"));
+    }
+
+    let flags = header[1];
+    if flags & 0x3C != 0 {
+        return Err(FsError::Unsupported("xz block flags"));
+    }
+
+    let mut pos = 2;
+    let compressed_size = if flags & BLOCK_COMPRESSED_SIZE != 0 { Some(read_varint(header, &mut pos)?) } else { None };
+    let uncompressed_size = if flags & BLOCK_UNCOMPRESSED_SIZE != 0 { Some(read_varint(header, &mut pos)?) } else { None };
+
+    let filter = read_varint(header, &mut pos)?;
+    let props_size = read_varint(header, &mut pos)?;
+    if flags & 0x03 != 0 || filter != FILTER_LZMA2 || props_size != 1 {
+        return Err(FsError::Unsupported("xz filters other than LZMA2"));
+    }
+    // The dictionary size doesn't matter as the whole output is kept
+    if header.get(pos).is_none_or(|props| *props > 40) {
+        return Err(FsError::Corrupt("invalid LZMA2 dictionary size"));
+    }
+
+    let start = output.len();
+    let used = decompress_lzma2(&input[header_size..], output)?;
+
+    if compressed_size.is_some_and(|size| size != used as u64) || uncompressed_size.is_some_and(|size| size != (output.len() - start) as u64) {
+        return Err(FsError::Corrupt("xz block size mismatch"));
+    }
+
+    // Blocks are padded to a multiple of 4 bytes before the check
+    Ok((header_size + used).next_multiple_of(4))
+}
+
+
+/// Decompresses LZMA2 data, a series of chunks that are either stored or LZMA compressed, appending to *output*. Returns the number of
+/// bytes used.
+pub fn decompress_lzma2(input: &[u8], output: &mut Vec<u8>) -> Result<usize, FsError> {
+    let mut decoder: Option<LzmaDecoder> = None;
+    // Start of the dictionary within the output, which matches can't reach past
+    let mut dict_start = output.len();
+    let mut pos = 0;
+
+    loop {
+        let control = *input.get(pos).ok_or(FsError::Corrupt("truncated LZMA2 data"))?;
+        pos += 1;
+
+        if control == 0x00 {
+            return Ok(pos);
+        }
+
+        let sizes = input.get(pos..pos + 4).ok_or(FsError::Corrupt("truncated LZMA2 data"))?;
+        let unpacked = u16::from_be_bytes([sizes[0], sizes[1]]) as usize + 1;
+
+        if control == 0x01 || control == 0x02 {
+            // Stored chunk, 0x01 resetting the dictionary first
+            if control == 0x01 {
+                dict_start = output.len();
+            }
+            output.extend_from_slice(input.get(pos + 2..pos + 2 + unpacked).ok_or(FsError::Corrupt("truncated LZMA2 data"))?);
+            pos += 2 + unpacked;
+        }
+        else if control >= 0x80 {
+            let unpacked = (((control & 0x1F) as usize) << 16) + unpacked;
+            let packed = u16::from_be_bytes([sizes[2], sizes[3]]) as usize + 1;
+            pos += 4;
+
+            // Bits 5 and 6 say how much is reset: 1 the state, 2 the state and properties, 3 the dictionary as well
+            let reset = (control >> 5) & 3;
+            if reset == 3 {
+                dict_start = output.len();
+            }
+            if reset >= 2 {
+                let props = *input.get(pos).ok_or(FsError::Corrupt("truncated LZMA2 data"))?;
+                pos += 1;
+                decoder = Some(LzmaDecoder::new(props)?);
+            }
+
+            let decoder = decoder.as_mut().ok_or(FsError::Corrupt("LZMA2 chunk without properties"))?;
+            if reset >= 1 {
+                decoder.reset()?;
+            }
+
+            let data = input.get(pos..pos + packed).ok_or(FsError::Corrupt("truncated LZMA2 data"))?;
+            decoder.decode(data, output, dict_start, unpacked)?;
+            pos += packed;
+        }
+        else {
+            return Err(FsError::Corrupt("invalid LZMA2 chunk"));
+        }
+    }
+}
+
+
+
+/// Binary range decoder, which LZMA codes everything with
+struct RangeDecoder<'a> {
+    input:  &'a [u8],
+    pos:    usize,
+    range:  u32,
+    code:   u32,
+}
+
+impl<'a> RangeDecoder<'a> {
+    fn new(input: &'a [u8]) -> Result<Self, FsError> {
+        if input.len() < 5 || input[0] != 0 {
+            return Err(FsError::Corrupt("invalid LZMA range coder start"));
+        }
+
+        Ok(Self {
+            input,
+            pos:    5,
+            range:  0xFFFFFFFF,
+            code:   u32::from_be_bytes(input[1..5].try_into().unwrap()),
+        })
+    }
+
+
+    /// Keeps the range above 2^24 by shifting in input bytes. Reading past the end gives zeros, which is caught once the chunk is done.
+    fn normalize(&mut self) {
+        if self.range < 1 << 24 {
+            self.range <<= 8;
+            self.code = (self.code << 8) | *self.input.get(self.pos).unwrap_or(&0) as u32;
+            self.pos += 1;
+        }
+    }
+
+
+    /// Decodes a bit whose probability of being 0 is *prob*, and adapts *prob* to it
+    fn bit(&mut self, prob: &mut u16) -> u32 {
+        self.normalize();
+        let bound = (self.range >> PROB_BITS) * *prob as u32;
+
+        if self.code < bound {
+            self.range = bound;
+            *prob += ((1 << PROB_BITS) - *prob) >> MOVE_BITS;
+            return 0;
+        }
+        else {
+            self.range -= bound;
+            self.code -= bound;
+            *prob -= *prob >> MOVE_BITS;
+            return 1;
+        }
+    }
+
+
+    /// Decodes *count* bits with fixed probabilities of one half, most significant first
+    fn direct_bits(&mut self, count: u32) -> u32 {
+        let mut value = 0;
+
+        for _ in 0..count {
+            self.normalize();
+            self.range >>= 1;
+            let bit = (self.code >= self.range) as u32;
+            if bit == 1 {
+                self.code -= self.range;
+            }
+            value = (value << 1) | bit;
+        }
+
+        value
+    }
+
+
+    /// Decodes *count* bits coded with a tree of probabilities, most significant first. probs[0] is unused.
+    fn bit_tree(&mut self, probs: &mut [u16], count: u32) -> u32 {
+        let mut m = 1;
+
+        for _ in 0..count {
+            m = (m << 1) | self.bit(&mut probs[m as usize]);
+        }
+
+        m - (1 << count)
+    }
+
+
+    /// Like bit_tree, but least significant bit first
+    fn reverse_bit_tree(&mut self, probs: &mut [u16], count: u32) -> u32 {
+        let mut m = 1;
+        let mut value = 0;
+
+        for i in 0..count {
+            let bit = self.bit(&mut probs[m as usize]);
+            m = (m << 1) | bit;
+            value |= bit << i;
+        }
+
+        value
+    }
+}
+
+
+
+/// Probabilities for decoding match lengths
+struct LengthDecoder {
+    choice:     u16,
+    choice2:    u16,
+    low:        [[u16; 8]; POS_STATES_MAX],
+    mid:        [[u16; 8]; POS_STATES_MAX],
+    high:       [u16; 256],
+}
+
+impl LengthDecoder {
+    fn new() -> Self {
+        Self {
+            choice:     PROB_INIT,
+            choice2:    PROB_INIT,
+            low:        [[PROB_INIT; 8]; POS_STATES_MAX],
+            mid:        [[PROB_INIT; 8]; POS_STATES_MAX],
+            high:       [PROB_INIT; 256],
+        }
+    }
+
+
+    /// Lengths 2-9 take 3 bits, 10-17 another 3 and the rest 8
+    fn decode(&mut self, rc: &mut RangeDecoder, pos_state: usize) -> usize {
+        if rc.bit(&mut self.choice) == 0 {
+            return MATCH_LEN_MIN + rc.bit_tree(&mut self.low[pos_state], 3) as usize;
+        }
+        else if rc.bit(&mut self.choice2) == 0 {
+            return MATCH_LEN_MIN + 8 + rc.bit_tree(&mut self.mid[pos_state], 3) as usize;
+        }
+        else {
+            return MATCH_LEN_MIN + 16 + rc.bit_tree(&mut self.high, 8) as usize;
+        }
+    }
+}
+
+
+
+/// LZMA decoder state, which carries over between the chunks of an LZMA2 stream unless they reset it
+struct LzmaDecoder {
+    props:          u8,
+    lc:             u32,
+    lp:             u32,
+    pb:             u32,
+
+    state:          usize,
+    /// The last four match distances, minus one
+    reps:           [u32; 4],
+
+    is_match:       [[u16; POS_STATES_MAX]; STATES],
+    is_rep:         [u16; STATES],
+    is_rep0:        [u16; STATES],
+    is_rep1:        [u16; STATES],
+    is_rep2:        [u16; STATES],
+    is_rep0_long:   [[u16; POS_STATES_MAX]; STATES],
+    /// 0x300 probabilities for each literal context
+    literal:        Vec<u16>,
+    dist_slot:      [[u16; DIST_SLOTS]; DIST_STATES],
+    dist_special:   [u16; FULL_DISTANCES - END_POS_MODEL_INDEX as usize + 1],
+    align:          [u16; 1 << ALIGN_BITS],
+    match_len:      LengthDecoder,
+    rep_len:        LengthDecoder,
+}
+
+impl LzmaDecoder {
+    /// Creates a decoder from the properties byte, which packs the number of literal context and position bits and position bits
+    fn new(props: u8) -> Result<Self, FsError> {
+        let (pb, lp, lc) = (props as u32 / 45, props as u32 % 45 / 9, props as u32 % 9);
+
+        // LZMA2 limits the literal context to 4 bits
+        if pb > 4 || lc + lp > 4 {
+            return Err(FsError::Corrupt("invalid LZMA properties"));
+        }
+
+        Ok(Self {
+            props,
+            lc,
+            lp,
+            pb,
+            state:          0,
+            reps:           [0; 4],
+            is_match:       [[PROB_INIT; POS_STATES_MAX]; STATES],
+            is_rep:         [PROB_INIT; STATES],
+            is_rep0:        [PROB_INIT; STATES],
+            is_rep1:        [PROB_INIT; STATES],
+            is_rep2:        [PROB_INIT; STATES],
+            is_rep0_long:   [[PROB_INIT; POS_STATES_MAX]; STATES],
+            literal:        vec![PROB_INIT; 0x300 << (lc + lp)],
+            dist_slot:      [[PROB_INIT; DIST_SLOTS]; DIST_STATES],
+            dist_special:   [PROB_INIT; FULL_DISTANCES - END_POS_MODEL_INDEX as usize + 1],
+            align:          [PROB_INIT; 1 << ALIGN_BITS],
+            match_len:      LengthDecoder::new(),
+            rep_len:        LengthDecoder::new(),
+        })
+    }
+
+
+    /// Puts the state and probabilities back to how they start, keeping the properties
+    fn reset(&mut self) -> Result<(), FsError> {
+        *self = Self::new(self.props)?;
+        Ok(())
+    }
+
+
+    /// Decodes a match distance, minus one
+    fn distance(&mut self, rc: &mut RangeDecoder, len: usize) -> u32 {
+        let dist_state = (len - MATCH_LEN_MIN).min(DIST_STATES - 1);
+        let slot = rc.bit_tree(&mut self.dist_slot[dist_state], 6);
+        if slot < 4 {
+            return slot;
+        }
+
+        // The slot gives the top two bits of the distance and how many follow
+        let bits = (slot >> 1) - 1;
+        let base = (2 | (slot & 1)) << bits;
+
+        if slot < END_POS_MODEL_INDEX {
+            return base + rc.reverse_bit_tree(&mut self.dist_special[(base - slot) as usize..], bits);
+        }
+        else {
+            return base + (rc.direct_bits(bits - ALIGN_BITS) << ALIGN_BITS) + rc.reverse_bit_tree(&mut self.align, ALIGN_BITS);
+        }
+    }
+
+
+    /// Decodes one LZMA chunk of *unpacked* bytes from *input*, appending to *output*. Matches may reach back to *dict_start*.
+    fn decode(&mut self, input: &[u8], output: &mut Vec<u8>, dict_start: usize, unpacked: usize) -> Result<(), FsError> {
+        let mut rc = RangeDecoder::new(input)?;
+        let end = output.len() + unpacked;
+        let pos_mask = (1 << self.pb) - 1;
+
+        while output.len() < end {
+            // Positions count from the dictionary reset
+            let pos = output.len() - dict_start;
+            let pos_state = pos & pos_mask;
+
+            if rc.bit(&mut self.is_match[self.state][pos_state]) == 0 {
+                let prev = if pos > 0 { output[output.len() - 1] } else { 0 };
+                let context = (((pos & ((1 << self.lp) - 1)) << self.lc) + (prev as usize >> (8 - self.lc))) * 0x300;
+                let probs = &mut self.literal[context..context + 0x300];
+
+                let mut symbol = 1u32;
+                if self.state >= LIT_STATES {
+                    // Right after a match the literal is likely to be the byte following the match, so its bits are predicted from it
+                    // until they differ
+                    if self.reps[0] as usize >= pos {
+                        return Err(FsError::Corrupt("LZMA match before the start of the dictionary"));
+                    }
+                    let mut match_byte = (output[output.len() - self.reps[0] as usize - 1] as u32) << 1;
+                    let mut offset = 0x100;
+
+                    while symbol < 0x100 {
+                        let match_bit = match_byte & offset;
+                        match_byte <<= 1;
+                        let bit = rc.bit(&mut probs[(offset + match_bit + symbol) as usize]);
+                        symbol = (symbol << 1) | bit;
+                        offset = if bit == 1 { match_bit } else { offset & !match_bit };
+                    }
+                }
+                else {
+                    symbol = rc.bit_tree(probs, 8) | 0x100;
+                }
+
+                output.push(symbol as u8);
+                self.state = if self.state < 4 { 0 } else if self.state < 10 { self.state - 3 } else { self.state - 6 };
+                continue;
+            }
+
+            let len;
+            if rc.bit(&mut self.is_rep[self.state]) == 0 {
+                // Match with a new distance
+                len = self.match_len.decode(&mut rc, pos_state);
+                self.state = if self.state < LIT_STATES { 7 } else { 10 };
+                self.reps = [self.distance(&mut rc, len), self.reps[0], self.reps[1], self.reps[2]];
+
+                // LZMA2 has no end marker, the chunk sizes say where data ends
+                if self.reps[0] == u32::MAX {
+                    return Err(FsError::Corrupt("LZMA end marker in LZMA2 data"));
+                }
+            }
+            else {
+                // Match reusing one of the last four distances
+                if rc.bit(&mut self.is_rep0[self.state]) == 0 {
+                    if rc.bit(&mut self.is_rep0_long[self.state][pos_state]) == 0 {
+                        // A single byte at the last distance
+                        if self.reps[0] as usize >= pos {
+                            return Err(FsError::Corrupt("LZMA match before the start of the dictionary"));
+                        }
+                        self.state = if self.state < LIT_STATES { 9 } else { 11 };
+                        copy_match(output, self.reps[0] as usize + 1, 1);
+                        continue;
+                    }
+                }
+                else {
+                    let dist;
+                    if rc.bit(&mut self.is_rep1[self.state]) == 0 {
+                        dist = self.reps[1];
+                    }
+                    else {
+                        if rc.bit(&mut self.is_rep2[self.state]) == 0 {
+                            dist = self.reps[2];
+                        }
+                        else {
+                            dist = self.reps[3];
+                            self.reps[3] = self.reps[2];
+                        }
+                        self.reps[2] = self.reps[1];
+                    }
+                    self.reps[1] = self.reps[0];
+                    self.reps[0] = dist;
+                }
+
+                len = self.rep_len.decode(&mut rc, pos_state);
+                self.state = if self.state < LIT_STATES { 8 } else { 11 };
+            }
+
+            let dist = self.reps[0] as usize + 1;
+            if dist > pos {
+                return Err(FsError::Corrupt("LZMA match before the start of the dictionary"));
+            }
+            if len > end - output.len() {
+                return Err(FsError::Corrupt("LZMA match past the end of the chunk"));
+            }
+            copy_match(output, dist, len);
+        }
+
+        // The encoder flushes exactly enough bytes for the range coder to finish with a zero code
+        rc.normalize();
+        if rc.pos != input.len() || rc.code != 0 {
+            return Err(FsError::Corrupt("LZMA chunk size mismatch"));
+        }
+
+        Ok(())
+    }
+}
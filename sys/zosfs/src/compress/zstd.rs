@@ -0,0 +1,715 @@
+/*  zstd.rs - Zstandard decompression
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Decoder for Zstandard frames (RFC 8878). Dictionaries aren't supported, and content checksums are skipped rather than checked as the
+//! containers zstd is found in have their own.
+
+use alloc::{vec, vec::Vec};
+use crate::error::FsError;
+use super::inflate::copy_match;
+
+
+const MAGIC: u32 = 0xFD2FB528;
+
+const BLOCK_RAW: u32 = 0;
+const BLOCK_RLE: u32 = 1;
+const BLOCK_COMPRESSED: u32 = 2;
+
+const LITERALS_RAW: u8 = 0;
+const LITERALS_RLE: u8 = 1;
+const LITERALS_COMPRESSED: u8 = 2;
+
+const MODE_PREDEFINED: u8 = 0;
+const MODE_RLE: u8 = 1;
+const MODE_FSE: u8 = 2;
+
+/// Default distributions of literal length, match length and offset codes, with their accuracy logs
+const LL_DEFAULT: [i16; 36] = [4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1, 1, 1, -1, -1, -1, -1];
+const ML_DEFAULT: [i16; 53] = [1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+                               1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1];
+const OF_DEFAULT: [i16; 29] = [1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1];
+const LL_DEFAULT_LOG: u8 = 6;
+const ML_DEFAULT_LOG: u8 = 6;
+const OF_DEFAULT_LOG: u8 = 5;
+
+/// Baselines and extra bits of literal length codes
+const LL_BASE: [u32; 36] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 18, 20, 22, 24, 28, 32, 40, 48, 64, 128, 256, 512,
+                            1024, 2048, 4096, 8192, 16384, 32768, 65536];
+const LL_BITS: [u8; 36] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 3, 3, 4, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+
+/// Baselines and extra bits of match length codes
+const ML_BASE: [u32; 53] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32,
+                            33, 34, 35, 37, 39, 41, 43, 47, 51, 59, 67, 83, 99, 131, 259, 515, 1027, 2051, 4099, 8195, 16387, 32771, 65539];
+const ML_BITS: [u8; 53] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2,
+                           3, 3, 4, 4, 5, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+
+
+
+/// Reads a bitstream backwards from its end, which is marked by the highest set bit of the last byte. Bits before the start of the stream
+/// read as zeros, which the final states of a stream rely on.
+struct BackwardBits<'a> {
+    data:   &'a [u8],
+    /// Number of bits not read yet, negative once more have been read than there are
+    pos:    isize,
+}
+
+impl<'a> BackwardBits<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, FsError> {
+        match data.last() {
+            Some(last) if *last != 0 => Ok(Self { data, pos: (data.len() * 8 - 8) as isize + (7 - last.leading_zeros() as isize) }),
+            _ => Err(FsError::Corrupt("invalid zstd bitstream")),
+        }
+    }
+
+    /// Returns the *n* bits starting at bit *start* of the stream
+    fn bits_at(&self, start: isize, n: usize) -> u64 {
+        if start < 0 {
+            let available = n as isize + start;
+            return if available <= 0 { 0 } else { self.bits_at(0, available as usize) << -start };
+        }
+
+        let byte = start as usize / 8;
+        let mut word = [0u8; 8];
+        let end = self.data.len().min(byte + 8);
+        word[..end - byte].copy_from_slice(&self.data[byte..end]);
+
+        (u64::from_le_bytes(word) >> (start % 8)) & ((1u64 << n) - 1)
+    }
+
+    fn peek(&self, n: usize) -> u64 {
+        if n == 0 { 0 } else { self.bits_at(self.pos - n as isize, n) }
+    }
+
+    fn read(&mut self, n: usize) -> u64 {
+        let value = self.peek(n);
+        self.pos -= n as isize;
+        value
+    }
+}
+
+
+
+#[derive(Clone, Copy, Default)]
+struct FseEntry {
+    symbol: u8,
+    bits:   u8,
+    base:   u16,
+}
+
+/// Decoding table of a finite state entropy code
+#[derive(Clone)]
+struct FseTable {
+    log:        u8,
+    entries:    Vec<FseEntry>,
+}
+
+impl FseTable {
+    /// Builds the table for a distribution of *counts* summing to 2^*log*, where -1 stands for a probability below 1
+    fn from_counts(counts: &[i16], log: u8) -> Result<Self, FsError> {
+        let size = 1usize << log;
+        let mut entries = vec![FseEntry::default(); size];
+        let mut next = vec![0u32; counts.len()];
+
+        // Symbols with "less than 1" probability get one state each at the end of the table
+        let mut high = size;
+        for (symbol, count) in counts.iter().enumerate() {
+            if *count == -1 {
+                high = high.checked_sub(1).ok_or(FsError::Corrupt("invalid zstd distribution"))?;
+                entries[high].symbol = symbol as u8;
+                next[symbol] = 1;
+            }
+            else {
+                next[symbol] = *count as u32;
+            }
+        }
+
+        let step = (size >> 1) + (size >> 3) + 3;
+        let mut pos = 0;
+        for (symbol, count) in counts.iter().enumerate() {
+            for _ in 0..(*count).max(0) {
+                entries[pos].symbol = symbol as u8;
+                loop {
+                    pos = (pos + step) & (size - 1);
+                    if pos < high {
+                        break;
+                    }
+                }
+            }
+        }
+        if pos != 0 {
+            return Err(FsError::Corrupt("invalid zstd distribution"));
+        }
+
+        for entry in entries.iter_mut() {
+            let state = next[entry.symbol as usize];
+            next[entry.symbol as usize] += 1;
+
+            entry.bits = log - (31 - state.leading_zeros()) as u8;
+            entry.base = ((state << entry.bits) as usize - size) as u16;
+        }
+
+        Ok(Self { log, entries })
+    }
+
+    /// A table that always decodes to *symbol* without using any bits
+    fn rle(symbol: u8) -> Self {
+        Self {
+            log:        0,
+            entries:    vec![FseEntry { symbol, bits: 0, base: 0 }],
+        }
+    }
+
+    /// Reads a distribution stored at the start of *data* and builds its table. Returns it and the number of bytes used.
+    fn read(data: &[u8], max_log: u8, max_symbol: usize) -> Result<(Self, usize), FsError> {
+        // Counts are packed least significant bit first
+        let peek = |bit: usize| -> u32 {
+            let mut word = [0u8; 8];
+            let start = (bit / 8).min(data.len());
+            let end = data.len().min(start + 8);
+            word[..end - start].copy_from_slice(&data[start..end]);
+
+            (u64::from_le_bytes(word) >> (bit % 8)) as u32
+        };
+
+        let log = (peek(0) & 0xF) as u8 + 5;
+        if log > max_log {
+            return Err(FsError::Corrupt("zstd accuracy log too large"));
+        }
+
+        let mut bit = 4;
+        let mut remaining: i32 = (1 << log) + 1;
+        let mut threshold: i32 = 1 << log;
+        let mut bits = log as usize + 1;
+        let mut counts: Vec<i16> = Vec::new();
+        let mut previous_zero = false;
+
+        while remaining > 1 {
+            // A zero count is followed by 2 bit repeat flags, 3 meaning "3 more zeros and another flag"
+            if previous_zero {
+                loop {
+                    let repeat = peek(bit) & 3;
+                    bit += 2;
+                    counts.extend(core::iter::repeat_n(0, repeat as usize));
+                    if repeat != 3 {
+                        break;
+                    }
+                }
+            }
+
+            let value = peek(bit) as i32;
+            let max = (2 * threshold - 1) - remaining;
+            let mut count;
+            if value & (threshold - 1) < max {
+                count = value & (threshold - 1);
+                bit += bits - 1;
+            }
+            else {
+                count = value & (2 * threshold - 1);
+                if count >= threshold {
+                    count -= max;
+                }
+                bit += bits;
+            }
+
+            count -= 1;
+            remaining -= count.abs();
+            counts.push(count as i16);
+            previous_zero = count == 0;
+
+            while remaining < threshold {
+                bits -= 1;
+                threshold >>= 1;
+            }
+
+            if counts.len() > max_symbol + 1 {
+                return Err(FsError::Corrupt("too many symbols in zstd distribution"));
+            }
+        }
+
+        let used = bit.div_ceil(8);
+        if remaining != 1 || used > data.len() {
+            return Err(FsError::Corrupt("invalid zstd distribution"));
+        }
+
+        Ok((Self::from_counts(&counts, log)?, used))
+    }
+
+    /// Reads the initial state
+    fn init(&self, bits: &mut BackwardBits) -> usize {
+        bits.read(self.log as usize) as usize
+    }
+
+    fn symbol(&self, state: usize) -> u8 {
+        self.entries[state].symbol
+    }
+
+    fn update(&self, state: usize, bits: &mut BackwardBits) -> usize {
+        let entry = self.entries[state];
+        entry.base as usize + bits.read(entry.bits as usize) as usize
+    }
+}
+
+
+
+/// Decoding table of the Huffman code for literals, indexed by the next *max_bits* bits and holding the symbol and its code length
+struct HuffmanTable {
+    max_bits:   usize,
+    entries:    Vec<(u8, u8)>,
+}
+
+impl HuffmanTable {
+    /// Reads a Huffman tree description at the start of *data*. Returns the table and the number of bytes used.
+    fn read(data: &[u8]) -> Result<(Self, usize), FsError> {
+        let header = *data.first().ok_or(FsError::Corrupt("truncated zstd literals"))? as usize;
+        let mut weights: Vec<u8> = Vec::new();
+        let used;
+
+        if header < 128 {
+            // Weights compressed with FSE, decoded by two states taking turns
+            let data = data.get(1..1 + header).ok_or(FsError::Corrupt("truncated zstd literals"))?;
+            let (table, table_size) = FseTable::read(data, 6, 255)?;
+            let mut bits = BackwardBits::new(&data[table_size..])?;
+            let mut states = [table.init(&mut bits), table.init(&mut bits)];
+
+            let mut turn = 0;
+            loop {
+                if weights.len() >= 255 {
+                    return Err(FsError::Corrupt("too many zstd Huffman weights"));
+                }
+                weights.push(table.symbol(states[turn]));
+                states[turn] = table.update(states[turn], &mut bits);
+
+                if bits.pos < 0 {
+                    weights.push(table.symbol(states[1 - turn]));
+                    break;
+                }
+                turn = 1 - turn;
+            }
+
+            used = 1 + header;
+        }
+        else {
+            // Weights stored as they are, 4 bits each
+            let count = header - 127;
+            let packed = data.get(1..1 + count.div_ceil(2)).ok_or(FsError::Corrupt("truncated zstd literals"))?;
+            weights.extend((0..count).map(|i| if i % 2 == 0 { packed[i / 2] >> 4 } else { packed[i / 2] & 0xF }));
+
+            used = 1 + packed.len();
+        }
+
+        // The last weight is left out, as it's whatever makes the total a power of 2
+        let mut total: u32 = 0;
+        for weight in &weights {
+            if *weight > 11 {
+                return Err(FsError::Corrupt("invalid zstd Huffman weight"));
+            }
+            total += (1 << *weight) >> 1;
+        }
+        if total == 0 {
+            return Err(FsError::Corrupt("invalid zstd Huffman weights"));
+        }
+
+        let max_bits = 32 - total.leading_zeros() as usize;
+        let left = (1u32 << max_bits) - total;
+        if !left.is_power_of_two() || max_bits > 11 {
+            return Err(FsError::Corrupt("invalid zstd Huffman weights"));
+        }
+        weights.push(left.trailing_zeros() as u8 + 1);
+
+        // Codes are handed out from the lowest weight (longest code) up, each taking 2^(weight-1) entries
+        let mut entries = vec![(0u8, 0u8); 1 << max_bits];
+        let mut pos = 0;
+        for weight in 1..=max_bits as u8 {
+            for (symbol, _) in weights.iter().enumerate().filter(|(_, w)| **w == weight) {
+                let span = 1 << (weight - 1);
+                entries[pos..pos + span].fill((symbol as u8, max_bits as u8 + 1 - weight));
+                pos += span;
+            }
+        }
+
+        Ok((Self { max_bits, entries }, used))
+    }
+
+    /// Decodes *count* literals from one stream
+    fn decode(&self, stream: &[u8], count: usize, output: &mut Vec<u8>) -> Result<(), FsError> {
+        let mut bits = BackwardBits::new(stream)?;
+
+        for _ in 0..count {
+            let (symbol, len) = self.entries[bits.peek(self.max_bits) as usize];
+            bits.pos -= len as isize;
+            output.push(symbol);
+        }
+
+        if bits.pos != 0 {
+            return Err(FsError::Corrupt("zstd literal stream size mismatch"));
+        }
+
+        Ok(())
+    }
+}
+
+
+
+/// State carried from one block of a frame to the next
+struct Decoder {
+    huffman:    Option<HuffmanTable>,
+    ll:         Option<FseTable>,
+    of:         Option<FseTable>,
+    ml:         Option<FseTable>,
+    offsets:    [usize; 3],
+}
+
+impl Decoder {
+    fn new() -> Self {
+        Self {
+            huffman:    None,
+            ll:         None,
+            of:         None,
+            ml:         None,
+            offsets:    [1, 4, 8],
+        }
+    }
+
+    /// Decodes a compressed block, appending it to *output*. Matches can't reach back past *frame_start*.
+    fn block(&mut self, block: &[u8], output: &mut Vec<u8>, frame_start: usize) -> Result<(), FsError> {
+        let (literals, used) = self.literals(block)?;
+        self.sequences(&block[used..], &literals, output, frame_start)
+    }
+
+    /// Decodes the literals section at the start of a block. Returns the literals and the section's size.
+    fn literals(&mut self, block: &[u8]) -> Result<(Vec<u8>, usize), FsError> {
+        let byte = |i: usize| -> Result<usize, FsError> { block.get(i).map(|b| *b as usize).ok_or(FsError::Corrupt("truncated zstd block")) };
+
+        let header = byte(0)?;
+        let literals_type = (header & 3) as u8;
+        let size_format = (header >> 2) & 3;
+
+        if literals_type == LITERALS_RAW || literals_type == LITERALS_RLE {
+            let (size, header_size) = match size_format {
+                0 | 2   => (header >> 3, 1),
+                1       => ((header >> 4) + (byte(1)? << 4), 2),
+                _       => ((header >> 4) + (byte(1)? << 4) + (byte(2)? << 12), 3),
+            };
+
+            if literals_type == LITERALS_RAW {
+                let literals = block.get(header_size..header_size + size).ok_or(FsError::Corrupt("truncated zstd block"))?;
+                return Ok((literals.to_vec(), header_size + size));
+            }
+            else {
+                return Ok((vec![byte(header_size)? as u8; size], header_size + 1));
+            }
+        }
+
+        let (header_size, size_bits, streams) = match size_format {
+            0   => (3, 10, 1),
+            1   => (3, 10, 4),
+            2   => (4, 14, 4),
+            _   => (5, 18, 4),
+        };
+        let sizes = (0..header_size).try_fold(0u64, |sizes, i| Ok::<u64, FsError>(sizes | (byte(i)? as u64) << (8 * i)))?;
+        let regenerated = ((sizes >> 4) & ((1 << size_bits) - 1)) as usize;
+        let compressed = ((sizes >> (4 + size_bits)) & ((1 << size_bits) - 1)) as usize;
+
+        let mut data = block.get(header_size..header_size + compressed).ok_or(FsError::Corrupt("truncated zstd block"))?;
+        if literals_type == LITERALS_COMPRESSED {
+            let (table, used) = HuffmanTable::read(data)?;
+            self.huffman = Some(table);
+            data = &data[used..];
+        }
+        let huffman = self.huffman.as_ref().ok_or(FsError::Corrupt("zstd literals reuse a missing Huffman table"))?;
+
+        let mut literals = Vec::with_capacity(regenerated);
+        if streams == 1 {
+            huffman.decode(data, regenerated, &mut literals)?;
+        }
+        else {
+            // A jump table gives the sizes of the first three streams, each decoding a quarter of the literals
+            if data.len() < 6 {
+                return Err(FsError::Corrupt("truncated zstd block"));
+            }
+            let mut pos = 6;
+            let quarter = regenerated.div_ceil(4);
+            if quarter * 3 > regenerated {
+                return Err(FsError::Corrupt("invalid zstd literals size"));
+            }
+
+            for i in 0..4 {
+                let (size, count) = if i < 3 {
+                    (u16::from_le_bytes([data[i * 2], data[i * 2 + 1]]) as usize, quarter)
+                } else {
+                    (data.len().saturating_sub(pos), regenerated - 3 * quarter)
+                };
+                let stream = data.get(pos..pos + size).ok_or(FsError::Corrupt("truncated zstd block"))?;
+                huffman.decode(stream, count, &mut literals)?;
+                pos += size;
+            }
+        }
+
+        Ok((literals, header_size + compressed))
+    }
+
+    /// Decodes the sequences section and executes it, appending the result to *output*
+    fn sequences(&mut self, data: &[u8], literals: &[u8], output: &mut Vec<u8>, frame_start: usize) -> Result<(), FsError> {
+        let byte = |i: usize| -> Result<usize, FsError> { data.get(i).map(|b| *b as usize).ok_or(FsError::Corrupt("truncated zstd block")) };
+
+        let (count, mut pos) = match byte(0)? {
+            0           => (0, 1),
+            n @ 1..=127 => (n, 1),
+            n @ 128..=254 => (((n - 128) << 8) + byte(1)?, 2),
+            _           => (byte(1)? + (byte(2)? << 8) + 0x7F00, 3),
+        };
+
+        if count == 0 {
+            output.extend_from_slice(literals);
+            return Ok(());
+        }
+
+        let modes = byte(pos)? as u8;
+        pos += 1;
+
+        let table = |mode: u8, previous: Option<FseTable>, default: &[i16], default_log: u8, max_log: u8, max_symbol: usize, pos: &mut usize| {
+            match mode {
+                MODE_PREDEFINED => FseTable::from_counts(default, default_log),
+                MODE_RLE        => {
+                    let symbol = byte(*pos)?;
+                    *pos += 1;
+                    Ok(FseTable::rle(symbol as u8))
+                }
+                MODE_FSE        => {
+                    let (table, used) = FseTable::read(data.get(*pos..).unwrap_or(&[]), max_log, max_symbol)?;
+                    *pos += used;
+                    Ok(table)
+                }
+                _               => previous.ok_or(FsError::Corrupt("zstd sequences reuse a missing table")),
+            }
+        };
+
+        let ll = table(modes >> 6, self.ll.take(), &LL_DEFAULT, LL_DEFAULT_LOG, 9, 35, &mut pos)?;
+        let of = table((modes >> 4) & 3, self.of.take(), &OF_DEFAULT, OF_DEFAULT_LOG, 8, 31, &mut pos)?;
+        let ml = table((modes >> 2) & 3, self.ml.take(), &ML_DEFAULT, ML_DEFAULT_LOG, 9, 52, &mut pos)?;
+
+        let mut bits = BackwardBits::new(data.get(pos..).unwrap_or(&[]))?;
+        let mut ll_state = ll.init(&mut bits);
+        let mut of_state = of.init(&mut bits);
+        let mut ml_state = ml.init(&mut bits);
+        let mut literal = 0;
+
+        for i in 0..count {
+            let (ll_code, of_code, ml_code) = (ll.symbol(ll_state) as usize, of.symbol(of_state) as usize, ml.symbol(ml_state) as usize);
+            if ll_code >= LL_BASE.len() || ml_code >= ML_BASE.len() || of_code > 31 {
+                return Err(FsError::Corrupt("invalid zstd sequence code"));
+            }
+
+            let offset_value = (1usize << of_code) + bits.read(of_code) as usize;
+            let match_len = ML_BASE[ml_code] as usize + bits.read(ML_BITS[ml_code] as usize) as usize;
+            let literal_len = LL_BASE[ll_code] as usize + bits.read(LL_BITS[ll_code] as usize) as usize;
+            let offset = self.offset(offset_value, literal_len);
+
+            if i + 1 < count {
+                ll_state = ll.update(ll_state, &mut bits);
+                ml_state = ml.update(ml_state, &mut bits);
+                of_state = of.update(of_state, &mut bits);
+            }
+
+            let data = literals.get(literal..literal + literal_len).ok_or(FsError::Corrupt("zstd sequence past the end of the literals"))?;
+            output.extend_from_slice(data);
+            literal += literal_len;
+
+            if offset == 0 || offset > output.len() - frame_start {
+                return Err(FsError::Corrupt("zstd match before the start of the output"));
+            }
+            copy_match(output, offset, match_len);
+        }
+
+        if bits.pos != 0 {
+            return Err(FsError::Corrupt("zstd sequence stream size mismatch"));
+        }
+        output.extend_from_slice(&literals[literal..]);
+
+        self.ll = Some(ll);
+        self.of = Some(of);
+        self.ml = Some(ml);
+
+        Ok(())
+    }
+
+    /// Turns an offset value into the match offset, keeping track of the 3 most recent offsets that values 1 to 3 refer to
+    fn offset(&mut self, value: usize, literal_len: usize) -> usize {
+        if value > 3 {
+            self.offsets = [value - 3, self.offsets[0], self.offsets[1]];
+            return self.offsets[0];
+        }
+
+        // Without literals, the repeat offsets are shifted by one
+        let index = if literal_len == 0 { value } else { value - 1 };
+
+        let offset = match index {
+            0 => return self.offsets[0],
+            3 => self.offsets[0].wrapping_sub(1),
+            _ => self.offsets[index],
+        };
+
+        if index == 1 {
+            self.offsets = [offset, self.offsets[0], self.offsets[2]];
+        }
+        else {
+            self.offsets = [offset, self.offsets[0], self.offsets[1]];
+        }
+
+        offset
+    }
+}
+
+
+
+/// Decompresses a zstd file, which can hold several frames and skippable frames
+pub fn decompress(input: &[u8]) -> Result<Vec<u8>, FsError> {
+    let mut output = Vec::new();
+    let mut pos = 0;
+
+    while pos < input.len() {
+        let magic = input.get(pos..pos + 4).ok_or(FsError::Corrupt("truncated zstd frame"))?;
+        let magic = u32::from_le_bytes(magic.try_into().unwrap());
+
+        if magic & 0xFFFFFFF0 == 0x184D2A50 {
+            let size = input.get(pos + 4..pos + 8).ok_or(FsError::Corrupt("truncated zstd frame"))?;
+            pos += 8 + u32::from_le_bytes(size.try_into().unwrap()) as usize;
+        }
+        else if magic == MAGIC {
+            pos += 4 + decompress_frame(&input[pos + 4..], &mut output)?;
+        }
+        else {
+            return Err(FsError::Corrupt("invalid zstd magic"));
+        }
+    }
+
+    Ok(output)
+}
+
+
+/// Adds up the content sizes frame headers record in a *len* byte file, reading only the frame and block headers through *read*.
+/// Returns None if a frame leaves it out, which zstd does when compressing from a pipe.
+pub fn content_size(len: u64, mut read: impl FnMut(u64, &mut [u8]) -> Option<()>) -> Option<u64> {
+    let mut size = 0u64;
+    let mut pos = 0u64;
+
+    while pos < len {
+        // The magic number, then a skippable frame's size or the largest frame header there can be
+        let mut header = [0u8; 18];
+        let header = &mut header[..(len - pos).min(18) as usize];
+        read(pos, header)?;
+        let magic = u32::from_le_bytes(header.get(..4)?.try_into().unwrap());
+
+        if magic & 0xFFFFFFF0 == 0x184D2A50 {
+            pos += 8 + u32::from_le_bytes(header.get(4..8)?.try_into().unwrap()) as u64;
+            continue;
+        }
+        else if magic != MAGIC {
+            return None;
+        }
+
+        let descriptor = *header.get(4)?;
+        let single_segment = descriptor & 0x20 != 0;
+        let dict_id_size = [0, 1, 2, 4][(descriptor & 3) as usize];
+        let content_size_size = [if single_segment { 1 } else { 0 }, 2, 4, 8][(descriptor >> 6) as usize];
+        if content_size_size == 0 {
+            return None;
+        }
+
+        let field_start = 5 + if single_segment { 0 } else { 1 } + dict_id_size;
+        let field = header.get(field_start..field_start + content_size_size)?;
+        let value = field.iter().rev().fold(0u64, |value, byte| value << 8 | *byte as u64);
+
+        // Two byte sizes are offset by 256, as a single byte covers the smaller ones
+        size += if content_size_size == 2 { value + 256 } else { value };
+        pos += (field_start + content_size_size) as u64;
+
+        // Skip the blocks to get to the next frame
+        loop {
+            let mut block = [0u8; 3];
+            read(pos, &mut block)?;
+            let block = u32::from_le_bytes([block[0], block[1], block[2], 0]);
+            pos += 3 + if (block >> 1) & 3 == BLOCK_RLE { 1 } else { (block >> 3) as u64 };
+
+            if block & 1 != 0 {
+                break;
+            }
+        }
+
+        pos += if descriptor & 0x04 != 0 { 4 } else { 0 };
+    }
+
+    Some(size)
+}
+
+
+/// Decompresses the frame following a magic number, appending to *output*. Returns the size of the frame.
+fn decompress_frame(input: &[u8], output: &mut Vec<u8>) -> Result<usize, FsError> {
+    let descriptor = *input.first().ok_or(FsError::Corrupt("truncated zstd frame"))?;
+    let single_segment = descriptor & 0x20 != 0;
+    let checksum = descriptor & 0x04 != 0;
+
+    if descriptor & 0x08 != 0 {
+        return Err(FsError::Corrupt("reserved zstd frame flag set"));
+    }
+
+    // The window descriptor, dictionary ID and content size only help decoders that don't keep the whole output around
+    let dict_id_size = [0, 1, 2, 4][(descriptor & 3) as usize];
+    let content_size_size = [if single_segment { 1 } else { 0 }, 2, 4, 8][(descriptor >> 6) as usize];
+    let mut pos = 1 + if single_segment { 0 } else { 1 };
+
+    let dict_id = input.get(pos..pos + dict_id_size).ok_or(FsError::Corrupt("truncated zstd frame"))?;
+    if dict_id.iter().any(|b| *b != 0) {
+        return Err(FsError::Unsupported("zstd dictionaries"));
+    }
+    pos += dict_id_size + content_size_size;
+
+    let frame_start = output.len();
+    let mut decoder = Decoder::new();
+
+    loop {
+        let header = input.get(pos..pos + 3).ok_or(FsError::Corrupt("truncated zstd frame"))?;
+        let header = u32::from_le_bytes([header[0], header[1], header[2], 0]);
+        let size = (header >> 3) as usize;
+        pos += 3;
+
+        match (header >> 1) & 3 {
+            BLOCK_RAW           => {
+                output.extend_from_slice(input.get(pos..pos + size).ok_or(FsError::Corrupt("truncated zstd frame"))?);
+                pos += size;
+            }
+            BLOCK_RLE           => {
+                let byte = *input.get(pos).ok_or(FsError::Corrupt("truncated zstd frame"))?;
+                output.resize(output.len() + size, byte);
+                pos += 1;
+            }
+            BLOCK_COMPRESSED    => {
+                decoder.block(input.get(pos..pos + size).ok_or(FsError::Corrupt("truncated zstd frame"))?, output, frame_start)?;
+                pos += size;
+            }
+            _                   => return Err(FsError::Corrupt("reserved zstd block type")),
+        }
+
+        if header & 1 != 0 {
+            break;
+        }
+    }
+
+    Ok(pos + if checksum { 4 } else { 0 })
+}
@@ -0,0 +1,87 @@
+/*  error.rs - Filesystem errors
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use core::fmt;
+use alloc::{string::String, vec::Vec};
+use crate::block::DiskError;
+use crate::uuid::GUID;
+
+
+/// Errors returned by the filesystem drivers
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum FsError {
+    /// A path component does not exist
+    NotFound,
+    /// A path component that should be a directory is not one
+    NotADirectory,
+    /// A file operation was attempted on a directory
+    IsADirectory,
+    /// The name can't be stored on this filesystem
+    InvalidName,
+    /// There are no free clusters or directory entries left
+    NoSpace,
+    /// Too many symlinks were followed while resolving a path
+    SymlinkLoop,
+    /// The slice does not hold a filesystem we know of
+    UnknownFilesystem,
+    /// The filesystem uses a feature the driver does not implement
+    Unsupported(&'static str),
+    /// The filesystem flags optional features the driver does not implement, listed by name
+    UnsupportedFeatures(Vec<String>),
+    /// The on-disk structures don't make sense
+    Corrupt(&'static str),
+    /// No slice has a filesystem with the requested UUID or label
+    VolumeNotFound,
+    /// Several slices have a filesystem with the requested UUID or label
+    AmbiguousVolume(Vec<GUID>),
+    /// Reading from the disk failed
+    Io(DiskError),
+}
+
+impl From<DiskError> for FsError {
+    fn from(err: DiskError) -> Self {
+        FsError::Io(err)
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsError::NotFound               => write!(f, "no such file or directory"),
+            FsError::NotADirectory          => write!(f, "not a directory"),
+            FsError::IsADirectory           => write!(f, "is a directory"),
+            FsError::InvalidName            => write!(f, "invalid file name"),
+            FsError::NoSpace                => write!(f, "no space left on device"),
+            FsError::SymlinkLoop            => write!(f, "too many levels of symbolic links"),
+            FsError::UnknownFilesystem      => write!(f, "unknown filesystem"),
+            FsError::Unsupported(feature)   => write!(f, "unsupported filesystem feature: {}", feature),
+            FsError::UnsupportedFeatures(features) => write!(f, "unsupported filesystem features: {}", features.join(" ")),
+            FsError::Corrupt(what)          => write!(f, "filesystem is corrupt: {}", what),
+            FsError::VolumeNotFound         => write!(f, "no slice has a filesystem with that UUID or label"),
+            FsError::AmbiguousVolume(slices) => {
+                write!(f, "the filesystem UUID or label is shared by slices")?;
+                for slice in slices {
+                    write!(f, " {}", slice.as_string())?;
+                }
+                Ok(())
+            }
+            FsError::Io(err)                => write!(f, "disk error: {}", err),
+        }
+    }
+}
@@ -0,0 +1,797 @@
+/*  exfat.rs - exFAT driver
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Read only exFAT driver.
+//!
+//! exFAT keeps a FAT, but files whose clusters are contiguous can skip it entirely by setting NoFatChain in their stream extension entry.
+//! Every file is described by an entry set: a file entry, a stream extension entry and one or more name entries, protected by a checksum.
+//! Names are compared case insensitively through the up-case table stored on the volume.
+
+#![allow(dead_code)]
+
+use alloc::{format, string::{String, ToString}, vec::Vec, vec};
+use crate::block::{self, BlockDevice};
+use crate::error::FsError;
+use crate::fat;
+use crate::fattable::{self, FatCache, FatTable};
+use crate::fs::{CaseRule, DirEntry, Extent, FileType, Filesystem, Metadata};
+
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct BootSector {
+    jmp_boot:                   [u8; 3],
+    pub fs_name:                [u8; 8],
+    _must_be_zero:              [u8; 53],
+    pub partition_offset:       u64,
+    pub volume_length:          u64,
+    pub fat_offset:             u32,
+    pub fat_length:             u32,
+    pub cluster_heap_offset:    u32,
+    pub cluster_count:          u32,
+    pub root_cluster:           u32,
+    pub serial_number:          u32,
+    pub fs_revision:            u16,
+    pub volume_flags:           u16,
+    pub bytes_per_sector_shift: u8,
+    pub sectors_per_cluster_shift: u8,
+    pub number_of_fats:         u8,
+    pub drive_select:           u8,
+    pub percent_in_use:         u8,
+    _reserved:                  [u8; 7],
+}
+
+/// First entry of a file's entry set
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct FileEntry {
+    pub entry_type:             u8,
+    pub secondary_count:        u8,
+    pub set_checksum:           u16,
+    pub attributes:             u16,
+    _reserved1:                 u16,
+    pub create_timestamp:       u32,
+    pub modified_timestamp:     u32,
+    pub accessed_timestamp:     u32,
+    pub create_10ms:            u8,
+    pub modified_10ms:          u8,
+    pub create_utc_offset:      u8,
+    pub modified_utc_offset:    u8,
+    pub accessed_utc_offset:    u8,
+    _reserved2:                 [u8; 7],
+}
+
+/// Second entry of a file's entry set, saying where its data is
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct StreamEntry {
+    pub entry_type:             u8,
+    pub flags:                  u8,
+    _reserved1:                 u8,
+    pub name_length:            u8,
+    pub name_hash:              u16,
+    _reserved2:                 u16,
+    pub valid_data_length:      u64,
+    _reserved3:                 u32,
+    pub first_cluster:          u32,
+    pub data_length:            u64,
+}
+
+/// Holds up to 15 UTF-16 characters of a file's name
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct NameEntry {
+    pub entry_type:             u8,
+    pub flags:                  u8,
+    pub name:                   [u16; 15],
+}
+
+/// Allocation bitmap and up-case table entries, which only live in the root directory
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct MetadataEntry {
+    pub entry_type:             u8,
+    pub flags:                  u8,
+    _reserved1:                 [u8; 2],
+    /// Only used by the up-case table
+    pub table_checksum:         u32,
+    _reserved2:                 [u8; 12],
+    pub first_cluster:          u32,
+    pub data_length:            u64,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct VolumeLabelEntry {
+    pub entry_type:             u8,
+    pub character_count:        u8,
+    pub label:                  [u16; 11],
+    _reserved:                  [u8; 8],
+}
+
+
+/// A file or directory, built from its entry set
+#[derive(Clone, Copy)]
+pub struct ExfatNode {
+    pub attributes:             u16,
+    pub first_cluster:          u32,
+    pub data_length:            u64,
+    /// Bytes past this point have never been written and read as zeros
+    pub valid_data_length:      u64,
+    /// The clusters are contiguous and the FAT must not be used
+    pub no_fat_chain:           bool,
+    pub name_hash:              u16,
+    pub mtime:                  i64,
+}
+
+
+const ENTRY_SIZE: usize = 32;
+
+const ENTRY_END_OF_DIRECTORY: u8 = 0x00;
+const ENTRY_IN_USE: u8 = 0x80;
+const ENTRY_BITMAP: u8 = 0x81;
+const ENTRY_UPCASE: u8 = 0x82;
+const ENTRY_VOLUME_LABEL: u8 = 0x83;
+const ENTRY_FILE: u8 = 0x85;
+const ENTRY_STREAM: u8 = 0xC0;
+const ENTRY_NAME: u8 = 0xC1;
+
+const ATTR_READ_ONLY: u16 = 0x01;
+const ATTR_DIRECTORY: u16 = 0x10;
+
+/// Set in a stream extension entry when the file's clusters are contiguous and not recorded in the FAT
+const FLAG_NO_FAT_CHAIN: u8 = 0x02;
+/// Set in the boot sector when the second FAT and allocation bitmap are the active ones
+const VOLUME_FLAG_ACTIVE_FAT: u16 = 0x01;
+
+const CLUSTER_BAD: u32 = 0xFFFFFFF7;
+const CLUSTER_EOC: u32 = 0xFFFFFFFF;
+
+/// Sectors in the boot region, not counting the checksum sector that follows them. The backup boot region comes right after.
+const BOOT_REGION_SECTORS: u64 = 11;
+
+/// Name entries hold 15 characters each and a name is at most 255 characters, so an entry set has at most 17 secondary entries + 1 stream
+const MAX_SECONDARY_ENTRIES: usize = 18;
+const NAME_ENTRY_CHARS: usize = 15;
+
+
+
+/// Detects whether or not the device contains an exFAT filesystem
+pub fn detect<D: BlockDevice>(dev: &D) -> Result<bool, FsError> {
+    let mut fs_name = [0u8; 8];
+    dev.read_bytes(3, &mut fs_name)?;
+
+    if &fs_name == b"EXFAT   " {
+        return Ok(true);
+    }
+    else {
+        return Ok(false);
+    }
+}
+
+
+/// Checksum over the first 11 sectors of a boot region. The volume flags and percent in use change at runtime, so they're left out.
+fn boot_checksum(sectors: &[u8]) -> u32 {
+    let mut checksum: u32 = 0;
+
+    for (i, byte) in sectors.iter().enumerate() {
+        if i == 106 || i == 107 || i == 112 {
+            continue;
+        }
+        checksum = checksum.rotate_right(1).wrapping_add(*byte as u32);
+    }
+
+    checksum
+}
+
+
+/// Checksum over the compressed up-case table
+fn table_checksum(raw: &[u8]) -> u32 {
+    raw.iter().fold(0u32, |checksum, byte| checksum.rotate_right(1).wrapping_add(*byte as u32))
+}
+
+
+/// Checksum over every entry of an entry set, skipping the checksum field in the first one
+fn set_checksum(entries: &[u8]) -> u16 {
+    let mut checksum: u16 = 0;
+
+    for (i, byte) in entries.iter().enumerate() {
+        if i == 2 || i == 3 {
+            continue;
+        }
+        checksum = checksum.rotate_right(1).wrapping_add(*byte as u16);
+    }
+
+    checksum
+}
+
+
+/// Hash of an up-cased name, stored in the stream extension entry so most names can be skipped without comparing them
+fn name_hash(upcased: &[u16]) -> u16 {
+    let mut hash: u16 = 0;
+
+    for c in upcased {
+        for byte in c.to_le_bytes() {
+            hash = hash.rotate_right(1).wrapping_add(byte as u16);
+        }
+    }
+
+    hash
+}
+
+
+/// Expands the up-case table. On disk, runs of characters that map to themselves are stored as 0xFFFF followed by the length of the run.
+fn decompress_upcase(raw: &[u8]) -> Vec<u16> {
+    let mut table: Vec<u16> = Vec::new();
+    let mut values = raw.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]]));
+
+    while let Some(value) = values.next() {
+        if value == 0xFFFF {
+            let run = values.next().unwrap_or(0);
+
+            for _ in 0..run {
+                table.push(table.len() as u16);
+            }
+        }
+        else {
+            table.push(value);
+        }
+    }
+
+    table
+}
+
+
+/// Converts an exFAT timestamp to seconds since the UNIX epoch. The layout is the same as a DOS date and time, with an extra count of
+/// 10ms increments and, if the top bit is set, the offset from UTC in 15 minute steps.
+fn exfat_to_unix_time(timestamp: u32, increment_10ms: u8, utc_offset: u8) -> i64 {
+    let mut time = fat::dos_to_unix_time((timestamp >> 16) as u16, timestamp as u16) + increment_10ms as i64 / 100;
+
+    if utc_offset & 0x80 != 0 {
+        // Sign extend the 7 bit offset
+        let offset = ((utc_offset << 1) as i8 >> 1) as i64;
+        time -= offset * 15 * 60;
+    }
+
+    time
+}
+
+
+
+
+/// Number of FAT sectors kept in memory
+const FAT_CACHE_SECTORS: usize = 8;
+
+
+/// A mounted exFAT filesystem
+pub struct ExfatFs<D: BlockDevice> {
+    dev:            D,
+    bs:             BootSector,
+    /// Maps each UTF-16 character to its upper case version. Characters past the end map to themselves.
+    upcase:         Vec<u16>,
+    /// The allocation bitmap in use
+    bitmap:         ExfatNode,
+    label:          String,
+    /// Recently used sectors of the active FAT
+    fat_cache:      FatCache,
+}
+
+impl<D: BlockDevice> ExfatFs<D> {
+    pub fn new(dev: D) -> Result<Self, FsError> {
+        let bs = read_boot_region(&dev)?;
+
+        let mut fs = Self {
+            dev,
+            bs,
+            upcase:         Vec::new(),
+            bitmap:         ExfatNode::new_zeroed(),
+            label:          String::new(),
+            fat_cache:      FatCache::new(1 << bs.bytes_per_sector_shift, FAT_CACHE_SECTORS),
+        };
+
+        fs.read_root_metadata()?;
+
+        Ok(fs)
+    }
+
+
+    /// Finds the allocation bitmap, the up-case table and the volume label, which are all stored as entries in the root directory
+    fn read_root_metadata(&mut self) -> Result<(), FsError> {
+        let root = self.root()?.node;
+        let raw = self.read_all(&root)?;
+
+        // With two FATs there are two bitmaps, and bit 0 of their flags says which FAT they go with
+        let active_fat = (self.bs.volume_flags & VOLUME_FLAG_ACTIVE_FAT) as u8;
+        let mut bitmap: Option<MetadataEntry> = None;
+        let mut upcase: Option<MetadataEntry> = None;
+
+        for slot in raw.chunks_exact(ENTRY_SIZE) {
+            match slot[0] {
+                ENTRY_END_OF_DIRECTORY  => break,
+
+                ENTRY_BITMAP => {
+                    let entry: MetadataEntry = block::struct_from_bytes(slot);
+                    if entry.flags & 1 == active_fat {
+                        bitmap = Some(entry);
+                    }
+                }
+
+                ENTRY_UPCASE => upcase = Some(block::struct_from_bytes(slot)),
+
+                ENTRY_VOLUME_LABEL => {
+                    let entry: VolumeLabelEntry = block::struct_from_bytes(slot);
+                    let label = entry.label;
+                    let count = (entry.character_count as usize).min(label.len());
+
+                    self.label = char::decode_utf16(label[..count].iter().map(|c| u16::from_le(*c)))
+                        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                        .collect();
+                }
+
+                _ => {}
+            }
+        }
+
+        let bitmap = bitmap.ok_or(FsError::Corrupt("no allocation bitmap"))?;
+        if bitmap.data_length < (self.bs.cluster_count as u64).div_ceil(8) {
+            return Err(FsError::Corrupt("allocation bitmap is too small"));
+        }
+        self.bitmap = ExfatNode::from_metadata_entry(&bitmap);
+
+        let upcase = upcase.ok_or(FsError::Corrupt("no up-case table"))?;
+        let raw_table = self.read_all(&ExfatNode::from_metadata_entry(&upcase))?;
+        if table_checksum(&raw_table) != upcase.table_checksum {
+            return Err(FsError::Corrupt("up-case table checksum mismatch"));
+        }
+        self.upcase = decompress_upcase(&raw_table);
+
+        Ok(())
+    }
+
+
+    /// Returns the volume serial number and label
+    pub fn volume_id(&self) -> (u128, String) {
+        (self.bs.serial_number as u128, self.label.clone())
+    }
+
+
+    /// Lists the clusters holding a file as extents counted in clusters
+    pub fn extents(&self, node: &ExfatNode) -> Result<Vec<Extent>, FsError> {
+        let cluster_count = self.bs.cluster_count;
+
+        if node.first_cluster == 0 {
+            return Ok(Vec::new());
+        }
+
+        // Contiguous files only record where they start, their length comes from the data length
+        if node.no_fat_chain {
+            let length = node.data_length.div_ceil(self.cluster_size());
+
+            if node.first_cluster < 2 || node.first_cluster as u64 + length > cluster_count as u64 + 2 {
+                return Err(FsError::Corrupt("file points outside the volume"));
+            }
+
+            return Ok(vec![Extent { logical: 0, physical: node.first_cluster as u64, length }]);
+        }
+
+        let chain = fattable::cluster_chain(node.first_cluster, cluster_count, |cluster| {
+            let next = self.fat_entry(cluster)?;
+
+            if next == CLUSTER_EOC {
+                return Ok(None);
+            }
+            else if next == CLUSTER_BAD {
+                return Err(FsError::Corrupt("cluster chain runs into a bad cluster"));
+            }
+            else {
+                return Ok(Some(next));
+            }
+        })?;
+
+        Ok(fattable::chain_extents(&chain))
+    }
+
+
+    /// Reads the FAT entry for *cluster*, i.e the next cluster in its chain
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let mut bytes = [0u8; 4];
+        self.fat_cache.read(self, cluster as u64 * 4, &mut bytes)?;
+
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+
+    /// Reads a node's whole data, used for directories and the volume metadata. The data length is checked against the clusters the
+    /// node has before a buffer that large is allocated.
+    fn read_all(&self, node: &ExfatNode) -> Result<Vec<u8>, FsError> {
+        let clusters: u64 = self.extents(node)?.iter().map(|extent| extent.length).sum();
+        if node.data_length > clusters * self.cluster_size() {
+            return Err(FsError::Corrupt("file is longer than its cluster chain"));
+        }
+
+        let mut buffer: Vec<u8> = vec![0; node.data_length as usize];
+        if self.read(node, 0, &mut buffer)? < buffer.len() {
+            return Err(FsError::Corrupt("file is shorter than its data length"));
+        }
+
+        Ok(buffer)
+    }
+
+
+    /// Counts the clusters not marked as used in the allocation bitmap
+    pub fn free_clusters(&self) -> Result<u32, FsError> {
+        let bitmap = self.read_all(&self.bitmap)?;
+        let cluster_count = self.bs.cluster_count as usize;
+        if bitmap.len() < cluster_count.div_ceil(8) {
+            return Err(FsError::Corrupt("allocation bitmap is too small"));
+        }
+
+        let used: u32 = (0..cluster_count).map(|i| ((bitmap[i / 8] >> (i % 8)) & 1) as u32).sum();
+
+        Ok(self.bs.cluster_count - used)
+    }
+
+
+    /// Lists the interesting boot sector fields as name/value pairs, for debugging tools
+    pub fn superblock_info(&self) -> Result<Vec<(&'static str, String)>, FsError> {
+        let bs = &self.bs;
+        let serial = bs.serial_number;
+        let revision = bs.fs_revision;
+
+        Ok(vec![
+            ("Revision",                format!("{}.{:02}", revision >> 8, revision & 0xFF)),
+            ("Volume serial",           format!("{:04X}-{:04X}", serial >> 16, serial & 0xFFFF)),
+            ("Volume label",            self.label.clone()),
+            ("Volume flags",            format!("{:#06x}", { bs.volume_flags })),
+            ("Bytes per sector",        self.bytes_per_sector().to_string()),
+            ("Sectors per cluster",     (1u32 << bs.sectors_per_cluster_shift).to_string()),
+            ("Number of FATs",          bs.number_of_fats.to_string()),
+            ("FAT offset",              { bs.fat_offset }.to_string()),
+            ("FAT length",              { bs.fat_length }.to_string()),
+            ("Cluster heap offset",     { bs.cluster_heap_offset }.to_string()),
+            ("Volume length",           { bs.volume_length }.to_string()),
+            ("Clusters",                { bs.cluster_count }.to_string()),
+            ("Free clusters",           self.free_clusters()?.to_string()),
+            ("Root cluster",            { bs.root_cluster }.to_string()),
+            ("Percent in use",          bs.percent_in_use.to_string()),
+        ])
+    }
+
+
+    fn bytes_per_sector(&self) -> u64 {
+        1 << self.bs.bytes_per_sector_shift
+    }
+
+
+    /// Size of a cluster in bytes
+    fn cluster_size(&self) -> u64 {
+        1 << (self.bs.bytes_per_sector_shift + self.bs.sectors_per_cluster_shift)
+    }
+
+
+    /// Byte offset of the first sector of *cluster*
+    fn cluster_offset(&self, cluster: u32) -> Result<u64, FsError> {
+        if cluster < 2 {
+            return Err(FsError::Corrupt("reference to a reserved cluster"));
+        }
+
+        Ok(self.bs.cluster_heap_offset as u64 * self.bytes_per_sector() + (cluster as u64 - 2) * self.cluster_size())
+    }
+
+
+    /// Up-cases a name with the volume's up-case table
+    fn upcase_name(&self, name: &[u16]) -> Vec<u16> {
+        name.iter().map(|c| *self.upcase.get(*c as usize).unwrap_or(c)).collect()
+    }
+
+
+    /// Parses the entry sets in a directory's raw contents. Sets that are damaged are skipped, the rest of the directory is still usable.
+    fn parse_dir(&self, raw: &[u8]) -> Vec<DirEntry<ExfatNode>> {
+        let slots = raw.len() / ENTRY_SIZE;
+        let mut entries = Vec::new();
+        let mut i = 0;
+
+        while i < slots {
+            let entry_type = raw[i * ENTRY_SIZE];
+
+            if entry_type == ENTRY_END_OF_DIRECTORY {
+                break;
+            }
+            // Deleted entries, stray secondary entries and the volume metadata
+            else if entry_type != ENTRY_FILE {
+                i += 1;
+                continue;
+            }
+
+            if let Some((entry, secondary_count)) = self.parse_entry_set(&raw[i * ENTRY_SIZE..]) {
+                entries.push(entry);
+                i += 1 + secondary_count;
+            }
+            else {
+                fslog!("exFAT: skipping damaged directory entry set");
+                i += 1;
+            }
+        }
+
+        entries
+    }
+
+
+    /// Builds a directory entry from the entry set at the start of *raw*. Also returns the number of secondary entries in the set.
+    fn parse_entry_set(&self, raw: &[u8]) -> Option<(DirEntry<ExfatNode>, usize)> {
+        let file: FileEntry = block::struct_from_bytes(raw);
+        let secondary_count = file.secondary_count as usize;
+        let slot = |n: usize| &raw[n * ENTRY_SIZE..(n + 1) * ENTRY_SIZE];
+
+        // There's always a stream extension entry and at least one name entry
+        if !(2..=MAX_SECONDARY_ENTRIES).contains(&secondary_count) || (1 + secondary_count) * ENTRY_SIZE > raw.len() {
+            return None;
+        }
+        if set_checksum(&raw[..(1 + secondary_count) * ENTRY_SIZE]) != file.set_checksum {
+            return None;
+        }
+
+        let stream: StreamEntry = block::struct_from_bytes(slot(1));
+        if stream.entry_type != ENTRY_STREAM {
+            return None;
+        }
+
+        let name_length = stream.name_length as usize;
+        let name_entries = name_length.div_ceil(NAME_ENTRY_CHARS);
+        if name_length == 0 || name_entries > secondary_count - 1 {
+            return None;
+        }
+
+        let mut name: Vec<u16> = Vec::new();
+        for n in 2..2 + name_entries {
+            let entry: NameEntry = block::struct_from_bytes(slot(n));
+            if entry.entry_type != ENTRY_NAME {
+                return None;
+            }
+
+            let chars = entry.name;
+            name.extend(chars.iter().map(|c| u16::from_le(*c)));
+        }
+        name.truncate(name_length);
+
+        let attributes = file.attributes;
+
+        let entry = DirEntry {
+            name:       char::decode_utf16(name).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect(),
+            file_type:  if attributes & ATTR_DIRECTORY != 0 { FileType::Directory } else { FileType::Regular },
+            node:       ExfatNode {
+                attributes,
+                first_cluster:      stream.first_cluster,
+                data_length:        stream.data_length,
+                valid_data_length:  stream.valid_data_length.min(stream.data_length),
+                no_fat_chain:       stream.flags & FLAG_NO_FAT_CHAIN != 0,
+                name_hash:          stream.name_hash,
+                mtime:              exfat_to_unix_time(file.modified_timestamp, file.modified_10ms, file.modified_utc_offset),
+            },
+        };
+
+        Some((entry, secondary_count))
+    }
+}
+
+impl<D: BlockDevice> FatTable for ExfatFs<D> {
+    fn read_table_sector(&self, sector: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        // The second FAT is only used when the volume flags say so
+        let active_fat = (self.bs.volume_flags & VOLUME_FLAG_ACTIVE_FAT) as u64;
+        let fat_start = self.bs.fat_offset as u64 + active_fat * self.bs.fat_length as u64;
+
+        Ok(self.dev.read_bytes((fat_start + sector) * self.bytes_per_sector(), buffer)?)
+    }
+
+    /// Nothing is ever written, as the driver is read only
+    fn write_table_sector(&self, _sector: u64, _data: &[u8]) -> Result<(), FsError> {
+        Err(FsError::Unsupported("writing to exFAT"))
+    }
+}
+
+
+impl<D: BlockDevice> Filesystem for ExfatFs<D> {
+    type Node = ExfatNode;
+
+    fn case_rule(&self) -> CaseRule {
+        CaseRule::Insensitive
+    }
+
+    fn root(&self) -> Result<DirEntry<ExfatNode>, FsError> {
+        // The root directory has no entry set of its own and is always in the FAT, so its length is that of its cluster chain
+        let mut root = ExfatNode::new_zeroed();
+        root.attributes = ATTR_DIRECTORY;
+        root.first_cluster = self.bs.root_cluster;
+
+        let clusters: u64 = self.extents(&root)?.iter().map(|extent| extent.length).sum();
+        root.data_length = clusters * self.cluster_size();
+        root.valid_data_length = root.data_length;
+
+        Ok(DirEntry {
+            name:       "/".to_string(),
+            file_type:  FileType::Directory,
+            node:       root,
+        })
+    }
+
+    fn read_dir(&self, dir: &ExfatNode) -> Result<Vec<DirEntry<ExfatNode>>, FsError> {
+        let raw = self.read_all(dir)?;
+
+        Ok(self.parse_dir(&raw))
+    }
+
+    /// Compares names through the volume's up-case table, checking the name hash first to skip most entries cheaply
+    fn lookup(&self, dir: &ExfatNode, name: &str) -> Result<Option<DirEntry<ExfatNode>>, FsError> {
+        let wanted = self.upcase_name(&name.encode_utf16().collect::<Vec<u16>>());
+        let hash = name_hash(&wanted);
+
+        Ok(self.read_dir(dir)?.into_iter().find(|entry| {
+            entry.node.name_hash == hash && self.upcase_name(&entry.name.encode_utf16().collect::<Vec<u16>>()) == wanted
+        }))
+    }
+
+    fn metadata(&self, node: &ExfatNode) -> Result<Metadata, FsError> {
+        let is_dir = node.attributes & ATTR_DIRECTORY != 0;
+
+        // exFAT has no permissions, only a read only flag
+        let mut mode = if is_dir { 0o755 } else { 0o644 };
+        if node.attributes & ATTR_READ_ONLY != 0 {
+            mode &= 0o555;
+        }
+
+        Ok(Metadata {
+            file_type:  if is_dir { FileType::Directory } else { FileType::Regular },
+            size:       node.data_length,
+            mode,
+            uid:        0,
+            gid:        0,
+            mtime:      node.mtime,
+            id:         node.first_cluster as u64,
+        })
+    }
+
+    fn read(&self, node: &ExfatNode, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if offset >= node.data_length {
+            return Ok(0);
+        }
+
+        let len = buffer.len().min((node.data_length - offset) as usize);
+        let cluster_size = self.cluster_size();
+
+        // Only the part up to the valid data length is read from disk, the rest was never written and is zero
+        let valid = if node.valid_data_length > offset { ((node.valid_data_length - offset) as usize).min(len) } else { 0 };
+        buffer[valid..len].fill(0);
+
+        let mut done = 0;
+        for extent in self.extents(node)? {
+            if done == valid {
+                break;
+            }
+
+            let extent_start = extent.logical * cluster_size;
+            let extent_end = extent_start + extent.length * cluster_size;
+            let pos = offset + done as u64;
+
+            if pos >= extent_end {
+                continue;
+            }
+
+            let count = ((extent_end - pos) as usize).min(valid - done);
+            let disk_offset = self.cluster_offset(extent.physical as u32)? + (pos - extent_start);
+            self.dev.read_bytes(disk_offset, &mut buffer[done..done + count])?;
+
+            done += count;
+        }
+
+        // The clusters must cover everything that was written
+        if done < valid {
+            return Err(FsError::Corrupt("cluster chain is shorter than the file"));
+        }
+
+        Ok(len)
+    }
+}
+
+
+impl ExfatNode {
+    fn new_zeroed() -> Self {
+        Self {
+            attributes:         0,
+            first_cluster:      0,
+            data_length:        0,
+            valid_data_length:  0,
+            no_fat_chain:       false,
+            name_hash:          0,
+            mtime:              0,
+        }
+    }
+
+    /// The allocation bitmap and up-case table are always in the FAT, fully written and as long as their data length says
+    fn from_metadata_entry(entry: &MetadataEntry) -> Self {
+        let mut node = Self::new_zeroed();
+        node.first_cluster = entry.first_cluster;
+        node.data_length = entry.data_length;
+        node.valid_data_length = entry.data_length;
+
+        node
+    }
+}
+
+
+
+/// Reads and checks the boot region, falling back to the backup boot region if the main one is damaged
+fn read_boot_region<D: BlockDevice>(dev: &D) -> Result<BootSector, FsError> {
+    let mut shift = [0u8];
+    dev.read_bytes(108, &mut shift)?;
+
+    let reason = match check_boot_region(dev, 0, shift[0]) {
+        Ok(bs)                      => return Ok(bs),
+        Err(FsError::Corrupt(why))  => why,
+        Err(err)                    => return Err(err),
+    };
+
+    // The backup starts right after the main region, which depends on the sector size. The main boot sector can't be trusted to give
+    // that, so try every size exFAT allows.
+    for shift in 9..=12 {
+        if let Ok(bs) = check_boot_region(dev, (BOOT_REGION_SECTORS + 1) << shift, shift) {
+            fslog!("exFAT: main boot region is damaged ({}), using the backup", reason);
+            return Ok(bs);
+        }
+    }
+
+    Err(FsError::Corrupt(reason))
+}
+
+
+/// Checks the boot region starting at byte *start* assuming 2^*shift* byte sectors, and returns its boot sector
+fn check_boot_region<D: BlockDevice>(dev: &D, start: u64, shift: u8) -> Result<BootSector, FsError> {
+    // exFAT allows 512 to 4096 byte sectors and clusters up to 32MB
+    if !(9..=12).contains(&shift) {
+        return Err(FsError::Corrupt("invalid bytes per sector"));
+    }
+    let bytes_per_sector = 1u64 << shift;
+
+    let mut sectors: Vec<u8> = vec![0; ((BOOT_REGION_SECTORS + 1) * bytes_per_sector) as usize];
+    dev.read_bytes(start, &mut sectors)?;
+
+    let (boot_region, checksum_sector) = sectors.split_at(BOOT_REGION_SECTORS as usize * bytes_per_sector as usize);
+    let checksum = boot_checksum(boot_region);
+
+    // The checksum sector holds the checksum repeated over and over
+    if checksum_sector.chunks_exact(4).any(|c| u32::from_le_bytes(c.try_into().unwrap()) != checksum) {
+        return Err(FsError::Corrupt("boot region checksum mismatch"));
+    }
+
+    let bs: BootSector = block::struct_from_bytes(boot_region);
+
+    if &bs.fs_name != b"EXFAT   " || bs.bytes_per_sector_shift != shift {
+        return Err(FsError::Corrupt("invalid boot sector"));
+    }
+    if bs.sectors_per_cluster_shift > 25 - shift {
+        return Err(FsError::Corrupt("invalid sectors per cluster"));
+    }
+    if bs.number_of_fats == 0 || bs.number_of_fats > 2 || bs.fat_length == 0 {
+        return Err(FsError::Corrupt("no FAT"));
+    }
+    if bs.cluster_count == 0 || bs.root_cluster < 2 || bs.root_cluster > bs.cluster_count + 1 {
+        return Err(FsError::Corrupt("invalid cluster heap"));
+    }
+
+    Ok(bs)
+}
@@ -0,0 +1,348 @@
+/*  htree.rs - Hashed directory indexes for the EXT driver
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Directories with the INDEX flag keep a B-tree of name hashes (an htree) in their blocks, hidden from plain readers behind directory
+//! entries that cover the whole block. The first block holds the root after the "." and ".." entries, deeper levels hold dx_node blocks
+//! and the leaves are ordinary directory blocks, each holding the names whose hashes fall in its range.
+
+use alloc::vec::Vec;
+use crate::block;
+use crate::error::FsError;
+use core::mem::size_of;
+
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct DxRootInfo {
+    pub reserved_zero:      u32,
+    pub hash_version:       u8,
+    pub info_length:        u8,
+    pub indirect_levels:    u8,
+    pub unused_flags:       u8,
+}
+
+/// Takes the place of the first entry's hash in every index node
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct DxCountLimit {
+    pub limit:      u16,
+    pub count:      u16,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct DxEntryRaw {
+    pub hash:       u32,
+    pub block:      u32,
+}
+
+
+
+/// An index entry: names hashing to *hash* or more (until the next entry) are under *block*, a block within the directory
+#[derive(Clone, Copy)]
+pub struct DxEntry {
+    pub hash:   u32,
+    pub block:  u32,
+}
+
+
+/// The root of an htree
+pub struct DxRoot {
+    pub hash_version:       u8,
+    /// Levels of dx_node blocks between the root and the leaves
+    pub indirect_levels:    u8,
+    pub entries:            Vec<DxEntry>,
+}
+
+
+/// Hash functions an htree can be built with. The unsigned variants exist because the originals hash name bytes as C chars, whose
+/// signedness depends on the architecture that created the filesystem.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HashVersion {
+    Legacy,
+    HalfMd4,
+    Tea,
+    LegacyUnsigned,
+    HalfMd4Unsigned,
+    TeaUnsigned,
+}
+
+impl HashVersion {
+    /// Decodes the hash version stored in an htree root. *unsigned* comes from the superblock, which records how chars were treated
+    /// for all three original versions. Returns None for hashes that aren't supported, like the SipHash of casefolded directories.
+    pub fn from_raw(version: u8, unsigned: bool) -> Option<Self> {
+        match (version, unsigned) {
+            (0, false)  => Some(HashVersion::Legacy),
+            (1, false)  => Some(HashVersion::HalfMd4),
+            (2, false)  => Some(HashVersion::Tea),
+            (0, true)   => Some(HashVersion::LegacyUnsigned),
+            (1, true)   => Some(HashVersion::HalfMd4Unsigned),
+            (2, true)   => Some(HashVersion::TeaUnsigned),
+            (3, _)      => Some(HashVersion::LegacyUnsigned),
+            (4, _)      => Some(HashVersion::HalfMd4Unsigned),
+            (5, _)      => Some(HashVersion::TeaUnsigned),
+            _           => None,
+        }
+    }
+
+    fn is_signed(&self) -> bool {
+        matches!(self, HashVersion::Legacy | HashVersion::HalfMd4 | HashVersion::Tea)
+    }
+}
+
+
+/// The deepest tree has a root, two levels of dx_node blocks (with the largedir feature) and the leaves
+pub const MAX_INDIRECT_LEVELS: u8 = 2;
+
+/// Hashes are 31 bits with the lowest bit cleared. A leaf's entry with the lowest bit set means the hash continues from the previous
+/// leaf, which happens when names with the same hash didn't fit in one block.
+const HASH_CONTINUED: u32 = 1;
+
+/// The value reserved to mark the end of a directory when it's read by hash, which no name may hash to
+const HTREE_EOF: u32 = 0x7FFFFFFF;
+
+/// Initial state of the half MD4 and TEA hashes when the superblock has no seed
+const DEFAULT_SEED: [u32; 4] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476];
+
+
+
+/// Parses the htree root in the first block of a directory
+pub fn parse_root(block: &[u8]) -> Result<DxRoot, FsError> {
+    // "." takes 12 bytes, ".." the rest of the block, and the root info sits inside ".." after its name
+    let info_offset = 24;
+    if block.len() < info_offset + size_of::<DxRootInfo>() {
+        return Err(FsError::Corrupt("htree root is truncated"));
+    }
+    let info: DxRootInfo = block::struct_from_bytes(&block[info_offset..]);
+
+    if info.reserved_zero != 0 || (info.info_length as usize) < size_of::<DxRootInfo>() {
+        return Err(FsError::Corrupt("invalid htree root"));
+    }
+    if info.indirect_levels > MAX_INDIRECT_LEVELS {
+        return Err(FsError::Corrupt("htree is too deep"));
+    }
+
+    Ok(DxRoot {
+        hash_version:       info.hash_version,
+        indirect_levels:    info.indirect_levels,
+        entries:            parse_entries(block, info_offset + info.info_length as usize)?,
+    })
+}
+
+
+/// Parses a dx_node block, whose index entries follow an empty directory entry covering the whole block
+pub fn parse_node(block: &[u8]) -> Result<Vec<DxEntry>, FsError> {
+    parse_entries(block, 8)
+}
+
+
+/// Parses the index entries starting at *offset* in *block*, the first of which has the count and limit instead of a hash
+fn parse_entries(block: &[u8], offset: usize) -> Result<Vec<DxEntry>, FsError> {
+    if offset + size_of::<DxEntryRaw>() > block.len() {
+        return Err(FsError::Corrupt("htree node is truncated"));
+    }
+
+    let count_limit: DxCountLimit = block::struct_from_bytes(&block[offset..]);
+    let count = u16::from_le(count_limit.count) as usize;
+    let limit = u16::from_le(count_limit.limit) as usize;
+
+    if count == 0 || count > limit || offset + limit * size_of::<DxEntryRaw>() > block.len() {
+        return Err(FsError::Corrupt("invalid htree node count"));
+    }
+
+    Ok(block[offset..offset + count * size_of::<DxEntryRaw>()].chunks_exact(size_of::<DxEntryRaw>()).enumerate().map(|(i, bytes)| {
+        let raw: DxEntryRaw = block::struct_from_bytes(bytes);
+
+        DxEntry {
+            // The first entry covers everything below the second one
+            hash:   if i == 0 { 0 } else { u32::from_le(raw.hash) },
+            // The top 4 bits are reserved
+            block:  u32::from_le(raw.block) & 0x0FFFFFFF,
+        }
+    }).collect())
+}
+
+
+/// Finds the entry whose range holds *hash*: the last one whose hash isn't above it
+pub fn find_entry(entries: &[DxEntry], hash: u32) -> usize {
+    entries.partition_point(|entry| entry.hash <= hash).max(1) - 1
+}
+
+
+/// Whether the entry following a leaf continues the run of names with *hash*, so a name that wasn't found could be in its leaf
+pub fn continues_hash(next: &DxEntry, hash: u32) -> bool {
+    next.hash & !HASH_CONTINUED == hash
+}
+
+
+/// Hashes a file name the way the htree was built. *seed* is the superblock's hash_seed, the default one is used if it's all zeros.
+pub fn name_hash(name: &[u8], version: HashVersion, seed: &[u32; 4]) -> u32 {
+    let mut state = if seed.iter().any(|word| *word != 0) { *seed } else { DEFAULT_SEED };
+    let signed = version.is_signed();
+
+    let hash = match version {
+        HashVersion::Legacy | HashVersion::LegacyUnsigned => legacy_hash(name, signed),
+
+        HashVersion::HalfMd4 | HashVersion::HalfMd4Unsigned => {
+            let mut input = [0u32; 8];
+            for i in (0..name.len()).step_by(32) {
+                str_to_hash_buf(&name[i..], signed, &mut input);
+                half_md4_transform(&mut state, &input);
+            }
+            state[1]
+        }
+
+        HashVersion::Tea | HashVersion::TeaUnsigned => {
+            let mut input = [0u32; 4];
+            for i in (0..name.len()).step_by(16) {
+                str_to_hash_buf(&name[i..], signed, &mut input);
+                tea_transform(&mut state, &input);
+            }
+            state[0]
+        }
+    };
+
+    let hash = hash & !HASH_CONTINUED;
+    if hash == HTREE_EOF << 1 {
+        return (HTREE_EOF - 1) << 1;
+    }
+    else {
+        return hash;
+    }
+}
+
+
+/// The original hash, from before the htree format was finalized
+fn legacy_hash(name: &[u8], signed: bool) -> u32 {
+    let mut hash0: u32 = 0x12A3FE2D;
+    let mut hash1: u32 = 0x37ABE8F9;
+
+    for byte in name {
+        let c = if signed { *byte as i8 as i32 } else { *byte as i32 };
+
+        let mut hash = hash1.wrapping_add(hash0 ^ c.wrapping_mul(7152373) as u32);
+        if hash & 0x80000000 != 0 {
+            hash = hash.wrapping_sub(0x7FFFFFFF);
+        }
+
+        hash1 = hash0;
+        hash0 = hash;
+    }
+
+    hash0 << 1
+}
+
+
+/// Packs up to 4 bytes of *msg* per word of *buf*, big endian, padding with a pattern made from the length left
+fn str_to_hash_buf(msg: &[u8], signed: bool, buf: &mut [u32]) {
+    let mut pad = msg.len() as u32 | (msg.len() as u32) << 8;
+    pad |= pad << 16;
+
+    let mut val = pad;
+    let mut word = 0;
+
+    for (i, byte) in msg.iter().take(buf.len() * 4).enumerate() {
+        let c = if signed { *byte as i8 as i32 as u32 } else { *byte as u32 };
+        val = c.wrapping_add(val << 8);
+
+        if i % 4 == 3 {
+            buf[word] = val;
+            word += 1;
+            val = pad;
+        }
+    }
+
+    if word < buf.len() {
+        buf[word] = val;
+        word += 1;
+    }
+    buf[word..].fill(pad);
+}
+
+
+/// Three rounds of MD4 over 8 words, without the last of the 4 rounds
+fn half_md4_transform(state: &mut [u32; 4], input: &[u32; 8]) {
+    const K2: u32 = 0x5A827999;
+    const K3: u32 = 0x6ED9EBA1;
+
+    let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+    let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+
+    let [mut a, mut b, mut c, mut d] = *state;
+
+    // Each round updates one word from the other three, rotating which word that is
+    macro_rules! round {
+        ($func:expr, $a:ident, $b:ident, $c:ident, $d:ident, $x:expr, $s:expr) => {
+            $a = $a.wrapping_add($func($b, $c, $d)).wrapping_add($x).rotate_left($s);
+        };
+    }
+
+    round!(f, a, b, c, d, input[0], 3);
+    round!(f, d, a, b, c, input[1], 7);
+    round!(f, c, d, a, b, input[2], 11);
+    round!(f, b, c, d, a, input[3], 19);
+    round!(f, a, b, c, d, input[4], 3);
+    round!(f, d, a, b, c, input[5], 7);
+    round!(f, c, d, a, b, input[6], 11);
+    round!(f, b, c, d, a, input[7], 19);
+
+    round!(g, a, b, c, d, input[1].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[3].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[5].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[7].wrapping_add(K2), 13);
+    round!(g, a, b, c, d, input[0].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[2].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[4].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[6].wrapping_add(K2), 13);
+
+    round!(h, a, b, c, d, input[3].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[7].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[2].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[6].wrapping_add(K3), 15);
+    round!(h, a, b, c, d, input[1].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[5].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[0].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[4].wrapping_add(K3), 15);
+
+    state[0] = state[0].wrapping_add(a);
+    state[1] = state[1].wrapping_add(b);
+    state[2] = state[2].wrapping_add(c);
+    state[3] = state[3].wrapping_add(d);
+}
+
+
+/// 16 rounds of the Tiny Encryption Algorithm, with the 4 input words as the key
+fn tea_transform(state: &mut [u32; 4], input: &[u32; 4]) {
+    const DELTA: u32 = 0x9E3779B9;
+
+    let [a, b, c, d] = *input;
+    let mut b0 = state[0];
+    let mut b1 = state[1];
+    let mut sum: u32 = 0;
+
+    for _ in 0..16 {
+        sum = sum.wrapping_add(DELTA);
+        b0 = b0.wrapping_add((b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b));
+        b1 = b1.wrapping_add((b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d));
+    }
+
+    state[0] = state[0].wrapping_add(b0);
+    state[1] = state[1].wrapping_add(b1);
+}
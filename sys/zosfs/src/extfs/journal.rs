@@ -0,0 +1,363 @@
+/*  journal.rs - JBD2 journal replay for the EXT driver
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! ext3 and ext4 write metadata to the JBD2 journal before putting it in place, so a volume that wasn't cleanly unmounted can have
+//! committed changes that only the journal holds. The journal is a circular log of transactions: a descriptor block lists the volume
+//! blocks whose new contents follow it, revoke blocks cancel blocks logged by earlier transactions, and a commit block makes the
+//! transaction count. Replaying it gives the contents the kernel would write back when mounting the volume, which are kept in memory
+//! rather than written to the disk.
+
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use crate::block::{self, BlockDevice};
+use crate::checksum::crc32c_update;
+use crate::error::FsError;
+use core::mem::size_of;
+use super::{check_checksum, feature_names, ChecksumPolicy};
+
+
+/// Starts every journal metadata block. Everything in the journal is big endian.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct JournalHeader {
+    pub magic:              u32,
+    pub block_type:         u32,
+    pub sequence:           u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct JournalSuperblock {
+    pub header:             JournalHeader,
+    pub block_size:         u32,
+    pub max_len:            u32,
+    pub first:              u32,
+    pub sequence:           u32,
+    pub start:              u32,
+    pub errno:              u32,
+    pub feature_compat:     u32,
+    pub feature_incompat:   u32,
+    pub feature_ro_compat:  u32,
+    pub uuid:               [u8; 16],
+    pub nr_users:           u32,
+    pub dyn_super:          u32,
+    pub max_transaction:    u32,
+    pub max_trans_data:     u32,
+    pub checksum_type:      u8,
+    pub padding2:           [u8; 3],
+    pub num_fc_blocks:      u32,
+    pub head:               u32,
+    pub padding:            [u32; 40],
+    pub checksum:           u32,
+    pub users:              [u8; 768],
+}
+
+const _: () = assert!(size_of::<JournalSuperblock>() == 1024);
+
+
+/// Blocks replayed from the journal, which take the place of the ones on the disk
+#[derive(Default)]
+pub struct Overlay {
+    block_size: u64,
+    blocks:     BTreeMap<u64, Vec<u8>>,
+}
+
+impl Overlay {
+    /// Reads *buffer.len()* bytes starting at byte *offset* of *dev*, as they are once the journal is replayed
+    pub fn read_bytes<D: BlockDevice>(&self, dev: &D, offset: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        dev.read_bytes(offset, buffer)?;
+
+        if self.blocks.is_empty() || buffer.is_empty() {
+            return Ok(());
+        }
+
+        let end = offset + buffer.len() as u64;
+        for (block, data) in self.blocks.range(offset / self.block_size..=(end - 1) / self.block_size) {
+            let start = block * self.block_size;
+            let from = start.max(offset);
+            let to = (start + self.block_size).min(end);
+
+            buffer[(from - offset) as usize..(to - offset) as usize].copy_from_slice(&data[(from - start) as usize..(to - start) as usize]);
+        }
+
+        Ok(())
+    }
+
+    /// Number of volume blocks the journal replaced
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+}
+
+
+/// A volume block logged by a transaction
+struct LoggedBlock {
+    /// Where the block goes on the volume
+    target:         u64,
+    /// Where its contents are in the journal
+    journal_block:  u64,
+    /// The block started with the journal magic, which was zeroed so it can't be mistaken for a journal block
+    escaped:        bool,
+    checksum:       u32,
+}
+
+/// A committed transaction
+struct Transaction {
+    sequence:   u32,
+    blocks:     Vec<LoggedBlock>,
+}
+
+
+const JOURNAL_MAGIC: u32 = 0xC03B3998;
+
+const BLOCK_TYPE_DESCRIPTOR: u32 = 1;
+const BLOCK_TYPE_COMMIT: u32 = 2;
+const BLOCK_TYPE_SUPERBLOCK_V1: u32 = 3;
+const BLOCK_TYPE_SUPERBLOCK_V2: u32 = 4;
+const BLOCK_TYPE_REVOKE: u32 = 5;
+
+const INCOMPAT_REVOKE: u32 = 0x1;
+const INCOMPAT_64BIT: u32 = 0x2;
+const INCOMPAT_ASYNC_COMMIT: u32 = 0x4;
+const INCOMPAT_CSUM_V2: u32 = 0x8;
+const INCOMPAT_CSUM_V3: u32 = 0x10;
+
+/// Names of the journal's incompatible features, spelled like dumpe2fs shows them
+const INCOMPAT_FEATURES: [(u32, &str); 6] = [
+    (0x1, "journal_incompat_revoke"), (0x2, "journal_64bit"), (0x4, "journal_async_commit"), (0x8, "journal_checksum_v2"),
+    (0x10, "journal_checksum_v3"), (0x20, "journal_fast_commit"),
+];
+
+/// Journal features replay understands. Fast commits log changes as operations on inodes rather than as blocks, and are left out.
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_REVOKE | INCOMPAT_64BIT | INCOMPAT_ASYNC_COMMIT | INCOMPAT_CSUM_V2 | INCOMPAT_CSUM_V3;
+
+/// Descriptor tag flags
+const TAG_FLAG_ESCAPE: u32 = 0x1;
+const TAG_FLAG_SAME_UUID: u32 = 0x2;
+const TAG_FLAG_LAST_TAG: u32 = 0x8;
+
+/// Tags not flagged SAME_UUID are followed by a UUID
+const UUID_SIZE: usize = 16;
+
+/// The only checksum type the v2 and v3 checksums use
+const CHECKSUM_TYPE_CRC32C: u8 = 4;
+const SUPERBLOCK_CHECKSUM_OFFSET: usize = 0xFC;
+/// Descriptor and revoke blocks end with their checksum
+const BLOCK_TAIL_SIZE: usize = 4;
+/// The commit block's checksum is the first of its checksum fields
+const COMMIT_CHECKSUM_OFFSET: usize = 0x10;
+
+/// Bytes used by a revoke block, counted from its start, and where its records begin
+const REVOKE_COUNT_OFFSET: usize = 0xC;
+const REVOKE_RECORDS_OFFSET: usize = 0x10;
+
+
+
+/// Replays the journal, reading its blocks with *read_block*, which takes a block number within the journal. Returns the volume blocks
+/// committed transactions logged, with their latest contents. Checksum mismatches are handled according to *policy*.
+pub fn replay<F>(block_size: u64, policy: ChecksumPolicy, read_block: F) -> Result<Overlay, FsError>
+where
+    F: Fn(u64, &mut [u8]) -> Result<(), FsError>
+{
+    let mut buffer = vec![0u8; block_size as usize];
+    read_block(0, &mut buffer)?;
+    let sb: JournalSuperblock = block::struct_from_bytes(&buffer);
+
+    let block_type = u32::from_be(sb.header.block_type);
+    if u32::from_be(sb.header.magic) != JOURNAL_MAGIC || (block_type != BLOCK_TYPE_SUPERBLOCK_V1 && block_type != BLOCK_TYPE_SUPERBLOCK_V2) {
+        return Err(FsError::Corrupt("invalid journal superblock"));
+    }
+    if u32::from_be(sb.block_size) as u64 != block_size {
+        return Err(FsError::Corrupt("journal block size differs from the filesystem's"));
+    }
+
+    // Version 1 journals have no features
+    let incompat = if block_type == BLOCK_TYPE_SUPERBLOCK_V2 { u32::from_be(sb.feature_incompat) } else { 0 };
+    let unsupported = feature_names(incompat & !SUPPORTED_INCOMPAT, &INCOMPAT_FEATURES, "journal_incompat");
+    if !unsupported.is_empty() {
+        return Err(FsError::UnsupportedFeatures(unsupported));
+    }
+
+    let checksums = incompat & (INCOMPAT_CSUM_V2 | INCOMPAT_CSUM_V3) != 0;
+    let csum_seed = crc32c_update(!0, &sb.uuid);
+    if checksums {
+        if sb.checksum_type != CHECKSUM_TYPE_CRC32C {
+            return Err(FsError::Unsupported("journal checksum types other than crc32c"));
+        }
+
+        let computed = crc32c_update(crc32c_update(!0, &buffer[..SUPERBLOCK_CHECKSUM_OFFSET]), &[0; 4]);
+        let computed = crc32c_update(computed, &buffer[SUPERBLOCK_CHECKSUM_OFFSET + 4..size_of::<JournalSuperblock>()]);
+        check_checksum(policy, computed == u32::from_be(sb.checksum), "ext journal superblock checksum mismatch")?;
+    }
+
+    // A start of 0 means the journal is empty
+    let (first, max_len, start) = (u32::from_be(sb.first) as u64, u32::from_be(sb.max_len) as u64, u32::from_be(sb.start) as u64);
+    if start == 0 {
+        return Ok(Overlay::default());
+    }
+    if first == 0 || first >= max_len || start < first || start >= max_len {
+        return Err(FsError::Corrupt("invalid journal superblock"));
+    }
+
+    let tag_size = if incompat & INCOMPAT_CSUM_V3 != 0 {
+        16
+    }
+    else {
+        8 + if incompat & INCOMPAT_CSUM_V2 != 0 { 2 } else { 0 } + if incompat & INCOMPAT_64BIT != 0 { 4 } else { 0 }
+    };
+    let tail_size = if checksums { BLOCK_TAIL_SIZE } else { 0 };
+    let next = |block: u64| if block + 1 == max_len { first } else { block + 1 };
+
+    // Scan the log from its start, as long as blocks carry the sequence number of the transaction that's expected next. Only
+    // transactions whose commit block is found (and intact) are replayed, so revokes are kept with their transaction until then.
+    let mut transactions: Vec<Transaction> = Vec::new();
+    let mut revoked: BTreeMap<u64, u32> = BTreeMap::new();
+    let mut current = Transaction { sequence: u32::from_be(sb.sequence), blocks: Vec::new() };
+    let mut current_revokes: Vec<u64> = Vec::new();
+    let mut block = start;
+    let mut scanned = 0;
+
+    loop {
+        // A log that wraps back onto itself without ending is broken
+        scanned += 1;
+        if scanned > max_len {
+            return Err(FsError::Corrupt("journal log has no end"));
+        }
+
+        read_block(block, &mut buffer)?;
+        let header: JournalHeader = block::struct_from_bytes(&buffer);
+        if u32::from_be(header.magic) != JOURNAL_MAGIC || u32::from_be(header.sequence) != current.sequence {
+            break;
+        }
+
+        let block_type = u32::from_be(header.block_type);
+        if block_type == BLOCK_TYPE_DESCRIPTOR {
+            // A descriptor failing its checksum is left over from before the journal last wrapped
+            if checksums && !tail_checksum_matches(&buffer, csum_seed) {
+                break;
+            }
+
+            let mut pos = size_of::<JournalHeader>();
+            while pos + tag_size <= buffer.len() - tail_size {
+                let tag = &buffer[pos..pos + tag_size];
+                let be32 = |offset: usize| u32::from_be_bytes(tag[offset..offset + 4].try_into().unwrap());
+
+                let (flags, checksum) = if incompat & INCOMPAT_CSUM_V3 != 0 {
+                    (be32(4), be32(12))
+                }
+                else {
+                    (u16::from_be_bytes([tag[6], tag[7]]) as u32, u16::from_be_bytes([tag[4], tag[5]]) as u32)
+                };
+                let high = if incompat & INCOMPAT_64BIT != 0 { be32(8) as u64 } else { 0 };
+
+                block = next(block);
+                current.blocks.push(LoggedBlock {
+                    target:         high << 32 | be32(0) as u64,
+                    journal_block:  block,
+                    escaped:        flags & TAG_FLAG_ESCAPE != 0,
+                    checksum,
+                });
+
+                pos += tag_size;
+                if flags & TAG_FLAG_SAME_UUID == 0 {
+                    pos += UUID_SIZE;
+                }
+                if flags & TAG_FLAG_LAST_TAG != 0 {
+                    break;
+                }
+            }
+        }
+        else if block_type == BLOCK_TYPE_COMMIT {
+            // A commit block failing its checksum didn't make it to the disk whole, so neither did its transaction
+            if checksums {
+                let computed = crc32c_update(crc32c_update(csum_seed, &buffer[..COMMIT_CHECKSUM_OFFSET]), &[0; 4]);
+                let computed = crc32c_update(computed, &buffer[COMMIT_CHECKSUM_OFFSET + 4..]);
+                if computed != u32::from_be_bytes(buffer[COMMIT_CHECKSUM_OFFSET..COMMIT_CHECKSUM_OFFSET + 4].try_into().unwrap()) {
+                    break;
+                }
+            }
+
+            let sequence = current.sequence;
+            for target in current_revokes.drain(..) {
+                revoked.insert(target, sequence);
+            }
+            transactions.push(core::mem::replace(&mut current, Transaction { sequence: sequence.wrapping_add(1), blocks: Vec::new() }));
+        }
+        else if block_type == BLOCK_TYPE_REVOKE {
+            if checksums {
+                check_checksum(policy, tail_checksum_matches(&buffer, csum_seed), "ext journal revoke block checksum mismatch")?;
+            }
+
+            let count = u32::from_be_bytes(buffer[REVOKE_COUNT_OFFSET..REVOKE_COUNT_OFFSET + 4].try_into().unwrap()) as usize;
+            if count < REVOKE_RECORDS_OFFSET || count > buffer.len() - tail_size {
+                return Err(FsError::Corrupt("invalid journal revoke block"));
+            }
+
+            let record_size = if incompat & INCOMPAT_64BIT != 0 { 8 } else { 4 };
+            for record in buffer[REVOKE_RECORDS_OFFSET..count].chunks_exact(record_size) {
+                current_revokes.push(if record_size == 8 {
+                    u64::from_be_bytes(record.try_into().unwrap())
+                }
+                else {
+                    u32::from_be_bytes(record.try_into().unwrap()) as u64
+                });
+            }
+        }
+        else {
+            break;
+        }
+
+        block = next(block);
+    }
+
+    // Later transactions overwrite what earlier ones logged. A revoke cancels the block in its own transaction and earlier ones.
+    let mut overlay = Overlay { block_size, blocks: BTreeMap::new() };
+    for transaction in transactions {
+        for logged in transaction.blocks {
+            if revoked.get(&logged.target).is_some_and(|revoked_in| revoked_in.wrapping_sub(transaction.sequence) as i32 >= 0) {
+                continue;
+            }
+
+            let mut data = vec![0u8; block_size as usize];
+            read_block(logged.journal_block, &mut data)?;
+
+            if checksums {
+                let computed = crc32c_update(crc32c_update(csum_seed, &transaction.sequence.to_be_bytes()), &data);
+                let computed = if incompat & INCOMPAT_CSUM_V3 != 0 { computed } else { computed & 0xFFFF };
+                check_checksum(policy, computed == logged.checksum, "ext journal block checksum mismatch")?;
+            }
+
+            if logged.escaped {
+                data[..4].copy_from_slice(&JOURNAL_MAGIC.to_be_bytes());
+            }
+
+            overlay.blocks.insert(logged.target, data);
+        }
+    }
+
+    Ok(overlay)
+}
+
+
+/// Checks the checksum ending a descriptor or revoke block, which covers the whole block with it zeroed
+fn tail_checksum_matches(block: &[u8], csum_seed: u32) -> bool {
+    let tail = block.len() - BLOCK_TAIL_SIZE;
+    let computed = crc32c_update(crc32c_update(csum_seed, &block[..tail]), &[0; BLOCK_TAIL_SIZE]);
+
+    computed == u32::from_be_bytes(block[tail..].try_into().unwrap())
+}
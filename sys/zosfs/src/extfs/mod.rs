@@ -0,0 +1,1489 @@
+/*  mod.rs - EXT filesystem driver
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+#![allow(dead_code)]
+
+use alloc::{boxed::Box, format, string::{String, ToString}, vec, vec::Vec};
+use crate::block::{self, BlockDevice};
+use crate::checksum::crc32c_update;
+use crate::error::FsError;
+use crate::fs::{CaseRule, DirEntry, Extent, FileType, Filesystem, Metadata};
+use core::mem::size_of;
+use core::ops::Range;
+use htree::{DxEntry, HashVersion};
+use journal::Overlay;
+
+mod htree;
+mod journal;
+mod xattr;
+
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Ext4Superblock {
+    pub inodes_count:               u32,
+    pub blocks_count_lo:            u32,
+    pub r_blocks_count_lo:          u32,
+    pub free_blocks_count_lo:       u32,
+    pub free_inodes_count:          u32,
+    pub first_data_block:           u32,
+    pub log_block_size:             u32,
+    pub log_cluster_size:           u32,
+    pub blocks_per_group:           u32,
+    pub clusters_per_group:         u32,
+    pub inodes_per_group:           u32,
+    pub mtime:                      u32,
+    pub wtime:                      u32,
+    pub mnt_count:                  u16,
+    pub max_mnt_count:              u16,
+    pub magic:                      u16,
+    pub state:                      u16,
+    pub errors:                     u16,
+    pub minor_rev_level:            u16,
+    pub lastcheck:                  u32,
+    pub checkinterval:              u32,
+    pub creator_os:                 u32,
+    pub rev_level:                  u32,
+    pub def_resuid:                 u16,
+    pub def_resgid:                 u16,
+    pub first_ino:                  u32,
+    pub inode_size:                 u16,
+    pub block_group_nr:             u16,
+    pub feature_compat:             u32,
+    pub feature_incompat:           u32,
+    pub feature_ro_compat:          u32,
+    pub uuid:                       [u8; 16],
+    pub volume_name:                [u8; 16],
+    pub last_mounted:               [u8; 64],
+    pub algorithm_usage_bitmap:     u32,
+    pub prealloc_blocks:            u8,
+    pub prealloc_dir_blocks:        u8,
+    pub reserved_gdt_blocks:        u16,
+    pub journal_uuid:               [u8; 16],
+    pub journal_inum:               u32,
+    pub journal_dev:                u32,
+    pub last_orphan:                u32,
+    pub hash_seed:                  [u32; 4],
+    pub def_hash_version:           u8,
+    pub jnl_backup_type:            u8,
+    pub desc_size:                  u16,
+    pub default_mount_opts:         u32,
+    pub first_meta_bg:              u32,
+    pub mkfs_time:                  u32,
+    pub jnl_blocks:                 [u32; 17],
+    pub blocks_count_hi:            u32,
+    pub r_blocks_count_hi:          u32,
+    pub free_blocks_count:          u32,
+    pub min_extra_isize:            u16,
+    pub want_extra_isize:           u16,
+    pub flags:                      u32,
+    pub raid_stride:                u16,
+    pub mmp_interval:               u16,
+    pub mmp_block:                  u64,
+    pub raid_stripe_width:          u32,
+    pub log_groups_per_flex:        u8,
+    pub checksum_type:              u8,
+    pub reserved_pad:               u16,
+    pub kbytes_written:             u64,
+    pub snapshot_inum:              u32,
+    pub snapshot_id:                u32,
+    pub snapshot_r_blocks_count:    u64,
+    pub snapshot_list:              u32,
+    pub error_count:                u32,
+    pub first_error_time:           u32,
+    pub first_error_ino:            u32,
+    pub first_error_block:          u64,
+    pub first_error_func:           [u8; 32],
+    pub first_error_line:           u32,
+    pub last_error_time:            u32,
+    pub last_error_ino:             u32,
+    pub last_error_line:            u32,
+    pub last_error_block:           u64,
+    pub last_error_func:            [u8; 32],
+    pub mount_opts:                 [u8; 64],
+    pub usr_quota_inum:             u32,
+    pub grp_quota_inum:             u32,
+    pub overhead_blocks:            u32,
+    pub backup_bgs:                 [u32; 2],
+    pub encrypt_algos:              [u8; 4],
+    pub encrypt_pw_salt:            [u8; 16],
+    pub lpf_ino:                    u32,
+    pub prj_quota_inum:             u32,
+    pub checksum_seed:              u32,
+    pub wtime_hi:                   u8,
+    pub mtime_hi:                   u8,
+    pub mkfs_time_hi:               u8,
+    pub lastcheck_hi:               u8,
+    pub first_error_time_hi:        u8,
+    pub last_error_time_hi:         u8,
+    pub pad:                        [u8; 2],
+    reserved:                       [u32; 96],
+    pub checksum:                   u32,
+}
+
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Ext4BlockGroupDescriptor {
+    pub block_bitmap_lo:            u32,
+    pub inode_bitmap_lo:            u32,
+    pub inode_table_lo:             u32,
+    pub free_blocks_count_lo:       u16,
+    pub free_inodes_count_lo:       u16,
+    pub used_dirs_count_lo:         u16,
+    pub flags:                      u16,
+    pub exlcude_bitmap_lo:          u32,
+    pub block_bitmap_csum_lo:       u16,
+    pub inode_bitmap_csum_lo:       u16,
+    pub itable_unused_lo:           u16,
+    pub checksum:                   u16,
+    pub block_bitmap_hi:            u32,
+    pub inode_bitmap_hi:            u32,
+    pub inode_table_hi:             u32,
+    pub free_blocks_count_hi:       u16,
+    pub free_inodes_count_hi:       u16,
+    pub used_dirs_count_hi:         u16,
+    pub itable_unused_hi:           u16,
+    pub exclude_bitmap_hi:          u32,
+    pub block_bitmap_csum_hi:       u16,
+    pub inode_bitmap_csum_hi:       u16,
+    reserved:                       u32,
+}
+
+
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Ext4INode {
+    pub mode:                       u16,
+    pub uid:                        u16,
+    pub size_lo:                    u32,
+    pub atime:                      u32,
+    pub ctime:                      u32,
+    pub mtime:                      u32,
+    pub dtime:                      u32,
+    pub gid:                        u16,
+    pub links_count:                u16,
+    pub blocks_lo:                  u32,
+    pub flags:                      u32,
+    pub osd1:                       [u8; 4],
+    pub block:                      [u8; 60],
+    pub generation:                 u32,
+    pub file_acl_lo:                u32,
+    pub size_high:                  u32,
+    obso_faddr:                     u32,
+    pub osd2:                       [u8; 12],
+    pub extra_isize:                u16,
+    pub checksum_hi:                u16,
+    pub ctime_extra:                u32,
+    pub mtime_extra:                u32,
+    pub atime_extra:                u32,
+    pub crtime:                     u32,
+    pub crtime_extra:               u32,
+    pub version_hi:                 u32,
+    pub projid:                     u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct ExtentHeader {
+    pub magic:      u16,
+    pub entries:    u16,
+    pub max:        u16,
+    pub depth:      u16,
+    pub generation: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct ExtentIndex {
+    pub block:      u32,
+    pub leaf_lo:    u32,
+    pub leaf_hi:    u16,
+    unused:         u16,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct ExtentLeaf {
+    pub block:      u32,
+    pub len:        u16,
+    pub start_hi:   u16,
+    pub start_lo:   u32,
+}
+
+
+
+/// An inode read from the disk, with the number it was read from
+struct Inode {
+    number:     u32,
+    raw:        Ext4INode,
+    /// Seeds the checksums of the inode and the blocks it owns, when the filesystem has them
+    csum_seed:  u32,
+    /// The rest of the on-disk inode after its fields, where extended attributes can be stored
+    xattr_area: Vec<u8>,
+}
+
+
+/// A leaf extent with its flag decoded
+#[derive(Clone, Copy)]
+struct LeafExtent {
+    logical:        u64,
+    physical:       u64,
+    length:         u64,
+    uninitialized:  bool,
+}
+
+
+
+#[repr(C, packed)]
+struct Ext4DirectoryEntry {
+    pub inode:                      u32,
+    pub rec_len:                    u16,
+    pub name_len:                   u8,
+    pub file_type:                  u8,
+    pub name:                       [u8],
+}
+
+impl Ext4Superblock{
+    pub fn new_zeroed() -> Self {
+        unsafe { core::mem::zeroed::<Self>() }
+    }
+}
+
+impl Ext4BlockGroupDescriptor {
+    pub fn new_zeroed() -> Self {
+        unsafe { core::mem::zeroed::<Self>() }
+    }
+}
+
+
+impl Ext4INode {
+    pub fn new_zeroed() -> Self {
+        unsafe { core::mem::zeroed::<Self>() }
+    }
+
+    pub const fn get_block_group(sb: &Ext4Superblock, inode_num: u32) -> u32 {
+        (inode_num - 1) / sb.inodes_per_group
+    }
+
+    pub const fn get_index(sb: &Ext4Superblock, inode_num: u32) -> u32 {
+        (inode_num - 1) % sb.inodes_per_group
+    }
+
+    pub const fn get_offset(sb: &Ext4Superblock, inode_num: u32) -> u32 {
+        let index = Self::get_index(sb, inode_num);
+
+        index * sb.inode_size as u32
+    }
+}
+
+
+
+
+const ROOT_INODE: u32 = 2;
+
+const S_IFMT: u16 = 0xF000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFREG: u16 = 0x8000;
+const S_IFLNK: u16 = 0xA000;
+
+/// The inode's block array holds an extent tree instead of a block map
+const EXT4_EXTENTS_FL: u32 = 0x80000;
+
+/// The directory has an htree index
+const EXT4_INDEX_FL: u32 = 0x1000;
+/// The inode holds the value of an extended attribute too large for the inode or an attribute block
+const EXT4_EA_INODE_FL: u32 = 0x200000;
+/// The contents are in the block array and the system.data attribute instead of blocks
+const EXT4_INLINE_DATA_FL: u32 = 0x10000000;
+
+/// Inline data kept in the block array. Inline directories start it with their parent's inode number, in place of a ".." entry.
+const INLINE_DATA_SIZE: usize = 60;
+const INLINE_DIR_PARENT_SIZE: usize = 4;
+
+const EXTENT_MAGIC: u16 = 0xF30A;
+/// Block map entries pointing straight at data, before the single, double and triple indirect blocks
+const DIRECT_BLOCKS: usize = 12;
+/// Deepest extent tree the kernel builds
+const EXTENT_MAX_DEPTH: u16 = 5;
+/// Longest initialized extent. Longer lengths are uninitialized extents of the length minus this.
+const EXTENT_INIT_MAX_LEN: u16 = 32768;
+
+/// Largest directory read into memory at once, well past what millions of entries take
+const MAX_DIR_SIZE: u64 = 256 << 20;
+/// Largest extended attribute value the kernel accepts, and so the most an EA inode can hold
+const XATTR_SIZE_MAX: u64 = 65536;
+/// Every logical block, for walks that map a whole inode
+const ALL_BLOCKS: Range<u64> = 0..u64::MAX;
+
+const COMPAT_HAS_JOURNAL: u32 = 0x4;
+const COMPAT_DIR_INDEX: u32 = 0x20;
+const COMPAT_SPARSE_SUPER2: u32 = 0x200;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+const RO_COMPAT_HUGE_FILE: u32 = 0x8;
+const RO_COMPAT_GDT_CSUM: u32 = 0x10;
+const RO_COMPAT_DIR_NLINK: u32 = 0x20;
+const RO_COMPAT_EXTRA_ISIZE: u32 = 0x40;
+const RO_COMPAT_QUOTA: u32 = 0x100;
+const RO_COMPAT_BIGALLOC: u32 = 0x200;
+const RO_COMPAT_METADATA_CSUM: u32 = 0x400;
+const RO_COMPAT_READONLY: u32 = 0x1000;
+const RO_COMPAT_PROJECT: u32 = 0x2000;
+const RO_COMPAT_SHARED_BLOCKS: u32 = 0x4000;
+const RO_COMPAT_VERITY: u32 = 0x8000;
+const RO_COMPAT_ORPHAN_PRESENT: u32 = 0x10000;
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_RECOVER: u32 = 0x4;
+const INCOMPAT_META_BG: u32 = 0x10;
+const INCOMPAT_EXTENTS: u32 = 0x40;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INCOMPAT_MMP: u32 = 0x100;
+const INCOMPAT_FLEX_BG: u32 = 0x200;
+const INCOMPAT_EA_INODE: u32 = 0x400;
+const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const INCOMPAT_LARGEDIR: u32 = 0x4000;
+const INCOMPAT_INLINE_DATA: u32 = 0x8000;
+
+/// Superblock flag saying htree hashes treat name bytes as unsigned
+const FLAGS_UNSIGNED_HASH: u32 = 0x2;
+
+/// Names of the feature flags, spelled like mke2fs and tune2fs take them
+const COMPAT_FEATURES: [(u32, &str); 12] = [
+    (0x1, "dir_prealloc"), (0x2, "imagic_inodes"), (0x4, "has_journal"), (0x8, "ext_attr"), (0x10, "resize_inode"), (0x20, "dir_index"),
+    (0x40, "lazy_bg"), (0x100, "snapshot_bitmap"), (0x200, "sparse_super2"), (0x400, "fast_commit"), (0x800, "stable_inodes"),
+    (0x1000, "orphan_file"),
+];
+const RO_COMPAT_FEATURES: [(u32, &str); 16] = [
+    (0x1, "sparse_super"), (0x2, "large_file"), (0x8, "huge_file"), (0x10, "uninit_bg"), (0x20, "dir_nlink"), (0x40, "extra_isize"),
+    (0x80, "snapshot"), (0x100, "quota"), (0x200, "bigalloc"), (0x400, "metadata_csum"), (0x800, "replica"), (0x1000, "read-only"),
+    (0x2000, "project"), (0x4000, "shared_blocks"), (0x8000, "verity"), (0x10000, "orphan_present"),
+];
+const INCOMPAT_FEATURES: [(u32, &str); 16] = [
+    (0x1, "compression"), (0x2, "filetype"), (0x4, "needs_recovery"), (0x8, "journal_dev"), (0x10, "meta_bg"), (0x40, "extent"),
+    (0x80, "64bit"), (0x100, "mmp"), (0x200, "flex_bg"), (0x400, "ea_inode"), (0x1000, "dirdata"), (0x2000, "metadata_csum_seed"),
+    (0x4000, "large_dir"), (0x8000, "inline_data"), (0x10000, "encrypt"), (0x20000, "casefold"),
+];
+
+/// Incompatible features the driver can read. Anything else, like encryption or casefolded directories, changes how files have to be
+/// found or read in ways it doesn't know about.
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE | INCOMPAT_RECOVER | INCOMPAT_META_BG | INCOMPAT_EXTENTS | INCOMPAT_64BIT | INCOMPAT_MMP
+    | INCOMPAT_FLEX_BG | INCOMPAT_EA_INODE | INCOMPAT_CSUM_SEED | INCOMPAT_LARGEDIR | INCOMPAT_INLINE_DATA;
+/// Read-only compatible features the driver knows about. They only matter to writers, but unknown ones, snapshots and replicas could
+/// still mean the volume isn't laid out the way it's read.
+const SUPPORTED_RO_COMPAT: u32 = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE | RO_COMPAT_HUGE_FILE | RO_COMPAT_GDT_CSUM | RO_COMPAT_DIR_NLINK
+    | RO_COMPAT_EXTRA_ISIZE | RO_COMPAT_QUOTA | RO_COMPAT_BIGALLOC | RO_COMPAT_METADATA_CSUM | RO_COMPAT_READONLY | RO_COMPAT_PROJECT
+    | RO_COMPAT_SHARED_BLOCKS | RO_COMPAT_VERITY | RO_COMPAT_ORPHAN_PRESENT;
+
+/// Group descriptor size without the 64bit feature
+const DESC_SIZE_32: u64 = 32;
+
+/// The superblock always starts 1024 bytes into the volume
+const SUPERBLOCK_OFFSET: u64 = 1024;
+
+/// The only checksum type metadata_csum defines
+const CHECKSUM_TYPE_CRC32C: u8 = 1;
+/// Offsets of checksum fields, which are zeroed or left out when computing the checksums that cover them
+const SUPERBLOCK_CHECKSUM_OFFSET: usize = 0x3FC;
+const GROUP_DESC_CHECKSUM_OFFSET: usize = 0x1E;
+const INODE_CHECKSUM_LO_OFFSET: usize = 0x7C;
+const INODE_CHECKSUM_HI_OFFSET: usize = 0x82;
+/// Inodes start with the fields of the original 128 byte inode, anything after that is counted by extra_isize
+const GOOD_OLD_INODE_SIZE: usize = 128;
+
+/// Directory blocks end with an empty entry of this size and file type holding the block's checksum
+const DIR_TAIL_SIZE: usize = 12;
+const DIR_TAIL_FILE_TYPE: u8 = 0xDE;
+
+const _: () = assert!(size_of::<Ext4Superblock>() == 1024);
+const _: () = assert!(size_of::<Ext4INode>() == 160);
+
+
+/// What the driver does when metadata doesn't match its checksum
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ChecksumPolicy {
+    /// Log the mismatch and use the metadata anyway, for getting what's possible off a damaged volume
+    Warn,
+    /// Fail with FsError::Corrupt
+    #[default]
+    Error,
+}
+
+
+
+/// Scans the device to determine if it contains an Ext filesystem. Returns true if it is.
+pub fn detect<D: BlockDevice>(dev: &D) -> Result<bool, FsError> {
+    let sb: Ext4Superblock = block::read_struct(dev, SUPERBLOCK_OFFSET)?;
+
+    Ok(u16::from_le(sb.magic) == 0xEF53)
+}
+
+
+
+/// A mounted EXT filesystem. Nodes are inode numbers.
+pub struct ExtFs<D: BlockDevice> {
+    dev:            D,
+    sb:             Box<Ext4Superblock>,
+    /// Size of a block group descriptor in bytes
+    desc_size:      u64,
+    group_count:    u64,
+    /// Seed of all metadata checksums, None without metadata_csum
+    csum_seed:      Option<u32>,
+    /// Blocks replayed from the journal. Every read goes through it.
+    replayed:       Overlay,
+    checksum_policy: ChecksumPolicy,
+}
+
+impl<D: BlockDevice> ExtFs<D> {
+    /// Mounts the filesystem on *dev*. If it wasn't cleanly unmounted, the whole journal is scanned and its committed
+    /// transactions replayed into memory here, so that cost is paid once per mount rather than on every read. Keep the mount
+    /// around instead of mounting again for each operation.
+    pub fn new(dev: D) -> Result<Self, FsError> {
+        Self::with_checksum_policy(dev, ChecksumPolicy::default())
+    }
+
+
+    /// Mounts the filesystem on *dev* like new, with *policy* deciding what happens when metadata doesn't match its checksum
+    pub fn with_checksum_policy(dev: D, policy: ChecksumPolicy) -> Result<Self, FsError> {
+        let fs = Self::open(dev, Overlay::default(), policy)?;
+
+        // Committed metadata can be left in the journal only, so it's replayed and everything, the superblock included, read again
+        // through it
+        if u32::from_le(fs.sb.feature_incompat) & INCOMPAT_RECOVER != 0 {
+            let replayed = fs.replay_journal()?;
+            return Self::open(fs.dev, replayed, policy);
+        }
+        else {
+            return Ok(fs);
+        }
+    }
+
+
+    /// Opens the filesystem as it is with the *replayed* journal blocks in place
+    fn open(dev: D, replayed: Overlay, checksum_policy: ChecksumPolicy) -> Result<Self, FsError> {
+        let mut buffer: Vec<u8> = vec![0; size_of::<Ext4Superblock>()];
+        replayed.read_bytes(&dev, SUPERBLOCK_OFFSET, &mut buffer)?;
+        let sb: Box<Ext4Superblock> = Box::new(block::struct_from_bytes(&buffer));
+
+        if u16::from_le(sb.magic) != 0xEF53 {
+            return Err(FsError::UnknownFilesystem);
+        }
+        if sb.inodes_per_group == 0 || sb.blocks_per_group == 0 || sb.log_block_size > 6 {
+            return Err(FsError::Corrupt("invalid superblock"));
+        }
+
+        let mut unsupported = feature_names(u32::from_le(sb.feature_incompat) & !SUPPORTED_INCOMPAT, &INCOMPAT_FEATURES, "incompat");
+        unsupported.extend(feature_names(u32::from_le(sb.feature_ro_compat) & !SUPPORTED_RO_COMPAT, &RO_COMPAT_FEATURES, "ro_compat"));
+        if !unsupported.is_empty() {
+            return Err(FsError::UnsupportedFeatures(unsupported));
+        }
+
+        // The seed is normally derived from the UUID, but csum_seed stores it so the UUID can change without rewriting every checksum
+        let csum_seed = if u32::from_le(sb.feature_ro_compat) & RO_COMPAT_METADATA_CSUM != 0 {
+            if sb.checksum_type != CHECKSUM_TYPE_CRC32C {
+                return Err(FsError::Unsupported("ext checksum types other than crc32c"));
+            }
+
+            let bytes = block::struct_to_bytes(&*sb);
+            let computed = crc32c_update(!0, &bytes[..SUPERBLOCK_CHECKSUM_OFFSET]);
+            check_checksum(checksum_policy, computed == u32::from_le(sb.checksum), "ext superblock checksum mismatch")?;
+
+            if u32::from_le(sb.feature_incompat) & INCOMPAT_CSUM_SEED != 0 {
+                Some(u32::from_le(sb.checksum_seed))
+            }
+            else {
+                Some(crc32c_update(!0, &sb.uuid))
+            }
+        }
+        else {
+            None
+        };
+
+        let block_size = 1024u64 << u32::from_le(sb.log_block_size);
+
+        // Descriptors are 32 bytes unless the 64bit feature gives their size, which is a power of two of at least 64
+        let desc_size = if u32::from_le(sb.feature_incompat) & INCOMPAT_64BIT != 0 {
+            u16::from_le(sb.desc_size) as u64
+        }
+        else {
+            DESC_SIZE_32
+        };
+        if desc_size < DESC_SIZE_32 || !desc_size.is_power_of_two() || desc_size > block_size {
+            return Err(FsError::Corrupt("invalid group descriptor size"));
+        }
+
+        let first_data_block = u32::from_le(sb.first_data_block) as u64;
+        let blocks_count = superblock_blocks_count(&sb);
+        if blocks_count <= first_data_block {
+            return Err(FsError::Corrupt("invalid superblock"));
+        }
+        let group_count = (blocks_count - first_data_block).div_ceil(u32::from_le(sb.blocks_per_group) as u64);
+
+        if (u32::from_le(sb.inodes_count) as u64).div_ceil(u32::from_le(sb.inodes_per_group) as u64) > group_count {
+            return Err(FsError::Corrupt("more inodes than block groups hold"));
+        }
+
+        Ok(Self {
+            dev,
+            sb,
+            desc_size,
+            group_count,
+            csum_seed,
+            replayed,
+            checksum_policy,
+        })
+    }
+
+
+    /// Replays the journal kept in the journal inode into memory
+    fn replay_journal(&self) -> Result<Overlay, FsError> {
+        let journal_inum = u32::from_le(self.sb.journal_inum);
+        if u32::from_le(self.sb.feature_compat) & COMPAT_HAS_JOURNAL == 0 || journal_inum == 0 {
+            return Err(FsError::Unsupported("replaying ext journals on another device"));
+        }
+
+        let inode = self.read_inode(journal_inum)?;
+        let extents = self.inode_extents(&inode, ALL_BLOCKS)?;
+        let block_size = self.block_size();
+
+        journal::replay(block_size, self.checksum_policy, |block, buffer| {
+            let extent = extents.iter().find(|extent| block >= extent.logical && block < extent.logical + extent.length)
+                .ok_or(FsError::Corrupt("journal block past the end of the journal inode"))?;
+
+            self.read_bytes((extent.physical + block - extent.logical) * block_size, buffer)
+        })
+    }
+
+
+    /// Reads *buffer.len()* bytes starting at byte *offset* of the volume, with the blocks replayed from the journal in place
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        self.replayed.read_bytes(&self.dev, offset, buffer)
+    }
+
+
+    /// Reads the filesystem UUID and volume label from the superblock
+    pub fn volume_id(&self) -> (u128, String) {
+        // The label is NUL padded
+        let label_len = self.sb.volume_name.iter().position(|b| *b == 0).unwrap_or(self.sb.volume_name.len());
+        let label = String::from_utf8_lossy(&self.sb.volume_name[..label_len]).to_string();
+
+        (u128::from_be_bytes(self.sb.uuid), label)
+    }
+
+
+    /// Reads an inode from the disk
+    fn read_inode(&self, inode_num: u32) -> Result<Inode, FsError> {
+        let sb = &self.sb;
+
+        if inode_num == 0 || inode_num > u32::from_le(sb.inodes_count) {
+            return Err(FsError::Corrupt("inode number out of range"));
+        }
+
+        let bg_descriptor = self.group_descriptor(Ext4INode::get_block_group(sb, inode_num))?;
+        let inode_table_offset = self.descriptor_inode_table(&bg_descriptor) * self.block_size();
+        let inode_size = u16::from_le(sb.inode_size) as usize;
+
+        // Location of the inode as an offset in bytes starting from the inode table
+        let inode_offset = Ext4INode::get_index(sb, inode_num) as u64 * inode_size as u64;
+
+        // Older filesystems have 128 byte inodes, whose missing fields read as zeros. The checksum covers all of it.
+        let mut buffer: Vec<u8> = vec![0; inode_size.max(size_of::<Ext4INode>())];
+        self.read_bytes(inode_table_offset + inode_offset, &mut buffer[..inode_size])?;
+        let raw: Ext4INode = block::struct_from_bytes(&buffer);
+
+        let mut csum_seed = 0;
+        if let Some(fs_seed) = self.csum_seed {
+            csum_seed = crc32c_update(fs_seed, &inode_num.to_le_bytes());
+            csum_seed = crc32c_update(csum_seed, &raw.generation.to_le_bytes());
+
+            let (computed, stored) = inode_checksum(&buffer[..inode_size], csum_seed);
+            check_checksum(self.checksum_policy, computed == stored, "ext inode checksum mismatch")?;
+        }
+
+        // extra_isize counts the fields past the original 128 bytes that this inode has
+        let fields_end = if inode_size > GOOD_OLD_INODE_SIZE { GOOD_OLD_INODE_SIZE + u16::from_le(raw.extra_isize) as usize } else { inode_size };
+        if fields_end > inode_size {
+            return Err(FsError::Corrupt("ext inode fields are bigger than the inode"));
+        }
+
+        Ok(Inode {
+            number:     inode_num,
+            raw,
+            csum_seed,
+            xattr_area: buffer[fields_end..inode_size].to_vec(),
+        })
+    }
+
+
+    /// Reads the descriptor of block group *group*
+    fn group_descriptor(&self, group: u32) -> Result<Ext4BlockGroupDescriptor, FsError> {
+        if group as u64 >= self.group_count {
+            return Err(FsError::Corrupt("block group out of range"));
+        }
+
+        let per_block = self.block_size() / self.desc_size;
+        let table_block = self.descriptor_table_block(group as u64 / per_block);
+        let offset = table_block * self.block_size() + (group as u64 % per_block) * self.desc_size;
+
+        // 32 byte descriptors leave the high halves zeroed, and anything past 64 bytes is unused but checksummed
+        let desc_size = self.desc_size as usize;
+        let mut buffer: Vec<u8> = vec![0; desc_size.max(size_of::<Ext4BlockGroupDescriptor>())];
+        self.read_bytes(offset, &mut buffer[..desc_size])?;
+
+        if let Some(fs_seed) = self.csum_seed {
+            // Only the low 16 bits of the CRC are kept, computed with the checksum field zeroed
+            let mut crc = crc32c_update(fs_seed, &group.to_le_bytes());
+            crc = crc32c_update(crc, &buffer[..GROUP_DESC_CHECKSUM_OFFSET]);
+            crc = crc32c_update(crc, &[0, 0]);
+            crc = crc32c_update(crc, &buffer[GROUP_DESC_CHECKSUM_OFFSET + 2..desc_size]);
+
+            let stored = u16::from_le_bytes([buffer[GROUP_DESC_CHECKSUM_OFFSET], buffer[GROUP_DESC_CHECKSUM_OFFSET + 1]]);
+            check_checksum(self.checksum_policy, crc as u16 == stored, "ext group descriptor checksum mismatch")?;
+        }
+
+        Ok(block::struct_from_bytes(&buffer))
+    }
+
+
+    /// Finds block *index* of the group descriptor table. Normally the table follows the superblock. With meta_bg only the first
+    /// first_meta_bg blocks of it do, and every later block sits at the start of the first group it describes, after that group's backup
+    /// superblock if it has one.
+    fn descriptor_table_block(&self, index: u64) -> u64 {
+        let sb = &self.sb;
+        let superblock_block = SUPERBLOCK_OFFSET / self.block_size();
+
+        if u32::from_le(sb.feature_incompat) & INCOMPAT_META_BG == 0 || index < u32::from_le(sb.first_meta_bg) as u64 {
+            return superblock_block + 1 + index;
+        }
+
+        let group = index * (self.block_size() / self.desc_size);
+        let first_data_block = u32::from_le(sb.first_data_block) as u64;
+        let mut block = first_data_block + group * u32::from_le(sb.blocks_per_group) as u64;
+
+        if self.group_has_superblock(group) {
+            block += 1;
+        }
+        // With 1K blocks and bigalloc, group 0 starts at block 0 but the superblock is in block 1
+        if group == 0 && first_data_block == 0 && self.block_size() == 1024 {
+            block += 1;
+        }
+
+        block
+    }
+
+
+    /// Whether block group *group* starts with a copy of the superblock. Group 0 always does, sparse_super keeps backups only in groups 1
+    /// and powers of 3, 5 and 7, and sparse_super2 only in the two groups the superblock lists.
+    fn group_has_superblock(&self, group: u64) -> bool {
+        let sb = &self.sb;
+
+        if group == 0 {
+            return true;
+        }
+        else if u32::from_le(sb.feature_compat) & COMPAT_SPARSE_SUPER2 != 0 {
+            let backup_bgs = sb.backup_bgs;
+            return backup_bgs.iter().any(|backup| u32::from_le(*backup) as u64 == group);
+        }
+        else if group == 1 || u32::from_le(sb.feature_ro_compat) & RO_COMPAT_SPARSE_SUPER == 0 {
+            return true;
+        }
+        else {
+            return [3, 5, 7].iter().any(|base| is_power_of(group, *base));
+        }
+    }
+
+
+    /// Block holding the inode table described by *desc*. The high half only exists in 64 byte descriptors.
+    fn descriptor_inode_table(&self, desc: &Ext4BlockGroupDescriptor) -> u64 {
+        (u32::from_le(desc.inode_table_hi) as u64) << 32 | u32::from_le(desc.inode_table_lo) as u64
+    }
+
+
+    /// Collects the extents under the extent tree node in *node* that overlap the logical blocks in *blocks*, in logical order. *depth*
+    /// is the depth the parent expects, None for the root in the inode.
+    fn walk_extents(&self, inode: &Inode, node: &[u8], depth: Option<u16>, blocks: &Range<u64>, extents: &mut Vec<LeafExtent>)
+        -> Result<(), FsError> {
+        let header: ExtentHeader = block::struct_from_bytes(node);
+        let entries = u16::from_le(header.entries) as usize;
+        let node_depth = u16::from_le(header.depth);
+
+        if u16::from_le(header.magic) != EXTENT_MAGIC || node_depth > EXTENT_MAX_DEPTH || (entries + 1) * size_of::<ExtentLeaf>() > node.len() {
+            return Err(FsError::Corrupt("invalid extent tree node"));
+        }
+        if depth.is_some_and(|depth| depth != node_depth) {
+            return Err(FsError::Corrupt("extent tree node at the wrong depth"));
+        }
+
+        // Entries follow the header and are the same size whether they are leaves or indexes
+        for i in 1..=entries {
+            let entry = &node[i * size_of::<ExtentLeaf>()..];
+
+            if node_depth == 0 {
+                let leaf: ExtentLeaf = block::struct_from_bytes(entry);
+
+                // Lengths over 32768 mark preallocated extents that haven't been written yet
+                let len = u16::from_le(leaf.len);
+                let extent = LeafExtent {
+                    logical:        u32::from_le(leaf.block) as u64,
+                    physical:       (u16::from_le(leaf.start_hi) as u64) << 32 | u32::from_le(leaf.start_lo) as u64,
+                    length:         if len > EXTENT_INIT_MAX_LEN { len - EXTENT_INIT_MAX_LEN } else { len } as u64,
+                    uninitialized:  len > EXTENT_INIT_MAX_LEN,
+                };
+
+                if extent.logical < blocks.end && extent.logical + extent.length > blocks.start {
+                    extents.push(extent);
+                }
+            }
+            else {
+                // An index covers the blocks from its own up to the next index's, so only the subtrees overlapping *blocks* are read
+                let index: ExtentIndex = block::struct_from_bytes(entry);
+                let first = u32::from_le(index.block) as u64;
+                let next = if i < entries {
+                    let next: ExtentIndex = block::struct_from_bytes(&node[(i + 1) * size_of::<ExtentLeaf>()..]);
+                    u32::from_le(next.block) as u64
+                }
+                else {
+                    u64::MAX
+                };
+
+                if first >= blocks.end || next <= blocks.start {
+                    continue;
+                }
+
+                let child_block = (u16::from_le(index.leaf_hi) as u64) << 32 | u32::from_le(index.leaf_lo) as u64;
+
+                let mut child = vec![0u8; self.block_size() as usize];
+                self.read_bytes(child_block * self.block_size(), &mut child)?;
+                self.verify_extent_block(inode, &child)?;
+
+                self.walk_extents(inode, &child, Some(node_depth - 1), blocks, extents)?;
+            }
+        }
+
+        Ok(())
+    }
+
+
+    /// Checks the checksum after the last possible entry of an extent tree block
+    fn verify_extent_block(&self, inode: &Inode, node: &[u8]) -> Result<(), FsError> {
+        if self.csum_seed.is_none() {
+            return Ok(());
+        }
+
+        let header: ExtentHeader = block::struct_from_bytes(node);
+        let tail = (u16::from_le(header.max) as usize + 1) * size_of::<ExtentLeaf>();
+        if tail + 4 > node.len() {
+            return Err(FsError::Corrupt("invalid extent tree node"));
+        }
+
+        let stored = u32::from_le_bytes(node[tail..tail + 4].try_into().unwrap());
+        let computed = crc32c_update(inode.csum_seed, &node[..tail]);
+        check_checksum(self.checksum_policy, computed == stored, "ext extent block checksum mismatch")
+    }
+
+
+    /// Checks the checksum in the tail of each block of directory entries in *data*. Blocks without a tail, like those of an htree's
+    /// index, aren't checked.
+    fn verify_dir_blocks(&self, inode: &Inode, data: &[u8]) -> Result<(), FsError> {
+        if self.csum_seed.is_none() {
+            return Ok(());
+        }
+
+        for block in data.chunks_exact(self.block_size() as usize) {
+            let tail = block.len() - DIR_TAIL_SIZE;
+            let is_tail = u32::from_le_bytes(block[tail..tail + 4].try_into().unwrap()) == 0
+                && u16::from_le_bytes([block[tail + 4], block[tail + 5]]) as usize == DIR_TAIL_SIZE
+                && block[tail + 6] == 0
+                && block[tail + 7] == DIR_TAIL_FILE_TYPE;
+
+            if is_tail {
+                let stored = u32::from_le_bytes(block[tail + 8..].try_into().unwrap());
+                let computed = crc32c_update(inode.csum_seed, &block[..tail]);
+                check_checksum(self.checksum_policy, computed == stored, "ext directory block checksum mismatch")?;
+            }
+        }
+
+        Ok(())
+    }
+
+
+    /// Lists the extents of an inode that overlap the logical blocks in *blocks*, from its extent tree or, on ext2/3, its block map.
+    /// Parts of the tree that map other blocks aren't read.
+    fn inode_extents(&self, inode: &Inode, blocks: Range<u64>) -> Result<Vec<LeafExtent>, FsError> {
+        let mut extents = Vec::new();
+
+        if u32::from_le(inode.raw.flags) & EXT4_INLINE_DATA_FL != 0 {
+            return Ok(extents);
+        }
+        else if u32::from_le(inode.raw.flags) & EXT4_EXTENTS_FL != 0 {
+            self.walk_extents(inode, &inode.raw.block, None, &blocks, &mut extents)?;
+        }
+        else {
+            let pointers: Vec<u32> = inode.raw.block.chunks_exact(4).map(|pointer| u32::from_le_bytes(pointer.try_into().unwrap())).collect();
+            let blocks = blocks.start..blocks.end.min(inode_size(&inode.raw).div_ceil(self.block_size()));
+
+            for (logical, pointer) in pointers[..DIRECT_BLOCKS].iter().enumerate() {
+                if blocks.contains(&(logical as u64)) {
+                    push_block(&mut extents, logical as u64, *pointer);
+                }
+            }
+
+            // Then single, double and triple indirect blocks
+            let mut logical = DIRECT_BLOCKS as u64;
+            for level in 1..=3 {
+                if logical >= blocks.end {
+                    break;
+                }
+                self.walk_block_map(pointers[DIRECT_BLOCKS + level - 1], level as u32, &mut logical, &blocks, &mut extents)?;
+            }
+        }
+
+        Ok(extents)
+    }
+
+
+    /// Returns the contents of an inode with inline data: the block array followed by the value of the system.data attribute, which
+    /// only exists if the contents don't fit in the block array. The result can be longer than the file.
+    fn inline_data(&self, inode: &Inode) -> Result<Vec<u8>, FsError> {
+        let mut data = inode.raw.block.to_vec();
+
+        let xattrs = xattr::parse_inode_xattrs(&inode.xattr_area)?;
+        if let Some(system_data) = xattr::find(&xattrs, xattr::INDEX_SYSTEM, b"data") {
+            data.extend_from_slice(&system_data.value);
+        }
+
+        Ok(data)
+    }
+
+
+    /// Collects an inode's extended attributes, from the inode and then from its attribute block
+    fn inode_xattrs(&self, inode: &Inode) -> Result<Vec<xattr::Xattr>, FsError> {
+        let mut xattrs = xattr::parse_inode_xattrs(&inode.xattr_area)?;
+
+        let block = xattr_block(&inode.raw);
+        if block != 0 {
+            let mut data = vec![0u8; self.block_size() as usize];
+            self.read_bytes(block * self.block_size(), &mut data)?;
+
+            if let Some(fs_seed) = self.csum_seed {
+                let (computed, stored) = xattr::block_checksum(&data, block, fs_seed);
+                check_checksum(self.checksum_policy, computed == stored, "ext xattr block checksum mismatch")?;
+            }
+
+            xattrs.extend(xattr::parse_block(&data)?);
+        }
+
+        Ok(xattrs)
+    }
+
+
+    /// Returns the value of an extended attribute, reading it from the inode holding it if it's too large to be stored with the name
+    fn xattr_value(&self, xattr: &xattr::Xattr) -> Result<Vec<u8>, FsError> {
+        if xattr.value_inode == 0 {
+            return Ok(xattr.value.clone());
+        }
+
+        let inode = self.read_inode(xattr.value_inode)?;
+        if u32::from_le(inode.raw.flags) & EXT4_EA_INODE_FL == 0 {
+            return Err(FsError::Corrupt("ext xattr value inode isn't marked as one"));
+        }
+
+        let extents = self.inode_extents(&inode, ALL_BLOCKS)?;
+        let mut value = vec![0u8; self.contents_size(&inode, &extents, XATTR_SIZE_MAX)?];
+        let read = self.read_extents(&inode, &extents, 0, &mut value)?;
+        value.truncate(read);
+
+        Ok(value)
+    }
+
+
+    /// Collects the blocks in *blocks* mapped by an indirect block *level* levels above the data, starting at logical block *logical*
+    /// which is advanced past them
+    fn walk_block_map(&self, block: u32, level: u32, logical: &mut u64, blocks: &Range<u64>, extents: &mut Vec<LeafExtent>)
+        -> Result<(), FsError> {
+        let per_block = self.block_size() / 4;
+
+        // A missing indirect block is a hole as big as everything it would map, and one mapping only blocks before *blocks* isn't read
+        let mapped = per_block.pow(level);
+        if block == 0 || *logical + mapped <= blocks.start {
+            *logical += mapped;
+            return Ok(());
+        }
+
+        let mut pointers = vec![0u8; self.block_size() as usize];
+        self.read_bytes(block as u64 * self.block_size(), &mut pointers)?;
+
+        for pointer in pointers.chunks_exact(4).map(|pointer| u32::from_le_bytes(pointer.try_into().unwrap())) {
+            if *logical >= blocks.end {
+                break;
+            }
+
+            if level == 1 {
+                if *logical >= blocks.start {
+                    push_block(extents, *logical, pointer);
+                }
+                *logical += 1;
+            }
+            else {
+                self.walk_block_map(pointer, level - 1, logical, blocks, extents)?;
+            }
+        }
+
+        Ok(())
+    }
+
+
+    /// Reads part of an inode's contents starting at byte *offset*. Holes and uninitialized extents read as zeros. Returns the number of
+    /// bytes read.
+    fn read_inode_data(&self, inode: &Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if u32::from_le(inode.raw.flags) & EXT4_INLINE_DATA_FL != 0 {
+            let size = inode_size(&inode.raw);
+            if offset >= size {
+                return Ok(0);
+            }
+
+            let data = self.inline_data(inode)?;
+            if (data.len() as u64) < size {
+                return Err(FsError::Corrupt("ext inline data is shorter than the file"));
+            }
+
+            let len = buffer.len().min((size - offset) as usize);
+            buffer[..len].copy_from_slice(&data[offset as usize..offset as usize + len]);
+            return Ok(len);
+        }
+
+        let block_size = self.block_size();
+        let end = offset.saturating_add(buffer.len() as u64);
+        let extents = self.inode_extents(inode, offset / block_size..end.div_ceil(block_size))?;
+
+        self.read_extents(inode, &extents, offset, buffer)
+    }
+
+
+    /// Reads part of an inode's contents starting at byte *offset* through *extents*, which map the blocks it covers. The inode
+    /// can't have inline data. Returns the number of bytes read.
+    fn read_extents(&self, inode: &Inode, extents: &[LeafExtent], offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let size = inode_size(&inode.raw);
+        if offset >= size {
+            return Ok(0);
+        }
+
+        let len = buffer.len().min((size - offset) as usize);
+        let buffer = &mut buffer[..len];
+        buffer.fill(0);
+
+        let block_size = self.block_size();
+        let end = offset + len as u64;
+
+        for extent in extents {
+            let start = extent.logical * block_size;
+            let extent_end = start + extent.length * block_size;
+            if extent.uninitialized || extent_end <= offset || start >= end {
+                continue;
+            }
+
+            let from = start.max(offset);
+            let to = extent_end.min(end);
+            self.read_bytes(extent.physical * block_size + (from - start), &mut buffer[(from - offset) as usize..(to - offset) as usize])?;
+        }
+
+        Ok(len)
+    }
+
+
+    /// Size of the contents of an inode that's read whole into memory, checked against the blocks *extents* map and *max* before
+    /// anything that large is allocated
+    fn contents_size(&self, inode: &Inode, extents: &[LeafExtent], max: u64) -> Result<usize, FsError> {
+        let size = inode_size(&inode.raw);
+        let mapped = extents.iter().map(|extent| extent.logical + extent.length).max().unwrap_or(0) * self.block_size();
+
+        if size > mapped || size > max {
+            return Err(FsError::Corrupt("ext inode is larger than the blocks it maps"));
+        }
+
+        Ok(size as usize)
+    }
+
+
+    /// Size of a block in bytes
+    fn block_size(&self) -> u64 {
+        1024 << u32::from_le(self.sb.log_block_size)
+    }
+
+
+    /// Lists the extents of an inode, in the order they appear in the extent tree
+    pub fn extents(&self, inode_num: u32) -> Result<Vec<Extent>, FsError> {
+        let inode = self.read_inode(inode_num)?;
+
+        Ok(self.inode_extents(&inode, ALL_BLOCKS)?.iter().map(|extent| Extent {
+            logical:    extent.logical,
+            physical:   extent.physical,
+            length:     extent.length,
+        }).collect())
+    }
+
+
+    /// Names every optional feature the volume uses
+    pub fn features(&self) -> Vec<String> {
+        let sb = &self.sb;
+
+        let mut features = feature_names(u32::from_le(sb.feature_compat), &COMPAT_FEATURES, "compat");
+        features.extend(feature_names(u32::from_le(sb.feature_incompat), &INCOMPAT_FEATURES, "incompat"));
+        features.extend(feature_names(u32::from_le(sb.feature_ro_compat), &RO_COMPAT_FEATURES, "ro_compat"));
+
+        features
+    }
+
+
+    /// Lists the interesting superblock fields as name/value pairs, for debugging tools
+    pub fn superblock_info(&self) -> Vec<(&'static str, String)> {
+        let sb = &self.sb;
+        let (uuid, label) = self.volume_id();
+        vec![
+            ("Filesystem UUID",         format!("{:032x}", uuid)),
+            ("Volume name",             label),
+            ("Revision",                format!("{}.{}", u32::from_le(sb.rev_level), u16::from_le(sb.minor_rev_level))),
+            ("State",                   format!("{:#x}", u16::from_le(sb.state))),
+            ("Inode count",             u32::from_le(sb.inodes_count).to_string()),
+            ("Block count",             superblock_blocks_count(sb).to_string()),
+            ("Free inodes",             u32::from_le(sb.free_inodes_count).to_string()),
+            ("Free blocks",             u32::from_le(sb.free_blocks_count_lo).to_string()),
+            ("First data block",        u32::from_le(sb.first_data_block).to_string()),
+            ("Block size",              self.block_size().to_string()),
+            ("Block groups",            self.group_count.to_string()),
+            ("Blocks per group",        u32::from_le(sb.blocks_per_group).to_string()),
+            ("Inodes per group",        u32::from_le(sb.inodes_per_group).to_string()),
+            ("Inode size",              u16::from_le(sb.inode_size).to_string()),
+            ("Group descriptor size",   self.desc_size.to_string()),
+            ("Reserved GDT blocks",     u16::from_le(sb.reserved_gdt_blocks).to_string()),
+            ("First meta_bg",           u32::from_le(sb.first_meta_bg).to_string()),
+            ("Journal inode",           u32::from_le(sb.journal_inum).to_string()),
+            ("Replayed journal blocks", self.replayed.len().to_string()),
+            ("Default directory hash",  sb.def_hash_version.to_string()),
+            ("Features",                self.features().join(" ")),
+            ("Compatible features",     format!("{:#x}", u32::from_le(sb.feature_compat))),
+            ("Incompatible features",   format!("{:#x}", u32::from_le(sb.feature_incompat))),
+            ("Read-only features",      format!("{:#x}", u32::from_le(sb.feature_ro_compat))),
+        ]
+    }
+
+
+    /// Whether a symlink's target is stored in its block array. Such symlinks own no blocks, apart from one holding extended attributes.
+    fn is_fast_symlink(&self, inode: &Inode) -> bool {
+        // Inline data doesn't take blocks either, but can continue past the block array
+        if u32::from_le(inode.raw.flags) & EXT4_INLINE_DATA_FL != 0 {
+            return false;
+        }
+
+        // Block counts are in 512 byte sectors
+        let xattr_sectors = if xattr_block(&inode.raw) != 0 { self.block_size() / 512 } else { 0 };
+
+        u32::from_le(inode.raw.blocks_lo) as u64 == xattr_sectors
+    }
+
+
+    /// Parses the directory entries in *data*, which holds one or more whole directory blocks. "." and ".." are left out.
+    fn parse_dir_entries(&self, data: &[u8]) -> Result<Vec<DirEntry<u32>>, FsError> {
+        // Each entry is: inode (u32), rec_len (u16), name_len (u8), file_type (u8), name
+        let mut entries = Vec::new();
+        let mut i = 0;
+        while i + 8 <= data.len() {
+            let inode_num = u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+            let rec_len = u16::from_le_bytes(data[i + 4..i + 6].try_into().unwrap()) as usize;
+            let name_len = data[i + 6] as usize;
+            let file_type = data[i + 7];
+
+            if rec_len < 8 || i + 8 + name_len > data.len() {
+                break;
+            }
+
+            // Unused entries have an inode number of 0
+            let name = String::from_utf8_lossy(&data[i + 8..i + 8 + name_len]).to_string();
+            if inode_num != 0 && name != "." && name != ".." {
+                let file_type = match file_type {
+                    1 => FileType::Regular,
+                    2 => FileType::Directory,
+                    7 => FileType::Symlink,
+                    0 => self.inode_file_type(inode_num)?,
+                    _ => FileType::Other,
+                };
+
+                entries.push(DirEntry {
+                    name,
+                    file_type,
+                    node: inode_num,
+                });
+            }
+
+            i += rec_len;
+        }
+
+        Ok(entries)
+    }
+
+
+    /// Looks *name* up through a directory's htree, reading one block per level and then the leaf. Returns Unsupported for hashes
+    /// that can't be computed, so the caller can fall back to a linear search.
+    fn htree_lookup(&self, inode: &Inode, name: &str) -> Result<Option<DirEntry<u32>>, FsError> {
+        // Index entries name blocks within the directory, so it's mapped once and every level is read through that map
+        let extents = self.inode_extents(inode, ALL_BLOCKS)?;
+
+        let mut block: Vec<u8> = vec![0; self.block_size() as usize];
+        self.read_dir_block(inode, &extents, 0, &mut block)?;
+        let root = htree::parse_root(&block)?;
+
+        if root.indirect_levels > 1 && u32::from_le(self.sb.feature_incompat) & INCOMPAT_LARGEDIR == 0 {
+            return Err(FsError::Corrupt("htree is too deep"));
+        }
+
+        let unsigned = u32::from_le(self.sb.flags) & FLAGS_UNSIGNED_HASH != 0;
+        let version = HashVersion::from_raw(root.hash_version, unsigned).ok_or(FsError::Unsupported("htree hash version"))?;
+        let seed = self.sb.hash_seed.map(u32::from_le);
+        let hash = htree::name_hash(name.as_bytes(), version, &seed);
+
+        // The index entries of every level down to the leaves, each with the position of the one being followed
+        let mut path: Vec<(Vec<DxEntry>, usize)> = Vec::new();
+        let mut entries = root.entries;
+        loop {
+            let at = htree::find_entry(&entries, hash);
+            let child = entries[at].block;
+            path.push((entries, at));
+
+            if path.len() > root.indirect_levels as usize {
+                break;
+            }
+            self.read_dir_block(inode, &extents, child, &mut block)?;
+            entries = htree::parse_node(&block)?;
+        }
+
+        loop {
+            let (entries, at) = path.last().unwrap();
+            self.read_dir_block(inode, &extents, entries[*at].block, &mut block)?;
+            self.verify_dir_blocks(inode, &block)?;
+
+            let found = self.parse_dir_entries(&block)?.into_iter().find(|entry| entry.name == name);
+            if found.is_some() {
+                return Ok(found);
+            }
+
+            if !self.next_htree_leaf(inode, &extents, &mut path, hash)? {
+                return Ok(None);
+            }
+        }
+    }
+
+
+    /// Moves *path* to the next leaf if it continues the run of names with *hash*. Returns false if it doesn't, or there are no more
+    /// leaves.
+    fn next_htree_leaf(&self, inode: &Inode, extents: &[LeafExtent], path: &mut [(Vec<DxEntry>, usize)], hash: u32)
+        -> Result<bool, FsError> {
+        // Climb until a level has an entry after the one we took
+        let mut level = path.len();
+        loop {
+            if level == 0 {
+                return Ok(false);
+            }
+            level -= 1;
+
+            if path[level].1 + 1 < path[level].0.len() {
+                break;
+            }
+        }
+
+        path[level].1 += 1;
+        if !htree::continues_hash(&path[level].0[path[level].1], hash) {
+            return Ok(false);
+        }
+
+        // Then back down along the first entries of the nodes below it
+        let mut block: Vec<u8> = vec![0; self.block_size() as usize];
+        for lower in level + 1..path.len() {
+            let (entries, at) = &path[lower - 1];
+            self.read_dir_block(inode, extents, entries[*at].block, &mut block)?;
+            path[lower] = (htree::parse_node(&block)?, 0);
+        }
+
+        Ok(true)
+    }
+
+
+    /// Reads block *index* of a directory whose blocks *extents* map
+    fn read_dir_block(&self, inode: &Inode, extents: &[LeafExtent], index: u32, buffer: &mut [u8]) -> Result<(), FsError> {
+        if self.read_extents(inode, extents, index as u64 * self.block_size(), buffer)? < buffer.len() {
+            return Err(FsError::Corrupt("htree points past the end of the directory"));
+        }
+
+        Ok(())
+    }
+
+
+    /// Determines the file type from the inode's mode, for directory entries that don't record it
+    fn inode_file_type(&self, inode_num: u32) -> Result<FileType, FsError> {
+        let inode = self.read_inode(inode_num)?;
+
+        Ok(file_type_from_mode(u16::from_le(inode.raw.mode)))
+    }
+}
+
+impl<D: BlockDevice> Filesystem for ExtFs<D> {
+    type Node = u32;
+
+    fn case_rule(&self) -> CaseRule {
+        CaseRule::Sensitive
+    }
+
+    fn root(&self) -> Result<DirEntry<u32>, FsError> {
+        Ok(DirEntry {
+            name:       "/".to_string(),
+            file_type:  FileType::Directory,
+            node:       ROOT_INODE,
+        })
+    }
+
+    fn read_dir(&self, dir: &u32) -> Result<Vec<DirEntry<u32>>, FsError> {
+        let inode = self.read_inode(*dir)?;
+
+        // Inline directories hold two runs of entries, one in the block array after the parent's inode number and one in system.data
+        if u32::from_le(inode.raw.flags) & EXT4_INLINE_DATA_FL != 0 {
+            let data = self.inline_data(&inode)?;
+
+            let mut entries = self.parse_dir_entries(&data[INLINE_DIR_PARENT_SIZE..INLINE_DATA_SIZE])?;
+            entries.extend(self.parse_dir_entries(&data[INLINE_DATA_SIZE..])?);
+            return Ok(entries);
+        }
+
+        // read its contents into memory (the directory entries)
+        let extents = self.inode_extents(&inode, ALL_BLOCKS)?;
+        let mut buff: Vec<u8> = vec![0; self.contents_size(&inode, &extents, MAX_DIR_SIZE)?];
+        self.read_extents(&inode, &extents, 0, &mut buff)?;
+        self.verify_dir_blocks(&inode, &buff)?;
+
+        self.parse_dir_entries(&buff)
+    }
+
+    fn lookup(&self, dir: &u32, name: &str) -> Result<Option<DirEntry<u32>>, FsError> {
+        let inode = self.read_inode(*dir)?;
+
+        if u32::from_le(inode.raw.flags) & EXT4_INDEX_FL != 0 && u32::from_le(self.sb.feature_compat) & COMPAT_DIR_INDEX != 0 {
+            match self.htree_lookup(&inode, name) {
+                Ok(entry)                       => return Ok(entry),
+                Err(FsError::Corrupt(reason))   => fslog!("ext: htree of directory inode {} is damaged ({}), searching it linearly", dir, reason),
+                Err(FsError::Unsupported(_))    => {},
+                Err(err)                        => return Err(err),
+            }
+        }
+
+        Ok(self.read_dir(dir)?.into_iter().find(|entry| entry.name == name))
+    }
+
+    fn read_link(&self, node: &u32) -> Result<String, FsError> {
+        let inode = self.read_inode(*node)?;
+        let size = inode_size(&inode.raw);
+
+        if file_type_from_mode(u16::from_le(inode.raw.mode)) != FileType::Symlink {
+            return Err(FsError::Corrupt("ext symlink inode isn't a symlink"));
+        }
+
+        // Fast symlinks keep targets under 60 bytes in the block array, slow ones in a data block
+        let target = if self.is_fast_symlink(&inode) {
+            if size > inode.raw.block.len() as u64 {
+                return Err(FsError::Corrupt("ext fast symlink target too long"));
+            }
+
+            inode.raw.block[..size as usize].to_vec()
+        }
+        else {
+            if size > self.block_size() {
+                return Err(FsError::Corrupt("ext symlink target too long"));
+            }
+
+            let mut target = vec![0u8; size as usize];
+            let read = self.read_inode_data(&inode, 0, &mut target)?;
+            target.truncate(read);
+            target
+        };
+
+        Ok(String::from_utf8_lossy(&target).to_string())
+    }
+
+    fn metadata(&self, node: &u32) -> Result<Metadata, FsError> {
+        let inode = self.read_inode(*node)?;
+        let mode = u16::from_le(inode.raw.mode);
+
+        // The high 16 bits of the owner are in the Linux specific part of osd2
+        let uid_high = u16::from_le_bytes([inode.raw.osd2[4], inode.raw.osd2[5]]) as u32;
+        let gid_high = u16::from_le_bytes([inode.raw.osd2[6], inode.raw.osd2[7]]) as u32;
+
+        Ok(Metadata {
+            file_type:  file_type_from_mode(mode),
+            size:       inode_size(&inode.raw),
+            mode:       mode & 0o7777,
+            uid:        u16::from_le(inode.raw.uid) as u32 | (uid_high << 16),
+            gid:        u16::from_le(inode.raw.gid) as u32 | (gid_high << 16),
+            mtime:      u32::from_le(inode.raw.mtime) as i32 as i64,
+            id:         *node as u64,
+        })
+    }
+
+    fn read(&self, node: &u32, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let inode = self.read_inode(*node)?;
+
+        self.read_inode_data(&inode, offset, buffer)
+    }
+
+    fn get_xattr(&self, node: &u32, name: &str) -> Result<Option<Vec<u8>>, FsError> {
+        let inode = self.read_inode(*node)?;
+
+        match self.inode_xattrs(&inode)?.iter().find(|xattr| xattr::full_name(xattr).as_deref() == Some(name)) {
+            Some(xattr) => Ok(Some(self.xattr_value(xattr)?)),
+            None        => Ok(None),
+        }
+    }
+
+    fn list_xattrs(&self, node: &u32) -> Result<Vec<String>, FsError> {
+        let inode = self.read_inode(*node)?;
+
+        Ok(self.inode_xattrs(&inode)?.iter().filter_map(xattr::full_name).collect())
+    }
+}
+
+
+
+/// Adds a block from a block map to *extents*, extending the last extent if it's contiguous. Zero pointers are holes.
+fn push_block(extents: &mut Vec<LeafExtent>, logical: u64, physical: u32) {
+    if physical == 0 {
+        return;
+    }
+
+    match extents.last_mut() {
+        Some(last) if last.logical + last.length == logical && last.physical + last.length == physical as u64 => last.length += 1,
+        _ => extents.push(LeafExtent {
+            logical,
+            physical:       physical as u64,
+            length:         1,
+            uninitialized:  false,
+        }),
+    }
+}
+
+
+/// Names the feature flags set in *flags*. Flags missing from *names* are shown as the *kind* of flag and their value.
+fn feature_names(flags: u32, names: &[(u32, &'static str)], kind: &str) -> Vec<String> {
+    (0..32).map(|bit| 1u32 << bit).filter(|flag| flags & flag != 0).map(|flag| {
+        match names.iter().find(|(value, _)| *value == flag) {
+            Some((_, name)) => name.to_string(),
+            None            => format!("{}:{:#x}", kind, flag),
+        }
+    }).collect()
+}
+
+
+/// Applies *policy* to a checksum that was checked. *error* is both logged and returned.
+fn check_checksum(policy: ChecksumPolicy, matches: bool, error: &'static str) -> Result<(), FsError> {
+    if matches {
+        return Ok(());
+    }
+    else if policy == ChecksumPolicy::Error {
+        return Err(FsError::Corrupt(error));
+    }
+    else {
+        fslog!("{}, using it anyway", error);
+        return Ok(());
+    }
+}
+
+
+/// Computes the checksum of the on-disk inode in *bytes* and returns it with the stored one. Inodes too small for the high half of the
+/// checksum only store, and are compared on, the low 16 bits.
+fn inode_checksum(bytes: &[u8], seed: u32) -> (u32, u32) {
+    let mut crc = crc32c_update(seed, &bytes[..INODE_CHECKSUM_LO_OFFSET]);
+    crc = crc32c_update(crc, &[0, 0]);
+    crc = crc32c_update(crc, &bytes[INODE_CHECKSUM_LO_OFFSET + 2..GOOD_OLD_INODE_SIZE]);
+    let mut stored = u16::from_le_bytes([bytes[INODE_CHECKSUM_LO_OFFSET], bytes[INODE_CHECKSUM_LO_OFFSET + 1]]) as u32;
+
+    if bytes.len() <= GOOD_OLD_INODE_SIZE {
+        return (crc & 0xFFFF, stored);
+    }
+
+    let extra_isize = u16::from_le_bytes([bytes[GOOD_OLD_INODE_SIZE], bytes[GOOD_OLD_INODE_SIZE + 1]]) as usize;
+    crc = crc32c_update(crc, &bytes[GOOD_OLD_INODE_SIZE..INODE_CHECKSUM_HI_OFFSET]);
+
+    let mut rest = INODE_CHECKSUM_HI_OFFSET;
+    let has_high = GOOD_OLD_INODE_SIZE + extra_isize >= INODE_CHECKSUM_HI_OFFSET + 2;
+    if has_high {
+        crc = crc32c_update(crc, &[0, 0]);
+        stored |= (u16::from_le_bytes([bytes[INODE_CHECKSUM_HI_OFFSET], bytes[INODE_CHECKSUM_HI_OFFSET + 1]]) as u32) << 16;
+        rest += 2;
+    }
+    crc = crc32c_update(crc, &bytes[rest..]);
+
+    if has_high {
+        return (crc, stored);
+    }
+    else {
+        return (crc & 0xFFFF, stored);
+    }
+}
+
+
+/// Number of blocks in the volume. The high 32 bits only exist with the 64bit feature.
+fn superblock_blocks_count(sb: &Ext4Superblock) -> u64 {
+    let blocks_count_hi = if u32::from_le(sb.feature_incompat) & INCOMPAT_64BIT != 0 {
+        u32::from_le(sb.blocks_count_hi) as u64
+    }
+    else {
+        0
+    };
+
+    blocks_count_hi << 32 | u32::from_le(sb.blocks_count_lo) as u64
+}
+
+
+/// Whether *n* is a power of *base*
+fn is_power_of(mut n: u64, base: u64) -> bool {
+    while n > 1 && n.is_multiple_of(base) {
+        n /= base;
+    }
+
+    n == 1
+}
+
+
+/// Size of an inode's contents. The high 32 bits were added for files over 4GiB.
+fn inode_size(inode: &Ext4INode) -> u64 {
+    (u32::from_le(inode.size_high) as u64) << 32 | u32::from_le(inode.size_lo) as u64
+}
+
+
+/// Block holding the extended attributes that don't fit in the inode, 0 if there's none
+fn xattr_block(inode: &Ext4INode) -> u64 {
+    // The high 16 bits are in the Linux specific part of osd2
+    (u16::from_le_bytes([inode.osd2[2], inode.osd2[3]]) as u64) << 32 | u32::from_le(inode.file_acl_lo) as u64
+}
+
+
+/// Converts the type bits of an inode's mode to a FileType
+fn file_type_from_mode(mode: u16) -> FileType {
+    match mode & S_IFMT {
+        S_IFDIR => FileType::Directory,
+        S_IFREG => FileType::Regular,
+        S_IFLNK => FileType::Symlink,
+        _       => FileType::Other,
+    }
+}
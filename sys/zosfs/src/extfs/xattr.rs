@@ -0,0 +1,193 @@
+/*  xattr.rs - Extended attributes for the EXT driver
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Extended attributes live in the space an inode's size leaves after its fields, and those that don't fit there in a block the inode
+//! points at, which inodes with the same attributes can share. Each entry names the attribute with a prefix index and the rest of the
+//! name, and points at its value further on in the same area or block.
+
+use alloc::{format, string::String, vec::Vec};
+use crate::block;
+use crate::checksum::crc32c_update;
+use crate::error::FsError;
+use core::mem::size_of;
+
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct XattrEntry {
+    pub name_len:       u8,
+    pub name_index:     u8,
+    pub value_offs:     u16,
+    pub value_inum:     u32,
+    pub value_size:     u32,
+    pub hash:           u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct XattrBlockHeader {
+    pub magic:          u32,
+    pub refcount:       u32,
+    pub blocks:         u32,
+    pub hash:           u32,
+    pub checksum:       u32,
+    pub reserved:       [u32; 3],
+}
+
+
+
+/// An extended attribute as stored on the disk
+pub struct Xattr {
+    /// Selects the name's prefix, like "user." or "system."
+    pub name_index:     u8,
+    pub name:           Vec<u8>,
+    pub value:          Vec<u8>,
+    /// Inode holding a large value instead of *value*, 0 if there's none
+    pub value_inode:    u32,
+}
+
+
+/// Starts the attributes stored in an inode, and attribute blocks
+const XATTR_MAGIC: u32 = 0xEA020000;
+
+/// Offset of the checksum in an attribute block, which is zeroed when computing it
+const BLOCK_CHECKSUM_OFFSET: usize = 0x10;
+
+/// What each name index stands for in front of the name. Index 0 has no prefix.
+const PREFIXES: [(u8, &str); 7] = [
+    (1, "user."), (2, "system.posix_acl_access"), (3, "system.posix_acl_default"), (4, "trusted."), (6, "security."), (7, "system."),
+    (8, "system.richacl"),
+];
+
+/// Name index of "system." attributes, like system.data which holds inline data past the block array
+pub const INDEX_SYSTEM: u8 = 7;
+
+/// Entries are padded to a multiple of this
+const ENTRY_ALIGN: usize = 4;
+
+
+
+/// Parses the attributes stored in an inode. *area* is what follows the inode's fields, up to the end of the on-disk inode.
+pub fn parse_inode_xattrs(area: &[u8]) -> Result<Vec<Xattr>, FsError> {
+    if area.len() < 4 || u32::from_le_bytes(area[..4].try_into().unwrap()) != XATTR_MAGIC {
+        return Ok(Vec::new());
+    }
+
+    // Values are placed relative to the first entry
+    parse_entries(&area[4..], &area[4..])
+}
+
+
+/// Parses the attributes in an attribute block
+pub fn parse_block(block: &[u8]) -> Result<Vec<Xattr>, FsError> {
+    let header: XattrBlockHeader = block::struct_from_bytes(block);
+
+    // Linux only ever makes attribute blocks one block long
+    if u32::from_le(header.magic) != XATTR_MAGIC || u32::from_le(header.blocks) != 1 {
+        return Err(FsError::Corrupt("invalid ext xattr block"));
+    }
+
+    // Values are placed relative to the start of the block
+    parse_entries(&block[size_of::<XattrBlockHeader>()..], block)
+}
+
+
+/// Computes the checksum of the attribute block *block*, found at block *block_number* of the volume, and returns it with the stored
+/// one. The block number is covered so that a block written in the wrong place is caught.
+pub fn block_checksum(block: &[u8], block_number: u64, csum_seed: u32) -> (u32, u32) {
+    let mut computed = crc32c_update(csum_seed, &block_number.to_le_bytes());
+    computed = crc32c_update(computed, &block[..BLOCK_CHECKSUM_OFFSET]);
+    computed = crc32c_update(computed, &[0; 4]);
+    computed = crc32c_update(computed, &block[BLOCK_CHECKSUM_OFFSET + 4..]);
+
+    let header: XattrBlockHeader = block::struct_from_bytes(block);
+    (computed, u32::from_le(header.checksum))
+}
+
+
+/// Returns the full name of *xattr*, like "user.comment", or None if its name index is one Linux doesn't know either
+pub fn full_name(xattr: &Xattr) -> Option<String> {
+    let prefix = if xattr.name_index == 0 {
+        ""
+    }
+    else {
+        PREFIXES.iter().find(|(index, _)| *index == xattr.name_index)?.1
+    };
+
+    Some(format!("{}{}", prefix, String::from_utf8_lossy(&xattr.name)))
+}
+
+
+/// Finds the attribute with *name_index* and *name* in *xattrs*
+pub fn find<'a>(xattrs: &'a [Xattr], name_index: u8, name: &[u8]) -> Option<&'a Xattr> {
+    xattrs.iter().find(|xattr| xattr.name_index == name_index && xattr.name == name)
+}
+
+
+/// Parses the entries at the start of *entries*, which end with four zero bytes. Their values are found at offsets into *values*.
+fn parse_entries(entries: &[u8], values: &[u8]) -> Result<Vec<Xattr>, FsError> {
+    let mut xattrs = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        if offset + 4 > entries.len() {
+            return Err(FsError::Corrupt("ext xattr entries run past their area"));
+        }
+        if entries[offset..offset + 4] == [0; 4] {
+            break;
+        }
+
+        let name_start = offset + size_of::<XattrEntry>();
+        if name_start > entries.len() {
+            return Err(FsError::Corrupt("ext xattr entries run past their area"));
+        }
+        let entry: XattrEntry = block::struct_from_bytes(&entries[offset..]);
+
+        let name_end = name_start + entry.name_len as usize;
+        if name_end > entries.len() {
+            return Err(FsError::Corrupt("ext xattr entries run past their area"));
+        }
+
+        // Large values can be kept in an inode of their own
+        let value_inode = u32::from_le(entry.value_inum);
+        let value = if value_inode != 0 {
+            Vec::new()
+        }
+        else {
+            let value_start = u16::from_le(entry.value_offs) as usize;
+            let value_end = value_start + u32::from_le(entry.value_size) as usize;
+            if value_end > values.len() {
+                return Err(FsError::Corrupt("ext xattr value out of bounds"));
+            }
+
+            values[value_start..value_end].to_vec()
+        };
+
+        xattrs.push(Xattr {
+            name_index: entry.name_index,
+            name:       entries[name_start..name_end].to_vec(),
+            value,
+            value_inode,
+        });
+
+        offset = name_end.next_multiple_of(ENTRY_ALIGN);
+    }
+
+    Ok(xattrs)
+}
@@ -0,0 +1,1310 @@
+/*  fat.rs - Basic FAT fs driver
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+#![allow(dead_code)]
+
+use core::cell::Cell;
+use core::mem::size_of;
+use alloc::{format, string::{String, ToString}, vec::Vec, vec};
+use crate::block::{self, BlockDevice};
+use crate::error::FsError;
+use crate::fattable::{self, FatCache, FatTable};
+use crate::fs::{self, CaseRule, DirEntry, Extent, FileType, Filesystem, Metadata};
+use crate::time::DateTime;
+
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum FATType {
+    FAT12,
+    FAT16,
+    FAT32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct BIOSParameterBlock {
+    jmp_boot:               [u8; 3],
+    pub oem_name:           [u8; 8],
+    pub bytspersec:         u16,
+    pub secperclus:         u8,
+    pub rsvdseccnt:         u16,
+    pub numfats:            u8,
+    pub rootentcnt:         u16,
+    pub totsec16:           u16,
+    pub media:              u8,
+    pub fatsz16:            u16,
+    pub secpertrack:        u16,
+    pub numheads:           u16,
+    pub hiddsec:            u32,
+    pub totsec32:           u32,
+    pub fatzs32:            u32,
+
+    pub extflags:           u16,
+    pub fsver:              u16,
+    pub rootclus:           u32,
+    pub fsinfo:             u16,
+    pub bkbootsec:          u16,
+    _reserved:              [u8; 12],
+}
+
+impl BIOSParameterBlock {
+    pub fn new_zeroed() -> Self {
+        unsafe { core::mem::zeroed::<Self>() }
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct FSInfo {
+    pub leadsig:            u32,
+    _reserved1:             [u8; 480],
+    pub strucsig:           u32,
+    pub free_count:         u32,
+    pub nxt_free:           u32,
+    _reserved2:             [u8; 12],
+    pub trailsig:           u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct DirectoryEntry {
+    pub name:               [u8; 11],
+    pub attr:               u8,
+    nt_rsvd:                u8,
+    pub crt_time_tenth:     u8,
+    pub crt_time:           u16,
+    pub crt_date:           u16,
+    pub lst_acc_date:       u16,
+    pub fst_clus_hi:        u16,
+    pub wrt_time:           u16,
+    pub wrt_date:           u16,
+    pub fst_clus_lo:        u16,
+    pub filesize:           u32,
+}
+
+/// VFAT long name entry. A long name is stored in 13 character pieces, in reverse order, in the entries just before its short entry.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct LongNameEntry {
+    pub ord:                u8,
+    pub name1:              [u16; 5],
+    pub attr:               u8,
+    pub lfn_type:           u8,
+    pub chksum:             u8,
+    pub name2:              [u16; 6],
+    fst_clus_lo:            u16,
+    pub name3:              [u16; 2],
+}
+
+impl LongNameEntry {
+    /// Returns the 13 UCS-2 characters held by this entry
+    fn chars(&self) -> [u16; 13] {
+        let (name1, name2, name3) = (self.name1, self.name2, self.name3);
+
+        let mut chars = [0u16; 13];
+        chars[..5].copy_from_slice(&name1);
+        chars[5..11].copy_from_slice(&name2);
+        chars[11..].copy_from_slice(&name3);
+
+        chars.map(u16::from_le)
+    }
+}
+
+
+/// A long name being put together from the long name entries of a directory
+struct LongName {
+    /// Pieces of the name, in the order they were found on disk (last piece first)
+    pieces:     Vec<[u16; 13]>,
+    /// Checksum of the short name this long name belongs to
+    checksum:   u8,
+    /// Sequence number the next entry must have. 0 once the first piece has been seen and the name is complete.
+    next:       u8,
+}
+
+impl LongName {
+    /// Adds a long name entry. Returns false if it doesn't continue the name being built, in which case the name is discarded.
+    fn push(&mut self, entry: &LongNameEntry) -> bool {
+        if self.next == 0 || entry.ord & !LAST_LONG_ENTRY != self.next || entry.chksum != self.checksum {
+            return false;
+        }
+
+        self.pieces.push(entry.chars());
+        self.next -= 1;
+        true
+    }
+
+    /// Returns the name if every piece was found and it belongs to *short*
+    fn finish(&self, short: &DirectoryEntry) -> Option<String> {
+        if self.next != 0 || self.checksum != short_name_checksum(&short.name) {
+            return None;
+        }
+
+        // The name is NUL terminated unless it exactly fills the last piece, and padded with 0xFFFF
+        let chars: Vec<u16> = self.pieces.iter().rev().flatten().copied().take_while(|c| *c != 0).collect();
+
+        Some(char::decode_utf16(chars).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect())
+    }
+}
+
+
+/// Checksum of an 8.3 name, stored in each of its long name entries so orphaned ones can be detected
+fn short_name_checksum(name: &[u8; 11]) -> u8 {
+    let mut sum: u8 = 0;
+
+    for b in name {
+        sum = sum.rotate_right(1).wrapping_add(*b);
+    }
+
+    sum
+}
+
+
+/// An entry found while parsing a directory, along with where it sits among the directory's 32 byte slots
+struct ParsedEntry {
+    entry:      DirEntry<DirectoryEntry>,
+    /// Slot of the short entry
+    slot:       usize,
+}
+
+
+/// Parses the raw contents of a directory
+fn parse_dir(raw_entries: &[u8]) -> Vec<ParsedEntry> {
+    let mut entries = Vec::new();
+    let mut long_name: Option<LongName> = None;
+
+    for (slot, raw) in raw_entries.chunks_exact(size_of::<DirectoryEntry>()).enumerate() {
+        let entry: DirectoryEntry = block::struct_from_bytes(raw);
+
+        // Last entry?
+        if entry.name[0] == 0x00 {
+            break;
+        }
+
+        // Free entries break up any long name in progress
+        if entry.name[0] == 0xE5 {
+            long_name = None;
+            continue;
+        }
+
+        if entry.attr & ATTR_LONG_NAME == ATTR_LONG_NAME {
+            let lfn: LongNameEntry = block::struct_from_bytes(raw);
+
+            // The entry flagged as last starts a new name, every other one has to continue the current name or it is an orphan
+            if lfn.ord & LAST_LONG_ENTRY != 0 {
+                let count = lfn.ord & !LAST_LONG_ENTRY;
+
+                long_name = if count > 0 && count <= MAX_LONG_ENTRIES {
+                    Some(LongName {
+                        pieces:     vec![lfn.chars()],
+                        checksum:   lfn.chksum,
+                        next:       count - 1,
+                    })
+                } else {
+                    None
+                };
+            }
+            else if !long_name.as_mut().is_some_and(|name| name.push(&lfn)) {
+                long_name = None;
+            }
+
+            continue;
+        }
+
+        // Skip the volume label
+        if entry.attr & ATTR_VOLUME_ID != 0 {
+            long_name = None;
+            continue;
+        }
+
+        // Use the long name if it is complete and belongs to this entry, otherwise fall back to the 8.3 name
+        let name = long_name.take().and_then(|name| name.finish(&entry)).unwrap_or_else(|| entry.display_name());
+        if name == "." || name == ".." {
+            continue;
+        }
+
+        let file_type = if entry.attr & ATTR_DIRECTORY != 0 { FileType::Directory } else { FileType::Regular };
+
+        entries.push(ParsedEntry {
+            entry: DirEntry {
+                name,
+                file_type,
+                node: entry,
+            },
+            slot,
+        });
+    }
+
+    entries
+}
+
+
+impl DirectoryEntry {
+    pub fn new_zeroed() -> Self {
+        unsafe { core::mem::zeroed::<Self>() }
+    }
+
+    /// First cluster of the file. The high word is always 0 on FAT12/16.
+    pub fn first_cluster(&self) -> u32 {
+        (self.fst_clus_hi as u32) << 16 | self.fst_clus_lo as u32
+    }
+
+    fn set_first_cluster(&mut self, cluster: u32) {
+        self.fst_clus_hi = (cluster >> 16) as u16;
+        self.fst_clus_lo = cluster as u16;
+    }
+}
+
+
+impl DirectoryEntry {
+    /// Converts the 8.3 name into a readable one, e.g "LOADER  CFG" -> "LOADER.CFG". Honours the lowercase flags set by Windows NT.
+    pub fn display_name(&self) -> String {
+        let mut base: String = self.name[..8].iter().map(|b| *b as char).collect::<String>().trim_end().to_string();
+        let mut ext: String = self.name[8..].iter().map(|b| *b as char).collect::<String>().trim_end().to_string();
+
+        // 0x05 is used in place of a leading 0xE5, as 0xE5 marks a free entry
+        if self.name[0] == 0x05 {
+            base.replace_range(..1, "\u{E5}");
+        }
+
+        if self.nt_rsvd & NT_LOWERCASE_BASE != 0 {
+            base = base.to_lowercase();
+        }
+        if self.nt_rsvd & NT_LOWERCASE_EXT != 0 {
+            ext = ext.to_lowercase();
+        }
+
+        if ext.is_empty() {
+            base
+        }
+        else {
+            format!("{}.{}", base, ext)
+        }
+    }
+}
+
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// Set in the sequence number of the long name entry holding the end of the name, which comes first on disk
+const LAST_LONG_ENTRY: u8 = 0x40;
+/// A long name is at most 255 characters, so 20 entries
+const MAX_LONG_ENTRIES: u8 = 20;
+
+const NT_LOWERCASE_BASE: u8 = 0x08;
+const NT_LOWERCASE_EXT: u8 = 0x10;
+
+
+
+/// Detects whether or not the device contains a FAT filesystem of any kind.
+///
+/// It should be noted that these strings are not used to determine the fat type, as Microsoft states you must determine FAT type using the total count of clusters and nothing else.
+pub fn detect<D: BlockDevice>(dev: &D) -> Result<bool, FsError> {
+
+    // Read the boot sector into memory
+    let bs = {
+        let mut buffer: Vec<u8> = vec![0; 512];
+        dev.read_bytes(0, &mut buffer)?;
+
+        buffer
+    };
+
+
+    // There are 'filesystype' fields in the FAT 12/16 and FAT32 BPB blocks with a string that reads one of: "FAT     ", "FAT12   ", "FAT16   ", or "FAT32   "
+    // We only check for "FAT" for maximum compatibility, as some tools may only put "FAT" in this field. The actual FAT type will be determined later on using the official algorithm.
+    if &bs[54..57] == b"FAT" ||  &bs[82..85] == b"FAT" {
+        return Ok(true);
+    }
+    else {
+        return Ok(false);
+    }
+}
+
+
+/// Uses the official calculation from Microsoft to determine the FAT type
+fn detect_fat_type(bpb: &BIOSParameterBlock) -> FATType {
+    let count_of_clusters = count_of_clusters(bpb);
+
+    if count_of_clusters < 4085 {
+        return FATType::FAT12;
+    }
+    else if count_of_clusters < 65525 {
+        return FATType::FAT16;
+    }
+    else {
+        return FATType::FAT32;
+    }
+}
+
+
+/// Number of clusters in the data region. Valid cluster numbers are 2 to count + 1.
+fn count_of_clusters(bpb: &BIOSParameterBlock) -> u32 {
+    let root_dir_sectors = (bpb.rootentcnt as u32 * 32).div_ceil(bpb.bytspersec as u32);
+
+    let fat_size: u32;
+    let total_sectors;
+
+    if bpb.fatsz16 != 0 {
+        fat_size = bpb.fatsz16.into();
+    }
+    else {
+        fat_size = bpb.fatzs32;
+    }
+
+    if bpb.totsec16 != 0 {
+        total_sectors = bpb.totsec16.into();
+    }
+    else {
+        total_sectors = bpb.totsec32;
+    }
+
+    let data_sectors = total_sectors.saturating_sub(bpb.rsvdseccnt as u32 + (bpb.numfats as u32 * fat_size) + root_dir_sectors);
+
+    data_sectors / bpb.secperclus as u32
+}
+
+
+
+
+/// Finds the first sector of cluster 'cluster_number
+const fn find_first_sector_of_cluster(bpb: &BIOSParameterBlock, cluster_number: u32) -> u32 {
+    let root_dir_sectors = (bpb.rootentcnt as u32 * 32).div_ceil(bpb.bytspersec as u32);
+
+    let fat_size: u32;
+
+    if bpb.fatsz16 != 0 {
+        fat_size = bpb.fatsz16 as u32;
+    }
+    else {
+        fat_size = bpb.fatzs32;
+    }
+
+    let first_data_sector: u32 = bpb.rsvdseccnt as u32 + (bpb.numfats as u32 * fat_size) + root_dir_sectors;
+
+
+    ((cluster_number - 2) * bpb.secperclus as u32) + first_data_sector
+}
+
+
+
+
+/// Determines whether the cluster contains the EOF mark or not
+const fn is_eof(fat_type: FATType, fat_content: u32) -> bool {
+    match fat_type {
+        FATType::FAT12 => {
+            if fat_content >= 0x0FF8 {
+                return true;
+            }
+            else {
+                return false;
+            }
+        }
+
+        FATType::FAT16 => {
+            if fat_content >= 0xFFF8 {
+                return true;
+            }
+            else {
+                return false;
+            }
+        }
+
+        FATType::FAT32 => {
+            if fat_content >= 0x0FFFFFF8 {
+                return true;
+            }
+            else {
+                return false;
+            }
+        }
+    }
+}
+
+
+
+
+/// Number of FAT sectors kept in memory. Following a chain touches the same few sectors over and over.
+const FAT_CACHE_SECTORS: usize = 32;
+
+/// Cluster values with special meanings
+const CLUSTER_FREE: u32 = 0;
+const CLUSTER_BAD32: u32 = 0x0FFFFFF7;
+const CLUSTER_EOC32: u32 = 0x0FFFFFFF;
+
+/// FSInfo signatures, and the value used for free_count and nxt_free when they are unknown
+const FSINFO_LEAD_SIG: u32 = 0x41615252;
+const FSINFO_STRUC_SIG: u32 = 0x61417272;
+const FSINFO_TRAIL_SIG: u32 = 0xAA550000;
+const FSINFO_UNKNOWN: u32 = 0xFFFFFFFF;
+
+/// Characters allowed in 8.3 names besides letters and digits
+const SHORT_NAME_CHARS: &[u8] = b"!#$%&'()-@^_`{}~";
+
+/// Characters never allowed in a file name, besides control characters
+const INVALID_NAME_CHARS: &str = "\"*/:<>?\\|";
+
+
+/// Allocation hints from FSInfo, which only FAT32 has. On FAT12/16 they live in memory only.
+#[derive(Clone, Copy)]
+struct AllocHints {
+    /// Number of free clusters, or FSINFO_UNKNOWN
+    free_count: u32,
+    /// Where to start looking for a free cluster
+    next_free:  u32,
+    /// Sector holding FSInfo, if the volume has a valid one
+    sector:     Option<u64>,
+}
+
+
+
+/// A mounted FAT filesystem
+pub struct FatFs<D: BlockDevice> {
+    dev:        D,
+    bpb:        BIOSParameterBlock,
+    fat_type:   FATType,
+    /// Recently used sectors of the first FAT
+    fat_cache:  FatCache,
+    hints:      Cell<AllocHints>,
+}
+
+impl<D: BlockDevice> FatFs<D> {
+    pub fn new(dev: D) -> Result<Self, FsError> {
+        let bpb: BIOSParameterBlock = block::read_struct(&dev, 0)?;
+
+        // Everything else is computed from these, so make sure they won't cause a division by zero or nonsensical sizes
+        let bytspersec = bpb.bytspersec;
+        if !bytspersec.is_power_of_two() || !(512..=4096).contains(&bytspersec) {
+            return Err(FsError::Corrupt("invalid bytes per sector"));
+        }
+        if !bpb.secperclus.is_power_of_two() {
+            return Err(FsError::Corrupt("invalid sectors per cluster"));
+        }
+        if bpb.numfats == 0 || (bpb.fatsz16 == 0 && bpb.fatzs32 == 0) {
+            return Err(FsError::Corrupt("no FAT"));
+        }
+
+        let fat_type = detect_fat_type(&bpb);
+        let hints = read_fsinfo(&dev, &bpb, fat_type)?;
+
+        Ok(Self {
+            dev,
+            bpb,
+            fat_type,
+            fat_cache:  FatCache::new(bytspersec as u64, FAT_CACHE_SECTORS),
+            hints:      Cell::new(hints),
+        })
+    }
+
+
+    /// Reads the volume ID and label from the boot sector. Their location depends on the FAT type.
+    pub fn volume_id(&self) -> Result<(u128, String), FsError> {
+        let bs = {
+            let mut buffer: Vec<u8> = vec![0; 512];
+            self.dev.read_bytes(0, &mut buffer)?;
+
+            buffer
+        };
+
+        let offset = match self.fat_type {
+            FATType::FAT32  => 67,
+            _               => 39,
+        };
+
+        let id = u32::from_le_bytes(bs[offset..offset + 4].try_into().unwrap());
+        let label: String = bs[offset + 4..offset + 15].iter().map(|b| *b as char).collect();
+
+        // Formatting tools write "NO NAME" when the user didn't give a label
+        let label = label.trim_end();
+        let label = if label == "NO NAME" { "" } else { label };
+
+        Ok((id as u128, label.to_string()))
+    }
+
+
+    /// Lists the clusters holding a file as extents counted in clusters
+    pub fn extents(&self, entry: &DirectoryEntry) -> Result<Vec<Extent>, FsError> {
+        Ok(fattable::chain_extents(&self.cluster_chain(entry.first_cluster())?))
+    }
+
+
+    /// Reads the FAT entry for *cluster*, i.e the next cluster in its chain
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        // FAT12 entries are 1.5 bytes, so two bytes are read and the right 12 bits picked out
+        let offset = match self.fat_type {
+            FATType::FAT12  => cluster as u64 + cluster as u64 / 2,
+            FATType::FAT16  => cluster as u64 * 2,
+            FATType::FAT32  => cluster as u64 * 4,
+        };
+
+        let mut bytes = [0u8; 4];
+        let len = if self.fat_type == FATType::FAT32 { 4 } else { 2 };
+        self.read_fat(offset, &mut bytes[..len])?;
+        let value = u32::from_le_bytes(bytes);
+
+        match self.fat_type {
+            FATType::FAT12 if cluster & 1 == 1  => Ok(value >> 4),
+            FATType::FAT12                      => Ok(value & 0x0FFF),
+            FATType::FAT16                      => Ok(value),
+            // The top 4 bits are reserved
+            FATType::FAT32                      => Ok(value & 0x0FFFFFFF),
+        }
+    }
+
+
+    /// Reads bytes from the first FAT, starting *offset* bytes into it, through the sector cache
+    fn read_fat(&self, offset: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        self.fat_cache.read(self, offset, buffer)
+    }
+
+
+    /// Writes bytes into the first FAT, starting *offset* bytes into it. They only reach the disk, in every FAT, on eviction or sync().
+    fn write_fat(&self, offset: u64, bytes: &[u8]) -> Result<(), FsError> {
+        self.fat_cache.write(self, offset, bytes)
+    }
+
+
+    /// Byte offset of sector *sector* of FAT number *fat*
+    fn fat_sector_offset(&self, fat: u64, sector: u64) -> u64 {
+        let bpb = &self.bpb;
+        let fat_size = if bpb.fatsz16 != 0 { bpb.fatsz16 as u64 } else { bpb.fatzs32 as u64 };
+
+        (bpb.rsvdseccnt as u64 + fat * fat_size + sector) * bpb.bytspersec as u64
+    }
+
+
+    /// Follows the chain starting at *first_cluster* through the FAT and returns every cluster in it. A first cluster of 0 is an empty file.
+    fn cluster_chain(&self, first_cluster: u32) -> Result<Vec<u32>, FsError> {
+        if first_cluster == CLUSTER_FREE {
+            return Ok(Vec::new());
+        }
+
+        fattable::cluster_chain(first_cluster, count_of_clusters(&self.bpb), |cluster| {
+            let next = self.fat_entry(cluster)?;
+
+            if is_eof(self.fat_type, next) {
+                return Ok(None);
+            }
+            else if next == CLUSTER_FREE || next == self.bad_cluster() {
+                return Err(FsError::Corrupt("cluster chain runs into a free or bad cluster"));
+            }
+            else {
+                return Ok(Some(next));
+            }
+        })
+    }
+
+
+    /// Reads the raw contents of a directory, along with the byte offset on disk of each of its 32 byte slots
+    fn read_dir_slots(&self, dir: &DirectoryEntry) -> Result<(Vec<u8>, Vec<u64>), FsError> {
+        // The FAT12/16 root directory sits right after the last FAT and holds *rootentcnt* entries. Other directories have no size of their
+        // own, their length is that of their cluster chain.
+        let ranges: Vec<(u64, usize)> = if dir.first_cluster() == 0 && self.fat_type != FATType::FAT32 {
+            vec![(self.fat_sector_offset(self.bpb.numfats as u64, 0), self.bpb.rootentcnt as usize * size_of::<DirectoryEntry>())]
+        } else {
+            let cluster_size = self.cluster_size() as usize;
+
+            self.cluster_chain(dir.first_cluster())?.into_iter()
+                .map(|cluster| Ok((self.cluster_offset(cluster)?, cluster_size)))
+                .collect::<Result<_, FsError>>()?
+        };
+
+        let mut buffer: Vec<u8> = vec![0; ranges.iter().map(|(_, len)| len).sum()];
+        let mut offsets = Vec::new();
+        let mut pos = 0;
+
+        for (offset, len) in ranges {
+            self.dev.read_bytes(offset, &mut buffer[pos..pos + len])?;
+            offsets.extend((0..len / size_of::<DirectoryEntry>()).map(|slot| offset + (slot * size_of::<DirectoryEntry>()) as u64));
+            pos += len;
+        }
+
+        Ok((buffer, offsets))
+    }
+
+
+    /// The FAT value marking a bad cluster
+    fn bad_cluster(&self) -> u32 {
+        match self.fat_type {
+            FATType::FAT12  => CLUSTER_BAD32 & 0x0FFF,
+            FATType::FAT16  => CLUSTER_BAD32 & 0xFFFF,
+            FATType::FAT32  => CLUSTER_BAD32,
+        }
+    }
+
+
+    /// Lists the interesting boot sector fields as name/value pairs, for debugging tools
+    pub fn superblock_info(&self) -> Result<Vec<(&'static str, String)>, FsError> {
+        let bpb = &self.bpb;
+        let (id, label) = self.volume_id()?;
+
+        let fat_type = match self.fat_type {
+            FATType::FAT12  => "FAT12",
+            FATType::FAT16  => "FAT16",
+            FATType::FAT32  => "FAT32",
+        };
+        let total_sectors = if bpb.totsec16 != 0 { bpb.totsec16 as u32 } else { bpb.totsec32 };
+        let fat_size = if bpb.fatsz16 != 0 { bpb.fatsz16 as u32 } else { bpb.fatzs32 };
+
+        let mut info = vec![
+            ("FAT type",                fat_type.to_string()),
+            ("OEM name",                bpb.oem_name.iter().map(|b| *b as char).collect::<String>().trim_end().to_string()),
+            ("Volume ID",               format!("{:04X}-{:04X}", id >> 16, id & 0xFFFF)),
+            ("Volume label",            label),
+            ("Bytes per sector",        { bpb.bytspersec }.to_string()),
+            ("Sectors per cluster",     bpb.secperclus.to_string()),
+            ("Reserved sectors",        { bpb.rsvdseccnt }.to_string()),
+            ("Number of FATs",          bpb.numfats.to_string()),
+            ("Sectors per FAT",         fat_size.to_string()),
+            ("Root entries",            { bpb.rootentcnt }.to_string()),
+            ("Total sectors",           total_sectors.to_string()),
+            ("Clusters",                count_of_clusters(bpb).to_string()),
+        ];
+
+        // FAT12/16 have their own extended boot record where these fields would be
+        if self.fat_type == FATType::FAT32 {
+            info.push(("Root cluster",  { bpb.rootclus }.to_string()));
+        }
+
+        Ok(info)
+    }
+
+
+    /// Size of a cluster in bytes
+    fn cluster_size(&self) -> u64 {
+        self.bpb.secperclus as u64 * self.bpb.bytspersec as u64
+    }
+
+
+    /// Byte offset of the first sector of *cluster*
+    fn cluster_offset(&self, cluster: u32) -> Result<u64, FsError> {
+        if cluster < 2 {
+            return Err(FsError::Corrupt("reference to a reserved cluster"));
+        }
+
+        Ok(find_first_sector_of_cluster(&self.bpb, cluster) as u64 * self.bpb.bytspersec as u64)
+    }
+}
+
+
+impl<D: BlockDevice> FatTable for FatFs<D> {
+    fn read_table_sector(&self, sector: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        Ok(self.dev.read_bytes(self.fat_sector_offset(0, sector), buffer)?)
+    }
+
+    /// Writes the sector to the same place in every FAT, so the copies stay identical
+    fn write_table_sector(&self, sector: u64, data: &[u8]) -> Result<(), FsError> {
+        for fat in 0..self.bpb.numfats as u64 {
+            self.dev.write_bytes(self.fat_sector_offset(fat, sector), data)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+impl<D: BlockDevice> Filesystem for FatFs<D> {
+    type Node = DirectoryEntry;
+
+    fn case_rule(&self) -> CaseRule {
+        CaseRule::Insensitive
+    }
+
+    fn root(&self) -> Result<DirEntry<DirectoryEntry>, FsError> {
+        // The root directory has no entry of its own, so we make one up pointing at its first cluster.
+        // On FAT12/16 it lives in a fixed region before the data clusters, which is marked by cluster 0 like in ".." entries.
+        let mut root = DirectoryEntry::new_zeroed();
+        root.attr = ATTR_DIRECTORY;
+
+        if self.fat_type == FATType::FAT32 {
+            root.fst_clus_lo = self.bpb.rootclus as u16;
+            root.fst_clus_hi = (self.bpb.rootclus >> 16) as u16;
+        }
+
+        Ok(DirEntry {
+            name:       "/".to_string(),
+            file_type:  FileType::Directory,
+            node:       root,
+        })
+    }
+
+    /// Reads the entries of a directory. Entries with a valid long name are returned under it, the rest under their 8.3 name.
+    fn read_dir(&self, dir: &DirectoryEntry) -> Result<Vec<DirEntry<DirectoryEntry>>, FsError> {
+        let (raw_entries, _) = self.read_dir_slots(dir)?;
+
+        Ok(parse_dir(&raw_entries).into_iter().map(|parsed| parsed.entry).collect())
+    }
+
+    /// Matches *name* against long names first, then falls back to the 8.3 name so "KERNEL~1.ELF" still works
+    fn lookup(&self, dir: &DirectoryEntry, name: &str) -> Result<Option<DirEntry<DirectoryEntry>>, FsError> {
+        let entries = self.read_dir(dir)?;
+
+        if let Some(pos) = entries.iter().position(|entry| CaseRule::Insensitive.matches(&entry.name, name)) {
+            return Ok(Some(entries[pos].clone()));
+        }
+        else {
+            return Ok(entries.into_iter().find(|entry| CaseRule::Insensitive.matches(&entry.node.display_name(), name)));
+        }
+    }
+
+    fn metadata(&self, node: &DirectoryEntry) -> Result<Metadata, FsError> {
+        let is_dir = node.attr & ATTR_DIRECTORY != 0;
+
+        // FAT has no permissions, only a read only flag
+        let mut mode = if is_dir { 0o755 } else { 0o644 };
+        if node.attr & ATTR_READ_ONLY != 0 {
+            mode &= 0o555;
+        }
+
+        Ok(Metadata {
+            file_type:  if is_dir { FileType::Directory } else { FileType::Regular },
+            size:       node.filesize as u64,
+            mode,
+            uid:        0,
+            gid:        0,
+            mtime:      dos_to_unix_time(node.wrt_date, node.wrt_time),
+            id:         node.first_cluster() as u64,
+        })
+    }
+
+    fn read(&self, node: &DirectoryEntry, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let filesize = node.filesize as u64;
+        if offset >= filesize {
+            return Ok(0);
+        }
+
+        let len = buffer.len().min((filesize - offset) as usize);
+        let cluster_size = self.cluster_size();
+
+        // Contiguous clusters are read in one go, which matters for kernels and initrds
+        let mut done = 0;
+        for extent in self.extents(node)? {
+            let extent_start = extent.logical * cluster_size;
+            let extent_end = extent_start + extent.length * cluster_size;
+            let pos = offset + done as u64;
+
+            if pos >= extent_end {
+                continue;
+            }
+
+            let count = ((extent_end - pos) as usize).min(len - done);
+            let disk_offset = self.cluster_offset(extent.physical as u32)? + (pos - extent_start);
+            self.dev.read_bytes(disk_offset, &mut buffer[done..done + count])?;
+
+            done += count;
+            if done == len {
+                break;
+            }
+        }
+
+        // The chain must cover the whole size recorded in the directory entry
+        if done < len {
+            return Err(FsError::Corrupt("cluster chain is shorter than the file"));
+        }
+
+        Ok(len)
+    }
+}
+
+
+
+/* Write support */
+
+impl<D: BlockDevice> FatFs<D> {
+
+    /// Creates the file at *path*, or replaces the contents of an existing one, then syncs. Its parent directory must exist.
+    ///
+    /// *mtime* is in seconds since the UNIX epoch, but is stored as is, since FAT timestamps are in local time.
+    pub fn write_file(&self, path: &str, contents: &[u8], mtime: i64) -> Result<(), FsError> {
+        let (parent_path, name) = split_parent(path)?;
+        let parent = fs::resolve(self, parent_path)?;
+        if parent.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+
+        if contents.len() > u32::MAX as usize {
+            return Err(FsError::Unsupported("files of 4 GiB or more"));
+        }
+        let cluster_count = contents.len().div_ceil(self.cluster_size() as usize);
+        let (date, time) = unix_to_dos_time(mtime);
+
+        let (raw_entries, offsets) = self.read_dir_slots(&parent.node)?;
+        let parsed = parse_dir(&raw_entries);
+        let existing = parsed.iter().find(|parsed| CaseRule::Insensitive.matches(&parsed.entry.name, name))
+            .or_else(|| parsed.iter().find(|parsed| CaseRule::Insensitive.matches(&parsed.entry.node.display_name(), name)));
+
+        match existing {
+            Some(parsed) => {
+                let mut entry = parsed.entry.node;
+                if entry.attr & ATTR_DIRECTORY != 0 {
+                    return Err(FsError::IsADirectory);
+                }
+
+                // Reuse the clusters the file already has, then only the directory entry needs updating
+                let chain = self.resize_chain(entry.first_cluster(), cluster_count)?;
+                self.write_clusters(&chain, contents)?;
+
+                entry.set_first_cluster(chain.first().copied().unwrap_or(0));
+                entry.filesize = contents.len() as u32;
+                entry.wrt_date = date;
+                entry.wrt_time = time;
+                entry.lst_acc_date = date;
+
+                self.dev.write_bytes(offsets[parsed.slot], &block::struct_to_bytes(&entry))?;
+            }
+
+            None => {
+                let taken: Vec<[u8; 11]> = raw_entries.chunks_exact(size_of::<DirectoryEntry>())
+                    .map(block::struct_from_bytes::<DirectoryEntry>)
+                    .filter(|entry| entry.name[0] != 0x00 && entry.name[0] != 0xE5 && entry.attr & ATTR_LONG_NAME != ATTR_LONG_NAME)
+                    .map(|entry| entry.name)
+                    .collect();
+                let (short, needs_long_name) = make_short_name(name, &taken)?;
+
+                let chain = self.resize_chain(0, cluster_count)?;
+
+                let mut entry = DirectoryEntry::new_zeroed();
+                entry.name = short;
+                entry.set_first_cluster(chain.first().copied().unwrap_or(0));
+                entry.filesize = contents.len() as u32;
+                entry.crt_date = date;
+                entry.crt_time = time;
+                entry.wrt_date = date;
+                entry.wrt_time = time;
+                entry.lst_acc_date = date;
+
+                let mut slots: Vec<Vec<u8>> = Vec::new();
+                if needs_long_name {
+                    slots.extend(long_name_entries(name, &short).iter().map(block::struct_to_bytes));
+                }
+                slots.push(block::struct_to_bytes(&entry));
+
+                // Don't leave the clusters allocated if the file can't be added to the directory
+                let result = self.write_clusters(&chain, contents).and_then(|_| self.add_dir_slots(&parent.node, &slots));
+                if let Err(err) = result {
+                    self.free_clusters(&chain)?;
+                    self.sync()?;
+                    return Err(err);
+                }
+            }
+        }
+
+        self.sync()
+    }
+
+
+    /// Writes the changed FAT sectors to every FAT, updates FSInfo and flushes the device
+    pub fn sync(&self) -> Result<(), FsError> {
+        self.fat_cache.flush(self)?;
+
+        let hints = self.hints.get();
+        if let Some(sector) = hints.sector {
+            // free_count and nxt_free sit next to each other, 488 bytes into the sector
+            let mut fields = [0u8; 8];
+            fields[..4].copy_from_slice(&hints.free_count.to_le_bytes());
+            fields[4..].copy_from_slice(&hints.next_free.to_le_bytes());
+
+            self.dev.write_bytes(sector * self.bpb.bytspersec as u64 + 488, &fields)?;
+        }
+
+        self.dev.flush()?;
+        Ok(())
+    }
+
+
+    /// Writes *contents* into the clusters of *chain*, which must be long enough to hold it
+    fn write_clusters(&self, chain: &[u32], contents: &[u8]) -> Result<(), FsError> {
+        for (cluster, chunk) in chain.iter().zip(contents.chunks(self.cluster_size() as usize)) {
+            self.dev.write_bytes(self.cluster_offset(*cluster)?, chunk)?;
+        }
+
+        Ok(())
+    }
+
+
+    /// Puts *slots* into consecutive free slots of *dir*, growing it by a cluster at a time if there is no room
+    fn add_dir_slots(&self, dir: &DirectoryEntry, slots: &[Vec<u8>]) -> Result<(), FsError> {
+        loop {
+            let (raw_entries, offsets) = self.read_dir_slots(dir)?;
+
+            if let Some(start) = find_free_slots(&raw_entries, slots.len()) {
+                for (i, slot) in slots.iter().enumerate() {
+                    self.dev.write_bytes(offsets[start + i], slot)?;
+                }
+
+                return Ok(());
+            }
+
+            // The FAT12/16 root directory can't grow
+            if dir.first_cluster() == 0 && self.fat_type != FATType::FAT32 {
+                return Err(FsError::NoSpace);
+            }
+
+            // New directory clusters must be zeroed so they read as the end of the directory
+            let length = self.cluster_chain(dir.first_cluster())?.len();
+            let chain = self.resize_chain(dir.first_cluster(), length + 1)?;
+            let cluster_size = self.cluster_size() as usize;
+            self.dev.write_bytes(self.cluster_offset(*chain.last().unwrap())?, &vec![0; cluster_size])?;
+        }
+    }
+
+
+    /// Makes the chain starting at *first_cluster* exactly *count* clusters long, freeing or allocating clusters at its end. Returns the new
+    /// chain, whose first cluster is different from *first_cluster* if the chain was empty before or is now.
+    fn resize_chain(&self, first_cluster: u32, count: usize) -> Result<Vec<u32>, FsError> {
+        let mut chain = self.cluster_chain(first_cluster)?;
+
+        if chain.len() > count {
+            self.free_clusters(&chain[count..])?;
+            chain.truncate(count);
+
+            if let Some(last) = chain.last() {
+                self.set_fat_entry(*last, self.end_of_chain())?;
+            }
+
+            return Ok(chain);
+        }
+
+        let old_len = chain.len();
+        while chain.len() < count {
+            match self.allocate_cluster() {
+                Ok(cluster) => {
+                    if let Some(last) = chain.last() {
+                        self.set_fat_entry(*last, cluster)?;
+                    }
+                    chain.push(cluster);
+                }
+
+                // Give back what was allocated so far so the chain is left as it was
+                Err(err) => {
+                    self.free_clusters(&chain[old_len..])?;
+                    if old_len > 0 {
+                        self.set_fat_entry(chain[old_len - 1], self.end_of_chain())?;
+                    }
+
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(chain)
+    }
+
+
+    /// Finds a free cluster, starting at the next_free hint, and marks it as the end of a chain
+    fn allocate_cluster(&self) -> Result<u32, FsError> {
+        let cluster_count = count_of_clusters(&self.bpb);
+        let mut hints = self.hints.get();
+
+        let start = if hints.next_free >= 2 && hints.next_free <= cluster_count + 1 { hints.next_free } else { 2 };
+
+        for i in 0..cluster_count {
+            let cluster = 2 + (start - 2 + i) % cluster_count;
+
+            if self.fat_entry(cluster)? == CLUSTER_FREE {
+                self.set_fat_entry(cluster, self.end_of_chain())?;
+
+                hints.next_free = cluster + 1;
+                if hints.free_count != FSINFO_UNKNOWN {
+                    hints.free_count = hints.free_count.saturating_sub(1);
+                }
+                self.hints.set(hints);
+
+                return Ok(cluster);
+            }
+        }
+
+        Err(FsError::NoSpace)
+    }
+
+
+    /// Marks *clusters* as free
+    fn free_clusters(&self, clusters: &[u32]) -> Result<(), FsError> {
+        for cluster in clusters {
+            self.set_fat_entry(*cluster, CLUSTER_FREE)?;
+        }
+
+        let mut hints = self.hints.get();
+        if hints.free_count != FSINFO_UNKNOWN {
+            hints.free_count += clusters.len() as u32;
+        }
+        self.hints.set(hints);
+
+        Ok(())
+    }
+
+
+    /// Sets the FAT entry for *cluster*
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        match self.fat_type {
+            // Two entries share the middle byte of every 3, so only the right 12 bits are replaced
+            FATType::FAT12 => {
+                let offset = cluster as u64 + cluster as u64 / 2;
+                let mut bytes = [0u8; 2];
+                self.read_fat(offset, &mut bytes)?;
+
+                let old = u16::from_le_bytes(bytes);
+                let new = if cluster & 1 == 1 {
+                    (old & 0x000F) | ((value as u16) << 4)
+                } else {
+                    (old & 0xF000) | (value as u16 & 0x0FFF)
+                };
+
+                self.write_fat(offset, &new.to_le_bytes())
+            }
+
+            FATType::FAT16 => self.write_fat(cluster as u64 * 2, &(value as u16).to_le_bytes()),
+
+            // The top 4 bits are reserved and must be kept
+            FATType::FAT32 => {
+                let mut bytes = [0u8; 4];
+                self.read_fat(cluster as u64 * 4, &mut bytes)?;
+
+                let new = (u32::from_le_bytes(bytes) & 0xF0000000) | (value & 0x0FFFFFFF);
+                self.write_fat(cluster as u64 * 4, &new.to_le_bytes())
+            }
+        }
+    }
+
+
+    /// The FAT value marking the end of a chain
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FATType::FAT12  => CLUSTER_EOC32 & 0x0FFF,
+            FATType::FAT16  => CLUSTER_EOC32 & 0xFFFF,
+            FATType::FAT32  => CLUSTER_EOC32,
+        }
+    }
+}
+
+
+
+/// Reads the allocation hints from FSInfo. Volumes without a valid FSInfo sector get hints that are only kept in memory.
+fn read_fsinfo<D: BlockDevice>(dev: &D, bpb: &BIOSParameterBlock, fat_type: FATType) -> Result<AllocHints, FsError> {
+    let mut hints = AllocHints {
+        free_count: FSINFO_UNKNOWN,
+        next_free:  2,
+        sector:     None,
+    };
+
+    if fat_type != FATType::FAT32 || bpb.fsinfo == 0 || bpb.fsinfo >= bpb.rsvdseccnt {
+        return Ok(hints);
+    }
+
+    let sector = bpb.fsinfo as u64;
+    let info: FSInfo = block::read_struct(dev, sector * bpb.bytspersec as u64)?;
+
+    if u32::from_le(info.leadsig) == FSINFO_LEAD_SIG && u32::from_le(info.strucsig) == FSINFO_STRUC_SIG && u32::from_le(info.trailsig) == FSINFO_TRAIL_SIG {
+        hints.sector = Some(sector);
+        hints.free_count = u32::from_le(info.free_count);
+
+        if u32::from_le(info.nxt_free) != FSINFO_UNKNOWN {
+            hints.next_free = u32::from_le(info.nxt_free);
+        }
+    }
+
+    Ok(hints)
+}
+
+
+/// Splits *path* into its parent directory and final name
+fn split_parent(path: &str) -> Result<(&str, &str), FsError> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidName);
+    }
+
+    Ok((parent, name))
+}
+
+
+/// Makes the 8.3 name for *name*, avoiding the ones in *taken*. Also returns whether long name entries are needed to keep the original
+/// name, which is the case unless it already is an upper case 8.3 name.
+fn make_short_name(name: &str, taken: &[[u8; 11]]) -> Result<([u8; 11], bool), FsError> {
+    let units = name.encode_utf16().count();
+
+    if units > 255 || name.ends_with('.') || name.ends_with(' ') || name.chars().any(|c| c < ' ' || INVALID_NAME_CHARS.contains(c)) {
+        return Err(FsError::InvalidName);
+    }
+
+    // Leading dots are dropped, and the extension is whatever follows the last remaining dot
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = match trimmed.rfind('.') {
+        Some(dot)   => (&trimmed[..dot], &trimmed[dot + 1..]),
+        None        => (trimmed, ""),
+    };
+
+    // Characters that can't be in a short name are dropped (spaces and dots) or replaced, either way the short name loses information
+    let mut lossy = trimmed.len() != name.len();
+    let mut convert = |part: &str, max: usize| -> Vec<u8> {
+        let mut out = Vec::new();
+
+        for c in part.chars() {
+            if c == ' ' || c == '.' {
+                lossy = true;
+            }
+            else if c.is_ascii_alphanumeric() || (c.is_ascii() && SHORT_NAME_CHARS.contains(&(c as u8))) {
+                out.push(c.to_ascii_uppercase() as u8);
+            }
+            else {
+                out.push(b'_');
+                lossy = true;
+            }
+        }
+
+        if out.len() > max {
+            out.truncate(max);
+            lossy = true;
+        }
+        out
+    };
+
+    let mut base = convert(base, 8);
+    let ext = convert(ext, 3);
+    if base.is_empty() {
+        base.push(b'_');
+        lossy = true;
+    }
+
+    let build = |base: &[u8]| -> [u8; 11] {
+        let mut short = [b' '; 11];
+        short[..base.len()].copy_from_slice(base);
+        short[8..8 + ext.len()].copy_from_slice(&ext);
+        short
+    };
+
+    if !lossy {
+        let short = build(&base);
+
+        if !taken.contains(&short) {
+            let exact = DirectoryEntry { name: short, ..DirectoryEntry::new_zeroed() }.display_name() == name;
+            return Ok((short, !exact));
+        }
+    }
+
+    // Otherwise add a numeric tail, e.g "KERNEL~1.ELF"
+    for n in 1..1000000 {
+        let tail = format!("~{}", n);
+        let mut tailed = base.clone();
+        tailed.truncate(8 - tail.len());
+        tailed.extend_from_slice(tail.as_bytes());
+
+        let short = build(&tailed);
+        if !taken.contains(&short) {
+            return Ok((short, true));
+        }
+    }
+
+    Err(FsError::NoSpace)
+}
+
+
+/// Builds the long name entries for *name*, in the order they go on disk
+fn long_name_entries(name: &str, short: &[u8; 11]) -> Vec<LongNameEntry> {
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(13);
+    let checksum = short_name_checksum(short);
+
+    // The last piece of the name comes first. The name is NUL terminated, unless it exactly fills the last piece, then padded with 0xFFFF.
+    (0..count).rev().map(|n| {
+        let mut chars = [0xFFFFu16; 13];
+        for (i, c) in chars.iter_mut().enumerate() {
+            let index = n * 13 + i;
+
+            if index < units.len() {
+                *c = units[index];
+            }
+            else if index == units.len() {
+                *c = 0;
+            }
+        }
+        let chars = chars.map(u16::to_le);
+
+        LongNameEntry {
+            ord:            (n + 1) as u8 | if n == count - 1 { LAST_LONG_ENTRY } else { 0 },
+            name1:          chars[..5].try_into().unwrap(),
+            attr:           ATTR_LONG_NAME,
+            lfn_type:       0,
+            chksum:         checksum,
+            name2:          chars[5..11].try_into().unwrap(),
+            fst_clus_lo:    0,
+            name3:          chars[11..].try_into().unwrap(),
+        }
+    }).collect()
+}
+
+
+/// Finds *count* consecutive free slots in a directory. Slots past the end marker are all free.
+fn find_free_slots(raw_entries: &[u8], count: usize) -> Option<usize> {
+    let total = raw_entries.len() / size_of::<DirectoryEntry>();
+    let mut run = 0;
+
+    for (slot, raw) in raw_entries.chunks_exact(size_of::<DirectoryEntry>()).enumerate() {
+        if raw[0] == 0x00 {
+            return if run + total - slot >= count { Some(slot - run) } else { None };
+        }
+        else if raw[0] == 0xE5 {
+            run += 1;
+            if run == count {
+                return Some(slot + 1 - count);
+            }
+        }
+        else {
+            run = 0;
+        }
+    }
+
+    None
+}
+
+
+
+/// Converts a DOS date and time, which are local time with 2 second resolution, to seconds since the UNIX epoch
+pub(crate) fn dos_to_unix_time(date: u16, time: u16) -> i64 {
+    DateTime {
+        year:   1980 + (date >> 9) as i64,
+        month:  ((date >> 5) & 0xF).clamp(1, 12) as u8,
+        day:    (date & 0x1F).max(1) as u8,
+        hour:   (time >> 11) as u8,
+        minute: ((time >> 5) & 0x3F) as u8,
+        second: ((time & 0x1F) * 2) as u8,
+    }.to_unix()
+}
+
+
+/// Converts seconds since the UNIX epoch to a DOS (date, time). Times outside 1980 to 2107 are clamped to the nearest one DOS can store.
+fn unix_to_dos_time(time: i64) -> (u16, u16) {
+    let dt = DateTime::from_unix(time);
+
+    if dt.year < 1980 {
+        return ((1 << 5) | 1, 0);
+    }
+    else if dt.year > 2107 {
+        return ((127 << 9) | (12 << 5) | 31, (23 << 11) | (59 << 5) | 29);
+    }
+    else {
+        let date = ((dt.year - 1980) as u16) << 9 | (dt.month as u16) << 5 | dt.day as u16;
+        let time = (dt.hour as u16) << 11 | (dt.minute as u16) << 5 | (dt.second / 2) as u16;
+
+        return (date, time);
+    }
+}
@@ -0,0 +1,172 @@
+/*  fattable.rs - Cluster table shared by the FAT and exFAT drivers
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! FAT and exFAT both chain the clusters of a file through a table with an entry per cluster. This holds what the two drivers share
+//! about it: a cache of its sectors, as following a chain reads the same few over and over, and the walk along a chain.
+
+use core::cell::RefCell;
+use alloc::{vec::Vec, vec};
+use crate::error::FsError;
+use crate::fs::Extent;
+
+
+/// Where a FatCache reads sectors of the table from and writes changed ones back to
+pub trait FatTable {
+    /// Reads sector *sector*, counted from the start of the table, into *buffer*
+    fn read_table_sector(&self, sector: u64, buffer: &mut [u8]) -> Result<(), FsError>;
+
+    /// Writes changed sector *sector* back, to every copy of the table the volume keeps
+    fn write_table_sector(&self, sector: u64, data: &[u8]) -> Result<(), FsError>;
+}
+
+
+/// A sector of the table held in memory
+struct CachedSector {
+    /// Sector number counted from the start of the table
+    sector: u64,
+    data:   Vec<u8>,
+    /// Changed since it was read, so it has to be written back
+    dirty:  bool,
+}
+
+
+/// The most recently used sectors of a table. Changes stay in memory until the sector is evicted or the cache flushed.
+pub struct FatCache {
+    /// Most recently used last, so the least recently used one is evicted first
+    sectors:        RefCell<Vec<CachedSector>>,
+    sector_size:    u64,
+    capacity:       usize,
+}
+
+impl FatCache {
+    /// Creates an empty cache holding up to *capacity* sectors of *sector_size* bytes
+    pub fn new(sector_size: u64, capacity: usize) -> Self {
+        Self {
+            sectors:        RefCell::new(Vec::new()),
+            sector_size,
+            capacity,
+        }
+    }
+
+
+    /// Reads bytes from the table, starting *offset* bytes into it
+    pub fn read(&self, table: &impl FatTable, offset: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        // A FAT12 entry can straddle two sectors, so go byte by byte through whichever sector holds each one
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            let pos = offset + i as u64;
+            *byte = self.with_sector(table, pos / self.sector_size, false, |data| data[(pos % self.sector_size) as usize])?;
+        }
+
+        Ok(())
+    }
+
+
+    /// Writes bytes into the table, starting *offset* bytes into it. They only reach the disk on eviction or flush().
+    pub fn write(&self, table: &impl FatTable, offset: u64, bytes: &[u8]) -> Result<(), FsError> {
+        for (i, byte) in bytes.iter().enumerate() {
+            let pos = offset + i as u64;
+            self.with_sector(table, pos / self.sector_size, true, |data| data[(pos % self.sector_size) as usize] = *byte)?;
+        }
+
+        Ok(())
+    }
+
+
+    /// Writes every changed sector back
+    pub fn flush(&self, table: &impl FatTable) -> Result<(), FsError> {
+        for cached in self.sectors.borrow_mut().iter_mut().filter(|cached| cached.dirty) {
+            table.write_table_sector(cached.sector, &cached.data)?;
+            cached.dirty = false;
+        }
+
+        Ok(())
+    }
+
+
+    /// Runs *f* on the cached copy of sector *sector*, reading it in first if needed. If *dirty* is set the sector is marked as changed.
+    fn with_sector<T>(&self, table: &impl FatTable, sector: u64, dirty: bool, f: impl FnOnce(&mut [u8]) -> T) -> Result<T, FsError> {
+        let mut sectors = self.sectors.borrow_mut();
+
+        let pos = match sectors.iter().position(|cached| cached.sector == sector) {
+            Some(pos)   => pos,
+            None        => {
+                let mut data: Vec<u8> = vec![0; self.sector_size as usize];
+                table.read_table_sector(sector, &mut data)?;
+
+                if sectors.len() >= self.capacity {
+                    let evicted = sectors.remove(0);
+                    if evicted.dirty {
+                        table.write_table_sector(evicted.sector, &evicted.data)?;
+                    }
+                }
+                sectors.push(CachedSector { sector, data, dirty: false });
+                sectors.len() - 1
+            }
+        };
+
+        let mut cached = sectors.remove(pos);
+        let result = f(&mut cached.data);
+        cached.dirty |= dirty;
+        sectors.push(cached);
+
+        Ok(result)
+    }
+}
+
+
+
+/// Follows the chain starting at *first* and returns every cluster in it. *next* returns the cluster after the one it's given, or None
+/// at the end of the chain. Clusters are numbered from 2 to *cluster_count* + 1.
+pub fn cluster_chain(first: u32, cluster_count: u32, mut next: impl FnMut(u32) -> Result<Option<u32>, FsError>) -> Result<Vec<u32>, FsError> {
+    let mut chain = Vec::new();
+    let mut cluster = first;
+
+    loop {
+        if cluster < 2 || cluster > cluster_count + 1 {
+            return Err(FsError::Corrupt("cluster chain points outside the volume"));
+        }
+
+        chain.push(cluster);
+
+        // A chain can't be longer than the number of clusters unless it loops back on itself
+        if chain.len() > cluster_count as usize {
+            return Err(FsError::Corrupt("cluster chain loops"));
+        }
+
+        match next(cluster)? {
+            Some(following) => cluster = following,
+            None            => return Ok(chain),
+        }
+    }
+}
+
+
+/// Merges the runs of consecutive clusters in *chain* into extents counted in clusters
+pub fn chain_extents(chain: &[u32]) -> Vec<Extent> {
+    let mut extents: Vec<Extent> = Vec::new();
+
+    for (i, cluster) in chain.iter().enumerate() {
+        match extents.last_mut() {
+            Some(last) if last.physical + last.length == *cluster as u64 => last.length += 1,
+            _ => extents.push(Extent { logical: i as u64, physical: *cluster as u64, length: 1 }),
+        }
+    }
+
+    extents
+}
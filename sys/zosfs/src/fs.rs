@@ -0,0 +1,222 @@
+/*  fs.rs - Filesystem interface and path resolution
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use alloc::{string::{String, ToString}, vec, vec::Vec};
+use crate::error::FsError;
+
+
+/// Maximum number of symlinks followed while resolving a single path
+const MAX_SYMLINKS: usize = 40;
+
+
+
+/// Type of a node within a filesystem
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    Other,
+}
+
+
+/// How a filesystem compares file names
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CaseRule {
+    Sensitive,
+    Insensitive,
+}
+
+impl CaseRule {
+    /// Compares two file names using this rule
+    pub fn matches(&self, a: &str, b: &str) -> bool {
+        match self {
+            CaseRule::Sensitive     => a == b,
+            CaseRule::Insensitive   => a.to_uppercase() == b.to_uppercase(),
+        }
+    }
+}
+
+
+/// An entry within a directory. *node* is whatever the driver needs to find the entry again (a directory entry, an inode number, ...)
+#[derive(Clone)]
+pub struct DirEntry<N> {
+    pub name:       String,
+    pub file_type:  FileType,
+    pub node:       N,
+}
+
+
+/// Information about a node, as returned by stat
+#[derive(Clone, Copy, Debug)]
+pub struct Metadata {
+    pub file_type:  FileType,
+    pub size:       u64,
+    /// Permission bits, synthesized by filesystems that don't store them
+    pub mode:       u16,
+    pub uid:        u32,
+    pub gid:        u32,
+    /// Last modification time in seconds since the UNIX epoch
+    pub mtime:      i64,
+    /// Inode number or whatever the filesystem uses to identify the node
+    pub id:         u64,
+}
+
+
+/// A run of contiguous blocks holding part of a file. Counted in the filesystem's allocation unit, blocks on EXT and clusters on FAT.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Extent {
+    /// First block within the file
+    pub logical:    u64,
+    /// First block within the volume
+    pub physical:   u64,
+    /// Number of blocks
+    pub length:     u64,
+}
+
+
+/// Operations every filesystem driver provides so paths can be resolved the same way on all of them
+pub trait Filesystem {
+    type Node: Clone;
+
+    /// How the filesystem compares file names
+    fn case_rule(&self) -> CaseRule;
+
+    /// Returns the root directory
+    fn root(&self) -> Result<DirEntry<Self::Node>, FsError>;
+
+    /// Returns every entry in *dir*, excluding "." and ".."
+    fn read_dir(&self, dir: &Self::Node) -> Result<Vec<DirEntry<Self::Node>>, FsError>;
+
+    /// Looks up a single name within *dir*. Drivers with indexed directories can override this.
+    fn lookup(&self, dir: &Self::Node, name: &str) -> Result<Option<DirEntry<Self::Node>>, FsError> {
+        let rule = self.case_rule();
+
+        Ok(self.read_dir(dir)?.into_iter().find(|entry| rule.matches(&entry.name, name)))
+    }
+
+    /// Returns the target of a symlink
+    fn read_link(&self, _node: &Self::Node) -> Result<String, FsError> {
+        Err(FsError::Unsupported("symlinks"))
+    }
+
+    /// Returns the value of the extended attribute with the full name *name*, like "user.comment", or None if the node has no such
+    /// attribute. Filesystems without extended attributes have none.
+    fn get_xattr(&self, _node: &Self::Node, _name: &str) -> Result<Option<Vec<u8>>, FsError> {
+        Ok(None)
+    }
+
+    /// Lists the full names of a node's extended attributes
+    fn list_xattrs(&self, _node: &Self::Node) -> Result<Vec<String>, FsError> {
+        Ok(Vec::new())
+    }
+
+    /// Returns information about a node
+    fn metadata(&self, node: &Self::Node) -> Result<Metadata, FsError>;
+
+    /// Reads file contents starting at byte *offset* into *buffer*. Returns the number of bytes read, which is only less than the size of
+    /// *buffer* at the end of the file.
+    fn read(&self, node: &Self::Node, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError>;
+
+    /// Reads a file's entire contents
+    fn read_to_vec(&self, node: &Self::Node) -> Result<Vec<u8>, FsError> {
+        let size = self.metadata(node)?.size;
+        let mut buffer: Vec<u8> = vec![0; size as usize];
+
+        let read = self.read(node, 0, &mut buffer)?;
+        buffer.truncate(read);
+
+        Ok(buffer)
+    }
+}
+
+
+/// Resolves *path* to a directory entry, starting at the root of *fs*.
+///
+/// "." and ".." are handled here instead of relying on the on-disk entries, repeated slashes are ignored and a trailing slash requires the
+/// final component to be a directory. Symlinks are followed, including the final component, up to MAX_SYMLINKS times.
+pub fn resolve<F: Filesystem>(fs: &F, path: &str) -> Result<DirEntry<F::Node>, FsError> {
+    // Every directory we walked through, so ".." can step back out of it. The root is always at the bottom.
+    let mut stack: Vec<DirEntry<F::Node>> = vec![fs.root()?];
+
+    // Components left to resolve, stored in reverse so the next one can be popped off the end
+    let mut remaining: Vec<String> = split_components(path);
+    let mut must_be_dir = path.ends_with('/');
+    let mut links_followed = 0;
+
+    while let Some(component) = remaining.pop() {
+        // Every component, "." and ".." included, is looked up in the one before it, so that one has to be a directory
+        let current = stack.last().unwrap();
+        if current.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+
+        match component.as_str() {
+            "." => continue,
+
+            ".." => {
+                if stack.len() > 1 {
+                    stack.pop();
+                }
+                continue;
+            }
+
+            _ => {}
+        }
+
+        let current = stack.last().unwrap();
+        let entry = fs.lookup(&current.node, &component)?.ok_or(FsError::NotFound)?;
+
+        if entry.file_type == FileType::Symlink {
+            links_followed += 1;
+            if links_followed > MAX_SYMLINKS {
+                return Err(FsError::SymlinkLoop);
+            }
+
+            let target = fs.read_link(&entry.node)?;
+
+            // Absolute targets restart from the root, relative ones from the directory holding the link
+            if target.starts_with('/') {
+                stack.truncate(1);
+            }
+
+            // The link's target has to be resolved before whatever came after the link
+            if remaining.is_empty() {
+                must_be_dir |= target.ends_with('/');
+            }
+            remaining.extend(split_components(&target));
+            continue;
+        }
+
+        stack.push(entry);
+    }
+
+    let entry = stack.pop().unwrap();
+    if must_be_dir && entry.file_type != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+
+    Ok(entry)
+}
+
+
+/// Splits a path into its components in reverse order, dropping empty ones caused by repeated, leading or trailing slashes
+fn split_components(path: &str) -> Vec<String> {
+    path.split('/').filter(|c| !c.is_empty()).rev().map(|c| c.to_string()).collect()
+}
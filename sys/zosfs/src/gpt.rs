@@ -0,0 +1,142 @@
+/*  gpt.rs - GUID partition table parsing
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Reads the partition table of a whole disk. The loader gets its slices from the firmware, this is for host tools working on disk images.
+
+use core::mem::size_of;
+use alloc::{string::String, vec, vec::Vec};
+use crate::block::{self, BlockDevice, Partition};
+use crate::error::FsError;
+use crate::uuid::GUID;
+
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct GptHeader {
+    pub signature:                  [u8; 8],
+    pub revision:                   u32,
+    pub header_size:                u32,
+    pub header_crc32:               u32,
+    reserved:                       u32,
+    pub my_lba:                     u64,
+    pub alternate_lba:              u64,
+    pub first_usable_lba:           u64,
+    pub last_usable_lba:            u64,
+    pub disk_guid:                  GUID,
+    pub partition_entry_lba:        u64,
+    pub num_partition_entries:      u32,
+    pub sizeof_partition_entry:     u32,
+    pub partition_entry_array_crc32: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct GptPartitionEntry {
+    pub partition_type_guid:        GUID,
+    pub unique_partition_guid:      GUID,
+    pub starting_lba:               u64,
+    pub ending_lba:                 u64,
+    pub attributes:                 u64,
+    pub partition_name:             [u16; 36],
+}
+
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+/// Anything bigger is almost certainly garbage, the usual table has 128 entries
+const MAX_PARTITION_ENTRIES: u32 = 1024;
+
+
+
+/// A partition as described by the GPT
+#[derive(Clone, Debug)]
+pub struct GptEntry {
+    pub index:      usize,
+    pub type_guid:  GUID,
+    pub guid:       GUID,
+    pub first_lba:  u64,
+    pub last_lba:   u64,
+    pub attributes: u64,
+    pub name:       String,
+}
+
+impl GptEntry {
+    /// Number of blocks in the partition
+    pub fn blocks(&self) -> u64 {
+        self.last_lba - self.first_lba + 1
+    }
+
+    /// Exposes the partition of *dev* as a device of its own
+    pub fn open<D: BlockDevice>(&self, dev: D) -> Partition<D> {
+        Partition::new(dev, self.first_lba, self.blocks())
+    }
+}
+
+
+/// Reads the partition entries from the primary GPT in LBA 1. Unused entries are left out, but keep their index.
+pub fn read_partitions<D: BlockDevice>(dev: &D) -> Result<Vec<GptEntry>, FsError> {
+    let block_size = dev.block_size() as u64;
+    let header: GptHeader = block::read_struct(dev, block_size)?;
+
+    if &header.signature != GPT_SIGNATURE {
+        return Err(FsError::Unsupported("disks without a GPT"));
+    }
+
+    let count = u32::from_le(header.num_partition_entries);
+    let entry_size = u32::from_le(header.sizeof_partition_entry) as usize;
+    if count > MAX_PARTITION_ENTRIES || entry_size < size_of::<GptPartitionEntry>() {
+        return Err(FsError::Corrupt("invalid GPT partition entry array"));
+    }
+
+    let mut buffer: Vec<u8> = vec![0; count as usize * entry_size];
+    dev.read_bytes(u64::from_le(header.partition_entry_lba) * block_size, &mut buffer)?;
+
+    let mut partitions = Vec::new();
+    for (index, raw) in buffer.chunks_exact(entry_size).enumerate() {
+        let entry: GptPartitionEntry = block::struct_from_bytes(raw);
+
+        // An all zero type GUID marks an unused entry
+        if raw[..16].iter().all(|b| *b == 0) {
+            continue;
+        }
+
+        let first_lba = u64::from_le(entry.starting_lba);
+        let last_lba = u64::from_le(entry.ending_lba);
+        if last_lba < first_lba {
+            return Err(FsError::Corrupt("GPT partition ends before it starts"));
+        }
+
+        // The name is UTF-16 and NUL padded
+        let name_units = entry.partition_name;
+        let name_len = name_units.iter().position(|c| *c == 0).unwrap_or(name_units.len());
+        let name = String::from_utf16_lossy(&name_units[..name_len].iter().map(|c| u16::from_le(*c)).collect::<Vec<u16>>());
+
+        partitions.push(GptEntry {
+            index,
+            type_guid:  entry.partition_type_guid,
+            guid:       entry.unique_partition_guid,
+            first_lba,
+            last_lba,
+            attributes: u64::from_le(entry.attributes),
+            name,
+        });
+    }
+
+    Ok(partitions)
+}
@@ -0,0 +1,103 @@
+/*  image.rs - File backed block device
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Lets host tools and tests run the drivers against disk images
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use crate::block::{BlockDevice, DiskError};
+
+
+/// A disk image file used as a block device
+pub struct ImageDevice {
+    file:       File,
+    block_size: usize,
+    writable:   bool,
+}
+
+impl ImageDevice {
+    /// Opens an image read only with 512 byte blocks
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self {
+            file:       File::open(path)?,
+            block_size: 512,
+            writable:   false,
+        })
+    }
+
+    /// Opens an image for reading and writing with 512 byte blocks
+    pub fn open_writable<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self {
+            file:       OpenOptions::new().read(true).write(true).open(path)?,
+            block_size: 512,
+            writable:   true,
+        })
+    }
+
+    /// Uses a different block size, e.g 2048 for CD images or 4096 for 4Kn disks
+    pub fn with_block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Number of whole blocks in the image
+    pub fn blocks(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len() / self.block_size as u64)
+    }
+}
+
+impl BlockDevice for ImageDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), DiskError> {
+        if !buffer.len().is_multiple_of(self.block_size) {
+            return Err(DiskError::Unaligned);
+        }
+
+        self.file.read_exact_at(buffer, lba * self.block_size as u64).map_err(io_error)
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), DiskError> {
+        if !self.writable {
+            return Err(DiskError::ReadOnly);
+        }
+        if !buffer.len().is_multiple_of(self.block_size) {
+            return Err(DiskError::Unaligned);
+        }
+
+        self.file.write_all_at(buffer, lba * self.block_size as u64).map_err(io_error)
+    }
+
+    fn flush(&self) -> Result<(), DiskError> {
+        self.file.sync_data().map_err(io_error)
+    }
+}
+
+
+/// Converts an I/O error to a DiskError, keeping the OS error number as the status
+fn io_error(err: io::Error) -> DiskError {
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof    => DiskError::OutOfRange,
+        _                               => DiskError::Io(err.raw_os_error().unwrap_or(0) as u32),
+    }
+}
@@ -0,0 +1,871 @@
+/*  iso9660.rs - ISO9660 driver
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Read only ISO9660 driver, with the Rock Ridge and Joliet extensions.
+//!
+//! Plain ISO9660 only allows short upper case names. Rock Ridge adds POSIX names, permissions and symlinks through System Use Sharing
+//! Protocol (SUSP) entries stored after each directory record, while Joliet adds a second directory tree with UCS-2 names. Rock Ridge is
+//! preferred when both are present. The El Torito boot catalog is also parsed so the images a CD boots from can be listed.
+
+#![allow(dead_code)]
+
+use alloc::{boxed::Box, format, string::{String, ToString}, vec::Vec, vec};
+use crate::block::{self, BlockDevice};
+use crate::error::FsError;
+use crate::fs::{CaseRule, DirEntry, Extent, FileType, Filesystem, Metadata};
+use crate::time::DateTime;
+
+
+/// Volume descriptors are always in 2048 byte sectors, starting after a 32KiB system area
+const SECTOR_SIZE: u64 = 2048;
+const FIRST_DESCRIPTOR: u64 = 16;
+/// Stop looking for the terminator after this many descriptors
+const MAX_DESCRIPTORS: u64 = 64;
+
+const DESCRIPTOR_BOOT_RECORD: u8 = 0;
+const DESCRIPTOR_PRIMARY: u8 = 1;
+const DESCRIPTOR_SUPPLEMENTARY: u8 = 2;
+const DESCRIPTOR_TERMINATOR: u8 = 255;
+
+const FLAG_HIDDEN: u8 = 0x01;
+const FLAG_DIRECTORY: u8 = 0x02;
+const FLAG_ASSOCIATED: u8 = 0x04;
+/// The file continues in the next directory record, used for files of 4GiB and over
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+/// Rock Ridge continuation areas followed for a single record before giving up
+const MAX_CONTINUATIONS: usize = 16;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// El Torito platform ID of EFI boot images
+pub const PLATFORM_EFI: u8 = 0xEF;
+
+
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct VolumeDescriptor {
+    pub vd_type:                u8,
+    pub id:                     [u8; 5],
+    pub version:                u8,
+    pub volume_flags:           u8,
+    pub system_id:              [u8; 32],
+    pub volume_id:              [u8; 32],
+    _unused1:                   [u8; 8],
+    pub volume_space_size:      u32,
+    _volume_space_size_be:      u32,
+    /// Only used by supplementary descriptors, Joliet puts its UCS-2 level here
+    pub escape_sequences:       [u8; 32],
+    pub volume_set_size:        u16,
+    _volume_set_size_be:        u16,
+    pub volume_sequence:        u16,
+    _volume_sequence_be:        u16,
+    pub logical_block_size:     u16,
+    _logical_block_size_be:     u16,
+    pub path_table_size:        u32,
+    _path_table_size_be:        u32,
+    pub l_path_table:           u32,
+    pub l_path_table_opt:       u32,
+    pub m_path_table:           u32,
+    pub m_path_table_opt:       u32,
+    pub root_record:            [u8; 34],
+    pub volume_set_id:          [u8; 128],
+    pub publisher_id:           [u8; 128],
+    pub preparer_id:            [u8; 128],
+    pub application_id:         [u8; 128],
+    pub copyright_file:         [u8; 37],
+    pub abstract_file:          [u8; 37],
+    pub bibliographic_file:     [u8; 37],
+    pub creation_date:          [u8; 17],
+    pub modification_date:      [u8; 17],
+    pub expiration_date:        [u8; 17],
+    pub effective_date:         [u8; 17],
+    pub fs_version:             u8,
+}
+
+/// Fixed part of a directory record. It's followed by the name, a padding byte if the name's length is even, and the system use area.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct DirectoryRecord {
+    pub length:                 u8,
+    pub ext_attr_length:        u8,
+    pub extent:                 u32,
+    _extent_be:                 u32,
+    pub data_length:            u32,
+    _data_length_be:            u32,
+    pub recorded:               [u8; 7],
+    pub flags:                  u8,
+    pub unit_size:              u8,
+    pub gap_size:               u8,
+    pub volume_sequence:        u16,
+    _volume_sequence_be:        u16,
+    pub name_length:            u8,
+}
+
+const RECORD_SIZE: usize = core::mem::size_of::<DirectoryRecord>();
+
+
+/// Which of the name formats on the disc is used
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum NameFormat {
+    Iso9660,
+    Joliet,
+    RockRidge,
+}
+
+
+/// A file or directory, built from its directory record and Rock Ridge entries
+#[derive(Clone)]
+pub struct IsoNode {
+    /// Where the data is, as (first logical block, length in bytes). Only files of 4GiB and over have more than one.
+    pub extents:    Vec<(u32, u32)>,
+    pub size:       u64,
+    pub file_type:  FileType,
+    pub mode:       u16,
+    pub uid:        u32,
+    pub gid:        u32,
+    pub mtime:      i64,
+    pub id:         u64,
+    /// Target of a Rock Ridge symlink
+    pub link:       Option<String>,
+    /// Interleaved files are stored in alternating runs of blocks, which isn't supported
+    pub interleaved: bool,
+}
+
+
+/// What the Rock Ridge entries of a directory record say about it
+#[derive(Default)]
+struct RockRidge {
+    name:           Option<String>,
+    mode:           Option<u32>,
+    uid:            u32,
+    gid:            u32,
+    ino:            Option<u64>,
+    mtime:          Option<i64>,
+    link:           Option<String>,
+    /// The directory was moved here to get around ISO9660's depth limit and is listed again at its real place, so it's hidden
+    relocated:      bool,
+    /// This is a placeholder for a directory that was moved away, found at the given block
+    child_link:     Option<u32>,
+}
+
+
+/// A boot image listed in the El Torito boot catalog
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BootImage {
+    /// 0 for x86 BIOS, PLATFORM_EFI for EFI
+    pub platform:   u8,
+    pub bootable:   bool,
+    /// 0 for no emulation, which EFI images always use
+    pub media_type: u8,
+    /// First 2048 byte sector of the image
+    pub start:      u32,
+    /// Size of the image in 512 byte sectors. Tools cap it at 65535 and some leave it at 1 for EFI images, so it can't be trusted much.
+    pub sectors:    u16,
+}
+
+
+
+/// Detects whether or not the device contains an ISO9660 filesystem
+pub fn detect<D: BlockDevice>(dev: &D) -> Result<bool, FsError> {
+    let mut id = [0u8; 5];
+
+    // The device may be too small to have a sector 16 at all
+    match dev.read_bytes(FIRST_DESCRIPTOR * SECTOR_SIZE + 1, &mut id) {
+        Ok(())                              => {}
+        Err(crate::DiskError::OutOfRange)   => return Ok(false),
+        Err(err)                            => return Err(err.into()),
+    }
+
+    if &id == b"CD001" {
+        return Ok(true);
+    }
+    else {
+        return Ok(false);
+    }
+}
+
+
+
+/// A mounted ISO9660 filesystem
+pub struct IsoFs<D: BlockDevice> {
+    dev:            D,
+    pvd:            Box<VolumeDescriptor>,
+    /// The Joliet supplementary volume descriptor, if there is one
+    joliet:         Option<Box<VolumeDescriptor>>,
+    names:          NameFormat,
+    /// Bytes skipped at the start of every system use area, given by the SUSP SP entry
+    susp_skip:      usize,
+    /// Sector of the El Torito boot catalog
+    boot_catalog:   Option<u32>,
+}
+
+impl<D: BlockDevice> IsoFs<D> {
+    pub fn new(dev: D) -> Result<Self, FsError> {
+        let mut pvd: Option<Box<VolumeDescriptor>> = None;
+        let mut joliet: Option<Box<VolumeDescriptor>> = None;
+        let mut boot_catalog: Option<u32> = None;
+
+        for sector in FIRST_DESCRIPTOR..FIRST_DESCRIPTOR + MAX_DESCRIPTORS {
+            let mut raw: Vec<u8> = vec![0; SECTOR_SIZE as usize];
+            dev.read_bytes(sector * SECTOR_SIZE, &mut raw)?;
+
+            if &raw[1..6] != b"CD001" {
+                return Err(FsError::Corrupt("missing volume descriptor terminator"));
+            }
+
+            match raw[0] {
+                DESCRIPTOR_PRIMARY if pvd.is_none() => pvd = Some(Box::new(block::struct_from_bytes(&raw))),
+
+                // Joliet is a supplementary descriptor with one of three UCS-2 escape sequences
+                DESCRIPTOR_SUPPLEMENTARY if raw[88..90] == *b"%/" && matches!(raw[90], b'@' | b'C' | b'E') && joliet.is_none() => {
+                    joliet = Some(Box::new(block::struct_from_bytes(&raw)));
+                }
+
+                DESCRIPTOR_BOOT_RECORD if raw[7..30] == *b"EL TORITO SPECIFICATION" => {
+                    boot_catalog = Some(u32::from_le_bytes(raw[71..75].try_into().unwrap()));
+                }
+
+                DESCRIPTOR_TERMINATOR => break,
+
+                _ => {}
+            }
+        }
+
+        let pvd = pvd.ok_or(FsError::Corrupt("no primary volume descriptor"))?;
+
+        // Blocks can be smaller than a sector, but never bigger
+        let block_size = pvd.logical_block_size;
+        if !block_size.is_power_of_two() || !(512..=2048).contains(&block_size) {
+            return Err(FsError::Corrupt("invalid logical block size"));
+        }
+
+        let mut fs = Self {
+            dev,
+            pvd,
+            joliet,
+            names:          NameFormat::Iso9660,
+            susp_skip:      0,
+            boot_catalog,
+        };
+
+        // Rock Ridge is announced by an SP entry at the very start of the system use area of the root's "." record
+        let root = parse_record(&fs.pvd.root_record).ok_or(FsError::Corrupt("invalid root directory record"))?;
+        let dot = fs.read_extent(root.0.extent, RECORD_SIZE.max(fs.block_size() as usize))?;
+        let (_, _, system_use) = parse_record(&dot).ok_or(FsError::Corrupt("invalid root directory"))?;
+
+        if system_use.len() >= 7 && system_use[..2] == *b"SP" && system_use[4..6] == [0xBE, 0xEF] {
+            fs.names = NameFormat::RockRidge;
+            fs.susp_skip = system_use[6] as usize;
+        }
+        else if fs.joliet.is_some() {
+            fs.names = NameFormat::Joliet;
+        }
+
+        Ok(fs)
+    }
+
+
+    /// Returns the volume's UUID and label, the way blkid shows them. The UUID is the modification or creation date, e.g
+    /// "2024-05-17-12-34-56-00", read as hex digits. The Joliet label is preferred since it can hold lower case letters.
+    pub fn volume_id(&self) -> (u128, String) {
+        let date = [self.pvd.modification_date, self.pvd.creation_date].into_iter()
+            .find(|date| date[..16].iter().any(|c| *c != b'0' && *c != 0))
+            .unwrap_or([b'0'; 17]);
+
+        let uuid = core::str::from_utf8(&date[..16]).ok().and_then(|digits| u128::from_str_radix(digits, 16).ok()).unwrap_or(0);
+
+        let joliet_label = self.joliet.as_ref().map(|joliet| decode_ucs2(&joliet.volume_id)).unwrap_or_default();
+        let label = if !joliet_label.trim().is_empty() { joliet_label } else { decode_ascii(&self.pvd.volume_id) };
+
+        (uuid, label.trim_end().to_string())
+    }
+
+
+    /// Lists the blocks holding a file
+    pub fn extents(&self, node: &IsoNode) -> Vec<Extent> {
+        let block_size = self.block_size();
+        let mut extents: Vec<Extent> = Vec::new();
+        let mut logical = 0;
+
+        for (start, length) in &node.extents {
+            let blocks = (*length as u64).div_ceil(block_size);
+
+            // Multi-extent files usually continue right where the previous part ended
+            match extents.last_mut() {
+                Some(last) if last.physical + last.length == *start as u64 => last.length += blocks,
+                _ if blocks != 0 => extents.push(Extent { logical, physical: *start as u64, length: blocks }),
+                _ => {}
+            }
+            logical += blocks;
+        }
+
+        extents
+    }
+
+
+    /// Lists the images in the El Torito boot catalog, or nothing if the disc isn't bootable
+    pub fn boot_images(&self) -> Result<Vec<BootImage>, FsError> {
+        let Some(catalog) = self.boot_catalog else { return Ok(Vec::new()) };
+
+        let mut raw: Vec<u8> = vec![0; SECTOR_SIZE as usize];
+        self.dev.read_bytes(catalog as u64 * SECTOR_SIZE, &mut raw)?;
+
+        // The validation entry's 16 bit words add up to 0
+        let sum = raw[..32].chunks_exact(2).fold(0u16, |sum, word| sum.wrapping_add(u16::from_le_bytes([word[0], word[1]])));
+        if raw[0] != 1 || raw[30..32] != [0x55, 0xAA] || sum != 0 {
+            return Err(FsError::Corrupt("invalid El Torito validation entry"));
+        }
+
+        // The initial entry uses the platform of the validation entry, the ones after it are grouped into sections with their own
+        let mut images = vec![parse_boot_entry(&raw[32..64], raw[1])];
+        let mut pos = 64;
+
+        while pos + 32 <= raw.len() && (raw[pos] == 0x90 || raw[pos] == 0x91) {
+            let last = raw[pos] == 0x91;
+            let platform = raw[pos + 1];
+            let mut entries = u16::from_le_bytes([raw[pos + 2], raw[pos + 3]]);
+            pos += 32;
+
+            while entries > 0 && pos + 32 <= raw.len() {
+                // Extension entries hold more selection criteria for the entry before them
+                if raw[pos] != 0x44 {
+                    images.push(parse_boot_entry(&raw[pos..pos + 32], platform));
+                    entries -= 1;
+                }
+                pos += 32;
+            }
+
+            if last {
+                break;
+            }
+        }
+
+        Ok(images)
+    }
+
+
+    /// Lists the interesting volume descriptor fields as name/value pairs, for debugging tools
+    pub fn superblock_info(&self) -> Result<Vec<(&'static str, String)>, FsError> {
+        let pvd = &self.pvd;
+        let (_, label) = self.volume_id();
+
+        let names = match self.names {
+            NameFormat::Iso9660     => "ISO9660",
+            NameFormat::Joliet      => "Joliet",
+            NameFormat::RockRidge   => "Rock Ridge",
+        };
+
+        let mut info = vec![
+            ("System ID",               decode_ascii(&pvd.system_id).trim_end().to_string()),
+            ("Volume ID",               label),
+            ("Volume set ID",           decode_ascii(&pvd.volume_set_id).trim_end().to_string()),
+            ("Publisher",               decode_ascii(&pvd.publisher_id).trim_end().to_string()),
+            ("Application",             decode_ascii(&pvd.application_id).trim_end().to_string()),
+            ("Created",                 decode_ascii(&pvd.creation_date[..16])),
+            ("Modified",                decode_ascii(&pvd.modification_date[..16])),
+            ("Logical block size",      self.block_size().to_string()),
+            ("Volume space size",       { pvd.volume_space_size }.to_string()),
+            ("Path table size",         { pvd.path_table_size }.to_string()),
+            ("Names",                   names.to_string()),
+            ("Joliet",                  if self.joliet.is_some() { "yes" } else { "no" }.to_string()),
+        ];
+
+        if let Some(catalog) = self.boot_catalog {
+            info.push(("Boot catalog",  catalog.to_string()));
+        }
+
+        for image in self.boot_images()? {
+            let platform = match image.platform {
+                0               => "BIOS".to_string(),
+                PLATFORM_EFI    => "EFI".to_string(),
+                other           => format!("{:#04x}", other),
+            };
+
+            info.push(("Boot image",    format!("{} at sector {}, {} sectors{}", platform, image.start, image.sectors,
+                                                if image.bootable { "" } else { " (not bootable)" })));
+        }
+
+        Ok(info)
+    }
+
+
+    fn block_size(&self) -> u64 {
+        self.pvd.logical_block_size as u64
+    }
+
+
+    /// Reads *length* bytes starting at logical block *start*
+    fn read_extent(&self, start: u32, length: usize) -> Result<Vec<u8>, FsError> {
+        let mut buffer: Vec<u8> = vec![0; length];
+        self.dev.read_bytes(start as u64 * self.block_size(), &mut buffer)?;
+
+        Ok(buffer)
+    }
+
+
+    /// Builds a node from a directory record. Returns None for records that shouldn't be listed.
+    fn make_node(&self, record: &DirectoryRecord, name: &[u8], system_use: &[u8]) -> Result<Option<(String, IsoNode)>, FsError> {
+        let rr = if self.names == NameFormat::RockRidge {
+            self.parse_rock_ridge(system_use.get(self.susp_skip..).unwrap_or(&[]))?
+        } else {
+            RockRidge::default()
+        };
+
+        if rr.relocated || record.flags & FLAG_ASSOCIATED != 0 {
+            return Ok(None);
+        }
+
+        let name = match &rr.name {
+            Some(name)  => name.clone(),
+            None        => {
+                let name = if self.names == NameFormat::Joliet { decode_ucs2(name) } else { decode_ascii(name) };
+                strip_version(&name).to_string()
+            }
+        };
+
+        let mut extent = record.extent + record.ext_attr_length as u32;
+        let mut size = record.data_length;
+        let mut is_dir = record.flags & FLAG_DIRECTORY != 0;
+
+        // A directory that was moved away is found through its own "." record
+        if let Some(child) = rr.child_link {
+            let dot = self.read_extent(child, self.block_size() as usize)?;
+            let (dot, _, _) = parse_record(&dot).ok_or(FsError::Corrupt("invalid relocated directory"))?;
+
+            extent = child;
+            size = dot.data_length;
+            is_dir = true;
+        }
+
+        let file_type = match rr.mode.map(|mode| mode & S_IFMT) {
+            Some(S_IFDIR)               => FileType::Directory,
+            Some(S_IFREG)               => FileType::Regular,
+            Some(S_IFLNK)               => FileType::Symlink,
+            Some(_)                     => FileType::Other,
+            None if rr.link.is_some()   => FileType::Symlink,
+            None if is_dir              => FileType::Directory,
+            None                        => FileType::Regular,
+        };
+
+        // Without Rock Ridge everything is read only
+        let mode = match rr.mode {
+            Some(mode)  => (mode & 0o7777) as u16,
+            None        => if is_dir { 0o555 } else { 0o444 },
+        };
+
+        Ok(Some((name, IsoNode {
+            extents:        vec![(extent, size)],
+            size:           size as u64,
+            file_type,
+            mode,
+            uid:            rr.uid,
+            gid:            rr.gid,
+            mtime:          rr.mtime.unwrap_or_else(|| record_time(&record.recorded)),
+            id:             rr.ino.unwrap_or(extent as u64),
+            link:           rr.link,
+            interleaved:    record.unit_size != 0,
+        })))
+    }
+
+
+    /// Parses the SUSP entries of a system use area, following continuation areas
+    fn parse_rock_ridge(&self, system_use: &[u8]) -> Result<RockRidge, FsError> {
+        let mut rr = RockRidge::default();
+        let mut name = String::new();
+        let mut has_name = false;
+        let mut link = String::new();
+        let mut has_link = false;
+        // Whether the last symlink component continues in the next one, instead of being followed by a slash
+        let mut link_continues = false;
+
+        let mut area = system_use.to_vec();
+        let mut continuations = 0;
+
+        loop {
+            let mut next_area: Option<(u32, u32, u32)> = None;
+            let mut pos = 0;
+
+            while pos + 4 <= area.len() {
+                let len = area[pos + 2] as usize;
+                if len < 4 || pos + len > area.len() {
+                    break;
+                }
+                let entry = &area[pos..pos + len];
+                pos += len;
+
+                match &entry[..2] {
+                    b"CE" if len >= 28 => {
+                        next_area = Some((both_u32(&entry[4..]), both_u32(&entry[12..]), both_u32(&entry[20..])));
+                    }
+
+                    b"ST" => break,
+
+                    b"PX" if len >= 36 => {
+                        rr.mode = Some(both_u32(&entry[4..]));
+                        rr.uid = both_u32(&entry[20..]);
+                        rr.gid = both_u32(&entry[28..]);
+
+                        // The inode number was only added in RRIP 1.12
+                        if len >= 44 {
+                            rr.ino = Some(both_u32(&entry[36..]) as u64);
+                        }
+                    }
+
+                    // Names can be split over several NM entries. The current and parent flags are for "." and "..", which aren't listed.
+                    b"NM" if len >= 5 && entry[4] & 0x06 == 0 => {
+                        name.push_str(&String::from_utf8_lossy(&entry[5..]));
+                        has_name = true;
+                    }
+
+                    b"SL" if len >= 5 => {
+                        parse_symlink_components(&entry[5..], &mut link, &mut link_continues);
+                        has_link = true;
+                    }
+
+                    b"TF" if len >= 5 => rr.mtime = parse_timestamps(entry),
+
+                    b"RE" => rr.relocated = true,
+
+                    b"CL" if len >= 12 => rr.child_link = Some(both_u32(&entry[4..])),
+
+                    _ => {}
+                }
+            }
+
+            let Some((block, offset, length)) = next_area else { break };
+
+            continuations += 1;
+            if continuations > MAX_CONTINUATIONS {
+                return Err(FsError::Corrupt("Rock Ridge continuation areas loop"));
+            }
+
+            area = vec![0; length as usize];
+            self.dev.read_bytes(block as u64 * self.block_size() + offset as u64, &mut area)?;
+        }
+
+        if has_name {
+            rr.name = Some(name);
+        }
+        if has_link {
+            rr.link = Some(link);
+        }
+
+        Ok(rr)
+    }
+}
+
+impl<D: BlockDevice> Filesystem for IsoFs<D> {
+    type Node = IsoNode;
+
+    /// Rock Ridge names are POSIX names. Plain ISO9660 names are upper case, and Joliet discs are written for Windows.
+    fn case_rule(&self) -> CaseRule {
+        if self.names == NameFormat::RockRidge {
+            return CaseRule::Sensitive;
+        }
+        else {
+            return CaseRule::Insensitive;
+        }
+    }
+
+    fn root(&self) -> Result<DirEntry<IsoNode>, FsError> {
+        let descriptor = if self.names == NameFormat::Joliet { self.joliet.as_ref().unwrap() } else { &self.pvd };
+        let (record, _, _) = parse_record(&descriptor.root_record).ok_or(FsError::Corrupt("invalid root directory record"))?;
+
+        let extent = record.extent + record.ext_attr_length as u32;
+
+        Ok(DirEntry {
+            name:       "/".to_string(),
+            file_type:  FileType::Directory,
+            node:       IsoNode {
+                extents:        vec![(extent, record.data_length)],
+                size:           record.data_length as u64,
+                file_type:      FileType::Directory,
+                mode:           0o555,
+                uid:            0,
+                gid:            0,
+                mtime:          record_time(&record.recorded),
+                id:             extent as u64,
+                link:           None,
+                interleaved:    false,
+            },
+        })
+    }
+
+    fn read_dir(&self, dir: &IsoNode) -> Result<Vec<DirEntry<IsoNode>>, FsError> {
+        let raw = self.read_to_vec(dir)?;
+        let block_size = self.block_size() as usize;
+
+        let mut entries: Vec<DirEntry<IsoNode>> = Vec::new();
+        // Parts of a multi-extent file seen so far
+        let mut pending: Option<(String, IsoNode)> = None;
+        let mut pos = 0;
+
+        while pos < raw.len() {
+            // Records never cross a block boundary. A zero length means the rest of the block is padding.
+            if raw[pos] == 0 {
+                pos = (pos / block_size + 1) * block_size;
+                continue;
+            }
+
+            let Some((record, name, system_use)) = parse_record(&raw[pos..]) else {
+                return Err(FsError::Corrupt("invalid directory record"));
+            };
+            pos += record.length as usize;
+
+            // "." and ".."
+            if name == [0] || name == [1] {
+                continue;
+            }
+
+            let Some((name, mut node)) = self.make_node(&record, name, system_use)? else { continue };
+
+            if let Some((_, mut first)) = pending.take() {
+                first.extents.extend(node.extents);
+                first.size += node.size;
+                node = first;
+            }
+
+            if record.flags & FLAG_MULTI_EXTENT != 0 {
+                pending = Some((name, node));
+                continue;
+            }
+
+            entries.push(DirEntry {
+                name,
+                file_type:  node.file_type,
+                node,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    fn read_link(&self, node: &IsoNode) -> Result<String, FsError> {
+        node.link.clone().ok_or(FsError::Corrupt("symlink without a target"))
+    }
+
+    fn metadata(&self, node: &IsoNode) -> Result<Metadata, FsError> {
+        Ok(Metadata {
+            file_type:  node.file_type,
+            size:       node.size,
+            mode:       node.mode,
+            uid:        node.uid,
+            gid:        node.gid,
+            mtime:      node.mtime,
+            id:         node.id,
+        })
+    }
+
+    fn read(&self, node: &IsoNode, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if node.interleaved {
+            return Err(FsError::Unsupported("interleaved files"));
+        }
+        if offset >= node.size {
+            return Ok(0);
+        }
+
+        let len = buffer.len().min((node.size - offset) as usize);
+
+        // Each extent holds exactly its length in bytes, so the parts of a multi-extent file are simply laid end to end
+        let mut done = 0;
+        let mut extent_start = 0;
+        for (start, length) in &node.extents {
+            let extent_end = extent_start + *length as u64;
+            let pos = offset + done as u64;
+
+            if pos < extent_end {
+                let count = ((extent_end - pos) as usize).min(len - done);
+                let disk_offset = *start as u64 * self.block_size() + (pos - extent_start);
+                self.dev.read_bytes(disk_offset, &mut buffer[done..done + count])?;
+
+                done += count;
+                if done == len {
+                    break;
+                }
+            }
+
+            extent_start = extent_end;
+        }
+
+        Ok(len)
+    }
+}
+
+
+
+/// Splits a directory record into its fixed part, name and system use area. Returns None if the record is truncated.
+fn parse_record(raw: &[u8]) -> Option<(DirectoryRecord, &[u8], &[u8])> {
+    if raw.len() < RECORD_SIZE {
+        return None;
+    }
+
+    let record: DirectoryRecord = block::struct_from_bytes(raw);
+    let length = record.length as usize;
+    let name_end = RECORD_SIZE + record.name_length as usize;
+
+    if length < name_end || length > raw.len() {
+        return None;
+    }
+
+    // The name is padded to an even length
+    let system_use_start = (name_end + (name_end & 1)).min(length);
+
+    Some((record, &raw[RECORD_SIZE..name_end], &raw[system_use_start..length]))
+}
+
+
+/// Parses one entry of the El Torito boot catalog
+fn parse_boot_entry(raw: &[u8], platform: u8) -> BootImage {
+    BootImage {
+        platform,
+        bootable:   raw[0] == 0x88,
+        media_type: raw[1] & 0x0F,
+        start:      u32::from_le_bytes(raw[8..12].try_into().unwrap()),
+        sectors:    u16::from_le_bytes([raw[6], raw[7]]),
+    }
+}
+
+
+/// Adds the components of a Rock Ridge SL entry to *link*
+fn parse_symlink_components(mut raw: &[u8], link: &mut String, continues: &mut bool) {
+    while raw.len() >= 2 {
+        let flags = raw[0];
+        let len = (raw[1] as usize).min(raw.len() - 2);
+        let content = &raw[2..2 + len];
+        raw = &raw[2 + len..];
+
+        if !*continues && !link.is_empty() && !link.ends_with('/') {
+            link.push('/');
+        }
+
+        if flags & 0x02 != 0 {
+            link.push('.');
+        }
+        else if flags & 0x04 != 0 {
+            link.push_str("..");
+        }
+        else if flags & 0x08 != 0 {
+            link.push('/');
+        }
+        else {
+            link.push_str(&String::from_utf8_lossy(content));
+        }
+
+        *continues = flags & 0x01 != 0;
+    }
+}
+
+
+/// Returns the modification time from a Rock Ridge TF entry. Timestamps appear in a fixed order, each one only if its flag is set.
+fn parse_timestamps(entry: &[u8]) -> Option<i64> {
+    const CREATION: u8 = 0x01;
+    const MODIFY: u8 = 0x02;
+    const LONG_FORM: u8 = 0x80;
+
+    let flags = entry[4];
+    if flags & MODIFY == 0 {
+        return None;
+    }
+
+    let size = if flags & LONG_FORM != 0 { 17 } else { 7 };
+    let start = if flags & CREATION != 0 { 5 + size } else { 5 };
+    let timestamp = entry.get(start..start + size)?;
+
+    if flags & LONG_FORM != 0 {
+        return descriptor_time(timestamp);
+    }
+    else {
+        return Some(record_time(timestamp));
+    }
+}
+
+
+/// Converts the 7 byte date and time of a directory record to seconds since the UNIX epoch
+fn record_time(raw: &[u8]) -> i64 {
+    let time = DateTime {
+        year:   1900 + raw[0] as i64,
+        month:  raw[1].clamp(1, 12),
+        day:    raw[2].max(1),
+        hour:   raw[3],
+        minute: raw[4],
+        second: raw[5],
+    }.to_unix();
+
+    // The last byte is the offset from UTC in 15 minute steps
+    time - raw[6] as i8 as i64 * 15 * 60
+}
+
+
+/// Converts the 17 byte "YYYYMMDDHHMMSScc" date of a volume descriptor to seconds since the UNIX epoch. Returns None if it isn't set.
+fn descriptor_time(raw: &[u8]) -> Option<i64> {
+    let digits = core::str::from_utf8(&raw[..16]).ok()?;
+    let field = |range: core::ops::Range<usize>| digits.get(range).and_then(|s| s.parse::<i64>().ok());
+
+    let year = field(0..4)?;
+    if year == 0 {
+        return None;
+    }
+
+    let time = DateTime {
+        year,
+        month:  field(4..6)?.clamp(1, 12) as u8,
+        day:    field(6..8)?.max(1) as u8,
+        hour:   field(8..10)? as u8,
+        minute: field(10..12)? as u8,
+        second: field(12..14)? as u8,
+    }.to_unix();
+
+    Some(time - raw[16] as i8 as i64 * 15 * 60)
+}
+
+
+/// Reads the little endian half of a "both byte order" 32 bit field
+fn both_u32(raw: &[u8]) -> u32 {
+    u32::from_le_bytes(raw[..4].try_into().unwrap())
+}
+
+
+/// Decodes a d-character or a-character field, which are plain ASCII
+fn decode_ascii(raw: &[u8]) -> String {
+    raw.iter().map(|b| *b as char).collect()
+}
+
+
+/// Decodes a big endian UCS-2 Joliet name
+fn decode_ucs2(raw: &[u8]) -> String {
+    let chars = raw.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]]));
+
+    char::decode_utf16(chars).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect()
+}
+
+
+/// Removes the ";1" version from a file name, and the trailing dot of names without an extension
+fn strip_version(name: &str) -> &str {
+    let name = match name.rfind(';') {
+        Some(pos)   => &name[..pos],
+        None        => name,
+    };
+
+    name.strip_suffix('.').unwrap_or(name)
+}
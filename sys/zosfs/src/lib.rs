@@ -0,0 +1,59 @@
+/*  lib.rs - zOS filesystem drivers
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Filesystem drivers shared by the loader, the kernel and host tools.
+//!
+//! Drivers only talk to storage through the BlockDevice trait, so they work the same on top of UEFI BlockIO, a disk image on the host, or
+//! anything else that can read blocks.
+
+#![no_std]
+// The drivers are written with explicit returns and if/else chains, like the rest of zOS
+#![allow(clippy::needless_return, clippy::needless_bool, clippy::needless_late_init, clippy::needless_range_loop)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+extern crate std;
+
+#[macro_use]
+pub mod log;
+pub mod archive;
+pub mod block;
+pub mod checksum;
+pub mod compress;
+pub mod error;
+pub mod exfat;
+pub mod extfs;
+pub mod fat;
+mod fattable;
+pub mod fs;
+pub mod gpt;
+#[cfg(feature = "std")]
+pub mod image;
+pub mod iso9660;
+pub mod mount;
+pub mod squashfs;
+pub mod time;
+pub mod uuid;
+pub mod zxfs;
+
+pub use block::{BlockDevice, DiskError, Partition};
+pub use error::FsError;
+pub use fs::{CaseRule, DirEntry, Extent, FileType, Filesystem, Metadata};
+pub use mount::{mount, mount_with, split_archive_path, FilesystemType, Mount, MountOptions};
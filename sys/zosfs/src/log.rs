@@ -0,0 +1,52 @@
+/*  log.rs - Logging hook
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! The drivers don't know where their messages should go, so whoever uses them registers a function to print them.
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+
+/// Address of the registered logger, 0 if there is none
+static LOGGER: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Prints a formatted message with a trailing newline through the registered logger
+#[macro_export]
+macro_rules! fslog {
+    ($($arg:tt)*) => ($crate::log::_log(format_args!("{}\n", format_args!($($arg)*))));
+}
+
+
+/// Registers the function used to print driver messages
+pub fn set_logger(logger: fn(fmt::Arguments)) {
+    LOGGER.store(logger as usize, Ordering::Release);
+}
+
+
+/// Log function that's used by the fslog macro
+#[doc(hidden)]
+pub fn _log(args: fmt::Arguments) {
+    let logger = LOGGER.load(Ordering::Acquire);
+
+    if logger != 0 {
+        let logger: fn(fmt::Arguments) = unsafe { core::mem::transmute(logger) };
+        logger(args);
+    }
+}
@@ -0,0 +1,381 @@
+/*  mount.rs - Filesystem detection and dispatch
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Callers that don't care which filesystem a device holds use Mount, which detects it and forwards path based operations to the right
+//! driver.
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use crate::archive::ArchiveFs;
+use crate::block::{BlockDevice, DiskError};
+use crate::error::FsError;
+use crate::exfat::{self, ExfatFs};
+use crate::extfs::{self, ChecksumPolicy, ExtFs};
+use crate::fat::{self, FatFs};
+use crate::iso9660::{self, IsoFs};
+use crate::squashfs::{self, SquashFs};
+use crate::zxfs::{self, ZxFs};
+use crate::fs::{self, Extent, FileType, Filesystem, Metadata};
+
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FilesystemType {
+    FAT,
+    EXFAT,
+    EXT,
+    ISO9660,
+    SQUASHFS,
+    ARCHIVE,
+    ZXFS,
+    UNKNOWN,
+}
+
+
+/// Detects the filesystem on the device
+pub fn detect_fs_type<D: BlockDevice>(dev: &D) -> Result<FilesystemType, FsError> {
+    if zxfs::detect(dev)? {
+        return Ok(FilesystemType::ZXFS);
+    }
+    else if extfs::detect(dev)? {
+        return Ok(FilesystemType::EXT);
+    }
+    // Checked before FAT, which is only recognized by a loose string match
+    else if exfat::detect(dev)? {
+        return Ok(FilesystemType::EXFAT);
+    }
+    else if squashfs::detect(dev)? {
+        return Ok(FilesystemType::SQUASHFS);
+    }
+    else if iso9660::detect(dev)? {
+        return Ok(FilesystemType::ISO9660);
+    }
+    else if fat::detect(dev)? {
+        return Ok(FilesystemType::FAT);
+    }
+    else {
+        return Ok(FilesystemType::UNKNOWN);
+    }
+}
+
+
+/// Choices about how filesystems are mounted, for the drivers that have any
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct MountOptions {
+    /// What the EXT driver does when metadata doesn't match its checksum
+    pub ext_checksums:  ChecksumPolicy,
+}
+
+
+/// Detects the filesystem on the device and mounts it
+pub fn mount<D: BlockDevice>(dev: D) -> Result<Mount<D>, FsError> {
+    mount_with(dev, MountOptions::default())
+}
+
+
+/// Detects the filesystem on the device and mounts it with *options*
+pub fn mount_with<D: BlockDevice>(dev: D, options: MountOptions) -> Result<Mount<D>, FsError> {
+    match detect_fs_type(&dev)? {
+        FilesystemType::FAT     => Ok(Mount::Fat(FatFs::new(dev)?)),
+        FilesystemType::EXFAT   => Ok(Mount::Exfat(ExfatFs::new(dev)?)),
+        FilesystemType::EXT     => Ok(Mount::Ext(ExtFs::with_checksum_policy(dev, options.ext_checksums)?)),
+        FilesystemType::ISO9660 => Ok(Mount::Iso(IsoFs::new(dev)?)),
+        FilesystemType::SQUASHFS => Ok(Mount::Squash(SquashFs::new(dev)?)),
+        FilesystemType::ZXFS    => Ok(Mount::Zxfs(ZxFs::new(dev)?)),
+        // Archives are opened from a file on another mount, not detected on devices
+        FilesystemType::ARCHIVE |
+        FilesystemType::UNKNOWN => Err(FsError::UnknownFilesystem),
+    }
+}
+
+
+/// Paths with this prefix point inside an archive, as in "archive:/boot/extensions.tar!/zxt/hello.zxt"
+pub const ARCHIVE_PREFIX: &str = "archive:";
+
+
+/// Splits an "archive:<archive>!<path>" path into the path of the archive and the path within it, or returns None for ordinary paths
+pub fn split_archive_path(path: &str) -> Option<(&str, &str)> {
+    return path.strip_prefix(ARCHIVE_PREFIX)?.split_once('!');
+}
+
+
+
+/// A mounted filesystem of any supported type
+pub enum Mount<D: BlockDevice> {
+    Fat(FatFs<D>),
+    Exfat(ExfatFs<D>),
+    Ext(ExtFs<D>),
+    Iso(IsoFs<D>),
+    Squash(SquashFs<D>),
+    /// A SquashFS image stored as a file on another filesystem
+    SquashImage(SquashFs<FileDevice<D>>),
+    Archive(ArchiveFs),
+    Zxfs(ZxFs<D>),
+}
+
+
+/// Runs *$body* with *$fs* bound to whichever driver is mounted
+macro_rules! with_fs {
+    ($mount:expr, $fs:ident => $body:expr) => {
+        match $mount {
+            Mount::Fat($fs) => $body,
+            Mount::Exfat($fs) => $body,
+            Mount::Ext($fs) => $body,
+            Mount::Iso($fs) => $body,
+            Mount::Squash($fs) => $body,
+            Mount::SquashImage($fs) => $body,
+            Mount::Archive($fs) => $body,
+            Mount::Zxfs($fs) => $body,
+        }
+    };
+}
+
+
+/// A file resolved on a Mount, holding the node of whichever driver is mounted so it can be read again without walking its path
+enum MountNode<D: BlockDevice> {
+    Fat(<FatFs<D> as Filesystem>::Node),
+    Exfat(<ExfatFs<D> as Filesystem>::Node),
+    Ext(<ExtFs<D> as Filesystem>::Node),
+    Iso(<IsoFs<D> as Filesystem>::Node),
+    Squash(<SquashFs<D> as Filesystem>::Node),
+    SquashImage(<SquashFs<FileDevice<D>> as Filesystem>::Node),
+    Archive(<ArchiveFs as Filesystem>::Node),
+    Zxfs(<ZxFs<D> as Filesystem>::Node),
+}
+
+
+/// Runs *$body* with *$fs* bound to whichever driver is mounted and *$n* to *$node*, which must have been resolved on it
+macro_rules! with_node {
+    ($mount:expr, $node:expr, $fs:ident, $n:ident => $body:expr) => {
+        match ($mount, $node) {
+            (Mount::Fat($fs), MountNode::Fat($n)) => $body,
+            (Mount::Exfat($fs), MountNode::Exfat($n)) => $body,
+            (Mount::Ext($fs), MountNode::Ext($n)) => $body,
+            (Mount::Iso($fs), MountNode::Iso($n)) => $body,
+            (Mount::Squash($fs), MountNode::Squash($n)) => $body,
+            (Mount::SquashImage($fs), MountNode::SquashImage($n)) => $body,
+            (Mount::Archive($fs), MountNode::Archive($n)) => $body,
+            (Mount::Zxfs($fs), MountNode::Zxfs($n)) => $body,
+            _ => unreachable!("node resolved on another mount"),
+        }
+    };
+}
+
+
+impl<D: BlockDevice> Mount<D> {
+    pub fn fs_type(&self) -> FilesystemType {
+        match self {
+            Mount::Fat(_) => FilesystemType::FAT,
+            Mount::Exfat(_) => FilesystemType::EXFAT,
+            Mount::Ext(_) => FilesystemType::EXT,
+            Mount::Iso(_) => FilesystemType::ISO9660,
+            Mount::Squash(_) | Mount::SquashImage(_) => FilesystemType::SQUASHFS,
+            Mount::Archive(_) => FilesystemType::ARCHIVE,
+            Mount::Zxfs(_) => FilesystemType::ZXFS,
+        }
+    }
+
+    /// Returns the filesystem's UUID and label
+    pub fn volume_id(&self) -> Result<(u128, String), FsError> {
+        match self {
+            Mount::Fat(fat) => fat.volume_id(),
+            Mount::Exfat(exfat) => Ok(exfat.volume_id()),
+            Mount::Ext(ext) => Ok(ext.volume_id()),
+            Mount::Iso(iso) => Ok(iso.volume_id()),
+            Mount::Squash(_) | Mount::SquashImage(_) => Ok((0, String::new())),
+            Mount::Archive(_) => Ok((0, String::new())),
+            Mount::Zxfs(zxfs) => Ok(zxfs.volume_id()),
+        }
+    }
+
+    /// Returns information about the file at *path*
+    pub fn metadata(&self, path: &str) -> Result<Metadata, FsError> {
+        with_fs!(self, fs => fs.metadata(&fs::resolve(fs, path)?.node))
+    }
+
+    /// Lists the names and types of the entries in the directory at *path*
+    pub fn list_dir(&self, path: &str) -> Result<Vec<(String, FileType)>, FsError> {
+        with_fs!(self, fs => {
+            let dir = fs::resolve(fs, path)?;
+
+            if dir.file_type != FileType::Directory {
+                return Err(FsError::NotADirectory);
+            }
+
+            Ok(fs.read_dir(&dir.node)?.into_iter().map(|entry| (entry.name, entry.file_type)).collect())
+        })
+    }
+
+    /// Reads the entire contents of the file at *path*
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, FsError> {
+        with_fs!(self, fs => fs.read_to_vec(&fs::resolve(fs, path)?.node))
+    }
+
+    /// Reads part of the file at *path* starting at byte *offset*. Returns the number of bytes read.
+    pub fn read_at(&self, path: &str, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        with_fs!(self, fs => fs.read(&fs::resolve(fs, path)?.node, offset, buffer))
+    }
+
+    /// Returns the value of the extended attribute *name* of the file at *path*, or None if it doesn't have it
+    pub fn get_xattr(&self, path: &str, name: &str) -> Result<Option<Vec<u8>>, FsError> {
+        with_fs!(self, fs => fs.get_xattr(&fs::resolve(fs, path)?.node, name))
+    }
+
+    /// Lists the names of the extended attributes of the file at *path*
+    pub fn list_xattrs(&self, path: &str) -> Result<Vec<String>, FsError> {
+        with_fs!(self, fs => fs.list_xattrs(&fs::resolve(fs, path)?.node))
+    }
+
+    /// Lists the blocks (or clusters) holding the file at *path*
+    pub fn extents(&self, path: &str) -> Result<Vec<Extent>, FsError> {
+        match self {
+            Mount::Fat(fat) => fat.extents(&fs::resolve(fat, path)?.node),
+            Mount::Exfat(exfat) => exfat.extents(&fs::resolve(exfat, path)?.node),
+            Mount::Ext(ext) => ext.extents(fs::resolve(ext, path)?.node),
+            Mount::Iso(iso) => Ok(iso.extents(&fs::resolve(iso, path)?.node)),
+            Mount::Squash(_) | Mount::SquashImage(_) => Err(FsError::Unsupported("extents of compressed files")),
+            Mount::Archive(_) => Err(FsError::Unsupported("extents of archive members")),
+            Mount::Zxfs(zxfs) => zxfs.extents(fs::resolve(zxfs, path)?.node),
+        }
+    }
+
+    /// Creates the file at *path* or replaces its contents. *mtime* is in seconds since the UNIX epoch. Only FAT can be written to.
+    pub fn write_file(&self, path: &str, contents: &[u8], mtime: i64) -> Result<(), FsError> {
+        match self {
+            Mount::Fat(fat)     => fat.write_file(path, contents, mtime),
+            Mount::Exfat(_)     => Err(FsError::Unsupported("writing to exFAT")),
+            Mount::Ext(_)       => Err(FsError::Unsupported("writing to EXT")),
+            Mount::Iso(_)       => Err(FsError::Unsupported("writing to ISO9660")),
+            Mount::Squash(_) |
+            Mount::SquashImage(_) => Err(FsError::Unsupported("writing to SquashFS")),
+            Mount::Archive(_)   => Err(FsError::Unsupported("writing to archives")),
+            Mount::Zxfs(_)      => Err(FsError::Unsupported("writing to ZXFS")),
+        }
+    }
+
+    /// Mounts the archive or filesystem image in the file at *path*. tar and cpio archives are read into memory, while SquashFS images
+    /// are read from the file as needed. The returned mount takes over this one.
+    pub fn open_archive(self, path: &str) -> Result<Mount<D>, FsError> {
+        let image = FileDevice::new(self, path)?;
+
+        if squashfs::detect(&image)? {
+            return Ok(Mount::SquashImage(SquashFs::new(image)?));
+        }
+        else {
+            return Ok(Mount::Archive(ArchiveFs::new(image.mount.read_file(path)?)?));
+        }
+    }
+
+    /// Resolves *path* to a node that read_node can read without walking the path again
+    fn resolve_node(&self, path: &str) -> Result<(MountNode<D>, Metadata), FsError> {
+        match self {
+            Mount::Fat(fs) => resolve_with_metadata(fs, path).map(|(node, metadata)| (MountNode::Fat(node), metadata)),
+            Mount::Exfat(fs) => resolve_with_metadata(fs, path).map(|(node, metadata)| (MountNode::Exfat(node), metadata)),
+            Mount::Ext(fs) => resolve_with_metadata(fs, path).map(|(node, metadata)| (MountNode::Ext(node), metadata)),
+            Mount::Iso(fs) => resolve_with_metadata(fs, path).map(|(node, metadata)| (MountNode::Iso(node), metadata)),
+            Mount::Squash(fs) => resolve_with_metadata(fs, path).map(|(node, metadata)| (MountNode::Squash(node), metadata)),
+            Mount::SquashImage(fs) => resolve_with_metadata(fs, path).map(|(node, metadata)| (MountNode::SquashImage(node), metadata)),
+            Mount::Archive(fs) => resolve_with_metadata(fs, path).map(|(node, metadata)| (MountNode::Archive(node), metadata)),
+            Mount::Zxfs(fs) => resolve_with_metadata(fs, path).map(|(node, metadata)| (MountNode::Zxfs(node), metadata)),
+        }
+    }
+
+    /// Reads part of a node from resolve_node starting at byte *offset*. Returns the number of bytes read.
+    fn read_node(&self, node: &MountNode<D>, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        with_node!(self, node, fs, node => fs.read(node, offset, buffer))
+    }
+
+    /// Lists the interesting superblock fields as name/value pairs
+    pub fn superblock_info(&self) -> Result<Vec<(&'static str, String)>, FsError> {
+        match self {
+            Mount::Fat(fat) => fat.superblock_info(),
+            Mount::Exfat(exfat) => exfat.superblock_info(),
+            Mount::Ext(ext) => Ok(ext.superblock_info()),
+            Mount::Iso(iso) => iso.superblock_info(),
+            Mount::Squash(squash) => Ok(squash.superblock_info()),
+            Mount::SquashImage(squash) => Ok(squash.superblock_info()),
+            Mount::Archive(archive) => Ok(archive.superblock_info()),
+            Mount::Zxfs(zxfs) => Ok(zxfs.superblock_info()),
+        }
+    }
+
+    /// Names the optional on-disk features the volume uses. Only EXT has feature flags, so every other filesystem lists none.
+    pub fn features(&self) -> Vec<String> {
+        match self {
+            Mount::Ext(ext) => ext.features(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+
+
+/// Resolves *path* on *fs*, returning its node along with its metadata
+fn resolve_with_metadata<F: Filesystem>(fs: &F, path: &str) -> Result<(F::Node, Metadata), FsError> {
+    let node = fs::resolve(fs, path)?.node;
+    let metadata = fs.metadata(&node)?;
+
+    Ok((node, metadata))
+}
+
+
+
+/// A file on a mounted filesystem, read as a device so that filesystem images stored as files can be mounted. The file is resolved
+/// once, when it's opened, and every block is read through its node.
+pub struct FileDevice<D: BlockDevice> {
+    mount:  Box<Mount<D>>,
+    node:   MountNode<D>,
+    size:   u64,
+}
+
+impl<D: BlockDevice> FileDevice<D> {
+    pub fn new(mount: Mount<D>, path: &str) -> Result<Self, FsError> {
+        let (node, metadata) = mount.resolve_node(path)?;
+        if metadata.file_type == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+
+        Ok(Self {
+            mount:  Box::new(mount),
+            node,
+            size:   metadata.size,
+        })
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for FileDevice<D> {
+    fn block_size(&self) -> usize {
+        512
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), DiskError> {
+        let offset = lba * 512;
+        if offset >= self.size {
+            return Err(DiskError::OutOfRange);
+        }
+
+        // The last block is padded with zeros, like a disk image that isn't a whole number of blocks
+        let read = match self.mount.read_node(&self.node, offset, buffer) {
+            Ok(read)                => read,
+            Err(FsError::Io(err))   => return Err(err),
+            Err(_)                  => return Err(DiskError::Io(0)),
+        };
+        buffer[read..].fill(0);
+
+        Ok(())
+    }
+}
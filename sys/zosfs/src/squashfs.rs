@@ -0,0 +1,666 @@
+/*  squashfs.rs - SquashFS driver
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Read only SquashFS 4.0 driver.
+//!
+//! Inodes and directories live in tables of metadata blocks, each compressed on its own and decompressing to at most 8KiB. Inodes are
+//! referred to by the position of their metadata block within the inode table and their offset inside it. File contents are stored as
+//! compressed blocks of the filesystem's block size, with the tails of files packed together into shared fragment blocks.
+
+#![allow(dead_code)]
+
+use core::cell::RefCell;
+use alloc::{format, rc::Rc, string::{String, ToString}, vec::Vec, vec};
+use crate::block::{self, BlockDevice};
+use crate::compress::{inflate, lz4, zstd};
+use crate::error::FsError;
+use crate::fs::{CaseRule, DirEntry, FileType, Filesystem, Metadata};
+
+
+const MAGIC: u32 = 0x73717368;
+const VERSION_MAJOR: u16 = 4;
+
+const COMPRESSION_ZLIB: u16 = 1;
+const COMPRESSION_LZMA: u16 = 2;
+const COMPRESSION_LZO: u16 = 3;
+const COMPRESSION_XZ: u16 = 4;
+const COMPRESSION_LZ4: u16 = 5;
+const COMPRESSION_ZSTD: u16 = 6;
+
+const FLAG_NO_FRAGMENTS: u16 = 0x0010;
+const FLAG_DUPLICATES: u16 = 0x0040;
+const FLAG_EXPORTABLE: u16 = 0x0080;
+const FLAG_COMPRESSOR_OPTIONS: u16 = 0x0400;
+
+/// Size of a metadata block once decompressed
+const METADATA_SIZE: usize = 8192;
+/// Number of decompressed metadata blocks kept in memory. Walking a directory or an inode's block list stays within a few of them.
+const METADATA_CACHE_BLOCKS: usize = 32;
+/// Set in a metadata block header when the block is stored uncompressed
+const METADATA_UNCOMPRESSED: u16 = 0x8000;
+/// Set in a data or fragment block size when the block is stored uncompressed
+const BLOCK_UNCOMPRESSED: u32 = 0x1000000;
+
+/// Fragment index of files without a fragment
+const NO_FRAGMENT: u32 = 0xFFFFFFFF;
+
+const INODE_DIR: u16 = 1;
+const INODE_FILE: u16 = 2;
+const INODE_SYMLINK: u16 = 3;
+const INODE_EXT_DIR: u16 = 8;
+const INODE_EXT_FILE: u16 = 9;
+const INODE_EXT_SYMLINK: u16 = 10;
+
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SuperBlock {
+    pub magic:                  u32,
+    pub inode_count:            u32,
+    pub mkfs_time:              u32,
+    pub block_size:             u32,
+    pub fragment_count:         u32,
+    pub compression:            u16,
+    pub block_log:              u16,
+    pub flags:                  u16,
+    pub id_count:               u16,
+    pub version_major:          u16,
+    pub version_minor:          u16,
+    pub root_inode:             u64,
+    pub bytes_used:             u64,
+    pub id_table_start:         u64,
+    pub xattr_table_start:      u64,
+    pub inode_table_start:      u64,
+    pub directory_table_start:  u64,
+    pub fragment_table_start:   u64,
+    pub export_table_start:     u64,
+}
+
+
+/// Fields every inode starts with
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct InodeHeader {
+    pub inode_type:     u16,
+    pub mode:           u16,
+    pub uid_index:      u16,
+    pub gid_index:      u16,
+    pub mtime:          u32,
+    pub inode_number:   u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct DirInode {
+    pub block_index:    u32,
+    pub link_count:     u32,
+    pub file_size:      u16,
+    pub block_offset:   u16,
+    pub parent_inode:   u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct ExtDirInode {
+    pub link_count:     u32,
+    pub file_size:      u32,
+    pub block_index:    u32,
+    pub parent_inode:   u32,
+    pub index_count:    u16,
+    pub block_offset:   u16,
+    pub xattr_index:    u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct FileInode {
+    pub blocks_start:       u32,
+    pub fragment_index:     u32,
+    pub fragment_offset:    u32,
+    pub file_size:          u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct ExtFileInode {
+    pub blocks_start:       u64,
+    pub file_size:          u64,
+    pub sparse:             u64,
+    pub link_count:         u32,
+    pub fragment_index:     u32,
+    pub fragment_offset:    u32,
+    pub xattr_index:        u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct SymlinkInode {
+    pub link_count:     u32,
+    pub target_size:    u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct DirHeader {
+    pub count:          u32,
+    pub start:          u32,
+    pub inode_number:   u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct DirEntryHeader {
+    pub offset:         u16,
+    pub inode_offset:   i16,
+    pub entry_type:     u16,
+    pub name_size:      u16,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct FragmentEntry {
+    pub start:          u64,
+    pub size:           u32,
+    _unused:            u32,
+}
+
+
+/// An inode, decoded from whichever of the basic or extended forms it was stored in
+#[derive(Clone)]
+struct Inode {
+    file_type:          FileType,
+    mode:               u16,
+    uid_index:          u16,
+    gid_index:          u16,
+    mtime:              u32,
+    number:             u32,
+    size:               u64,
+    /// Directories: where the listing starts in the directory table
+    dir_block:          u32,
+    dir_offset:         u16,
+    /// Files: where the first block is, the stored size of each block, and the fragment holding the tail
+    blocks_start:       u64,
+    block_sizes:        Vec<u32>,
+    fragment_index:     u32,
+    fragment_offset:    u32,
+    /// Symlinks: the target
+    target:             Vec<u8>,
+}
+
+
+
+/// Checks for the SquashFS magic at the start of the device
+pub fn detect<D: BlockDevice>(dev: &D) -> Result<bool, FsError> {
+    let mut magic = [0u8; 4];
+    dev.read_bytes(0, &mut magic)?;
+
+    if u32::from_le_bytes(magic) == MAGIC {
+        return Ok(true);
+    }
+    else {
+        return Ok(false);
+    }
+}
+
+
+
+/// A decompressed metadata block held in memory
+struct CachedMetadata {
+    /// Position of the block on the device
+    pos:    u64,
+    data:   Rc<Vec<u8>>,
+    /// Position of the block after it
+    next:   u64,
+}
+
+
+pub struct SquashFs<D: BlockDevice> {
+    dev:            D,
+    sb:             SuperBlock,
+    /// Recently used metadata blocks, the most recent last
+    metadata_cache: RefCell<Vec<CachedMetadata>>,
+}
+
+impl<D: BlockDevice> SquashFs<D> {
+    pub fn new(dev: D) -> Result<Self, FsError> {
+        let sb: SuperBlock = block::read_struct(&dev, 0)?;
+
+        if sb.magic != MAGIC {
+            return Err(FsError::UnknownFilesystem);
+        }
+        if sb.version_major != VERSION_MAJOR {
+            return Err(FsError::Unsupported("SquashFS versions other than 4"));
+        }
+        if sb.block_size.count_ones() != 1 || sb.block_size != 1 << sb.block_log || sb.block_size > 1 << 20 {
+            return Err(FsError::Corrupt("invalid SquashFS block size"));
+        }
+
+        match sb.compression {
+            COMPRESSION_ZLIB | COMPRESSION_LZ4 | COMPRESSION_ZSTD   => {}
+            COMPRESSION_LZMA                                        => return Err(FsError::Unsupported("LZMA compressed SquashFS")),
+            COMPRESSION_LZO                                         => return Err(FsError::Unsupported("LZO compressed SquashFS")),
+            COMPRESSION_XZ                                          => return Err(FsError::Unsupported("XZ compressed SquashFS")),
+            _                                                       => return Err(FsError::Corrupt("unknown SquashFS compression")),
+        }
+
+        Ok(Self {
+            dev,
+            sb,
+            metadata_cache: RefCell::new(Vec::new()),
+        })
+    }
+
+
+    /// Decompresses a data or metadata block, which can't be larger than *max* bytes once decompressed
+    fn decompress(&self, data: &[u8], max: usize) -> Result<Vec<u8>, FsError> {
+        let decompressed = match self.sb.compression {
+            COMPRESSION_ZLIB    => inflate::zlib_decompress(data)?,
+            COMPRESSION_ZSTD    => zstd::decompress(data)?,
+            _                   => {
+                let mut decompressed = Vec::with_capacity(max);
+                lz4::decompress_block(data, &mut decompressed)?;
+                decompressed
+            }
+        };
+
+        if decompressed.len() > max {
+            return Err(FsError::Corrupt("SquashFS block decompresses to more than the block size"));
+        }
+
+        Ok(decompressed)
+    }
+
+
+    /// Reads the metadata block at byte *pos* of the device through the cache. Returns its contents and the position of the block after
+    /// it.
+    fn metadata_block(&self, pos: u64) -> Result<(Rc<Vec<u8>>, u64), FsError> {
+        let mut cache = self.metadata_cache.borrow_mut();
+
+        if let Some(index) = cache.iter().position(|cached| cached.pos == pos) {
+            let cached = cache.remove(index);
+            let block = (cached.data.clone(), cached.next);
+            cache.push(cached);
+
+            return Ok(block);
+        }
+
+        let mut header = [0u8; 2];
+        self.dev.read_bytes(pos, &mut header)?;
+        let header = u16::from_le_bytes(header);
+        let size = (header & !METADATA_UNCOMPRESSED) as usize;
+
+        let mut raw = vec![0u8; size];
+        self.dev.read_bytes(pos + 2, &mut raw)?;
+
+        let data = Rc::new(if header & METADATA_UNCOMPRESSED != 0 { raw } else { self.decompress(&raw, METADATA_SIZE)? });
+        let next = pos + 2 + size as u64;
+
+        if cache.len() >= METADATA_CACHE_BLOCKS {
+            cache.remove(0);
+        }
+        cache.push(CachedMetadata { pos, data: data.clone(), next });
+
+        Ok((data, next))
+    }
+
+
+    /// Reads *len* bytes of metadata starting *offset* bytes into the block at *pos*, continuing into the following blocks as needed.
+    /// Returns the bytes and the position right after them.
+    fn read_metadata(&self, mut pos: u64, mut offset: usize, len: usize) -> Result<(Vec<u8>, u64, usize), FsError> {
+        let mut data = Vec::with_capacity(len);
+
+        while data.len() < len {
+            let (block, next) = self.metadata_block(pos)?;
+            if offset >= block.len() {
+                if offset == block.len() && offset == METADATA_SIZE {
+                    (pos, offset) = (next, 0);
+                    continue;
+                }
+                return Err(FsError::Corrupt("SquashFS metadata reference past the end of its block"));
+            }
+
+            let count = (len - data.len()).min(block.len() - offset);
+            data.extend_from_slice(&block[offset..offset + count]);
+            offset += count;
+
+            if offset == block.len() && data.len() < len {
+                (pos, offset) = (next, 0);
+            }
+        }
+
+        Ok((data, pos, offset))
+    }
+
+
+    /// Reads entry *index* of a lookup table (ids, fragments), which is a list of metadata blocks indexed by an array of their positions
+    fn table_entry<T: Copy>(&self, table_start: u64, index: u32, count: u32) -> Result<T, FsError> {
+        if index >= count {
+            return Err(FsError::Corrupt("SquashFS table index out of range"));
+        }
+
+        let size = core::mem::size_of::<T>();
+        let per_block = METADATA_SIZE / size;
+
+        let mut pos = [0u8; 8];
+        self.dev.read_bytes(table_start + (index as usize / per_block) as u64 * 8, &mut pos)?;
+        let (data, _, _) = self.read_metadata(u64::from_le_bytes(pos), (index as usize % per_block) * size, size)?;
+
+        Ok(block::struct_from_bytes(&data))
+    }
+
+
+    /// Looks up a uid or gid in the id table
+    fn id(&self, index: u16) -> Result<u32, FsError> {
+        self.table_entry(self.sb.id_table_start, index as u32, self.sb.id_count as u32)
+    }
+
+
+    fn fragment(&self, index: u32) -> Result<FragmentEntry, FsError> {
+        self.table_entry(self.sb.fragment_table_start, index, self.sb.fragment_count)
+    }
+
+
+    /// Reads the inode referred to by *reference*
+    fn inode(&self, reference: u64) -> Result<Inode, FsError> {
+        let pos = self.sb.inode_table_start + (reference >> 16);
+        let offset = (reference & 0xFFFF) as usize;
+
+        let (raw, pos, offset) = self.read_metadata(pos, offset, size_of::<InodeHeader>())?;
+        let header: InodeHeader = block::struct_from_bytes(&raw);
+
+        let mut inode = Inode {
+            file_type:          FileType::Other,
+            mode:               header.mode & 0o7777,
+            uid_index:          header.uid_index,
+            gid_index:          header.gid_index,
+            mtime:              header.mtime,
+            number:             header.inode_number,
+            size:               0,
+            dir_block:          0,
+            dir_offset:         0,
+            blocks_start:       0,
+            block_sizes:        Vec::new(),
+            fragment_index:     NO_FRAGMENT,
+            fragment_offset:    0,
+            target:             Vec::new(),
+        };
+
+        match header.inode_type {
+            INODE_DIR           => {
+                let dir: DirInode = block::struct_from_bytes(&self.read_metadata(pos, offset, size_of::<DirInode>())?.0);
+                inode.file_type = FileType::Directory;
+                // Directory sizes count 3 bytes more than the listing, for the "." and ".." entries that aren't stored
+                inode.size = (dir.file_size as u64).saturating_sub(3);
+                inode.dir_block = dir.block_index;
+                inode.dir_offset = dir.block_offset;
+            }
+
+            INODE_EXT_DIR       => {
+                let dir: ExtDirInode = block::struct_from_bytes(&self.read_metadata(pos, offset, size_of::<ExtDirInode>())?.0);
+                inode.file_type = FileType::Directory;
+                inode.size = (dir.file_size as u64).saturating_sub(3);
+                inode.dir_block = dir.block_index;
+                inode.dir_offset = dir.block_offset;
+            }
+
+            INODE_FILE | INODE_EXT_FILE => {
+                let (pos, offset) = if header.inode_type == INODE_FILE {
+                    let (raw, pos, offset) = self.read_metadata(pos, offset, size_of::<FileInode>())?;
+                    let file: FileInode = block::struct_from_bytes(&raw);
+                    inode.blocks_start = file.blocks_start as u64;
+                    inode.size = file.file_size as u64;
+                    inode.fragment_index = file.fragment_index;
+                    inode.fragment_offset = file.fragment_offset;
+
+                    (pos, offset)
+                } else {
+                    let (raw, pos, offset) = self.read_metadata(pos, offset, size_of::<ExtFileInode>())?;
+                    let file: ExtFileInode = block::struct_from_bytes(&raw);
+                    inode.blocks_start = file.blocks_start;
+                    inode.size = file.file_size;
+                    inode.fragment_index = file.fragment_index;
+                    inode.fragment_offset = file.fragment_offset;
+
+                    (pos, offset)
+                };
+
+                // Every full block has a size, and so does the tail unless it went into a fragment
+                let block_size = self.sb.block_size as u64;
+                let count = if inode.fragment_index == NO_FRAGMENT { inode.size.div_ceil(block_size) } else { inode.size / block_size };
+                if count > self.sb.bytes_used {
+                    return Err(FsError::Corrupt("SquashFS file larger than the filesystem"));
+                }
+
+                let (sizes, _, _) = self.read_metadata(pos, offset, count as usize * 4)?;
+                inode.block_sizes = sizes.chunks(4).map(|size| u32::from_le_bytes(size.try_into().unwrap())).collect();
+                inode.file_type = FileType::Regular;
+            }
+
+            INODE_SYMLINK | INODE_EXT_SYMLINK => {
+                let (raw, pos, offset) = self.read_metadata(pos, offset, size_of::<SymlinkInode>())?;
+                let symlink: SymlinkInode = block::struct_from_bytes(&raw);
+                if symlink.target_size > 4096 {
+                    return Err(FsError::Corrupt("SquashFS symlink target too long"));
+                }
+
+                inode.target = self.read_metadata(pos, offset, symlink.target_size as usize)?.0;
+                inode.size = symlink.target_size as u64;
+                inode.file_type = FileType::Symlink;
+            }
+
+            // Devices, FIFOs and sockets
+            4..=7 | 11..=14     => {}
+
+            _                   => return Err(FsError::Corrupt("unknown SquashFS inode type")),
+        }
+
+        Ok(inode)
+    }
+
+
+    /// Returns block *index* of a file, decompressed. The last block may come from a fragment.
+    fn data_block(&self, inode: &Inode, index: usize, block_start: u64) -> Result<Vec<u8>, FsError> {
+        let block_size = self.sb.block_size as usize;
+        let len = block_size.min((inode.size - (index * block_size) as u64) as usize);
+
+        if index < inode.block_sizes.len() {
+            let stored = inode.block_sizes[index];
+            let size = (stored & !BLOCK_UNCOMPRESSED) as usize;
+
+            // Blocks of zeros aren't stored at all
+            if size == 0 {
+                return Ok(vec![0; len]);
+            }
+
+            let mut raw = vec![0u8; size];
+            self.dev.read_bytes(block_start, &mut raw)?;
+
+            let data = if stored & BLOCK_UNCOMPRESSED != 0 { raw } else { self.decompress(&raw, block_size)? };
+            if data.len() != len {
+                return Err(FsError::Corrupt("SquashFS data block has the wrong size"));
+            }
+
+            Ok(data)
+        }
+        else {
+            let fragment = self.fragment(inode.fragment_index)?;
+            let size = (fragment.size & !BLOCK_UNCOMPRESSED) as usize;
+            if size > block_size {
+                return Err(FsError::Corrupt("SquashFS fragment larger than the block size"));
+            }
+
+            let mut raw = vec![0u8; size];
+            self.dev.read_bytes(fragment.start, &mut raw)?;
+
+            let data = if fragment.size & BLOCK_UNCOMPRESSED != 0 { raw } else { self.decompress(&raw, block_size)? };
+            let start = inode.fragment_offset as usize;
+            let tail = data.get(start..start + len).ok_or(FsError::Corrupt("file tail past the end of its SquashFS fragment"))?;
+
+            Ok(tail.to_vec())
+        }
+    }
+
+
+    /// Lists the interesting properties of the filesystem as name/value pairs, for debugging tools
+    pub fn superblock_info(&self) -> Vec<(&'static str, String)> {
+        let sb = &self.sb;
+
+        let compression = match sb.compression {
+            COMPRESSION_ZLIB    => "gzip",
+            COMPRESSION_LZMA    => "lzma",
+            COMPRESSION_LZO     => "lzo",
+            COMPRESSION_XZ      => "xz",
+            COMPRESSION_LZ4     => "lz4",
+            COMPRESSION_ZSTD    => "zstd",
+            _                   => "unknown",
+        };
+
+        vec![
+            ("Version",             format!("{}.{}", { sb.version_major }, { sb.version_minor })),
+            ("Compression",         compression.to_string()),
+            ("Block size",          { sb.block_size }.to_string()),
+            ("Inode count",         { sb.inode_count }.to_string()),
+            ("Fragment count",      { sb.fragment_count }.to_string()),
+            ("ID count",            { sb.id_count }.to_string()),
+            ("Bytes used",          { sb.bytes_used }.to_string()),
+            ("Created",             { sb.mkfs_time }.to_string()),
+            ("Flags",               format!("{:#06x}", { sb.flags })),
+            ("Compressor options",  if sb.flags & FLAG_COMPRESSOR_OPTIONS != 0 { "yes" } else { "no" }.to_string()),
+            ("Fragments",           if sb.flags & FLAG_NO_FRAGMENTS != 0 { "no" } else { "yes" }.to_string()),
+            ("Exportable",          if sb.flags & FLAG_EXPORTABLE != 0 { "yes" } else { "no" }.to_string()),
+        ]
+    }
+}
+
+impl<D: BlockDevice> Filesystem for SquashFs<D> {
+    /// An inode reference: the position of the inode's metadata block in the inode table, shifted left by 16, plus its offset within
+    /// the block
+    type Node = u64;
+
+    fn case_rule(&self) -> CaseRule {
+        CaseRule::Sensitive
+    }
+
+    fn root(&self) -> Result<DirEntry<u64>, FsError> {
+        Ok(DirEntry {
+            name:       "/".to_string(),
+            file_type:  FileType::Directory,
+            node:       self.sb.root_inode,
+        })
+    }
+
+    fn read_dir(&self, dir: &u64) -> Result<Vec<DirEntry<u64>>, FsError> {
+        let inode = self.inode(*dir)?;
+        if inode.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+
+        let (listing, _, _) = self.read_metadata(self.sb.directory_table_start + inode.dir_block as u64, inode.dir_offset as usize,
+                                                 inode.size as usize)?;
+
+        // Entries come in runs sharing a header, which holds the metadata block of their inodes
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos + size_of::<DirHeader>() <= listing.len() {
+            let header: DirHeader = block::struct_from_bytes(&listing[pos..]);
+            pos += size_of::<DirHeader>();
+
+            for _ in 0..=header.count {
+                let raw = listing.get(pos..pos + size_of::<DirEntryHeader>()).ok_or(FsError::Corrupt("truncated SquashFS directory"))?;
+                let entry: DirEntryHeader = block::struct_from_bytes(raw);
+                pos += size_of::<DirEntryHeader>();
+
+                let name = listing.get(pos..pos + entry.name_size as usize + 1).ok_or(FsError::Corrupt("truncated SquashFS directory"))?;
+                pos += name.len();
+
+                let file_type = match entry.entry_type {
+                    INODE_DIR       => FileType::Directory,
+                    INODE_FILE      => FileType::Regular,
+                    INODE_SYMLINK   => FileType::Symlink,
+                    _               => FileType::Other,
+                };
+
+                entries.push(DirEntry {
+                    name:   String::from_utf8_lossy(name).to_string(),
+                    file_type,
+                    node:   ((header.start as u64) << 16) | entry.offset as u64,
+                });
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn read_link(&self, node: &u64) -> Result<String, FsError> {
+        Ok(String::from_utf8_lossy(&self.inode(*node)?.target).to_string())
+    }
+
+    fn metadata(&self, node: &u64) -> Result<Metadata, FsError> {
+        let inode = self.inode(*node)?;
+
+        Ok(Metadata {
+            file_type:  inode.file_type,
+            size:       inode.size,
+            mode:       inode.mode,
+            uid:        self.id(inode.uid_index)?,
+            gid:        self.id(inode.gid_index)?,
+            mtime:      inode.mtime as i64,
+            id:         inode.number as u64,
+        })
+    }
+
+    fn read(&self, node: &u64, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let inode = self.inode(*node)?;
+        if inode.file_type == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        if inode.file_type != FileType::Regular || offset >= inode.size {
+            return Ok(0);
+        }
+
+        let len = buffer.len().min((inode.size - offset) as usize);
+        let block_size = self.sb.block_size as u64;
+
+        // Blocks are stored back to back, so where one starts is the sum of the sizes before it
+        let mut block_start = inode.blocks_start;
+        let first = (offset / block_size) as usize;
+        for size in inode.block_sizes.iter().take(first) {
+            block_start += (size & !BLOCK_UNCOMPRESSED) as u64;
+        }
+
+        let mut done = 0;
+        let mut index = first;
+        while done < len {
+            let data = self.data_block(&inode, index, block_start)?;
+            let pos = offset + done as u64;
+            let skip = (pos - index as u64 * block_size) as usize;
+            let count = (len - done).min(data.len() - skip);
+
+            buffer[done..done + count].copy_from_slice(&data[skip..skip + count]);
+            done += count;
+
+            if let Some(size) = inode.block_sizes.get(index) {
+                block_start += (size & !BLOCK_UNCOMPRESSED) as u64;
+            }
+            index += 1;
+        }
+
+        Ok(len)
+    }
+}
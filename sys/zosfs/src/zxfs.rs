@@ -0,0 +1,559 @@
+/*  zxfs.rs - ZXFS, the native zOS filesystem
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Read only driver for ZXFS, the native zOS filesystem found on partitions of type ab37caea-8669-47e9-9373-425e6de819e3. Images are
+//! created with mkfs-zxfs.
+//!
+//! # On-disk format (version 1)
+//!
+//! All integers are little endian. The volume is divided into blocks of 1KiB to 64KiB, numbered from 0.
+//!
+//! - **Superblock**: the first 1024 bytes of block 0. Identifies the volume and holds the inode describing the inode table, so
+//!   everything else can be found from it. The last field is a CRC-32C of the rest.
+//! - **Inodes**: 256 bytes each, stored in the inode table, a file whose inode lives in the superblock. Inode *n* is at byte
+//!   *n* × 256 of the table, so inode 0 is never used. Inode 1 is the inode table itself, 2 the free space bitmap, 3 the root
+//!   directory, and inodes from 16 up belong to files. Each inode records its own number and ends with a CRC-32C, so a misplaced or
+//!   damaged inode is caught.
+//! - **Extents**: file contents are mapped by an extent tree rooted in the inode. A node has a header followed by entries; at depth 0
+//!   the entries are extents (logical block, physical block, length), above that they are index entries pointing to extent blocks one
+//!   level down. The root holds up to 7 entries, extent blocks as many as fit and carry a CRC-32C and their owner's inode number.
+//!   Blocks no extent covers are holes and read as zeros. Contents up to 176 bytes, e.g symlink targets, can be stored in the inode
+//!   instead of the tree (INODE_INLINE_DATA).
+//! - **Directories**: files made of blocks of variable length entries (inode number, entry length, name length, type, name). Entries
+//!   are 8 byte aligned and never cross a block, unused space is covered by entries with inode 0, and each block ends with an 8 byte
+//!   tail holding a CRC-32C of the block. Names are UTF-8, up to 255 bytes, and case sensitive. There are no "." and ".." entries.
+//! - **Free space**: inode 2 is a bitmap with one bit per block, set when the block is in use.
+//!
+//! File data isn't checksummed.
+
+#![allow(dead_code)]
+
+use alloc::{boxed::Box, format, string::{String, ToString}, vec::Vec, vec};
+use crate::block::{self, BlockDevice};
+use crate::checksum::crc32c;
+use crate::error::FsError;
+use crate::fs::{CaseRule, DirEntry, Extent, FileType, Filesystem, Metadata};
+
+
+pub const MAGIC: [u8; 8] = *b"zOS XFS\0";
+pub const VERSION: u32 = 1;
+
+pub const SUPERBLOCK_SIZE: usize = 1024;
+pub const INODE_SIZE: usize = 256;
+pub const MIN_BLOCK_SIZE: u32 = 1024;
+pub const MAX_BLOCK_SIZE: u32 = 65536;
+
+pub const INODE_TABLE: u64 = 1;
+pub const INODE_BITMAP: u64 = 2;
+pub const INODE_ROOT: u64 = 3;
+/// First inode number available to files
+pub const FIRST_INODE: u64 = 16;
+
+/// Contents are stored in the inode's extent area instead of blocks
+pub const INODE_INLINE_DATA: u16 = 0x0001;
+/// Size of the extent area, which is also the most that can be stored inline
+pub const INLINE_SIZE: usize = 176;
+
+pub const EXTENT_MAGIC: u16 = 0x5845;
+/// Entries the extent tree root in an inode has room for
+pub const ROOT_EXTENTS: u16 = 7;
+/// Deepest extent tree that is accepted, which is far more than any volume needs
+const MAX_DEPTH: u16 = 4;
+/// The extent is allocated but not written yet, and reads as zeros
+pub const EXTENT_UNWRITTEN: u32 = 0x0001;
+
+pub const DIR_TAIL_MAGIC: u32 = 0x4C494154;
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Directory entry types, the same values as EXT uses
+pub const TYPE_REGULAR: u8 = 1;
+pub const TYPE_DIRECTORY: u8 = 2;
+pub const TYPE_CHARDEV: u8 = 3;
+pub const TYPE_BLOCKDEV: u8 = 4;
+pub const TYPE_FIFO: u8 = 5;
+pub const TYPE_SOCKET: u8 = 6;
+pub const TYPE_SYMLINK: u8 = 7;
+
+pub const S_IFMT: u16 = 0o170000;
+pub const S_IFDIR: u16 = 0o040000;
+pub const S_IFREG: u16 = 0o100000;
+pub const S_IFLNK: u16 = 0o120000;
+
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SuperBlock {
+    pub magic:              [u8; 8],
+    pub version:            u32,
+    pub block_size:         u32,
+    pub block_count:        u64,
+    pub free_blocks:        u64,
+    /// Number of inodes in use
+    pub inode_count:        u64,
+    pub root_inode:         u64,
+    pub uuid:               [u8; 16],
+    /// UTF-8, NUL padded
+    pub label:              [u8; 64],
+    pub created:            i64,
+    pub modified:           i64,
+    /// Feature flags. Volumes with unknown incompatible features can't be read, unknown read-only compatible ones can't be written.
+    pub compat:             u32,
+    pub incompat:           u32,
+    pub ro_compat:          u32,
+    pub _reserved1:         u32,
+    pub inode_table:        Inode,
+    pub _reserved2:         [u8; 604],
+    pub checksum:           u32,
+}
+
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct Inode {
+    /// File type and permissions, as in st_mode
+    pub mode:               u16,
+    pub flags:              u16,
+    pub links:              u32,
+    pub uid:                u32,
+    pub gid:                u32,
+    pub size:               u64,
+    /// Blocks allocated to the file, including extent blocks
+    pub blocks:             u64,
+    pub atime:              i64,
+    pub mtime:              i64,
+    pub ctime:              i64,
+    pub crtime:             i64,
+    /// The inode's own number
+    pub number:             u64,
+    pub generation:         u32,
+    /// Extent tree root, or the contents with INODE_INLINE_DATA
+    pub data:               [u8; INLINE_SIZE],
+    pub checksum:           u32,
+}
+
+
+/// Starts every extent tree node. Extent blocks follow it with the owner and checksum, see ExtentBlockHeader.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct ExtentHeader {
+    pub magic:              u16,
+    /// 0 when the entries are extents, otherwise the number of levels of extent blocks below
+    pub depth:              u16,
+    pub count:              u16,
+    pub max:                u16,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct ExtentBlockHeader {
+    pub header:             ExtentHeader,
+    /// Inode the block belongs to
+    pub owner:              u64,
+    /// CRC-32C of the whole block with this field zeroed
+    pub checksum:           u32,
+    pub _reserved:          u32,
+}
+
+/// A run of blocks, the entries of depth 0 nodes
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct ExtentEntry {
+    pub logical:            u64,
+    pub physical:           u64,
+    pub length:             u32,
+    pub flags:              u32,
+}
+
+/// Points to the extent block covering logical blocks from *logical* on, the entries of nodes above depth 0
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct IndexEntry {
+    pub logical:            u64,
+    pub block:              u64,
+    pub _reserved:          u64,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct DirEntryHeader {
+    pub inode:              u64,
+    /// Length of the whole entry including padding, a multiple of 8
+    pub entry_len:          u16,
+    pub name_len:           u8,
+    pub file_type:          u8,
+    pub _reserved:          u32,
+}
+
+/// Last 8 bytes of every directory block
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct DirTail {
+    pub magic:              u32,
+    /// CRC-32C of the block with this field zeroed
+    pub checksum:           u32,
+}
+
+
+const _: () = assert!(size_of::<SuperBlock>() == SUPERBLOCK_SIZE);
+const _: () = assert!(size_of::<Inode>() == INODE_SIZE);
+const _: () = assert!(size_of::<ExtentHeader>() + ROOT_EXTENTS as usize * size_of::<ExtentEntry>() == INLINE_SIZE);
+
+
+/// Computes the checksum of a structure or block whose checksum is in its bytes *field*..*field* + 4, treating that field as zero
+pub fn checksum(data: &[u8], field: usize) -> u32 {
+    let mut copy = data.to_vec();
+    copy[field..field + 4].fill(0);
+
+    crc32c(&copy)
+}
+
+
+/// Offsets of the checksum fields within their structures
+pub const SUPERBLOCK_CHECKSUM: usize = SUPERBLOCK_SIZE - 4;
+pub const INODE_CHECKSUM: usize = INODE_SIZE - 4;
+pub const EXTENT_BLOCK_CHECKSUM: usize = 16;
+pub const DIR_TAIL_SIZE: usize = 8;
+
+
+/// Checks for the ZXFS magic at the start of the device
+pub fn detect<D: BlockDevice>(dev: &D) -> Result<bool, FsError> {
+    let mut magic = [0u8; 8];
+    dev.read_bytes(0, &mut magic)?;
+
+    if magic == MAGIC {
+        return Ok(true);
+    }
+    else {
+        return Ok(false);
+    }
+}
+
+
+
+pub struct ZxFs<D: BlockDevice> {
+    dev:    D,
+    sb:     Box<SuperBlock>,
+}
+
+impl<D: BlockDevice> ZxFs<D> {
+    pub fn new(dev: D) -> Result<Self, FsError> {
+        let mut raw = vec![0u8; SUPERBLOCK_SIZE];
+        dev.read_bytes(0, &mut raw)?;
+        let sb: SuperBlock = block::struct_from_bytes(&raw);
+
+        if sb.magic != MAGIC {
+            return Err(FsError::UnknownFilesystem);
+        }
+        if checksum(&raw, SUPERBLOCK_CHECKSUM) != sb.checksum {
+            return Err(FsError::Corrupt("ZXFS superblock checksum mismatch"));
+        }
+        if sb.version != VERSION || sb.incompat != 0 {
+            return Err(FsError::Unsupported("ZXFS version or features"));
+        }
+        if !sb.block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&{ sb.block_size }) {
+            return Err(FsError::Corrupt("invalid ZXFS block size"));
+        }
+
+        let fs = Self {
+            dev,
+            sb: Box::new(sb),
+        };
+        fs.check_inode(&{ fs.sb.inode_table }, INODE_TABLE)?;
+
+        Ok(fs)
+    }
+
+
+    fn block_size(&self) -> u64 {
+        self.sb.block_size as u64
+    }
+
+
+    /// Returns the filesystem's UUID and label
+    pub fn volume_id(&self) -> (u128, String) {
+        let label_len = self.sb.label.iter().position(|b| *b == 0).unwrap_or(self.sb.label.len());
+        let label = String::from_utf8_lossy(&self.sb.label[..label_len]).to_string();
+
+        (u128::from_be_bytes(self.sb.uuid), label)
+    }
+
+
+    /// Verifies an inode's checksum and that it's the inode it should be
+    fn check_inode(&self, inode: &Inode, number: u64) -> Result<(), FsError> {
+        let raw = block::struct_to_bytes(inode);
+
+        if checksum(&raw, INODE_CHECKSUM) != inode.checksum {
+            return Err(FsError::Corrupt("ZXFS inode checksum mismatch"));
+        }
+        if inode.number != number {
+            return Err(FsError::Corrupt("ZXFS inode has the wrong number"));
+        }
+
+        Ok(())
+    }
+
+
+    /// Reads inode *number* from the inode table
+    fn inode(&self, number: u64) -> Result<Inode, FsError> {
+        if number == INODE_TABLE {
+            return Ok(self.sb.inode_table);
+        }
+
+        let mut raw = [0u8; INODE_SIZE];
+        let table = self.sb.inode_table;
+        if self.read_inode_data(&table, INODE_TABLE, number * INODE_SIZE as u64, &mut raw)? != INODE_SIZE {
+            return Err(FsError::Corrupt("ZXFS inode number past the end of the inode table"));
+        }
+
+        let inode: Inode = block::struct_from_bytes(&raw);
+        self.check_inode(&inode, number)?;
+
+        Ok(inode)
+    }
+
+
+    /// Walks the extent tree of an inode, returning every extent in logical order
+    fn inode_extents(&self, inode: &Inode, number: u64) -> Result<Vec<ExtentEntry>, FsError> {
+        if inode.flags & INODE_INLINE_DATA != 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut extents = Vec::new();
+        self.walk_node(&inode.data, number, None, &mut extents)?;
+
+        Ok(extents)
+    }
+
+
+    /// Collects the extents under the tree node in *node*. *depth* is the depth the parent expects, None for the root.
+    fn walk_node(&self, node: &[u8], owner: u64, depth: Option<u16>, extents: &mut Vec<ExtentEntry>) -> Result<(), FsError> {
+        let header: ExtentHeader = block::struct_from_bytes(node);
+        let entries_start = if depth.is_none() { size_of::<ExtentHeader>() } else { size_of::<ExtentBlockHeader>() };
+
+        if header.magic != EXTENT_MAGIC || header.depth > MAX_DEPTH || header.count > header.max
+           || entries_start + header.max as usize * size_of::<ExtentEntry>() > node.len() {
+            return Err(FsError::Corrupt("invalid ZXFS extent tree node"));
+        }
+        if depth.is_some_and(|depth| depth != header.depth) {
+            return Err(FsError::Corrupt("ZXFS extent tree node at the wrong depth"));
+        }
+
+        for i in 0..header.count as usize {
+            let entry = &node[entries_start + i * size_of::<ExtentEntry>()..];
+
+            if header.depth == 0 {
+                extents.push(block::struct_from_bytes(entry));
+            }
+            else {
+                let index: IndexEntry = block::struct_from_bytes(entry);
+                let mut child = vec![0u8; self.block_size() as usize];
+                self.dev.read_bytes(index.block * self.block_size(), &mut child)?;
+
+                let child_header: ExtentBlockHeader = block::struct_from_bytes(&child);
+                if checksum(&child, EXTENT_BLOCK_CHECKSUM) != child_header.checksum {
+                    return Err(FsError::Corrupt("ZXFS extent block checksum mismatch"));
+                }
+                if child_header.owner != owner {
+                    return Err(FsError::Corrupt("ZXFS extent block belongs to another inode"));
+                }
+
+                self.walk_node(&child, owner, Some(header.depth - 1), extents)?;
+            }
+        }
+
+        Ok(())
+    }
+
+
+    /// Reads part of an inode's contents starting at byte *offset*. Holes and unwritten extents read as zeros.
+    fn read_inode_data(&self, inode: &Inode, number: u64, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let len = buffer.len().min((inode.size - offset) as usize);
+
+        if inode.flags & INODE_INLINE_DATA != 0 {
+            if inode.size as usize > INLINE_SIZE {
+                return Err(FsError::Corrupt("ZXFS inline data larger than the inode"));
+            }
+            buffer[..len].copy_from_slice(&inode.data[offset as usize..offset as usize + len]);
+            return Ok(len);
+        }
+
+        buffer[..len].fill(0);
+        let block_size = self.block_size();
+
+        for extent in self.inode_extents(inode, number)? {
+            let start = extent.logical * block_size;
+            let end = start + extent.length as u64 * block_size;
+            if end <= offset || start >= offset + len as u64 || extent.flags & EXTENT_UNWRITTEN != 0 {
+                continue;
+            }
+
+            let from = start.max(offset);
+            let to = end.min(offset + len as u64);
+            let dest = &mut buffer[(from - offset) as usize..(to - offset) as usize];
+            self.dev.read_bytes(extent.physical * block_size + (from - start), dest)?;
+        }
+
+        Ok(len)
+    }
+
+
+    /// Lists the blocks holding the file with inode *number*
+    pub fn extents(&self, number: u64) -> Result<Vec<Extent>, FsError> {
+        let inode = self.inode(number)?;
+
+        Ok(self.inode_extents(&inode, number)?.iter().map(|extent| Extent {
+            logical:    extent.logical,
+            physical:   extent.physical,
+            length:     extent.length as u64,
+        }).collect())
+    }
+
+
+    /// Lists the interesting superblock fields as name/value pairs
+    pub fn superblock_info(&self) -> Vec<(&'static str, String)> {
+        let sb = &self.sb;
+        let (uuid, label) = self.volume_id();
+
+        vec![
+            ("Version",             { sb.version }.to_string()),
+            ("UUID",                format!("{:032x}", uuid)),
+            ("Label",               label),
+            ("Block size",          { sb.block_size }.to_string()),
+            ("Block count",         { sb.block_count }.to_string()),
+            ("Free blocks",         { sb.free_blocks }.to_string()),
+            ("Inode count",         { sb.inode_count }.to_string()),
+            ("Inode table size",    { sb.inode_table.size }.to_string()),
+            ("Root inode",          { sb.root_inode }.to_string()),
+            ("Created",             { sb.created }.to_string()),
+            ("Modified",            { sb.modified }.to_string()),
+            ("Features",            format!("compat {:#x}, incompat {:#x}, ro_compat {:#x}", { sb.compat }, { sb.incompat }, { sb.ro_compat })),
+        ]
+    }
+}
+
+impl<D: BlockDevice> Filesystem for ZxFs<D> {
+    /// Inode number
+    type Node = u64;
+
+    fn case_rule(&self) -> CaseRule {
+        CaseRule::Sensitive
+    }
+
+    fn root(&self) -> Result<DirEntry<u64>, FsError> {
+        Ok(DirEntry {
+            name:       "/".to_string(),
+            file_type:  FileType::Directory,
+            node:       self.sb.root_inode,
+        })
+    }
+
+    fn read_dir(&self, dir: &u64) -> Result<Vec<DirEntry<u64>>, FsError> {
+        let inode = self.inode(*dir)?;
+        if inode.mode & S_IFMT != S_IFDIR {
+            return Err(FsError::NotADirectory);
+        }
+
+        let block_size = self.block_size() as usize;
+        if !inode.size.is_multiple_of(block_size as u64) {
+            return Err(FsError::Corrupt("ZXFS directory isn't a whole number of blocks"));
+        }
+
+        let mut data = vec![0u8; inode.size as usize];
+        self.read_inode_data(&inode, *dir, 0, &mut data)?;
+
+        let mut entries = Vec::new();
+        for block in data.chunks(block_size) {
+            let tail: DirTail = block::struct_from_bytes(&block[block_size - DIR_TAIL_SIZE..]);
+            if tail.magic != DIR_TAIL_MAGIC || checksum(block, block_size - 4) != tail.checksum {
+                return Err(FsError::Corrupt("ZXFS directory block checksum mismatch"));
+            }
+
+            let mut pos = 0;
+            while pos < block_size - DIR_TAIL_SIZE {
+                let header: DirEntryHeader = block::struct_from_bytes(&block[pos..]);
+                let entry_len = header.entry_len as usize;
+                let name_start = pos + size_of::<DirEntryHeader>();
+
+                if entry_len < size_of::<DirEntryHeader>() || !entry_len.is_multiple_of(8) || pos + entry_len > block_size - DIR_TAIL_SIZE
+                   || name_start + header.name_len as usize > pos + entry_len {
+                    return Err(FsError::Corrupt("invalid ZXFS directory entry"));
+                }
+
+                if header.inode != 0 {
+                    let name = &block[name_start..name_start + header.name_len as usize];
+
+                    entries.push(DirEntry {
+                        name:       String::from_utf8_lossy(name).to_string(),
+                        file_type:  match header.file_type {
+                            TYPE_REGULAR    => FileType::Regular,
+                            TYPE_DIRECTORY  => FileType::Directory,
+                            TYPE_SYMLINK    => FileType::Symlink,
+                            _               => FileType::Other,
+                        },
+                        node:       header.inode,
+                    });
+                }
+
+                pos += entry_len;
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn read_link(&self, node: &u64) -> Result<String, FsError> {
+        let inode = self.inode(*node)?;
+        if inode.size > 4096 {
+            return Err(FsError::Corrupt("ZXFS symlink target too long"));
+        }
+
+        let mut target = vec![0u8; inode.size as usize];
+        self.read_inode_data(&inode, *node, 0, &mut target)?;
+
+        Ok(String::from_utf8_lossy(&target).to_string())
+    }
+
+    fn metadata(&self, node: &u64) -> Result<Metadata, FsError> {
+        let inode = self.inode(*node)?;
+
+        Ok(Metadata {
+            file_type:  match inode.mode & S_IFMT {
+                S_IFREG => FileType::Regular,
+                S_IFDIR => FileType::Directory,
+                S_IFLNK => FileType::Symlink,
+                _       => FileType::Other,
+            },
+            size:       inode.size,
+            mode:       inode.mode & 0o7777,
+            uid:        inode.uid,
+            gid:        inode.gid,
+            mtime:      inode.mtime,
+            id:         *node,
+        })
+    }
+
+    fn read(&self, node: &u64, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let inode = self.inode(*node)?;
+
+        self.read_inode_data(&inode, *node, offset, buffer)
+    }
+}
@@ -0,0 +1,262 @@
+/*  archive.rs - Tests for mounting tar archives built by tar and hand made cpio archives
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+mod common;
+
+use std::fs;
+use common::{run, TempDir};
+use zosfs::archive::{self, ArchiveFormat, ArchiveFs};
+use zosfs::image::ImageDevice;
+use zosfs::{mount, split_archive_path, FileType, FilesystemType, FsError, Mount};
+
+
+/// 2024-05-17 12:34:56
+const MTIME: i64 = 1715949296;
+
+
+/// A path over the 100 bytes a ustar name field holds, which each format stores differently: ustar splits it into a prefix, GNU tar
+/// puts it in a long name member and pax in an extended header
+fn long_path() -> String {
+    format!("zxt/{}/{}.zxt", "d".repeat(70), "f".repeat(90))
+}
+
+
+/// Several tar blocks worth of bytes that differ from block to block
+fn big_contents() -> Vec<u8> {
+    (0..5000u32).flat_map(|i| i.to_le_bytes()).collect()
+}
+
+
+/// Fills a directory with the tree the tar archives hold and returns its path
+fn build_tree(tmp: &TempDir) -> String {
+    let root = tmp.path().join("root");
+    let long = root.join(long_path());
+    fs::create_dir_all(long.parent().unwrap()).unwrap();
+    fs::create_dir_all(root.join("zxt/empty")).unwrap();
+
+    fs::write(root.join("zxt/hello.zxt"), b"hello").unwrap();
+    fs::write(&long, big_contents()).unwrap();
+    fs::hard_link(root.join("zxt/hello.zxt"), root.join("hello-again.zxt")).unwrap();
+    std::os::unix::fs::symlink("zxt/hello.zxt", root.join("hello-link")).unwrap();
+
+    root.to_str().unwrap().to_string()
+}
+
+
+/// Packs the tree from build_tree with tar in *format*. Returns the archive's path.
+fn build_tar(tmp: &TempDir, format: &str) -> String {
+    let root = build_tree(tmp);
+    let archive = tmp.path().join("extensions.tar");
+    let archive = archive.to_str().unwrap();
+
+    run("tar", &["--format", format, "--mtime", "@1715949296", "-C", &root, "-cf", archive, "."]);
+
+    archive.to_string()
+}
+
+
+/// Checks everything build_tree put in the archive
+fn check_tar(mount: &Mount<ImageDevice>) {
+    assert_eq!(mount.fs_type(), FilesystemType::ARCHIVE);
+
+    let root = mount.list_dir("/").unwrap();
+    assert_eq!(root.len(), 3);
+    assert!(root.contains(&("zxt".into(), FileType::Directory)));
+    assert!(root.contains(&("hello-link".into(), FileType::Symlink)));
+
+    assert_eq!(mount.read_file("/zxt/hello.zxt").unwrap(), b"hello");
+    assert_eq!(mount.read_file("/hello-again.zxt").unwrap(), b"hello");
+    assert_eq!(mount.read_file("/hello-link").unwrap(), b"hello");
+    assert_eq!(mount.read_file(&long_path()).unwrap(), big_contents());
+    assert_eq!(mount.list_dir("/zxt/empty").unwrap(), vec![]);
+
+    let metadata = mount.metadata("/zxt/hello.zxt").unwrap();
+    assert_eq!(metadata.size, 5);
+    assert_eq!(metadata.mtime, MTIME);
+
+    let mut buffer = [0u8; 8];
+    assert_eq!(mount.read_at(&long_path(), 4 * 4000, &mut buffer).unwrap(), 8);
+    assert_eq!(buffer, [0xA0, 0x0F, 0, 0, 0xA1, 0x0F, 0, 0]);
+
+    // Names are case sensitive, and nothing can be written
+    assert_eq!(mount.read_file("/ZXT/hello.zxt"), Err(FsError::NotFound));
+    assert_eq!(mount.write_file("/zxt/new", b"", MTIME), Err(FsError::Unsupported("writing to archives")));
+}
+
+
+fn mount_tar(format: &str, name: &str) {
+    let tmp = TempDir::new(name);
+    let archive = build_tar(&tmp, format);
+    let data = fs::read(archive).unwrap();
+
+    assert_eq!(archive::detect(&data), Some(ArchiveFormat::Tar));
+    check_tar(&Mount::Archive(ArchiveFs::new(data).unwrap()));
+}
+
+
+#[test]
+fn reads_ustar() {
+    mount_tar("ustar", "archive-ustar");
+}
+
+
+#[test]
+fn reads_pax() {
+    mount_tar("pax", "archive-pax");
+}
+
+
+#[test]
+fn reads_gnu_tar() {
+    mount_tar("gnu", "archive-gnu");
+}
+
+
+/// Opens an archive stored on a filesystem, the way the loader does for "archive:" paths
+#[test]
+fn opens_archive_on_filesystem() {
+    let tmp = TempDir::new("archive-ext");
+    let archive = build_tar(&tmp, "pax");
+
+    let root = tmp.path().join("ext");
+    fs::create_dir_all(root.join("boot")).unwrap();
+    fs::copy(archive, root.join("boot/extensions.tar")).unwrap();
+
+    let image = tmp.path().join("ext4.img");
+    let image = image.to_str().unwrap();
+    run("mkfs.ext4", &["-q", "-F", "-b", "4096", "-d", root.to_str().unwrap(), image, "8M"]);
+
+    let (archive, path) = split_archive_path("archive:/boot/extensions.tar!/zxt/hello.zxt").unwrap();
+    assert_eq!((archive, path), ("/boot/extensions.tar", "/zxt/hello.zxt"));
+    assert_eq!(split_archive_path("/boot/extensions.tar"), None);
+
+    let open = |path: &str| mount(ImageDevice::open(image).unwrap()).unwrap().open_archive(path);
+    assert_eq!(open(archive).unwrap().read_file(path).unwrap(), b"hello");
+    assert!(matches!(open("/boot"), Err(FsError::IsADirectory)));
+}
+
+
+/// Appends a ustar header for a member of type *typeflag* whose size field holds *size*
+fn tar_header(archive: &mut Vec<u8>, name: &str, typeflag: u8, size: &[u8; 12]) {
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..108].copy_from_slice(b"0000644\0");
+    header[124..136].copy_from_slice(size);
+    header[156] = typeflag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+    archive.extend_from_slice(&header);
+}
+
+
+/// Member sizes so large that the end of the member overflows are refused, not trusted
+#[test]
+fn rejects_oversized_tar_members() {
+    let record = b"29 size=18446744073709551000\n";
+    let mut pax = Vec::new();
+    tar_header(&mut pax, "PaxHeader", b'x', format!("{:011o}\0", record.len()).as_bytes().try_into().unwrap());
+    pax.extend_from_slice(record);
+    pax.resize(1024, 0);
+    tar_header(&mut pax, "kernel", b'0', b"00000000000\0");
+    assert_eq!(ArchiveFs::new(pax).err(), Some(FsError::Corrupt("tar member runs past the end of the archive")));
+
+    // GNU base 256 sizes can be just as large
+    let mut gnu = Vec::new();
+    tar_header(&mut gnu, "kernel", b'0', &[0x80, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00]);
+    gnu.resize(1536, 0);
+    assert_eq!(ArchiveFs::new(gnu).err(), Some(FsError::Corrupt("tar member runs past the end of the archive")));
+}
+
+
+/// Appends a newc cpio member. *ino* is shared by hard links, only the last of which carries the contents.
+fn newc_member(archive: &mut Vec<u8>, path: &str, ino: u32, mode: u32, nlink: u32, contents: &[u8]) {
+    let checksum = contents.iter().fold(0u32, |sum, b| sum.wrapping_add(*b as u32));
+    let fields = [ino, mode, 0, 0, nlink, MTIME as u32, contents.len() as u32, 0, 0, 0, 0, path.len() as u32 + 1, checksum];
+
+    archive.extend_from_slice(b"070702");
+    for field in fields {
+        archive.extend_from_slice(format!("{:08X}", field).as_bytes());
+    }
+    archive.extend_from_slice(path.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+    archive.extend_from_slice(contents);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+}
+
+
+/// Appends an odc cpio member
+fn odc_member(archive: &mut Vec<u8>, path: &str, ino: u32, mode: u32, contents: &[u8]) {
+    archive.extend_from_slice(format!("070707{:06o}{:06o}{:06o}{:06o}{:06o}{:06o}{:06o}{:011o}{:06o}{:011o}",
+                                      0, ino, mode, 0, 0, 1, 0, MTIME, path.len() + 1, contents.len()).as_bytes());
+    archive.extend_from_slice(path.as_bytes());
+    archive.push(0);
+    archive.extend_from_slice(contents);
+}
+
+
+#[test]
+fn reads_newc_cpio() {
+    // Members in the order an initramfs generator might list them, with the parent of hello.zxt left implicit
+    let mut data = Vec::new();
+    newc_member(&mut data, ".", 1, 0o040755, 2, b"");
+    newc_member(&mut data, "zxt/hello-again.zxt", 2, 0o100644, 2, b"");
+    newc_member(&mut data, "zxt/hello.zxt", 2, 0o100644, 2, b"hello");
+    newc_member(&mut data, "hello-link", 3, 0o120777, 1, b"zxt/hello.zxt");
+    newc_member(&mut data, "big", 4, 0o100600, 1, &big_contents());
+    newc_member(&mut data, "TRAILER!!!", 0, 0, 1, b"");
+
+    assert_eq!(archive::detect(&data), Some(ArchiveFormat::CpioNewc));
+    let mount: Mount<ImageDevice> = Mount::Archive(ArchiveFs::new(data.clone()).unwrap());
+
+    assert_eq!(mount.list_dir("/zxt").unwrap(), vec![("hello-again.zxt".into(), FileType::Regular), ("hello.zxt".into(), FileType::Regular)]);
+    assert_eq!(mount.read_file("/zxt/hello-again.zxt").unwrap(), b"hello");
+    assert_eq!(mount.read_file("/hello-link").unwrap(), b"hello");
+    assert_eq!(mount.read_file("/big").unwrap(), big_contents());
+
+    let metadata = mount.metadata("/big").unwrap();
+    assert_eq!((metadata.mode, metadata.mtime), (0o600, MTIME));
+
+    // A flipped byte in a member of a crc archive doesn't match its checksum
+    let last = data.len() - 200;
+    data[last] ^= 1;
+    assert!(matches!(ArchiveFs::new(data), Err(FsError::Corrupt(_))));
+}
+
+
+#[test]
+fn reads_odc_cpio() {
+    let mut data = Vec::new();
+    odc_member(&mut data, "zxt", 1, 0o040755, b"");
+    odc_member(&mut data, "zxt/hello.zxt", 2, 0o100644, b"hello");
+    odc_member(&mut data, "TRAILER!!!", 0, 0, b"");
+
+    assert_eq!(archive::detect(&data), Some(ArchiveFormat::CpioOdc));
+    let mount: Mount<ImageDevice> = Mount::Archive(ArchiveFs::new(data.clone()).unwrap());
+    assert_eq!(mount.read_file("/zxt/hello.zxt").unwrap(), b"hello");
+    assert_eq!(mount.metadata("/zxt/hello.zxt").unwrap().mtime, MTIME);
+
+    // Cut off before its trailer
+    assert!(matches!(ArchiveFs::new(data[..data.len() - 40].to_vec()), Err(FsError::Corrupt(_))));
+}
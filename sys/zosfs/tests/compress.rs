@@ -0,0 +1,193 @@
+/*  compress.rs - Tests for the decompressors against files compressed by gzip, zstd and lz4
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+mod common;
+
+use std::fs;
+use std::process::Command;
+use common::TempDir;
+use zosfs::compress::{self, inflate, lz4, xz, zstd, Format};
+
+
+/// Text that compresses well, followed by bytes that don't, so both literals and long matches come up
+fn sample() -> Vec<u8> {
+    let mut data: Vec<u8> = (0..20000).flat_map(|i| format!("line {} of the sample, {}\n", i, i % 7).into_bytes()).collect();
+
+    let mut seed: u32 = 1;
+    data.extend((0..300000).map(|_| {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        (seed >> 16) as u8
+    }));
+    data.extend(std::iter::repeat_n(b'z', 100000));
+
+    data
+}
+
+
+/// Compresses *data* by piping it through *cmd*, which has to be installed
+fn compress(tmp: &TempDir, cmd: &str, args: &[&str], data: &[u8]) -> Vec<u8> {
+    let input = tmp.path().join("input");
+    fs::write(&input, data).unwrap();
+
+    let output = Command::new(cmd).args(args).arg("-c").arg(&input).output()
+        .unwrap_or_else(|err| panic!("couldn't run {}, is it installed? {}", cmd, err));
+    assert!(output.status.success(), "{} failed: {}", cmd, String::from_utf8_lossy(&output.stderr));
+
+    output.stdout
+}
+
+
+#[test]
+fn inflates_gzip() {
+    let tmp = TempDir::new("compress-gzip");
+
+    for level in ["-1", "-9"] {
+        let compressed = compress(&tmp, "gzip", &[level, "-n"], &sample());
+        assert_eq!(inflate::gzip_decompress(&compressed).unwrap(), sample());
+    }
+
+    // Concatenated members and an empty file
+    let mut compressed = compress(&tmp, "gzip", &[], b"first ");
+    compressed.extend(compress(&tmp, "gzip", &[], b"second"));
+    assert_eq!(inflate::gzip_decompress(&compressed).unwrap(), b"first second");
+    assert_eq!(inflate::gzip_decompress(&compress(&tmp, "gzip", &[], b"")).unwrap(), b"");
+
+    let mut corrupt = compress(&tmp, "gzip", &[], &sample());
+    let middle = corrupt.len() / 2;
+    corrupt[middle] ^= 0x40;
+    assert!(inflate::gzip_decompress(&corrupt).is_err());
+}
+
+
+#[test]
+fn decompresses_zstd() {
+    let tmp = TempDir::new("compress-zstd");
+
+    for level in ["-1", "-3", "-19", "--ultra", "-22"] {
+        let compressed = compress(&tmp, "zstd", &["-q", level], &sample());
+        assert_eq!(zstd::decompress(&compressed).unwrap(), sample(), "level {}", level);
+    }
+
+    let compressed = compress(&tmp, "zstd", &["-q", "--no-check"], b"tiny");
+    assert_eq!(zstd::decompress(&compressed).unwrap(), b"tiny");
+}
+
+
+#[test]
+fn decompresses_lz4() {
+    let tmp = TempDir::new("compress-lz4");
+
+    for args in [&["-1"][..], &["-9", "-BD"], &["-l"], &["--content-size", "-BX"]] {
+        let compressed = compress(&tmp, "lz4", &[&["-q"], args].concat(), &sample());
+        assert_eq!(lz4::decompress(&compressed).unwrap(), sample(), "{:?}", args);
+    }
+}
+
+
+#[test]
+fn decompresses_xz() {
+    let tmp = TempDir::new("compress-xz");
+
+    for args in [&["-0"][..], &["-6", "--check=crc32"], &["-9e", "--check=sha256"], &["--check=none", "--lzma2=lc=1,lp=3,pb=0"],
+                 &["-T2", "--block-size=100000"]] {
+        let compressed = compress(&tmp, "xz", &[&["-q"], args].concat(), &sample());
+        assert_eq!(xz::decompress(&compressed).unwrap(), sample(), "{:?}", args);
+    }
+
+    // Concatenated streams and an empty file
+    let mut compressed = compress(&tmp, "xz", &[], b"first ");
+    compressed.extend(compress(&tmp, "xz", &[], b"second"));
+    assert_eq!(xz::decompress(&compressed).unwrap(), b"first second");
+    assert_eq!(xz::decompress(&compress(&tmp, "xz", &[], b"")).unwrap(), b"");
+
+    let mut corrupt = compress(&tmp, "xz", &[], &sample());
+    let middle = corrupt.len() / 2;
+    corrupt[middle] ^= 0x40;
+    assert!(xz::decompress(&corrupt).is_err());
+
+    let filtered = compress(&tmp, "xz", &["--x86", "--lzma2"], &sample());
+    assert_eq!(xz::decompress(&filtered), Err(zosfs::FsError::Unsupported("xz filters other than LZMA2")));
+}
+
+
+#[test]
+fn reads_decompressed_size_from_headers() {
+    let tmp = TempDir::new("compress-size");
+    let size = Some(sample().len() as u64);
+
+    let compressed = compress(&tmp, "zstd", &["-q"], &sample());
+    assert_eq!(compress::decompressed_size(Format::Zstd, &compressed), size);
+    assert_eq!(compress::decompressed_size(Format::Zstd, &compress(&tmp, "zstd", &["-q", "--no-content-size"], &sample())), None);
+
+    let compressed = compress(&tmp, "lz4", &["-q", "--content-size", "-BD"], &sample());
+    assert_eq!(compress::decompressed_size(Format::Lz4, &compressed), size);
+    assert_eq!(compress::decompressed_size(Format::Lz4, &compress(&tmp, "lz4", &["-q", "--no-content-size"], &sample())), None);
+    assert_eq!(compress::decompressed_size(Format::Lz4, &compress(&tmp, "lz4", &["-q", "-l"], &sample())), None);
+
+    // Several blocks, then several streams with padding between them
+    let mut compressed = compress(&tmp, "xz", &["-q", "-T2", "--block-size=100000"], &sample());
+    assert_eq!(compress::decompressed_size(Format::Xz, &compressed), size);
+    compressed.extend([0; 8]);
+    compressed.extend(compress(&tmp, "xz", &[], b"second"));
+    assert_eq!(compress::decompressed_size(Format::Xz, &compressed), Some(sample().len() as u64 + 6));
+
+    let compressed = compress(&tmp, "gzip", &[], &sample());
+    assert_eq!(compress::decompressed_size(Format::Gzip, &compressed), None);
+}
+
+
+/// Sizes of files that aren't in memory come from their headers alone, a few bytes per block
+#[test]
+fn reads_decompressed_size_without_the_data() {
+    let tmp = TempDir::new("compress-size-at");
+    let size = Some(sample().len() as u64);
+
+    for (cmd, args, format) in [("zstd", &["-q"][..], Format::Zstd), ("lz4", &["-q", "--content-size", "-BD"][..], Format::Lz4),
+                                ("xz", &["-q", "-T2", "--block-size=100000"][..], Format::Xz)] {
+        let compressed = compress(&tmp, cmd, args, &sample());
+        let mut bytes_read = 0;
+
+        let read = |offset: u64, buffer: &mut [u8]| {
+            bytes_read += buffer.len();
+            buffer.copy_from_slice(compressed.get(offset as usize..offset as usize + buffer.len())?);
+            Some(())
+        };
+        assert_eq!(compress::decompressed_size_at(format, compressed.len() as u64, read), size, "{}", cmd);
+        assert!(bytes_read < 1024, "{} read {} bytes", cmd, bytes_read);
+
+        // A file cut short runs out of headers instead of reading past its end
+        let truncated = &compressed[..compressed.len() / 2];
+        assert_eq!(compress::decompressed_size(format, truncated), None, "{}", cmd);
+    }
+}
+
+
+#[test]
+fn detects_format() {
+    let tmp = TempDir::new("compress-detect");
+
+    for (cmd, format) in [("gzip", Format::Gzip), ("zstd", Format::Zstd), ("lz4", Format::Lz4), ("xz", Format::Xz)] {
+        let compressed = compress(&tmp, cmd, &["-q"], &sample());
+        assert_eq!(compress::detect(&compressed), Some(format));
+        assert_eq!(compress::decompress(format, &compressed).unwrap(), sample());
+    }
+
+    assert_eq!(compress::detect(&sample()), None);
+    assert_eq!(compress::detect(b""), None);
+}
@@ -0,0 +1,359 @@
+/*  exfat.rs - exFAT driver tests
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+mod common;
+
+use std::fs;
+use common::TempDir;
+use zosfs::image::ImageDevice;
+use zosfs::{mount, Extent, FileType, FilesystemType, FsError};
+
+
+// There's no host tool that can put files on an exFAT image without mounting it, so the tests lay out a small volume by hand:
+// 512 byte sectors and clusters, both boot regions, one FAT at sector 24 and 256 clusters starting at sector 32.
+const SECTOR: usize = 512;
+const FAT_OFFSET: usize = 24;
+const FAT_LENGTH: usize = 8;
+const HEAP_OFFSET: usize = 32;
+const CLUSTERS: u32 = 256;
+
+const SERIAL: u32 = 0x1234ABCD;
+
+/// 2024-05-17 10:34:57 UTC. The entries store 12:34:56 local time at UTC+2, plus 100 10ms increments.
+const MTIME: i64 = 1715942097;
+
+
+/// An exFAT image being put together
+struct Image {
+    data:   Vec<u8>,
+    next:   u32,
+}
+
+impl Image {
+    fn new() -> Self {
+        Self {
+            data:   vec![0; (HEAP_OFFSET + CLUSTERS as usize) * SECTOR],
+            next:   2,
+        }
+    }
+
+    /// Allocates *count* clusters, leaving a gap after each one unless they should be contiguous
+    fn allocate(&mut self, count: usize, contiguous: bool) -> Vec<u32> {
+        let mut clusters = Vec::new();
+
+        for _ in 0..count {
+            clusters.push(self.next);
+            self.next += if contiguous { 1 } else { 2 };
+        }
+        if !contiguous {
+            self.next -= 1;
+        }
+
+        clusters
+    }
+
+    /// Writes *contents* to *clusters*, marks them as used and links them in the FAT unless the file is NoFatChain
+    fn write(&mut self, clusters: &[u32], contents: &[u8], fat_chain: bool) {
+        for (i, cluster) in clusters.iter().enumerate() {
+            let chunk = &contents[(i * SECTOR).min(contents.len())..((i + 1) * SECTOR).min(contents.len())];
+            let offset = (HEAP_OFFSET + *cluster as usize - 2) * SECTOR;
+            self.data[offset..offset + chunk.len()].copy_from_slice(chunk);
+
+            // The bitmap is in cluster 2
+            let bit = *cluster as usize - 2;
+            self.data[HEAP_OFFSET * SECTOR + bit / 8] |= 1 << (bit % 8);
+
+            if fat_chain {
+                let next = clusters.get(i + 1).copied().unwrap_or(0xFFFFFFFF);
+                let entry = FAT_OFFSET * SECTOR + *cluster as usize * 4;
+                self.data[entry..entry + 4].copy_from_slice(&next.to_le_bytes());
+            }
+        }
+    }
+}
+
+
+/// Up-cases ASCII and Latin-1 letters, which is all the test's up-case table covers
+fn upcase(c: u16) -> u16 {
+    if (0x61..=0x7A).contains(&c) || ((0xE0..=0xFE).contains(&c) && c != 0xF7) {
+        return c - 0x20;
+    }
+
+    c
+}
+
+
+/// The same mapping as upcase(), compressed the way exFAT stores it
+fn upcase_table() -> Vec<u8> {
+    let mut table: Vec<u16> = vec![0xFFFF, 0x61];
+    table.extend(0x41..=0x5A);
+    table.extend([0xFFFF, 0xE0 - 0x7B]);
+    table.extend((0xE0..=0xFE).map(upcase));
+
+    table.iter().flat_map(|c| c.to_le_bytes()).collect()
+}
+
+
+/// Builds the entry set for a file or directory
+fn entry_set(name: &str, directory: bool, first_cluster: u32, valid_length: u64, length: u64, no_fat_chain: bool) -> Vec<u8> {
+    let name: Vec<u16> = name.encode_utf16().collect();
+    let name_entries = name.len().div_ceil(15);
+
+    let mut hash: u16 = 0;
+    for byte in name.iter().flat_map(|c| upcase(*c).to_le_bytes()) {
+        hash = hash.rotate_right(1).wrapping_add(byte as u16);
+    }
+
+    // 2024-05-17 12:34:56 as a DOS timestamp
+    let timestamp: u32 = (44 << 25) | (5 << 21) | (17 << 16) | (12 << 11) | (34 << 5) | 28;
+
+    let mut set = vec![0u8; (2 + name_entries) * 32];
+    set[0] = 0x85;
+    set[1] = 1 + name_entries as u8;
+    set[4..6].copy_from_slice(&(if directory { 0x10u16 } else { 0x20 }).to_le_bytes());
+    for offset in [8, 12, 16] {
+        set[offset..offset + 4].copy_from_slice(&timestamp.to_le_bytes());
+    }
+    set[21] = 100;
+    // UTC+2, in 15 minute steps with the valid bit set
+    set[22..25].fill(0x80 | 8);
+
+    let stream = &mut set[32..64];
+    stream[0] = 0xC0;
+    stream[1] = if no_fat_chain { 0x03 } else { 0x01 };
+    stream[3] = name.len() as u8;
+    stream[4..6].copy_from_slice(&hash.to_le_bytes());
+    stream[8..16].copy_from_slice(&valid_length.to_le_bytes());
+    stream[20..24].copy_from_slice(&first_cluster.to_le_bytes());
+    stream[24..32].copy_from_slice(&length.to_le_bytes());
+
+    for (i, chunk) in name.chunks(15).enumerate() {
+        let entry = &mut set[(2 + i) * 32..(3 + i) * 32];
+        entry[0] = 0xC1;
+        for (j, c) in chunk.iter().enumerate() {
+            entry[2 + j * 2..4 + j * 2].copy_from_slice(&c.to_le_bytes());
+        }
+    }
+
+    let mut checksum: u16 = 0;
+    for (i, byte) in set.iter().enumerate() {
+        if i != 2 && i != 3 {
+            checksum = checksum.rotate_right(1).wrapping_add(*byte as u16);
+        }
+    }
+    set[2..4].copy_from_slice(&checksum.to_le_bytes());
+
+    set
+}
+
+
+fn kernel_contents() -> Vec<u8> {
+    (0..1500u32).map(|i| (i * 7) as u8).collect()
+}
+
+fn notes_contents() -> Vec<u8> {
+    (0..1300u32).map(|i| b'a' + (i % 26) as u8).collect()
+}
+
+
+/// Lays out the test volume:
+///
+///  /Kernel.elf                    3 contiguous clusters, NoFatChain
+///  /Äpfel und Birnen.txt          3 scattered clusters in the FAT, name spread over two name entries
+///  /boot/config.txt               600 valid bytes of 1024, NoFatChain
+///  /empty                         no clusters at all
+///  /filler-N                      enough empty files to push the root directory into its second cluster
+///
+/// The root also holds a deleted entry set and one with a bad checksum, neither of which should show up.
+fn build_image() -> Vec<u8> {
+    let mut image = Image::new();
+
+    let bitmap = image.allocate(1, true);
+    image.write(&bitmap, &[], true);
+
+    let upcase = upcase_table();
+    let upcase_clusters = image.allocate(1, true);
+    image.write(&upcase_clusters, &upcase, true);
+
+    let root = image.allocate(2, false);
+
+    let kernel = image.allocate(3, true);
+    image.write(&kernel, &kernel_contents(), false);
+
+    let notes = image.allocate(3, false);
+    image.write(&notes, &notes_contents(), true);
+
+    // Whatever lies past the valid data length must read back as zeros
+    let mut config = vec![0xAA; 1024];
+    config[..600].fill(b'c');
+    let config_clusters = image.allocate(2, true);
+    image.write(&config_clusters, &config, false);
+
+    let boot = image.allocate(1, true);
+    let mut boot_dir = entry_set("config.txt", false, config_clusters[0], 600, 1024, true);
+    boot_dir.resize(SECTOR, 0);
+    image.write(&boot, &boot_dir, false);
+
+    // Volume metadata entries
+    let mut root_dir = vec![0u8; 96];
+    root_dir[0] = 0x83;
+    root_dir[1] = 9;
+    for (i, c) in "ZOS EXFAT".encode_utf16().enumerate() {
+        root_dir[2 + i * 2..4 + i * 2].copy_from_slice(&c.to_le_bytes());
+    }
+
+    root_dir[32] = 0x81;
+    root_dir[52..56].copy_from_slice(&bitmap[0].to_le_bytes());
+    root_dir[56..64].copy_from_slice(&(CLUSTERS as u64 / 8).to_le_bytes());
+
+    let mut table_checksum: u32 = 0;
+    for byte in &upcase {
+        table_checksum = table_checksum.rotate_right(1).wrapping_add(*byte as u32);
+    }
+    root_dir[64] = 0x82;
+    root_dir[68..72].copy_from_slice(&table_checksum.to_le_bytes());
+    root_dir[84..88].copy_from_slice(&upcase_clusters[0].to_le_bytes());
+    root_dir[88..96].copy_from_slice(&(upcase.len() as u64).to_le_bytes());
+
+    root_dir.extend(entry_set("Kernel.elf", false, kernel[0], 1500, 1500, true));
+
+    let mut deleted = entry_set("deleted.txt", false, 0, 0, 0, false);
+    for entry in deleted.chunks_mut(32) {
+        entry[0] &= 0x7F;
+    }
+    root_dir.extend(deleted);
+
+    root_dir.extend(entry_set("Äpfel und Birnen.txt", false, notes[0], 1300, 1300, false));
+
+    let mut broken = entry_set("broken.txt", false, 0, 0, 0, false);
+    broken[70] ^= 0xFF;
+    root_dir.extend(broken);
+
+    root_dir.extend(entry_set("boot", true, boot[0], SECTOR as u64, SECTOR as u64, true));
+    root_dir.extend(entry_set("empty", false, 0, 0, 0, false));
+
+    // A directory claiming far more than the two clusters of its chain
+    root_dir.extend(entry_set("huge", true, root[0], 1 << 40, 1 << 40, false));
+
+    for i in 0..2 {
+        root_dir.extend(entry_set(&format!("filler-{}", i), false, 0, 0, 0, false));
+    }
+    assert!(root_dir.len() > SECTOR && root_dir.len() <= 2 * SECTOR);
+    image.write(&root, &root_dir, true);
+
+    // Boot sector
+    let bs = &mut image.data[..SECTOR];
+    bs[0..3].copy_from_slice(&[0xEB, 0x76, 0x90]);
+    bs[3..11].copy_from_slice(b"EXFAT   ");
+    bs[72..80].copy_from_slice(&(HEAP_OFFSET as u64 + CLUSTERS as u64).to_le_bytes());
+    bs[80..84].copy_from_slice(&(FAT_OFFSET as u32).to_le_bytes());
+    bs[84..88].copy_from_slice(&(FAT_LENGTH as u32).to_le_bytes());
+    bs[88..92].copy_from_slice(&(HEAP_OFFSET as u32).to_le_bytes());
+    bs[92..96].copy_from_slice(&CLUSTERS.to_le_bytes());
+    bs[96..100].copy_from_slice(&root[0].to_le_bytes());
+    bs[100..104].copy_from_slice(&SERIAL.to_le_bytes());
+    bs[104..106].copy_from_slice(&0x0100u16.to_le_bytes());
+    bs[108] = 9;
+    bs[109] = 0;
+    bs[110] = 1;
+    bs[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+    // The media descriptor and the first two FAT entries are reserved
+    image.data[FAT_OFFSET * SECTOR..FAT_OFFSET * SECTOR + 8].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+
+    let mut checksum: u32 = 0;
+    for (i, byte) in image.data[..11 * SECTOR].iter().enumerate() {
+        if i != 106 && i != 107 && i != 112 {
+            checksum = checksum.rotate_right(1).wrapping_add(*byte as u32);
+        }
+    }
+    for i in 0..SECTOR / 4 {
+        image.data[11 * SECTOR + i * 4..11 * SECTOR + i * 4 + 4].copy_from_slice(&checksum.to_le_bytes());
+    }
+
+    // Backup boot region
+    image.data.copy_within(..12 * SECTOR, 12 * SECTOR);
+
+    image.data
+}
+
+
+fn write_image(tmp: &TempDir, data: &[u8]) -> String {
+    let image = tmp.path().join("exfat.img");
+    fs::write(&image, data).unwrap();
+
+    image.to_str().unwrap().to_string()
+}
+
+
+#[test]
+fn reads_exfat() {
+    let tmp = TempDir::new("exfat-read");
+    let mount = mount(ImageDevice::open(write_image(&tmp, &build_image())).unwrap()).unwrap();
+
+    assert_eq!(mount.fs_type(), FilesystemType::EXFAT);
+    assert_eq!(mount.volume_id().unwrap(), (SERIAL as u128, "ZOS EXFAT".to_string()));
+
+    let mut names: Vec<String> = mount.list_dir("/").unwrap().into_iter().map(|(name, _)| name).collect();
+    names.sort();
+    assert_eq!(names, ["Kernel.elf", "boot", "empty", "filler-0", "filler-1", "huge", "Äpfel und Birnen.txt"]);
+    assert_eq!(mount.list_dir("/boot").unwrap(), [("config.txt".to_string(), FileType::Regular)]);
+
+    assert_eq!(mount.read_file("/Kernel.elf").unwrap(), kernel_contents());
+    assert_eq!(mount.read_file("/Äpfel und Birnen.txt").unwrap(), notes_contents());
+    assert_eq!(mount.read_file("/empty").unwrap(), b"");
+
+    let mut config = vec![b'c'; 600];
+    config.resize(1024, 0);
+    assert_eq!(mount.read_file("/boot/config.txt").unwrap(), config);
+
+    // Names are compared through the up-case table, which also covers non-ASCII letters
+    assert_eq!(mount.read_file("/KERNEL.ELF").unwrap(), kernel_contents());
+    assert_eq!(mount.read_file("/äPFEL UND BIRNEN.TXT").unwrap(), notes_contents());
+    assert_eq!(mount.metadata("/BOOT/Config.TXT").unwrap().size, 1024);
+    assert!(matches!(mount.read_file("/broken.txt"), Err(FsError::NotFound)));
+    assert!(matches!(mount.read_file("/deleted.txt"), Err(FsError::NotFound)));
+    assert!(matches!(mount.list_dir("/huge"), Err(FsError::Corrupt(_))));
+
+    let metadata = mount.metadata("/Kernel.elf").unwrap();
+    assert_eq!(metadata.mtime, MTIME);
+    assert_eq!(metadata.file_type, FileType::Regular);
+
+    assert_eq!(mount.extents("/Kernel.elf").unwrap(), [Extent { logical: 0, physical: 7, length: 3 }]);
+    assert_eq!(mount.extents("/Äpfel und Birnen.txt").unwrap().len(), 3);
+
+    // bitmap, up-case table, 2 root clusters, kernel, notes, config and the boot directory
+    let info = mount.superblock_info().unwrap();
+    assert!(info.contains(&("Free clusters", (CLUSTERS - 13).to_string())));
+}
+
+
+#[test]
+fn falls_back_to_backup_boot_region() {
+    let tmp = TempDir::new("exfat-backup");
+    let mut data = build_image();
+
+    // Damaging the boot code breaks the checksum without touching any field the driver uses
+    data[200] ^= 0xFF;
+    let mount = mount(ImageDevice::open(write_image(&tmp, &data)).unwrap()).unwrap();
+    assert_eq!(mount.read_file("/Kernel.elf").unwrap(), kernel_contents());
+
+    data[12 * SECTOR + 200] ^= 0xFF;
+    assert!(matches!(zosfs::mount(ImageDevice::open(write_image(&tmp, &data)).unwrap()), Err(FsError::Corrupt(_))));
+}
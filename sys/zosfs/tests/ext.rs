@@ -0,0 +1,652 @@
+/*  ext.rs - Tests for the ext driver against images built by mkfs.ext4
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+mod common;
+
+use std::fs;
+use common::{run, TempDir};
+use zosfs::image::ImageDevice;
+use zosfs::extfs::{ChecksumPolicy, ExtFs};
+use zosfs::fs::{self as zfs, Filesystem};
+use zosfs::{mount, mount_with, BlockDevice, DiskError, FileType, FilesystemType, FsError, Mount, MountOptions};
+
+
+/// Builds a small ext4 image from a directory tree
+fn build_image(tmp: &TempDir) -> Mount<ImageDevice> {
+    let root = tmp.path().join("root");
+    fs::create_dir_all(root.join("boot/zxt")).unwrap();
+    fs::write(root.join("boot/loader.cfg"), "root=\"LABEL=zOS\"\n").unwrap();
+    fs::write(root.join("boot/zxt/hello.zxt"), b"hello").unwrap();
+
+    let image = tmp.path().join("ext4.img");
+    let image = image.to_str().unwrap();
+
+    run("mkfs.ext4", &["-q", "-F", "-b", "4096", "-L", "zOS", "-d", root.to_str().unwrap(), image, "8M"]);
+
+    mount(ImageDevice::open(image).unwrap()).unwrap()
+}
+
+
+#[test]
+fn reads_files_and_directories() {
+    let tmp = TempDir::new("ext-read");
+    let mount = build_image(&tmp);
+
+    assert_eq!(mount.fs_type(), FilesystemType::EXT);
+    assert_eq!(mount.volume_id().unwrap().1, "zOS");
+
+    let entries = mount.list_dir("/boot").unwrap();
+    assert!(entries.contains(&("loader.cfg".into(), FileType::Regular)));
+    assert!(entries.contains(&("zxt".into(), FileType::Directory)));
+
+    assert_eq!(mount.read_file("/boot/loader.cfg").unwrap(), b"root=\"LABEL=zOS\"\n");
+    assert_eq!(mount.metadata("/boot/zxt/hello.zxt").unwrap().size, 5);
+}
+
+
+#[test]
+fn resolves_dot_entries() {
+    let tmp = TempDir::new("ext-resolve");
+    let mount = build_image(&tmp);
+
+    assert_eq!(mount.read_file("//boot/./zxt/../zxt/hello.zxt").unwrap(), b"hello");
+    assert_eq!(mount.read_file("/../boot/loader.cfg").unwrap(), b"root=\"LABEL=zOS\"\n");
+
+    assert!(matches!(mount.read_file("/boot/missing"), Err(FsError::NotFound)));
+    assert!(matches!(mount.list_dir("/boot/loader.cfg/"), Err(FsError::NotADirectory)));
+    assert!(matches!(mount.read_file("/boot/loader.cfg/../zxt/hello.zxt"), Err(FsError::NotADirectory)));
+    assert!(matches!(mount.list_dir("/boot/loader.cfg/."), Err(FsError::NotADirectory)));
+}
+
+
+/// A directory whose size is far past its blocks must be refused before a buffer that large is allocated
+#[test]
+fn refuses_directories_larger_than_their_blocks() {
+    let tmp = TempDir::new("ext-dir-size");
+    let root = tmp.path().join("root");
+    fs::create_dir_all(root.join("boot")).unwrap();
+    fs::write(root.join("boot/loader.cfg"), b"timeout=0\n").unwrap();
+
+    let image = tmp.path().join("ext4.img");
+    let image = image.to_str().unwrap();
+
+    run("mkfs.ext4", &["-q", "-F", "-b", "4096", "-d", root.to_str().unwrap(), image, "8M"]);
+    run("debugfs", &["-w", "-R", "sif /boot size 1095216660480", image]);
+
+    let mount = mount(ImageDevice::open(image).unwrap()).unwrap();
+    assert!(matches!(mount.list_dir("/boot"), Err(FsError::Corrupt(_))));
+    assert!(matches!(mount.read_file("/boot/loader.cfg"), Err(FsError::Corrupt(_))));
+}
+
+
+/// Builds an image with files whose extents don't fit in the inode: one fragmented enough to need two levels of index blocks, one over
+/// 4GiB that is mostly a hole, and one with preallocated blocks past its data.
+fn build_extent_image(tmp: &TempDir) -> String {
+    let root = tmp.path().join("root");
+    fs::create_dir_all(&root).unwrap();
+
+    // Every other block holds data, so each one is an extent of its own
+    let mut fragmented = vec![0u8; 1500 * 8192];
+    for i in 0..1500 {
+        fragmented[i * 8192..i * 8192 + 16].copy_from_slice(format!("island {:9}", i).as_bytes());
+    }
+    fs::write(root.join("fragmented"), &fragmented).unwrap();
+
+    let huge = fs::File::create(root.join("huge")).unwrap();
+    huge.set_len(5 << 30).unwrap();
+    std::os::unix::fs::FileExt::write_all_at(&huge, b"tail", (5 << 30) - 4).unwrap();
+
+    fs::write(root.join("preallocated"), vec![b'x'; 8192]).unwrap();
+
+    let image = tmp.path().join("ext4.img");
+    let image = image.to_str().unwrap();
+
+    run("mkfs.ext4", &["-q", "-F", "-b", "4096", "-d", root.to_str().unwrap(), image, "32M"]);
+    // Blocks 2-39 are allocated but unwritten, and the size covers them
+    run("debugfs", &["-w", "-R", "fallocate /preallocated 2 39", image]);
+    run("debugfs", &["-w", "-R", "sif /preallocated size 163840", image]);
+
+    image.to_string()
+}
+
+
+#[test]
+fn reads_extent_trees() {
+    let tmp = TempDir::new("ext-extents");
+    let image = build_extent_image(&tmp);
+    let mount = mount(ImageDevice::open(&image).unwrap()).unwrap();
+
+    let fragmented = mount.read_file("/fragmented").unwrap();
+    assert_eq!(fragmented.len(), 1500 * 8192);
+    for i in [0, 339, 340, 1499] {
+        assert_eq!(&fragmented[i * 8192..i * 8192 + 16], format!("island {:9}", i).as_bytes());
+        assert!(fragmented[i * 8192 + 16..(i + 1) * 8192].iter().all(|b| *b == 0));
+    }
+    assert_eq!(mount.extents("/fragmented").unwrap().len(), 1500);
+
+    // Reads that start mid file only map the blocks they cover
+    let mut island = [0u8; 24];
+    assert_eq!(mount.read_at("/fragmented", 340 * 8192 - 8, &mut island).unwrap(), 24);
+    assert_eq!(island, fragmented[340 * 8192 - 8..340 * 8192 + 16]);
+
+    let mut buffer = [0u8; 8];
+    assert_eq!(mount.metadata("/huge").unwrap().size, 5 << 30);
+    assert_eq!(mount.read_at("/huge", (5 << 30) - 8, &mut buffer).unwrap(), 8);
+    assert_eq!(&buffer, b"\0\0\0\0tail");
+    assert_eq!(mount.read_at("/huge", 3 << 30, &mut buffer).unwrap(), 8);
+    assert_eq!(buffer, [0; 8]);
+}
+
+
+/// Preallocated blocks read as zeros whatever is on disk
+#[test]
+fn reads_uninitialized_extents() {
+    let tmp = TempDir::new("ext-uninit");
+    let image = build_extent_image(&tmp);
+
+    let extents = mount(ImageDevice::open(&image).unwrap()).unwrap().extents("/preallocated").unwrap();
+    let unwritten = extents.iter().find(|extent| extent.logical == 2).unwrap();
+    assert_eq!(unwritten.length, 38);
+
+    let contents = fs::OpenOptions::new().write(true).open(&image).unwrap();
+    std::os::unix::fs::FileExt::write_all_at(&contents, &vec![0xFF; 38 * 4096], unwritten.physical * 4096).unwrap();
+
+    let data = mount(ImageDevice::open(&image).unwrap()).unwrap().read_file("/preallocated").unwrap();
+    assert_eq!(data.len(), 163840);
+    assert!(data[..8192].iter().all(|b| *b == b'x'));
+    assert!(data[8192..].iter().all(|b| *b == 0));
+}
+
+
+/// Formats an image as *fs_type* (ext2 or ext3), which map blocks with indirect blocks instead of extents. The files need direct,
+/// single, double and triple indirect blocks, and the directory several blocks.
+fn check_block_map(fs_type: &str) {
+    let tmp = TempDir::new(&format!("ext-{}", fs_type));
+    let root = tmp.path().join("root");
+    fs::create_dir_all(root.join("many")).unwrap();
+
+    let contents: Vec<u8> = (0..6 << 20).map(|i: u32| (i % 251) as u8).collect();
+    fs::write(root.join("double"), &contents).unwrap();
+    fs::write(root.join("small"), b"direct").unwrap();
+
+    let triple = fs::File::create(root.join("triple")).unwrap();
+    triple.set_len(5 << 30).unwrap();
+    std::os::unix::fs::FileExt::write_all_at(&triple, b"tail", (5 << 30) - 4).unwrap();
+
+    for i in 0..300 {
+        fs::write(root.join(format!("many/file-with-a-longish-name-{}", i)), format!("file {}\n", i)).unwrap();
+    }
+
+    let image = tmp.path().join("ext.img");
+    let image = image.to_str().unwrap();
+    run(&format!("mkfs.{}", fs_type), &["-q", "-F", "-b", "4096", "-d", root.to_str().unwrap(), image, "32M"]);
+
+    let mount = mount(ImageDevice::open(image).unwrap()).unwrap();
+    assert_eq!(mount.read_file("/small").unwrap(), b"direct");
+    assert_eq!(mount.read_file("/double").unwrap(), contents);
+
+    let mut span = vec![0u8; 12288];
+    let offset = (4 << 20) + 4000;
+    assert_eq!(mount.read_at("/double", offset as u64, &mut span).unwrap(), span.len());
+    assert_eq!(span, contents[offset..offset + span.len()]);
+    assert_eq!(mount.list_dir("/many").unwrap().len(), 300);
+    assert_eq!(mount.read_file("/many/file-with-a-longish-name-299").unwrap(), b"file 299\n");
+
+    let mut buffer = [0u8; 8];
+    assert_eq!(mount.read_at("/triple", (5 << 30) - 8, &mut buffer).unwrap(), 8);
+    assert_eq!(&buffer, b"\0\0\0\0tail");
+    assert_eq!(mount.read_at("/triple", 1 << 30, &mut buffer).unwrap(), 8);
+    assert_eq!(buffer, [0; 8]);
+}
+
+
+#[test]
+fn reads_ext2_block_maps() {
+    check_block_map("ext2");
+}
+
+
+#[test]
+fn reads_ext3_block_maps() {
+    check_block_map("ext3");
+}
+
+
+/// Block sizes, descriptor sizes and descriptor table layouts. Few inodes per group spread the files over many groups, so descriptors from
+/// deep in the table (and, with meta_bg, from blocks scattered over the volume) are needed.
+#[test]
+fn reads_every_geometry() {
+    let tmp = TempDir::new("ext-geometry");
+    let root = tmp.path().join("root");
+    fs::create_dir_all(&root).unwrap();
+    for i in 0..200 {
+        fs::write(root.join(format!("file{}", i)), format!("contents of file {}\n", i).repeat(i + 1)).unwrap();
+    }
+
+    let image = tmp.path().join("ext.img");
+    let image = image.to_str().unwrap();
+
+    for (block_size, features) in [(1024, "^64bit"), (2048, "64bit"), (4096, "^64bit"), (65536, "64bit"), (1024, "64bit,meta_bg,^resize_inode"),
+                                   (4096, "^64bit,meta_bg,^resize_inode"), (1024, "sparse_super2,^resize_inode")] {
+        // 8MiB block groups, or the smallest mkfs allows
+        let blocks_per_group = ((8 << 20) / block_size).max(256);
+
+        let _ = fs::remove_file(image);
+        run("mkfs.ext4", &["-q", "-F", "-b", &block_size.to_string(), "-g", &blocks_per_group.to_string(), "-O", features, "-N", "256",
+                           "-d", root.to_str().unwrap(), image, "640M"]);
+
+        let mount = mount(ImageDevice::open(image).unwrap()).unwrap();
+        let inodes_per_group: u64 = mount.superblock_info().unwrap().iter().find(|(name, _)| *name == "Inodes per group").unwrap().1.parse().unwrap();
+
+        let mut last_group = 0;
+        for i in 0..200 {
+            let path = format!("/file{}", i);
+            assert_eq!(mount.read_file(&path).unwrap(), format!("contents of file {}\n", i).repeat(i + 1).as_bytes(), "{} {}", block_size, features);
+            last_group = last_group.max((mount.metadata(&path).unwrap().id - 1) / inodes_per_group);
+        }
+        // With 64K blocks, a single block of inode table already holds every file
+        if inodes_per_group < 20 {
+            assert!(last_group >= 10, "{} {}: files only reach group {}", block_size, features, last_group);
+        }
+    }
+}
+
+
+/// Counts the reads made through it, so tests can check how much of the disk a lookup touches
+struct CountingDevice {
+    dev:    ImageDevice,
+    reads:  std::cell::Cell<usize>,
+}
+
+impl BlockDevice for CountingDevice {
+    fn block_size(&self) -> usize {
+        self.dev.block_size()
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), DiskError> {
+        self.reads.set(self.reads.get() + 1);
+        self.dev.read_blocks(lba, buffer)
+    }
+}
+
+
+/// Looks names up through htrees built by e2fsck with every hash, signed and unsigned, deep enough to need a level of dx_node blocks
+#[test]
+fn looks_up_htree_directories() {
+    let tmp = TempDir::new("ext-htree");
+    let root = tmp.path().join("root");
+    fs::create_dir_all(root.join("big")).unwrap();
+
+    // Names with bytes over 0x7F hash differently depending on signedness
+    let names: Vec<String> = (0..6000).map(|i| format!("{}-a-name-long-enough-to-fill-leaves-{}", if i % 3 == 0 { "ünïcödé" } else { "ascii" }, i)).collect();
+    for name in &names {
+        fs::File::create(root.join("big").join(name)).unwrap();
+    }
+
+    // mkfs is slow to fill such a big directory, so it's only done once
+    let linear = tmp.path().join("linear.img");
+    run("mkfs.ext4", &["-q", "-F", "-b", "1024", "-d", root.to_str().unwrap(), linear.to_str().unwrap(), "64M"]);
+
+    let image = tmp.path().join("ext.img");
+    let image = image.to_str().unwrap();
+
+    for (hash, flags) in [("legacy", "1"), ("half_md4", "1"), ("tea", "1"), ("legacy", "2"), ("half_md4", "2"), ("tea", "2")] {
+        fs::copy(&linear, image).unwrap();
+        run("debugfs", &["-w", "-R", &format!("ssv def_hash_version {}", hash), image]);
+        run("debugfs", &["-w", "-R", &format!("ssv flags {}", flags), image]);
+
+        // Rebuilding the directories indexes them with the default hash. Exit code 1 means it changed something.
+        let status = std::process::Command::new("e2fsck").args(["-fyD", image]).output().expect("couldn't run e2fsck").status;
+        assert!(status.code().unwrap_or(8) <= 1, "e2fsck failed on {}", hash);
+
+        let dev = CountingDevice {
+            dev:    ImageDevice::open(image).unwrap(),
+            reads:  std::cell::Cell::new(0),
+        };
+        let ext = ExtFs::new(&dev).unwrap();
+        let big = zfs::resolve(&ext, "/big").unwrap();
+
+        for name in names.iter().step_by(97) {
+            dev.reads.set(0);
+            let entry = ext.lookup(&big.node, name).unwrap().unwrap_or_else(|| panic!("{} {}: {} not found", hash, flags, name));
+            assert_eq!(&entry.name, name);
+
+            // The inode, the root, a dx_node and the leaf, each of which might straddle two device blocks
+            assert!(dev.reads.get() <= 8, "{} {}: {} reads", hash, flags, dev.reads.get());
+        }
+        assert!(ext.lookup(&big.node, "missing").unwrap().is_none());
+        assert_eq!(ext.read_dir(&big.node).unwrap().len(), names.len());
+    }
+}
+
+
+/// Fast and slow symlinks, relative and absolute, as the final component and in the middle of paths, on ext4 and on ext2's block maps
+#[test]
+fn follows_symlinks() {
+    let tmp = TempDir::new("ext-symlinks");
+    let root = tmp.path().join("root");
+    fs::create_dir_all(root.join("boot")).unwrap();
+    fs::create_dir_all(root.join("etc")).unwrap();
+    fs::write(root.join("boot/kernel-0.0.2"), b"kernel").unwrap();
+
+    let long_target = format!("{}/../boot/kernel", "./".repeat(100));
+    for (link, target) in [("boot/kernel", "kernel-0.0.2"), ("latest", "/boot/kernel"), ("b", "boot"), ("etc/long", long_target.as_str()),
+                           ("loop-a", "loop-b"), ("loop-b", "loop-a"), ("dangling", "boot/missing")] {
+        std::os::unix::fs::symlink(target, root.join(link)).unwrap();
+    }
+
+    let image = tmp.path().join("ext.img");
+    let image = image.to_str().unwrap();
+
+    for fs_type in ["ext4", "ext2"] {
+        run(&format!("mkfs.{}", fs_type), &["-q", "-F", "-b", "1024", "-d", root.to_str().unwrap(), image, "8M"]);
+
+        let ext = ExtFs::new(ImageDevice::open(image).unwrap()).unwrap();
+        let etc = zfs::resolve(&ext, "/etc").unwrap();
+        let long = ext.lookup(&etc.node, "long").unwrap().unwrap();
+        assert_eq!(long.file_type, FileType::Symlink);
+        assert_eq!(ext.read_link(&long.node).unwrap(), long_target);
+
+        let mount = mount(ImageDevice::open(image).unwrap()).unwrap();
+        for path in ["/boot/kernel", "/latest", "/b/kernel", "/b/./../latest", "/etc/long"] {
+            assert_eq!(mount.read_file(path), Ok(b"kernel".to_vec()), "{} {}", fs_type, path);
+        }
+        assert!(mount.list_dir("/").unwrap().contains(&("latest".into(), FileType::Symlink)));
+        assert!(mount.list_dir("/b/").unwrap().contains(&("kernel-0.0.2".into(), FileType::Regular)));
+
+        assert_eq!(mount.read_file("/loop-a"), Err(FsError::SymlinkLoop));
+        assert_eq!(mount.read_file("/dangling"), Err(FsError::NotFound));
+    }
+}
+
+
+/// Runs a debugfs request against *image* and returns what it printed
+fn debugfs(image: &str, request: &str) -> String {
+    let output = std::process::Command::new("debugfs").args(["-R", request, image]).output().unwrap();
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+
+/// Damages each kind of checksummed metadata in turn. The read through it fails, unless the policy says to only warn.
+#[test]
+fn verifies_metadata_checksums() {
+    let tmp = TempDir::new("ext-csum");
+    let root = tmp.path().join("root");
+    fs::create_dir_all(root.join("boot")).unwrap();
+    fs::write(root.join("boot/loader.cfg"), "root=\"LABEL=zOS\"\n").unwrap();
+
+    // More extents than fit in the inode, so there's an extent block
+    let mut fragmented = vec![0u8; 40 * 4096];
+    for i in (0..40).step_by(2) {
+        fragmented[i * 4096..i * 4096 + 5].copy_from_slice(b"frag!");
+    }
+    fs::write(root.join("boot/fragmented"), &fragmented).unwrap();
+
+    let pristine = tmp.path().join("pristine.img");
+    let pristine = pristine.to_str().unwrap();
+    run("mkfs.ext4", &["-q", "-F", "-b", "4096", "-O", "metadata_csum", "-d", root.to_str().unwrap(), pristine, "16M"]);
+
+    // "located at block 35, offset 0x0d00"
+    let imap = debugfs(pristine, "imap /boot/loader.cfg");
+    let location = imap.split("located at block ").nth(1).unwrap();
+    let (block, offset) = location.trim().split_once(", offset 0x").unwrap();
+    let inode_offset = block.parse::<u64>().unwrap() * 4096 + u64::from_str_radix(offset, 16).unwrap();
+
+    // "(ETB0):1297, (0):1292, ..."
+    let stat = debugfs(pristine, "stat /boot/fragmented");
+    let extent_block: u64 = stat.split("(ETB0):").nth(1).unwrap().split(',').next().unwrap().parse().unwrap();
+
+    let dir_block = mount(ImageDevice::open(pristine).unwrap()).unwrap().extents("/boot").unwrap()[0].physical;
+
+    // Paths ending in a slash are listed, the others read
+    let read = |image: &str, path: &str, ext_checksums| {
+        let mount = mount_with(ImageDevice::open(image).unwrap(), MountOptions { ext_checksums })?;
+        if path.ends_with('/') { mount.list_dir(path).map(|_| ()) } else { mount.read_file(path).map(|_| ()) }
+    };
+
+    let cases = [
+        (1024 + 120, "ext superblock checksum mismatch", "/boot/loader.cfg"),
+        (4096 + 12, "ext group descriptor checksum mismatch", "/boot/loader.cfg"),
+        (inode_offset + 8, "ext inode checksum mismatch", "/boot/loader.cfg"),
+        (extent_block * 4096 + 100, "ext extent block checksum mismatch", "/boot/fragmented"),
+        (dir_block * 4096 + 30, "ext directory block checksum mismatch", "/boot/"),
+    ];
+
+    let image = tmp.path().join("ext.img");
+    let image = image.to_str().unwrap();
+
+    for (offset, error, path) in cases {
+        let mut contents = fs::read(pristine).unwrap();
+        contents[offset as usize] ^= 0x10;
+        fs::write(image, &contents).unwrap();
+
+        assert_eq!(read(image, path, ChecksumPolicy::Error), Err(FsError::Corrupt(error)));
+        assert_eq!(read(image, path, ChecksumPolicy::Warn), Ok(()), "{}", error);
+    }
+}
+
+
+/// Small files, directories and symlinks kept in the inode, some long enough to continue in the system.data attribute
+#[test]
+fn reads_inline_data() {
+    let tmp = TempDir::new("ext-inline");
+    let root = tmp.path().join("root");
+    for dir in ["boot", "small", "many"] {
+        fs::create_dir_all(root.join(dir)).unwrap();
+    }
+
+    let medium = "m".repeat(100);
+    let long_target = format!("{}loader.cfg", "./".repeat(40));
+    fs::write(root.join("boot/loader.cfg"), "root=\"LABEL=zOS\"\n").unwrap();
+    fs::write(root.join("boot/medium"), &medium).unwrap();
+    std::os::unix::fs::symlink(&long_target, root.join("boot/link")).unwrap();
+    fs::write(root.join("small/a"), "a").unwrap();
+    for i in 0..200 {
+        fs::write(root.join(format!("many/file-{}", i)), format!("{}", i)).unwrap();
+    }
+
+    let image = tmp.path().join("ext.img");
+    let image = image.to_str().unwrap();
+    run("mkfs.ext4", &["-q", "-F", "-O", "inline_data", "-d", root.to_str().unwrap(), image, "16M"]);
+
+    // mkfs moves directories out of the inode once the block array is full, so /small is made to continue in system.data by hand, with
+    // an entry linking to /boot/loader.cfg
+    let config_inode = mount(ImageDevice::open(image).unwrap()).unwrap().metadata("/boot/loader.cfg").unwrap().id as u32;
+    let name = b"in-system-data";
+    let mut entry = config_inode.to_le_bytes().to_vec();
+    entry.extend_from_slice(&24u16.to_le_bytes());
+    entry.extend_from_slice(&[name.len() as u8, 1]);
+    entry.extend_from_slice(name);
+    entry.resize(24, 0);
+
+    let value = tmp.path().join("system.data");
+    fs::write(&value, &entry).unwrap();
+    run("debugfs", &["-w", "-R", &format!("ea_set -f {} /small system.data", value.to_str().unwrap()), image]);
+    run("debugfs", &["-w", "-R", "sif /small size 84", image]);
+    run("debugfs", &["-w", "-R", "sif /boot/loader.cfg links_count 2", image]);
+    run("e2fsck", &["-fn", image]);
+
+    let mount = mount(ImageDevice::open(image).unwrap()).unwrap();
+    assert_eq!(mount.read_file("/boot/loader.cfg").unwrap(), b"root=\"LABEL=zOS\"\n");
+    assert_eq!(mount.read_file("/boot/medium").unwrap(), medium.as_bytes());
+    assert_eq!(mount.read_file("/boot/link").unwrap(), b"root=\"LABEL=zOS\"\n");
+    assert!(mount.extents("/boot/medium").unwrap().is_empty());
+
+    let mut buffer = [0u8; 10];
+    assert_eq!(mount.read_at("/boot/medium", 95, &mut buffer).unwrap(), 5);
+
+    assert_eq!(mount.list_dir("/small").unwrap(), vec![("a".into(), FileType::Regular), ("in-system-data".into(), FileType::Regular)]);
+    assert_eq!(mount.read_file("/small/in-system-data").unwrap(), b"root=\"LABEL=zOS\"\n");
+    assert_eq!(mount.read_file("/small/../small/a").unwrap(), b"a");
+    assert_eq!(mount.list_dir("/many").unwrap().len(), 200);
+}
+
+
+/// Volumes with incompatible features the driver doesn't know are refused up front, naming the features, rather than misread
+#[test]
+fn refuses_unsupported_features() {
+    let tmp = TempDir::new("ext-features");
+    let volume = build_image(&tmp);
+    let features = volume.features();
+    assert!(["has_journal", "extent", "metadata_csum"].iter().all(|feature| features.contains(&feature.to_string())), "{:?}", features);
+
+    let pristine = tmp.path().join("ext4.img");
+    let image = tmp.path().join("featured.img");
+    let image = image.to_str().unwrap();
+
+    let cases = [
+        ("casefold", vec!["casefold"]),
+        ("encrypt compression", vec!["compression", "encrypt"]),
+        ("FEATURE_I31", vec!["incompat:0x80000000"]),
+        ("FEATURE_R31", vec!["ro_compat:0x80000000"]),
+    ];
+    for (request, expected) in cases {
+        fs::copy(&pristine, image).unwrap();
+        run("debugfs", &["-w", "-R", &format!("feature {}", request), image]);
+
+        let expected = expected.into_iter().map(String::from).collect();
+        assert_eq!(mount(ImageDevice::open(image).unwrap()).err(), Some(FsError::UnsupportedFeatures(expected)), "{}", request);
+    }
+
+    // Unknown compat features don't change how the volume is read
+    fs::copy(&pristine, image).unwrap();
+    run("debugfs", &["-w", "-R", "feature FEATURE_C31", image]);
+    let volume = mount(ImageDevice::open(image).unwrap()).unwrap();
+    assert!(volume.features().contains(&"compat:0x80000000".to_string()));
+    assert_eq!(volume.read_file("/boot/zxt/hello.zxt").unwrap(), b"hello");
+}
+
+
+/// Transactions a crash left in the journal are replayed in memory, so the volume reads as Linux would leave it after recovery. Each
+/// case logs descriptor tags in another layout: 32 or 64 bit block numbers, with no checksum, a v2 one or a v3 one.
+#[test]
+fn replays_the_journal() {
+    let tmp = TempDir::new("ext-journal");
+    let root = tmp.path().join("root");
+    fs::create_dir_all(root.join("boot")).unwrap();
+    fs::write(root.join("boot/loader.cfg"), "root=\"LABEL=zOS\"\n").unwrap();
+
+    // Starts with the journal's magic number, so the journal has to escape it
+    let mut kernel = 0xC03B3998u32.to_be_bytes().to_vec();
+    kernel.extend((0..5000u32).flat_map(|i| i.to_le_bytes()));
+    let kernel_file = tmp.path().join("kernel");
+    fs::write(&kernel_file, &kernel).unwrap();
+    let garbage = tmp.path().join("garbage");
+    fs::write(&garbage, vec![0xAA; 4096]).unwrap();
+
+    let pristine = tmp.path().join("pristine.img");
+    let pristine = pristine.to_str().unwrap();
+    let updated = tmp.path().join("updated.img");
+    let crashed = tmp.path().join("crashed.img");
+    let crashed = crashed.to_str().unwrap();
+    let script = tmp.path().join("script");
+    let changes = tmp.path().join("changes");
+
+    for (features, journal_open) in [("64bit,metadata_csum", "jo -c"), ("64bit,metadata_csum", "jo -c -v 2"), ("^64bit,metadata_csum", "jo -c -v 2"),
+                                     ("64bit,^metadata_csum", "jo"), ("^64bit,^metadata_csum", "jo")] {
+        run("mkfs.ext4", &["-q", "-F", "-b", "4096", "-O", features, "-d", root.to_str().unwrap(), pristine, "16M"]);
+
+        // What the volume looks like once the crashed transaction is in place: a new file and directory, and an inode changed in place
+        fs::copy(pristine, &updated).unwrap();
+        fs::write(&script, format!("write {} /boot/kernel\nmkdir /boot/zxt\nsif /boot/loader.cfg size 5\n", kernel_file.display())).unwrap();
+        run("debugfs", &["-w", "-f", script.to_str().unwrap(), updated.to_str().unwrap()]);
+
+        let (before, after) = (fs::read(pristine).unwrap(), fs::read(&updated).unwrap());
+        let changed: Vec<usize> = (0..before.len() / 4096).filter(|i| before[i * 4096..(i + 1) * 4096] != after[i * 4096..(i + 1) * 4096]).collect();
+        fs::write(&changes, changed.iter().flat_map(|i| after[i * 4096..(i + 1) * 4096].to_vec()).collect::<Vec<u8>>()).unwrap();
+        let list = changed.iter().map(|i| i.to_string()).collect::<Vec<String>>().join(",");
+
+        // The loader.cfg block is overwritten by a transaction whose write is then revoked, and by one that never commits. Journal
+        // blocks 1-3 hold the first transaction, 4-5 the revoke and the changes follow from block 6 on.
+        let config_block = mount(ImageDevice::open(pristine).unwrap()).unwrap().extents("/boot/loader.cfg").unwrap()[0].physical;
+        fs::copy(pristine, crashed).unwrap();
+        fs::write(&script, format!("{}\njw -b {} {}\njw -r {}\njw -b {} {}\njw -b {} -c {}\njc\n", journal_open, config_block, garbage.display(),
+                                   config_block, list, changes.display(), config_block, garbage.display())).unwrap();
+        run("debugfs", &["-w", "-f", script.to_str().unwrap(), crashed]);
+
+        let volume = mount(ImageDevice::open(crashed).unwrap()).unwrap();
+        assert!(!volume.features().contains(&"needs_recovery".to_string()), "{}", features);
+        assert_eq!(volume.list_dir("/boot").unwrap(), mount(ImageDevice::open(&updated).unwrap()).unwrap().list_dir("/boot").unwrap());
+        assert_eq!(volume.read_file("/boot/kernel").unwrap(), kernel, "{}", features);
+        assert_eq!(volume.read_file("/boot/loader.cfg").unwrap(), b"root=", "{}", features);
+        assert!(volume.list_dir("/boot/zxt").unwrap().is_empty());
+
+        // Without an intact commit block, the changes never happened
+        if journal_open.starts_with("jo -c") {
+            let commit_block = 6 + changed.len() + 1;
+            let physical: usize = debugfs(crashed, &format!("bmap <8> {}", commit_block)).trim().parse().unwrap();
+            let mut contents = fs::read(crashed).unwrap();
+            contents[physical * 4096 + 100] ^= 1;
+            fs::write(crashed, &contents).unwrap();
+
+            let volume = mount(ImageDevice::open(crashed).unwrap()).unwrap();
+            assert_eq!(volume.read_file("/boot/kernel"), Err(FsError::NotFound));
+            assert_eq!(volume.read_file("/boot/loader.cfg").unwrap(), b"root=\"LABEL=zOS\"\n");
+        }
+    }
+}
+
+
+/// Extended attributes kept in the inode, in an attribute block and, for a value as large as a block, in an inode of their own
+#[test]
+fn reads_extended_attributes() {
+    let tmp = TempDir::new("ext-xattr");
+    let root = tmp.path().join("root");
+    fs::create_dir_all(root.join("boot")).unwrap();
+    fs::write(root.join("boot/loader.cfg"), "root=\"LABEL=zOS\"\n").unwrap();
+    fs::write(root.join("boot/kernel"), b"kernel").unwrap();
+
+    let image = tmp.path().join("ext4.img");
+    let image = image.to_str().unwrap();
+    run("mkfs.ext4", &["-q", "-F", "-b", "4096", "-I", "256", "-O", "ea_inode,metadata_csum", "-d", root.to_str().unwrap(), image, "16M"]);
+
+    let signature: Vec<u8> = (0..2048).map(|i| i as u8).collect();
+    let signature_file = tmp.path().join("signature");
+    fs::write(&signature_file, &signature).unwrap();
+    let huge_file = tmp.path().join("huge");
+    fs::write(&huge_file, vec![b'x'; 4096]).unwrap();
+
+    let script = tmp.path().join("script");
+    fs::write(&script, format!("ea_set /boot/kernel user.zos.flags load\nea_set -f {} /boot/kernel user.zos.signature\n\
+                                ea_set -f {} /boot/kernel trusted.huge\nea_set /boot/kernel security.selinux ctx\n",
+                               signature_file.display(), huge_file.display())).unwrap();
+    run("debugfs", &["-w", "-f", script.to_str().unwrap(), image]);
+
+    let volume = mount(ImageDevice::open(image).unwrap()).unwrap();
+    let mut names = volume.list_xattrs("/boot/kernel").unwrap();
+    names.sort();
+    assert_eq!(names, ["security.selinux", "trusted.huge", "user.zos.flags", "user.zos.signature"]);
+
+    assert_eq!(volume.get_xattr("/boot/kernel", "user.zos.flags").unwrap(), Some(b"load".to_vec()));
+    assert_eq!(volume.get_xattr("/boot/kernel", "security.selinux").unwrap(), Some(b"ctx".to_vec()));
+    assert_eq!(volume.get_xattr("/boot/kernel", "user.zos.signature").unwrap(), Some(signature));
+    assert_eq!(volume.get_xattr("/boot/kernel", "trusted.huge").unwrap(), Some(vec![b'x'; 4096]));
+    assert_eq!(volume.get_xattr("/boot/kernel", "user.zos.missing").unwrap(), None);
+    assert_eq!(volume.get_xattr("/boot/loader.cfg", "user.zos.flags").unwrap(), None);
+
+    // "File ACL: 1293"
+    let stat = debugfs(image, "stat /boot/kernel");
+    let block: usize = stat.split("File ACL: ").nth(1).unwrap().split_whitespace().next().unwrap().parse().unwrap();
+    let mut contents = fs::read(image).unwrap();
+    contents[block * 4096 + 40] ^= 1;
+    fs::write(image, &contents).unwrap();
+
+    let volume = mount(ImageDevice::open(image).unwrap()).unwrap();
+    assert_eq!(volume.get_xattr("/boot/kernel", "user.zos.signature"), Err(FsError::Corrupt("ext xattr block checksum mismatch")));
+}
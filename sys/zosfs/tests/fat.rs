@@ -0,0 +1,184 @@
+/*  fat.rs - Tests for the FAT driver against images built by mkfs.fat and mtools
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+mod common;
+
+use std::fs;
+use std::process::Command;
+use common::{run, TempDir};
+use zosfs::image::ImageDevice;
+use zosfs::{mount, FileType, FilesystemType, FsError, Mount};
+
+
+/// 2024-05-17 12:34:56, an even number of seconds as FAT only stores those
+const MTIME: i64 = 1715949296;
+
+
+/// Formats an image of *size_kb* KiB as FAT*bits* with one sector per cluster and fills it with mtools. Returns the image's path.
+///
+/// The image gets a kernel spanning many clusters, a directory too big for one cluster and a long name, which covers chains, FAT12 packing and
+/// VFAT entries.
+fn build_image(tmp: &TempDir, bits: u32, size_kb: u32) -> String {
+    let image = tmp.path().join(format!("fat{}.img", bits));
+    let image = image.to_str().unwrap();
+
+    let kernel = tmp.path().join("kernel-6.2.elf");
+    fs::write(&kernel, kernel_contents()).unwrap();
+
+    let mut files: Vec<String> = Vec::new();
+    for i in 0..40 {
+        let file = tmp.path().join(format!("F{}.TXT", i));
+        fs::write(&file, format!("file {}\n", i)).unwrap();
+        files.push(file.to_str().unwrap().to_string());
+    }
+
+    run("mkfs.fat", &["-C", "-F", &bits.to_string(), "-s", "1", "-n", "ZOSESP", image, &size_kb.to_string()]);
+    run("mmd", &["-i", image, "::/EFI", "::/EFI/BOOT", "::/EFI/BOOT/ZOS"]);
+
+    run("mcopy", &["-i", image, kernel.to_str().unwrap(), "::/EFI/BOOT/ZOS/"]);
+
+    let mut args = vec!["-i", image];
+    args.extend(files.iter().map(|file| file.as_str()));
+    args.push("::/EFI/BOOT/");
+    run("mcopy", &args);
+
+    image.to_string()
+}
+
+
+/// A few clusters worth of bytes that differ from cluster to cluster, so reading the wrong one shows up
+fn kernel_contents() -> Vec<u8> {
+    (0..20000u32).flat_map(|i| i.to_le_bytes()).collect()
+}
+
+
+/// Checks everything build_image put on the volume
+fn check_volume(mount: &Mount<ImageDevice>) {
+    assert_eq!(mount.fs_type(), FilesystemType::FAT);
+    assert_eq!(mount.volume_id().unwrap().1, "ZOSESP");
+
+    let boot = mount.list_dir("/EFI/BOOT").unwrap();
+    assert_eq!(boot.len(), 41);
+    assert!(boot.contains(&("ZOS".into(), FileType::Directory)));
+
+    // Long name, matched case insensitively, and its 8.3 alias
+    assert_eq!(mount.list_dir("/EFI/BOOT/ZOS").unwrap(), vec![("kernel-6.2.elf".into(), FileType::Regular)]);
+    assert_eq!(mount.read_file("/efi/boot/zos/KERNEL-6.2.ELF").unwrap(), kernel_contents());
+    assert_eq!(mount.read_file("/EFI/BOOT/ZOS/KERNEL~1.ELF").unwrap(), kernel_contents());
+
+    assert_eq!(mount.read_file("/EFI/BOOT/F39.TXT").unwrap(), b"file 39\n");
+
+    let mut buffer = [0u8; 8];
+    assert_eq!(mount.read_at("/EFI/BOOT/ZOS/kernel-6.2.elf", 4 * 10000, &mut buffer).unwrap(), 8);
+    assert_eq!(buffer, [0x10, 0x27, 0, 0, 0x11, 0x27, 0, 0]);
+}
+
+
+/// Writes to the volume the way the loader does, then checks the result with fsck.fat and by reading it back
+fn check_writes(image: &str) {
+    {
+        let mount = mount(ImageDevice::open_writable(image).unwrap()).unwrap();
+
+        // A new file with a long name, an empty one, and enough others that the directory needs more clusters
+        mount.write_file("/EFI/BOOT/ZOS/boot.log", b"booted\n", MTIME).unwrap();
+        mount.write_file("/EFI/BOOT/ZOS/LAST", b"", MTIME).unwrap();
+        for i in 0..40 {
+            mount.write_file(&format!("/EFI/BOOT/ZOS/crash-{}.txt", i), format!("crash {}\n", i).as_bytes(), MTIME).unwrap();
+        }
+
+        // Shrink one file and grow another past one cluster
+        mount.write_file("/EFI/BOOT/ZOS/KERNEL-6.2.ELF", b"small", MTIME).unwrap();
+        mount.write_file("/EFI/BOOT/F1.TXT", &kernel_contents(), MTIME).unwrap();
+
+        assert_eq!(mount.write_file("/EFI/BOOT/ZOS", b"", MTIME), Err(FsError::IsADirectory));
+        assert_eq!(mount.write_file("/EFI/BOOT/F2.TXT/log", b"", MTIME), Err(FsError::NotADirectory));
+        assert_eq!(mount.write_file("/EFI/BOOT/a:b", b"", MTIME), Err(FsError::InvalidName));
+    }
+
+    // fsck.fat exits non-zero when it finds something to repair, and says what on stdout
+    let fsck = Command::new("fsck.fat").args(["-n", image]).output().expect("couldn't run fsck.fat");
+    assert!(fsck.status.success(), "fsck.fat found errors: {}", String::from_utf8_lossy(&fsck.stdout));
+
+    let mount = mount(ImageDevice::open(image).unwrap()).unwrap();
+    let zos = mount.list_dir("/EFI/BOOT/ZOS").unwrap();
+    assert_eq!(zos.len(), 43);
+    assert!(zos.contains(&("boot.log".into(), FileType::Regular)));
+    assert!(zos.contains(&("LAST".into(), FileType::Regular)));
+
+    assert_eq!(mount.read_file("/EFI/BOOT/ZOS/boot.log").unwrap(), b"booted\n");
+    assert_eq!(mount.read_file("/EFI/BOOT/ZOS/crash-39.txt").unwrap(), b"crash 39\n");
+    assert_eq!(mount.read_file("/EFI/BOOT/ZOS/LAST").unwrap(), b"");
+    assert_eq!(mount.read_file("/EFI/BOOT/ZOS/kernel-6.2.elf").unwrap(), b"small");
+    assert_eq!(mount.read_file("/EFI/BOOT/F1.TXT").unwrap(), kernel_contents());
+    assert_eq!(mount.metadata("/EFI/BOOT/ZOS/boot.log").unwrap().mtime, MTIME);
+}
+
+
+#[test]
+fn reads_fat12() {
+    let tmp = TempDir::new("fat12");
+    let image = build_image(&tmp, 12, 1440);
+
+    check_volume(&mount(ImageDevice::open(image).unwrap()).unwrap());
+}
+
+
+#[test]
+fn writes_fat12() {
+    let tmp = TempDir::new("fat12-write");
+    let image = build_image(&tmp, 12, 1440);
+
+    check_writes(&image);
+}
+
+
+#[test]
+fn reads_fat16() {
+    let tmp = TempDir::new("fat16");
+    let image = build_image(&tmp, 16, 16 * 1024);
+
+    check_volume(&mount(ImageDevice::open(image).unwrap()).unwrap());
+}
+
+
+#[test]
+fn writes_fat16() {
+    let tmp = TempDir::new("fat16-write");
+    let image = build_image(&tmp, 16, 16 * 1024);
+
+    check_writes(&image);
+}
+
+
+#[test]
+fn reads_fat32() {
+    let tmp = TempDir::new("fat32");
+    let image = build_image(&tmp, 32, 40 * 1024);
+
+    check_volume(&mount(ImageDevice::open(image).unwrap()).unwrap());
+}
+
+
+#[test]
+fn writes_fat32() {
+    let tmp = TempDir::new("fat32-write");
+    let image = build_image(&tmp, 32, 40 * 1024);
+
+    check_writes(&image);
+}
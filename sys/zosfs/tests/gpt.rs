@@ -0,0 +1,108 @@
+/*  gpt.rs - Tests for GPT parsing and partition devices
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+mod common;
+
+use std::fs;
+use common::{run, TempDir};
+use zosfs::gpt;
+use zosfs::image::ImageDevice;
+use zosfs::uuid::GUID;
+use zosfs::{mount, BlockDevice, DiskError, FilesystemType};
+
+
+const PART_START: u64 = 2048;
+
+
+/// Writes a disk image with a GPT holding one partition, named "rootfs", containing *fs_image*. The CRCs are left at zero as the driver
+/// doesn't check them.
+fn build_disk(disk: &str, fs_image: &str, guid: GUID) {
+    let contents = fs::read(fs_image).unwrap();
+    let part_blocks = contents.len() as u64 / 512;
+
+    let mut image = vec![0u8; ((PART_START + part_blocks + 34) * 512) as usize];
+
+    // Header in LBA 1, entries in LBA 2
+    let header = &mut image[512..1024];
+    header[0..8].copy_from_slice(b"EFI PART");
+    header[8..12].copy_from_slice(&0x10000u32.to_le_bytes());
+    header[12..16].copy_from_slice(&92u32.to_le_bytes());
+    header[72..80].copy_from_slice(&2u64.to_le_bytes());
+    header[80..84].copy_from_slice(&128u32.to_le_bytes());
+    header[84..88].copy_from_slice(&128u32.to_le_bytes());
+
+    // Entry 2, leaving entry 1 unused to check the numbering
+    let entry = &mut image[1024 + 128..1024 + 256];
+    let type_guid = GUID::new_from_string("0FC63DAF-8483-4772-8E79-3D69D8477DE4").unwrap();
+    entry[0..16].copy_from_slice(&guid_bytes(type_guid));
+    entry[16..32].copy_from_slice(&guid_bytes(guid));
+    entry[32..40].copy_from_slice(&PART_START.to_le_bytes());
+    entry[40..48].copy_from_slice(&(PART_START + part_blocks - 1).to_le_bytes());
+    for (i, c) in "rootfs".encode_utf16().enumerate() {
+        entry[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+    }
+
+    let start = (PART_START * 512) as usize;
+    image[start..start + contents.len()].copy_from_slice(&contents);
+
+    fs::write(disk, image).unwrap();
+}
+
+
+/// Returns the on-disk bytes of a GUID
+fn guid_bytes(guid: GUID) -> [u8; 16] {
+    unsafe { core::mem::transmute(guid) }
+}
+
+
+#[test]
+fn mounts_gpt_partition() {
+    let tmp = TempDir::new("gpt");
+    let root = tmp.path().join("root");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("hello"), b"hello").unwrap();
+
+    let fs_image = tmp.path().join("ext4.img");
+    let fs_image = fs_image.to_str().unwrap();
+    run("mkfs.ext4", &["-q", "-F", "-b", "4096", "-d", root.to_str().unwrap(), fs_image, "4M"]);
+
+    let disk = tmp.path().join("disk.img");
+    let disk = disk.to_str().unwrap();
+    let guid = GUID::new_from_string("6A2C5F1E-3B8D-4C29-9E71-0D4F8A2B7C36").unwrap();
+    build_disk(disk, fs_image, guid);
+
+    let dev = ImageDevice::open(disk).unwrap();
+    let partitions = gpt::read_partitions(&dev).unwrap();
+
+    assert_eq!(partitions.len(), 1);
+    let entry = &partitions[0];
+    assert_eq!(entry.index, 1);
+    assert_eq!(entry.guid, guid);
+    assert_eq!(entry.first_lba, PART_START);
+    assert_eq!(entry.name, "rootfs");
+
+    // Reads past the end of the partition must not reach the rest of the disk
+    let part = entry.open(&dev);
+    let mut block = [0u8; 512];
+    assert_eq!(part.read_blocks(entry.blocks(), &mut block), Err(DiskError::OutOfRange));
+
+    let mount = mount(part).unwrap();
+    assert_eq!(mount.fs_type(), FilesystemType::EXT);
+    assert_eq!(mount.read_file("/hello").unwrap(), b"hello");
+}
@@ -0,0 +1,153 @@
+/*  iso9660.rs - ISO9660 driver tests
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+mod common;
+
+use std::fs;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use common::{run, TempDir};
+use zosfs::image::ImageDevice;
+use zosfs::iso9660::{IsoFs, PLATFORM_EFI};
+use zosfs::{mount, FileType, FilesystemType, FsError, Mount};
+
+
+const LONG_NAME: &str = "A file name far too long for plain ISO9660.txt";
+
+
+fn kernel_contents() -> Vec<u8> {
+    (0..100_000u32).map(|i| (i * 13) as u8).collect()
+}
+
+fn efiboot_contents() -> Vec<u8> {
+    (0..5000u32).map(|i| (i % 251) as u8).collect()
+}
+
+
+/// Builds an ISO with xorriso, passing *args* on top of the volume label, date and source tree. Returns the image's path.
+fn build_iso(tmp: &TempDir, args: &[&str]) -> String {
+    let root = tmp.path().join("root");
+    fs::create_dir_all(root.join("boot/zxt")).unwrap();
+    fs::create_dir_all(root.join("efi")).unwrap();
+
+    fs::write(root.join("boot/loader.cfg"), "root=\"LABEL=ZOS_LIVE\"\n").unwrap();
+    fs::write(root.join("boot/kernel"), kernel_contents()).unwrap();
+    fs::write(root.join("boot/zxt/hello.zxt"), b"hello").unwrap();
+    fs::set_permissions(root.join("boot/zxt/hello.zxt"), fs::Permissions::from_mode(0o640)).unwrap();
+    symlink("kernel", root.join("boot/current")).unwrap();
+    fs::write(root.join("ReadMe.txt"), b"read me").unwrap();
+    fs::write(root.join(LONG_NAME), b"long").unwrap();
+    fs::write(root.join("efi/efiboot.img"), efiboot_contents()).unwrap();
+
+    let image = tmp.path().join("zos.iso");
+    let image = image.to_str().unwrap().to_string();
+
+    let mut xorriso_args = vec!["-as", "mkisofs", "-quiet", "-V", "ZOS_LIVE", "--modification-date=2024051712345600", "-o", &image];
+    xorriso_args.extend(args);
+    xorriso_args.push(root.to_str().unwrap());
+
+    run("xorriso", &xorriso_args);
+
+    image
+}
+
+
+fn open(image: &str) -> Mount<ImageDevice> {
+    mount(ImageDevice::open(image).unwrap().with_block_size(2048)).unwrap()
+}
+
+
+#[test]
+fn reads_rock_ridge() {
+    let tmp = TempDir::new("iso-rr");
+    let image = build_iso(&tmp, &["-R", "-J"]);
+    let mount = open(&image);
+
+    assert_eq!(mount.fs_type(), FilesystemType::ISO9660);
+    // blkid shows the modification date as the UUID, "2024-05-17-12-34-56-00"
+    assert_eq!(mount.volume_id().unwrap(), (0x2024051712345600, "ZOS_LIVE".to_string()));
+
+    let root = mount.list_dir("/").unwrap();
+    assert!(root.contains(&("ReadMe.txt".into(), FileType::Regular)));
+    assert!(root.contains(&(LONG_NAME.into(), FileType::Regular)));
+    assert!(root.contains(&("boot".into(), FileType::Directory)));
+
+    let boot = mount.list_dir("/boot").unwrap();
+    assert!(boot.contains(&("current".into(), FileType::Symlink)));
+
+    assert_eq!(mount.read_file("/boot/loader.cfg").unwrap(), b"root=\"LABEL=ZOS_LIVE\"\n");
+    assert_eq!(mount.read_file("/boot/kernel").unwrap(), kernel_contents());
+    assert_eq!(mount.read_file("/boot/current").unwrap(), kernel_contents());
+    assert_eq!(mount.read_file(&format!("/{}", LONG_NAME)).unwrap(), b"long");
+
+    // Rock Ridge keeps POSIX permissions and case sensitive names
+    assert_eq!(mount.metadata("/boot/zxt/hello.zxt").unwrap().mode, 0o640);
+    assert!(matches!(mount.read_file("/README.TXT"), Err(FsError::NotFound)));
+
+    let extents = mount.extents("/boot/kernel").unwrap();
+    assert_eq!(extents.len(), 1);
+    assert_eq!(extents[0].length, 100_000u64.div_ceil(2048));
+}
+
+
+#[test]
+fn reads_joliet() {
+    let tmp = TempDir::new("iso-joliet");
+    let image = build_iso(&tmp, &["-J"]);
+    let mount = open(&image);
+
+    let root = mount.list_dir("/").unwrap();
+    assert!(root.contains(&("ReadMe.txt".into(), FileType::Regular)));
+    assert!(root.contains(&(LONG_NAME.into(), FileType::Regular)));
+
+    // Joliet names keep their case but are compared without it
+    assert_eq!(mount.read_file("/README.TXT").unwrap(), b"read me");
+    assert_eq!(mount.read_file("/Boot/ZXT/hello.zxt").unwrap(), b"hello");
+    assert_eq!(mount.read_file("/boot/kernel").unwrap(), kernel_contents());
+}
+
+
+#[test]
+fn reads_plain_iso9660() {
+    let tmp = TempDir::new("iso-plain");
+    let image = build_iso(&tmp, &[]);
+    let mount = open(&image);
+
+    assert!(mount.list_dir("/").unwrap().contains(&("README.TXT".into(), FileType::Regular)));
+    assert_eq!(mount.read_file("/boot/loader.cfg").unwrap(), b"root=\"LABEL=ZOS_LIVE\"\n");
+    assert_eq!(mount.metadata("/boot/loader.cfg").unwrap().mode, 0o444);
+}
+
+
+#[test]
+fn lists_el_torito_boot_images() {
+    let tmp = TempDir::new("iso-eltorito");
+    let image = build_iso(&tmp, &["-R", "-e", "efi/efiboot.img", "-no-emul-boot"]);
+
+    let iso = IsoFs::new(ImageDevice::open(&image).unwrap().with_block_size(2048)).unwrap();
+    let images = iso.boot_images().unwrap();
+    let efi = images.iter().find(|image| image.platform == PLATFORM_EFI && image.bootable).expect("no EFI boot image");
+
+    // The catalog points at the same blocks as the file
+    let contents = fs::read(&image).unwrap();
+    let start = efi.start as usize * 2048;
+    assert_eq!(&contents[start..start + 5000], efiboot_contents());
+
+    let info = iso.superblock_info().unwrap();
+    assert!(info.iter().any(|(name, value)| *name == "Boot image" && value.starts_with("EFI")));
+}
@@ -0,0 +1,151 @@
+/*  squashfs.rs - Tests for the SquashFS driver against images built by mksquashfs
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+mod common;
+
+use std::fs;
+use common::{run, TempDir};
+use zosfs::image::ImageDevice;
+use zosfs::{mount, FileType, FilesystemType, FsError, Mount};
+
+
+/// 2024-05-17 12:34:56
+const MTIME: i64 = 1715949296;
+
+
+/// Several blocks worth of bytes that differ from block to block, with a run of zeros long enough to be stored as a sparse block
+fn kernel_contents() -> Vec<u8> {
+    let mut kernel: Vec<u8> = (0..100000u32).flat_map(|i| i.to_le_bytes()).collect();
+    kernel.splice(140000..140000, std::iter::repeat_n(0, 200000));
+    kernel
+}
+
+
+/// Packs a small root filesystem with mksquashfs, compressed with *compression* and 64KiB blocks. Returns the image's path.
+///
+/// Besides files spanning blocks and fragments, the image gets a directory big enough to need several metadata blocks, a hard link and
+/// a symlink.
+fn build_image(tmp: &TempDir, compression: &str, extra: &[&str]) -> String {
+    let root = tmp.path().join("root");
+    fs::create_dir_all(root.join("boot/zxt")).unwrap();
+    fs::create_dir_all(root.join("etc/many")).unwrap();
+
+    fs::write(root.join("boot/kernel"), kernel_contents()).unwrap();
+    fs::write(root.join("boot/zxt/hello.zxt"), b"hello").unwrap();
+    fs::write(root.join("boot/empty"), b"").unwrap();
+    fs::hard_link(root.join("boot/zxt/hello.zxt"), root.join("boot/hello-again.zxt")).unwrap();
+    std::os::unix::fs::symlink("../boot/kernel", root.join("etc/kernel")).unwrap();
+    for i in 0..500 {
+        fs::write(root.join(format!("etc/many/file-with-a-longish-name-{}", i)), format!("file {}\n", i)).unwrap();
+    }
+
+    let image = tmp.path().join(format!("{}.sqfs", compression));
+    let image = image.to_str().unwrap();
+
+    let mut args = vec![root.to_str().unwrap(), image, "-noappend", "-quiet", "-no-progress", "-no-xattrs", "-all-root",
+                        "-all-time", "1715949296", "-comp", compression, "-b", "65536"];
+    args.extend(extra);
+    run("mksquashfs", &args);
+
+    image.to_string()
+}
+
+
+/// Checks everything build_image put in the filesystem
+fn check_volume<D: zosfs::BlockDevice>(mount: &Mount<D>) {
+    assert_eq!(mount.fs_type(), FilesystemType::SQUASHFS);
+
+    let boot = mount.list_dir("/boot").unwrap();
+    assert_eq!(boot.len(), 4);
+    assert!(boot.contains(&("kernel".into(), FileType::Regular)));
+    assert!(boot.contains(&("zxt".into(), FileType::Directory)));
+
+    assert_eq!(mount.read_file("/boot/kernel").unwrap(), kernel_contents());
+    assert_eq!(mount.read_file("/etc/kernel").unwrap(), kernel_contents());
+    assert_eq!(mount.read_file("/boot/zxt/hello.zxt").unwrap(), b"hello");
+    assert_eq!(mount.read_file("/boot/hello-again.zxt").unwrap(), b"hello");
+    assert_eq!(mount.read_file("/boot/empty").unwrap(), b"");
+
+    let many = mount.list_dir("/etc/many").unwrap();
+    assert_eq!(many.len(), 500);
+    assert_eq!(mount.read_file("/etc/many/file-with-a-longish-name-499").unwrap(), b"file 499\n");
+
+    let metadata = mount.metadata("/boot/kernel").unwrap();
+    assert_eq!((metadata.size, metadata.mtime, metadata.uid), (kernel_contents().len() as u64, MTIME, 0));
+
+    // Reads straddling a block boundary, and inside the sparse block
+    let mut buffer = [0u8; 8];
+    assert_eq!(mount.read_at("/boot/kernel", 65536 - 4, &mut buffer).unwrap(), 8);
+    assert_eq!(buffer, [0xFF, 0x3F, 0, 0, 0, 0x40, 0, 0]);
+    assert_eq!(mount.read_at("/boot/kernel", 200000, &mut buffer).unwrap(), 8);
+    assert_eq!(buffer, [0; 8]);
+
+    assert_eq!(mount.read_file("/Boot/kernel"), Err(FsError::NotFound));
+    assert_eq!(mount.write_file("/boot/new", b"", MTIME), Err(FsError::Unsupported("writing to SquashFS")));
+}
+
+
+fn mount_image(compression: &str, extra: &[&str], name: &str) {
+    let tmp = TempDir::new(name);
+    let image = build_image(&tmp, compression, extra);
+
+    check_volume(&mount(ImageDevice::open(image).unwrap()).unwrap());
+}
+
+
+#[test]
+fn reads_gzip() {
+    mount_image("gzip", &[], "squashfs-gzip");
+}
+
+
+#[test]
+fn reads_zstd() {
+    mount_image("zstd", &[], "squashfs-zstd");
+}
+
+
+#[test]
+fn reads_lz4() {
+    mount_image("lz4", &["-Xhc"], "squashfs-lz4");
+}
+
+
+#[test]
+fn reads_uncompressed() {
+    mount_image("gzip", &["-noI", "-noD", "-noF", "-no-fragments"], "squashfs-uncompressed");
+}
+
+
+/// Opens a SquashFS image stored as a file, the way the loader does for "archive:" paths
+#[test]
+fn opens_image_file() {
+    let tmp = TempDir::new("squashfs-file");
+    let image = build_image(&tmp, "zstd", &[]);
+
+    let root = tmp.path().join("ext");
+    fs::create_dir_all(root.join("images")).unwrap();
+    fs::copy(image, root.join("images/root.sqfs")).unwrap();
+
+    let ext = tmp.path().join("ext4.img");
+    let ext = ext.to_str().unwrap();
+    run("mkfs.ext4", &["-q", "-F", "-b", "4096", "-d", root.to_str().unwrap(), ext, "8M"]);
+
+    check_volume(&mount(ImageDevice::open(ext).unwrap()).unwrap().open_archive("/images/root.sqfs").unwrap());
+}
@@ -0,0 +1,175 @@
+/*  zxfs.rs - ZXFS driver tests
+ *
+ *  zOS  --  Advanced *NIX System
+ *  Copyright (C) 2024  Free Software Foundation, Inc.
+ *
+ *  zOS is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  zOS is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with zOS. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+mod common;
+
+use std::fs;
+use std::time::{Duration, UNIX_EPOCH};
+use common::{run, TempDir};
+use zosfs::image::ImageDevice;
+use zosfs::zxfs::ZxFs;
+use zosfs::{fs as zfs, mount, FileType, Filesystem, FilesystemType, FsError, Mount};
+
+
+/// 2024-05-17 12:34:56
+const MTIME: i64 = 1715949296;
+
+const UUID: &str = "1b4e28ba-2fa1-11d2-883f-b9a761bde3fb";
+
+
+/// Several blocks worth of bytes that differ from block to block, with a run of zeros long enough to leave holes
+fn kernel_contents() -> Vec<u8> {
+    let mut kernel: Vec<u8> = (0..100000u32).flat_map(|i| i.to_le_bytes()).collect();
+    kernel.splice(140000..140000, std::iter::repeat_n(0, 200000));
+    kernel
+}
+
+
+/// Formats a small root filesystem with mkfs-zxfs and *args*. Returns the image's path.
+///
+/// Besides files spanning blocks and holes, the image gets a directory needing several blocks, a hard link, a symlink short enough to
+/// be stored in its inode and one that isn't.
+fn build_image(tmp: &TempDir, args: &[&str]) -> String {
+    let root = tmp.path().join("root");
+    fs::create_dir_all(root.join("boot/zxt")).unwrap();
+    fs::create_dir_all(root.join("etc/many")).unwrap();
+
+    fs::write(root.join("boot/kernel"), kernel_contents()).unwrap();
+    fs::write(root.join("boot/zxt/hello.zxt"), b"hello").unwrap();
+    fs::write(root.join("boot/empty"), b"").unwrap();
+    fs::hard_link(root.join("boot/zxt/hello.zxt"), root.join("boot/hello-again.zxt")).unwrap();
+    std::os::unix::fs::symlink("../boot/kernel", root.join("etc/kernel")).unwrap();
+    std::os::unix::fs::symlink(format!("{}/../boot/kernel", "./".repeat(100)), root.join("etc/long-link")).unwrap();
+    for i in 0..500 {
+        fs::write(root.join(format!("etc/many/file-with-a-longish-name-{}", i)), format!("file {}\n", i)).unwrap();
+    }
+    fs::File::options().write(true).open(root.join("boot/kernel")).unwrap()
+        .set_modified(UNIX_EPOCH + Duration::from_secs(MTIME as u64)).unwrap();
+
+    let image = tmp.path().join("zxfs.img");
+    let image = image.to_str().unwrap();
+
+    let mut mkfs_args = vec!["-L", "zOS", "-U", UUID, "-d", root.to_str().unwrap()];
+    mkfs_args.extend(args);
+    mkfs_args.extend([image, "4M"]);
+    run(env!("CARGO_BIN_EXE_mkfs-zxfs"), &mkfs_args);
+
+    image.to_string()
+}
+
+
+/// Checks everything build_image put in the filesystem
+fn check_volume<D: zosfs::BlockDevice>(mount: &Mount<D>) {
+    assert_eq!(mount.fs_type(), FilesystemType::ZXFS);
+    assert_eq!(mount.volume_id().unwrap(), (0x1b4e28ba2fa111d2883fb9a761bde3fb, "zOS".to_string()));
+
+    let boot = mount.list_dir("/boot").unwrap();
+    assert_eq!(boot.len(), 4);
+    assert!(boot.contains(&("kernel".into(), FileType::Regular)));
+    assert!(boot.contains(&("zxt".into(), FileType::Directory)));
+
+    assert_eq!(mount.read_file("/boot/kernel").unwrap(), kernel_contents());
+    assert_eq!(mount.read_file("/etc/kernel").unwrap(), kernel_contents());
+    assert_eq!(mount.read_file("/etc/long-link").unwrap(), kernel_contents());
+    assert_eq!(mount.read_file("/boot/zxt/hello.zxt").unwrap(), b"hello");
+    assert_eq!(mount.read_file("/boot/hello-again.zxt").unwrap(), b"hello");
+    assert_eq!(mount.read_file("/boot/empty").unwrap(), b"");
+
+    let many = mount.list_dir("/etc/many").unwrap();
+    assert_eq!(many.len(), 500);
+    assert_eq!(mount.read_file("/etc/many/file-with-a-longish-name-499").unwrap(), b"file 499\n");
+
+    let metadata = mount.metadata("/boot/kernel").unwrap();
+    assert_eq!((metadata.size, metadata.mtime, metadata.mode), (kernel_contents().len() as u64, MTIME, 0o644));
+    assert_eq!(mount.metadata("/boot/hello-again.zxt").unwrap().id, mount.metadata("/boot/zxt/hello.zxt").unwrap().id);
+
+    // Reads straddling a block boundary, and inside a hole
+    let mut buffer = [0u8; 8];
+    assert_eq!(mount.read_at("/boot/kernel", 4096 - 4, &mut buffer).unwrap(), 8);
+    assert_eq!(buffer, [0xFF, 0x03, 0, 0, 0, 0x04, 0, 0]);
+    assert_eq!(mount.read_at("/boot/kernel", 200000, &mut buffer).unwrap(), 8);
+    assert_eq!(buffer, [0; 8]);
+
+    assert_eq!(mount.read_file("/Boot/kernel"), Err(FsError::NotFound));
+    assert_eq!(mount.write_file("/boot/new", b"", MTIME), Err(FsError::Unsupported("writing to ZXFS")));
+}
+
+
+#[test]
+fn reads_volume() {
+    let tmp = TempDir::new("zxfs");
+    let image = build_image(&tmp, &[]);
+
+    let mount = mount(ImageDevice::open(image).unwrap()).unwrap();
+    check_volume(&mount);
+
+    // The run of zeros is left as a hole
+    let extents = mount.extents("/boot/kernel").unwrap();
+    assert_eq!(extents.len(), 2);
+    assert_eq!(extents.iter().map(|extent| extent.length).sum::<u64>(), 99);
+}
+
+
+/// Small blocks and one block extents give files more extents than fit in the inode, so they go through extent blocks
+#[test]
+fn reads_extent_tree() {
+    let tmp = TempDir::new("zxfs-extents");
+    let image = build_image(&tmp, &["-b", "1024", "-e", "1"]);
+
+    let mount = mount(ImageDevice::open(image).unwrap()).unwrap();
+    check_volume(&mount);
+    assert_eq!(mount.extents("/boot/kernel").unwrap().len(), 391);
+}
+
+
+#[test]
+fn reads_symlinks() {
+    let tmp = TempDir::new("zxfs-symlinks");
+    let zxfs = ZxFs::new(ImageDevice::open(build_image(&tmp, &[])).unwrap()).unwrap();
+
+    let etc = zfs::resolve(&zxfs, "/etc").unwrap();
+
+    for (name, target) in [("kernel", "../boot/kernel".to_string()), ("long-link", format!("{}/../boot/kernel", "./".repeat(100)))] {
+        let link = zxfs.lookup(&etc.node, name).unwrap().unwrap();
+        assert_eq!(link.file_type, FileType::Symlink);
+        assert_eq!(zxfs.read_link(&link.node).unwrap(), target);
+    }
+}
+
+
+/// Damaged metadata is reported rather than read
+#[test]
+fn detects_corruption() {
+    let tmp = TempDir::new("zxfs-corrupt");
+    let image = build_image(&tmp, &[]);
+
+    let dir_block = mount(ImageDevice::open(&image).unwrap()).unwrap().extents("/etc/many").unwrap()[0].physical;
+    let mut contents = fs::read(&image).unwrap();
+    contents[dir_block as usize * 4096 + 40] ^= 1;
+    fs::write(&image, &contents).unwrap();
+
+    let mount = mount(ImageDevice::open(&image).unwrap()).unwrap();
+    assert_eq!(mount.read_file("/boot/zxt/hello.zxt").unwrap(), b"hello");
+    assert_eq!(mount.list_dir("/etc/many"), Err(FsError::Corrupt("ZXFS directory block checksum mismatch")));
+
+    // The label is covered by the superblock checksum
+    contents[100] ^= 1;
+    fs::write(&image, &contents).unwrap();
+    assert!(matches!(zosfs::mount(ImageDevice::open(&image).unwrap()), Err(FsError::Corrupt("ZXFS superblock checksum mismatch"))));
+}